@@ -1,9 +1,11 @@
 use serde::{Deserialize, Serialize};
 use std::path::Path;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use crate::error::{PKError, PKResult};
 
 pub mod nonmem;
+#[cfg(test)]
+pub(crate) mod testing;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -11,12 +13,63 @@ pub struct Config {
     pub dosing: DosingConfig,
     pub population: PopulationConfig,
     pub simulation: SimulationConfig,
+    #[serde(default)]
+    pub nca: Option<NcaConfig>,
+    #[serde(default)]
+    pub exposure: Option<ExposureConfig>,
+    #[serde(default)]
+    pub summary: Option<SummaryConfig>,
+    #[serde(default)]
+    pub vpc: Option<VpcConfig>,
+    #[serde(default)]
+    pub units: UnitsConfig,
+    #[serde(default)]
+    pub report: Option<ReportConfig>,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub estimation: Option<EstimationConfig>,
+    #[serde(default)]
+    pub map: Option<MapConfig>,
+    #[serde(default)]
+    pub dose_optimization: Option<DoseOptimizationConfig>,
+    #[serde(default)]
+    pub pta: Option<PtaConfig>,
+    #[serde(default)]
+    pub trial: Option<TrialConfig>,
+    #[serde(default)]
+    pub study: Option<StudyConfig>,
+    #[serde(default)]
+    pub design: Option<DesignConfig>,
+    #[serde(default)]
+    pub sensitivity: Option<SensitivityConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelConfig {
     pub compartments: u8, // 1, 2, or 3
     pub parameters: HashMap<String, ParameterConfig>,
+    #[serde(default)]
+    pub parameterization: Option<Parameterization>, // Clearance/volume form if omitted
+}
+
+impl ModelConfig {
+    pub fn parameterization(&self) -> Parameterization {
+        self.parameterization.clone()
+            .unwrap_or_else(|| Parameterization::default_for(self.compartments))
+    }
+}
+
+/// NONMEM TRANS parameterizations of the structural model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Parameterization {
+    Trans1, // K, K12, K21, (K13, K31), V
+    Trans2, // CL, V (one compartment)
+    Trans3, // CL, V, Q, VSS
+    Trans4, // CL, V1, Q, V2, (Q3, V3)
+    Trans5, // AOB, ALPHA, BETA, V
+    Trans6, // ALPHA, BETA, (GAMMA), K21, (K31), V
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,6 +77,8 @@ pub struct ParameterConfig {
     pub theta: f64,           // Typical value
     pub omega: Option<f64>,   // Inter-individual variability (CV%)
     pub bounds: Option<(f64, f64)>, // Lower and upper bounds
+    #[serde(default)]
+    pub unit: Option<String>, // Converted to the model units if given
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -55,12 +110,47 @@ pub struct PopulationConfig {
     pub covariates: Option<HashMap<String, CovariateConfig>>,
 }
 
+/// Normal distributions of weight and age, truncated to their bounds.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DemographicsConfig {
     pub weight_mean: f64,
     pub weight_sd: f64,
     pub age_mean: f64,
     pub age_sd: f64,
+    #[serde(default)]
+    pub weight_bounds: Option<(f64, f64)>, // Positive weights if omitted
+    #[serde(default)]
+    pub age_bounds: Option<(f64, f64)>,    // Non-negative ages if omitted
+}
+
+impl DemographicsConfig {
+    pub fn weight_bounds(&self) -> (f64, f64) {
+        self.weight_bounds.unwrap_or((0.0, f64::INFINITY))
+    }
+
+    pub fn age_bounds(&self) -> (f64, f64) {
+        self.age_bounds.unwrap_or((0.0, f64::INFINITY))
+    }
+
+    pub fn validate(&self) -> PKResult<()> {
+        let (weight_lower, weight_upper) = self.weight_bounds();
+        let (age_lower, age_upper) = self.age_bounds();
+        if weight_lower < 0.0 || age_lower < 0.0 || weight_lower >= weight_upper || age_lower >= age_upper {
+            return Err(PKError::Validation(
+                "Demographic bounds must not be negative and must end above where they start".to_string()
+            ));
+        }
+        if !(weight_lower..=weight_upper).contains(&self.weight_mean) || self.weight_mean <= 0.0
+            || !(age_lower..=age_upper).contains(&self.age_mean) {
+            return Err(PKError::Validation(
+                "Mean weight and age must be within their bounds, with a positive weight".to_string()
+            ));
+        }
+        if self.weight_sd < 0.0 || self.age_sd < 0.0 {
+            return Err(PKError::Validation("Demographic SDs must not be negative".to_string()));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -84,6 +174,21 @@ pub struct SimulationConfig {
     pub error_model: ErrorModel,
     pub integration_method: IntegrationMethod,
     pub tolerance: Option<f64>,
+    #[serde(default)]
+    pub lloq: Option<f64>, // Lower limit of quantification of the observations
+    #[serde(default)]
+    pub blq_method: BlqMethod,
+}
+
+/// How simulated observations below the LLOQ are reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlqMethod {
+    #[default]
+    Flag,     // Keep the value, flagged BLQ and censored (MDV=1) in the NONMEM dataset
+    HalfLloq, // Replace the value by LLOQ/2, flagged BLQ
+    Drop,     // Remove the observation
+    Keep,     // Report the value as simulated, negative values included
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -101,6 +206,790 @@ pub enum ErrorModel {
     },
 }
 
+impl ErrorModel {
+    /// Number of EPS drawn per observation.
+    pub fn n_eps(&self) -> usize {
+        match self {
+            ErrorModel::Proportional { .. } | ErrorModel::Additive { .. } => 1,
+            ErrorModel::Combined { .. } => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NcaConfig {
+    #[serde(default)]
+    pub auc_method: AucMethod,
+    #[serde(default = "default_lambda_z_min_points")]
+    pub lambda_z_min_points: usize,
+    pub tau: Option<f64>,     // Dosing interval; inferred from regular dosing times if omitted
+}
+
+impl Default for NcaConfig {
+    fn default() -> Self {
+        Self {
+            auc_method: AucMethod::default(),
+            lambda_z_min_points: default_lambda_z_min_points(),
+            tau: None,
+        }
+    }
+}
+
+fn default_lambda_z_min_points() -> usize {
+    3
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SummaryConfig {
+    #[serde(default)]
+    pub percentiles: Vec<f64>, // Reported in addition to the 5th, 50th and 95th
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnitsConfig {
+    #[serde(default = "default_amount_unit")]
+    pub amount: String,
+    #[serde(default = "default_volume_unit")]
+    pub volume: String,
+    #[serde(default = "default_time_unit")]
+    pub time: String,
+    pub concentration: Option<String>, // Amount per volume if omitted
+}
+
+impl Default for UnitsConfig {
+    fn default() -> Self {
+        Self {
+            amount: default_amount_unit(),
+            volume: default_volume_unit(),
+            time: default_time_unit(),
+            concentration: None,
+        }
+    }
+}
+
+fn default_amount_unit() -> String {
+    "mg".to_string()
+}
+
+fn default_volume_unit() -> String {
+    "L".to_string()
+}
+
+fn default_time_unit() -> String {
+    "h".to_string()
+}
+
+impl UnitsConfig {
+    /// Check that each unit has the expected dimension.
+    pub fn validate(&self) -> PKResult<()> {
+        use crate::units::{Dimensions, Unit};
+        
+        let amount = Unit::parse(&self.amount)?.dimensions;
+        if amount != Dimensions::MASS && amount != Dimensions::SUBSTANCE {
+            return Err(PKError::Validation(format!("'{}' is not an amount unit", self.amount)));
+        }
+        if Unit::parse(&self.volume)?.dimensions != Dimensions::VOLUME {
+            return Err(PKError::Validation(format!("'{}' is not a volume unit", self.volume)));
+        }
+        if Unit::parse(&self.time)?.dimensions != Dimensions::TIME {
+            return Err(PKError::Validation(format!("'{}' is not a time unit", self.time)));
+        }
+        self.concentration_factor()?;
+        Ok(())
+    }
+    
+    /// Factor converting amount/volume, the unit the model computes
+    /// concentrations in, to the output concentration unit.
+    pub fn concentration_factor(&self) -> PKResult<f64> {
+        let model_unit = format!("{}/{}", self.amount, self.volume);
+        crate::units::convert(&model_unit, &self.concentration()).map_err(|_| PKError::Validation(format!(
+            "Concentration unit {} is not an amount per volume like {}", self.concentration(), model_unit
+        )))
+    }
+    
+    pub fn concentration(&self) -> String {
+        self.concentration.clone()
+            .unwrap_or_else(|| format!("{}/{}", self.amount, self.volume))
+    }
+    
+    pub fn clearance(&self) -> String {
+        format!("{}/{}", self.volume, self.time)
+    }
+    
+    pub fn auc(&self) -> String {
+        format!("{}*{}", self.concentration(), self.time)
+    }
+    
+    pub fn rate_constant(&self) -> String {
+        format!("1/{}", self.time)
+    }
+    
+    /// Unit of a model parameter inferred from its name; empty for
+    /// dimensionless or unknown parameters.
+    pub fn parameter_unit(&self, name: &str) -> String {
+        let name = name.to_uppercase();
+        if name == "CL" || name.starts_with('Q') {
+            self.clearance()
+        } else if name.starts_with('V') {
+            self.volume.clone()
+        } else if name.starts_with('K') || name == "ALPHA" || name == "BETA" || name == "GAMMA" {
+            self.rate_constant()
+        } else {
+            String::new()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportConfig {
+    #[serde(default = "default_report_sections")]
+    pub sections: Vec<ReportSection>,
+}
+
+impl Default for ReportConfig {
+    fn default() -> Self {
+        Self { sections: default_report_sections() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportSection {
+    Overview,
+    Parameters,
+    PkEndpoints,
+    Nca,
+    Covariates,
+    Dosing,
+}
+
+fn default_report_sections() -> Vec<ReportSection> {
+    vec![
+        ReportSection::Overview,
+        ReportSection::Dosing,
+        ReportSection::Parameters,
+        ReportSection::PkEndpoints,
+        ReportSection::Nca,
+        ReportSection::Covariates,
+    ]
+}
+
+/// File formats of the tabular outputs. Tables not listed in `tables` use
+/// `format`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OutputConfig {
+    #[serde(default)]
+    pub format: OutputFormat,
+    #[serde(default)]
+    pub tables: BTreeMap<String, OutputFormat>,
+    #[serde(default)]
+    pub compression: OutputCompression,
+}
+
+impl OutputConfig {
+    /// Tables whose format can be chosen.
+    pub const TABLES: [&'static str; 22] = [
+        "individual_data", "concentrations", "parameters", "prediction_intervals",
+        "nca", "exposure", "pta", "cfr", "vpc", "vpc_observations",
+        "estimates", "individual_etas", "individual_predictions", "estimation_iterations",
+        "map_parameters", "map_predictions", "design", "design_rse",
+        "sensitivity_local", "sensitivity_morris", "sensitivity_sobol", "dose_optimization",
+    ];
+    
+    pub fn format_for(&self, table: &str) -> OutputFormat {
+        self.tables.get(table).copied().unwrap_or(self.format)
+    }
+    
+    /// Apply a `FORMAT` or `TABLE=FORMAT` override, as given on the command
+    /// line.
+    pub fn set_format(&mut self, spec: &str) -> PKResult<()> {
+        match spec.split_once('=') {
+            Some((table, format)) => {
+                self.tables.insert(table.trim().to_string(), format.parse()?);
+            },
+            None => self.format = spec.parse()?,
+        }
+        Ok(())
+    }
+    
+    pub fn validate(&self) -> PKResult<()> {
+        if let Some(table) = self.tables.keys().find(|t| !Self::TABLES.contains(&t.as_str())) {
+            return Err(PKError::Validation(format!(
+                "Unknown output table '{}'; expected one of {}", table, Self::TABLES.join(", ")
+            )));
+        }
+        let arrow = Self::TABLES.iter().any(|t| self.format_for(t) == OutputFormat::Arrow);
+        if arrow && self.compression == OutputCompression::Snappy {
+            return Err(PKError::Validation(
+                "Arrow IPC output supports zstd or no compression, not snappy".to_string()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Parquet,
+    Arrow,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Parquet => "parquet",
+            OutputFormat::Arrow => "arrow",
+        }
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = PKError;
+    
+    fn from_str(s: &str) -> PKResult<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "csv" => Ok(OutputFormat::Csv),
+            "parquet" => Ok(OutputFormat::Parquet),
+            "arrow" | "ipc" | "feather" => Ok(OutputFormat::Arrow),
+            other => Err(PKError::Validation(format!(
+                "Unknown output format '{}'; expected csv, parquet or arrow", other
+            ))),
+        }
+    }
+}
+
+/// Compression of Parquet and Arrow IPC files; CSV is never compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputCompression {
+    None,
+    Snappy,
+    #[default]
+    Zstd,
+}
+
+/// Settings for fitting the model to a dataset. THETAs, the OMEGAs of
+/// parameters with an omega and the error model SDs are estimated, starting
+/// from their configured values.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EstimationConfig {
+    #[serde(default)]
+    pub method: EstimationMethod,
+    #[serde(default = "default_max_iterations")]
+    pub max_iterations: usize,
+    /// Convergence when no scaled gradient element exceeds this
+    #[serde(default = "default_estimation_tolerance")]
+    pub tolerance: f64,
+    /// Compute standard errors from the Hessian of the objective function
+    #[serde(default = "default_true")]
+    pub covariance: bool,
+    #[serde(default)]
+    pub saem: SaemConfig,
+}
+
+impl Default for EstimationConfig {
+    fn default() -> Self {
+        Self {
+            method: EstimationMethod::default(),
+            max_iterations: default_max_iterations(),
+            tolerance: default_estimation_tolerance(),
+            covariance: true,
+            saem: SaemConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EstimationMethod {
+    /// First-order conditional estimation with interaction
+    #[default]
+    Focei,
+    /// Stochastic approximation expectation maximization
+    Saem,
+}
+
+/// Iterations and MCMC kernels of SAEM. Each iteration runs the kernels the
+/// given number of times for every subject: proposals from the population
+/// distribution, random walks of all ETAs and random walks of one ETA at a
+/// time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SaemConfig {
+    /// Iterations with step size 1, exploring the parameter space
+    pub burn_in_iterations: usize,
+    /// Iterations with decreasing step size 1/k, averaging the estimates
+    pub accumulation_iterations: usize,
+    pub prior_kernel: usize,
+    pub random_walk_kernel: usize,
+    pub componentwise_kernel: usize,
+}
+
+impl Default for SaemConfig {
+    fn default() -> Self {
+        Self {
+            burn_in_iterations: 150,
+            accumulation_iterations: 100,
+            prior_kernel: 2,
+            random_walk_kernel: 2,
+            componentwise_kernel: 2,
+        }
+    }
+}
+
+fn default_max_iterations() -> usize {
+    200
+}
+
+fn default_estimation_tolerance() -> f64 {
+    1e-3
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Settings for individual MAP (POSTHOC) estimation: the ETAs of each
+/// subject are estimated with the configured model as the prior.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapConfig {
+    #[serde(default)]
+    pub prediction_times: Vec<f64>, // Times of the predicted profile; simulation time points if empty
+    #[serde(default = "default_map_samples")]
+    pub samples: usize,             // Draws from the posterior for the intervals
+    #[serde(default = "default_map_coverage")]
+    pub coverage: f64,              // Coverage of the intervals
+}
+
+impl Default for MapConfig {
+    fn default() -> Self {
+        Self {
+            prediction_times: Vec::new(),
+            samples: default_map_samples(),
+            coverage: default_map_coverage(),
+        }
+    }
+}
+
+fn default_map_samples() -> usize {
+    1000
+}
+
+fn default_map_coverage() -> f64 {
+    0.9
+}
+
+/// Candidate regimens and the target they are ranked by. Every combination
+/// of amount, interval and (for infusions) duration is a candidate, given as
+/// `n_doses` doses and evaluated over the last dosing interval.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DoseOptimizationConfig {
+    pub target: DoseTarget,
+    pub amounts: Vec<f64>,
+    pub intervals: Vec<f64>,
+    #[serde(default)]
+    pub durations: Vec<f64>,        // Infusion durations; the configured one if empty
+    #[serde(default = "default_n_doses")]
+    pub n_doses: usize,
+}
+
+impl DoseOptimizationConfig {
+    pub fn validate(&self, dosing: &DosingConfig) -> PKResult<()> {
+        let positive = |values: &[f64]| !values.is_empty() && values.iter().all(|v| *v > 0.0);
+        if !positive(&self.amounts) || !positive(&self.intervals) || self.n_doses == 0 {
+            return Err(PKError::Validation(
+                "Dose optimization needs positive amounts, intervals and number of doses".to_string()
+            ));
+        }
+        if !self.durations.is_empty() && !matches!(dosing.route, DosingRoute::IvInfusion) {
+            return Err(PKError::Validation(
+                "Dose optimization durations need an infusion route".to_string()
+            ));
+        }
+        if self.durations.iter().any(|d| *d <= 0.0) {
+            return Err(PKError::Validation(
+                "Dose optimization durations must be positive".to_string()
+            ));
+        }
+        match self.target {
+            DoseTarget::Trough { lower, upper } | DoseTarget::Auc { lower, upper } if lower > upper => {
+                Err(PKError::Validation("Dose target lower limit exceeds its upper limit".to_string()))
+            },
+            DoseTarget::TimeAboveMic { fraction, .. } if !(0.0..=1.0).contains(&fraction) => {
+                Err(PKError::Validation("Dose target fraction must be between 0 and 1".to_string()))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+fn default_n_doses() -> usize {
+    10
+}
+
+/// Exposure target of dose optimization, in the output units.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DoseTarget {
+    /// Concentration at the end of the dosing interval
+    Trough { lower: f64, upper: f64 },
+    /// AUC over 24 hours at the dosing rate of the interval
+    Auc { lower: f64, upper: f64 },
+    /// Fraction of the dosing interval above the MIC
+    TimeAboveMic { mic: f64, fraction: f64 },
+}
+
+/// Probability of target attainment of the simulated subjects for a range
+/// of MICs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PtaConfig {
+    pub mics: Vec<f64>,
+    pub targets: Vec<PtaTarget>,
+    #[serde(default)]
+    pub interval: Option<(f64, f64)>, // Evaluation window; first dose to last time point if omitted
+    #[serde(default = "default_unbound_fraction")]
+    pub unbound_fraction: f64,        // Applied to concentrations before comparing with the MIC
+}
+
+/// PK/PD index with the value it has to reach.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PtaTarget {
+    pub index: PkPdIndex,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PkPdIndex {
+    /// Fraction of the window with the concentration above the MIC
+    FtAboveMic,
+    /// AUC over 24 hours divided by the MIC
+    AucMic,
+    /// Peak concentration divided by the MIC
+    CmaxMic,
+}
+
+fn default_unbound_fraction() -> f64 {
+    1.0
+}
+
+/// Study design with several treatment arms simulated in one run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StudyConfig {
+    pub arms: Vec<ArmConfig>,
+}
+
+/// One treatment arm: its size and whatever differs from the top-level
+/// dosing, sampling times and demographics.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArmConfig {
+    pub name: String,
+    pub n_subjects: usize,
+    #[serde(default)]
+    pub dosing: Option<DosingConfig>,
+    #[serde(default)]
+    pub time_points: Option<Vec<f64>>,
+    #[serde(default)]
+    pub demographics: Option<DemographicsConfig>,
+}
+
+/// Sampling design evaluation and D-optimal search. Each sampling time may
+/// move within its window, given in the order of the time points of the
+/// simulation or of each study arm; times without windows stay fixed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DesignConfig {
+    #[serde(default)]
+    pub windows: Vec<(f64, f64)>,
+    #[serde(default)]
+    pub arm_windows: BTreeMap<String, Vec<(f64, f64)>>, // Windows of study arms that differ from `windows`
+    #[serde(default = "default_design_grid_points")]
+    pub grid_points: usize,         // Candidate times per window in each search pass
+    #[serde(default = "default_max_iterations")]
+    pub max_iterations: usize,      // Search passes over all sampling times
+}
+
+impl Default for DesignConfig {
+    fn default() -> Self {
+        Self {
+            windows: Vec::new(),
+            arm_windows: BTreeMap::new(),
+            grid_points: default_design_grid_points(),
+            max_iterations: default_max_iterations(),
+        }
+    }
+}
+
+impl DesignConfig {
+    /// Windows of the sampling times, fixed at the times where none are
+    /// configured.
+    pub fn windows(&self, time_points: &[f64]) -> Vec<(f64, f64)> {
+        if self.windows.is_empty() {
+            time_points.iter().map(|&t| (t, t)).collect()
+        } else {
+            self.windows.clone()
+        }
+    }
+
+    /// Check the settings; the windows of study arms are checked against
+    /// their time points with the configuration of each arm.
+    pub fn validate(&self, study: Option<&StudyConfig>, time_points: &[f64]) -> PKResult<()> {
+        if self.grid_points < 2 || self.max_iterations == 0 {
+            return Err(PKError::Validation(
+                "Design grid points must be at least 2 and max_iterations positive".to_string()
+            ));
+        }
+        let invalid = |windows: &Vec<(f64, f64)>| windows.iter().any(|&(lower, upper)| lower < 0.0 || lower > upper);
+        if invalid(&self.windows) || self.arm_windows.values().any(invalid) {
+            return Err(PKError::Validation(
+                "Design windows must not start before 0 or end before they start".to_string()
+            ));
+        }
+        match study {
+            Some(study) => {
+                if let Some(name) = self.arm_windows.keys().find(|name| !study.arms.iter().any(|arm| &arm.name == *name)) {
+                    return Err(PKError::Validation(format!("Design windows for unknown arm '{}'", name)));
+                }
+            },
+            None if !self.arm_windows.is_empty() => {
+                return Err(PKError::Validation("Design arm windows need a study".to_string()));
+            },
+            None if !self.windows.is_empty() && self.windows.len() != time_points.len() => {
+                return Err(PKError::Validation("Design needs one window per time point".to_string()));
+            },
+            None => {},
+        }
+        Ok(())
+    }
+}
+
+fn default_design_grid_points() -> usize {
+    21
+}
+
+/// Sensitivity of the concentrations and exposure metrics of a typical
+/// subject to THETAs and covariates (`WT`, `AGE`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SensitivityConfig {
+    #[serde(default = "default_sensitivity_methods")]
+    pub methods: Vec<SensitivityMethod>,
+    #[serde(default)]
+    pub factors: Vec<String>,       // All THETAs and the covariates with effects if empty
+    #[serde(default)]
+    pub ranges: HashMap<String, (f64, f64)>, // Global ranges; THETA ± 50% and demographic mean ± 2 SD if omitted
+    #[serde(default = "default_morris_trajectories")]
+    pub trajectories: usize,
+    #[serde(default = "default_morris_levels")]
+    pub levels: usize,              // Grid levels of the Morris trajectories, even
+    #[serde(default = "default_sobol_samples")]
+    pub samples: usize,             // Base samples of the Sobol indices
+}
+
+impl Default for SensitivityConfig {
+    fn default() -> Self {
+        Self {
+            methods: default_sensitivity_methods(),
+            factors: Vec::new(),
+            ranges: HashMap::new(),
+            trajectories: default_morris_trajectories(),
+            levels: default_morris_levels(),
+            samples: default_sobol_samples(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensitivityMethod {
+    /// Finite-difference derivatives at the nominal values
+    Local,
+    /// Elementary effects along random trajectories
+    Morris,
+    /// First-order and total variance-based indices
+    Sobol,
+}
+
+impl SensitivityConfig {
+    /// Covariates that can be varied besides the THETAs.
+    pub const COVARIATES: [&'static str; 2] = ["WT", "AGE"];
+
+    pub fn validate(&self, model: &ModelConfig) -> PKResult<()> {
+        if self.methods.is_empty() {
+            return Err(PKError::Validation("Sensitivity analysis needs at least one method".to_string()));
+        }
+        let known = |name: &String| model.parameters.contains_key(name) || Self::COVARIATES.contains(&name.as_str());
+        if let Some(name) = self.factors.iter().chain(self.ranges.keys()).find(|name| !known(name)) {
+            return Err(PKError::Validation(format!(
+                "Unknown sensitivity factor '{}'; expected a THETA, WT or AGE", name
+            )));
+        }
+        if self.ranges.values().any(|&(lower, upper)| lower >= upper) {
+            return Err(PKError::Validation(
+                "Sensitivity ranges must end above where they start".to_string()
+            ));
+        }
+        if self.trajectories == 0 || self.levels < 2 || !self.levels.is_multiple_of(2) || self.samples < 2 {
+            return Err(PKError::Validation(
+                "Sensitivity needs trajectories, an even number of levels and at least 2 samples".to_string()
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_sensitivity_methods() -> Vec<SensitivityMethod> {
+    vec![SensitivityMethod::Local, SensitivityMethod::Morris, SensitivityMethod::Sobol]
+}
+
+fn default_morris_trajectories() -> usize {
+    20
+}
+
+fn default_morris_levels() -> usize {
+    4
+}
+
+fn default_sobol_samples() -> usize {
+    1000
+}
+
+/// Trial execution in population simulations: dropout, adherence to the
+/// dosing regimen and deviations from the nominal sampling times.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrialConfig {
+    #[serde(default)]
+    pub dropout: Option<DropoutConfig>,
+    #[serde(default)]
+    pub adherence: Option<AdherenceConfig>,
+    #[serde(default)]
+    pub sampling: Option<SamplingConfig>,
+}
+
+impl TrialConfig {
+    /// Check the settings; sampling windows must be narrower than half the
+    /// smallest gap between `time_points`, so that samples keep their order.
+    pub fn validate(&self, time_points: &[f64]) -> PKResult<()> {
+        if let Some(dropout) = &self.dropout {
+            if dropout.hazards.iter().any(|&(start, hazard)| start < 0.0 || hazard < 0.0) {
+                return Err(PKError::Validation(
+                    "Dropout hazards and their start times must not be negative".to_string()
+                ));
+            }
+        }
+        if let Some(adherence) = &self.adherence {
+            let probability = 0.0..=1.0;
+            if !probability.contains(&adherence.missed_probability)
+                || !probability.contains(&adherence.delay_probability)
+                || adherence.max_delay < 0.0 {
+                return Err(PKError::Validation(
+                    "Adherence probabilities must be between 0 and 1 and the delay not negative".to_string()
+                ));
+            }
+        }
+        if let Some(sampling) = &self.sampling {
+            if sampling.window < 0.0 {
+                return Err(PKError::Validation("Sampling window must not be negative".to_string()));
+            }
+            let mut times = time_points.to_vec();
+            times.sort_by(|a, b| a.total_cmp(b));
+            let min_gap = times.windows(2).map(|w| w[1] - w[0]).fold(f64::INFINITY, f64::min);
+            if sampling.window >= min_gap / 2.0 {
+                return Err(PKError::Validation(format!(
+                    "Sampling window {} must be less than half the smallest gap between time points ({})",
+                    sampling.window, min_gap
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Piecewise-constant dropout hazard: each `(start, hazard)` applies from
+/// its start time until the next one, in dropouts per subject and time unit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DropoutConfig {
+    pub hazards: Vec<(f64, f64)>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AdherenceConfig {
+    #[serde(default)]
+    pub missed_probability: f64,
+    #[serde(default)]
+    pub delay_probability: f64,
+    #[serde(default)]
+    pub max_delay: f64,             // Delays are uniform up to this
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SamplingConfig {
+    pub window: f64,                // Samples are taken uniformly within ± window of the nominal time
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VpcConfig {
+    #[serde(default = "default_vpc_replicates")]
+    pub replicates: usize,
+    #[serde(default)]
+    pub bins: Vec<f64>,          // Bin edges; automatic binning if empty
+    #[serde(default = "default_vpc_n_bins")]
+    pub n_bins: usize,           // Number of automatic bins
+    #[serde(default = "default_vpc_percentiles")]
+    pub percentiles: Vec<f64>,
+    #[serde(default = "default_vpc_confidence")]
+    pub confidence: f64,         // Coverage of the intervals around simulated percentiles
+    #[serde(default)]
+    pub prediction_corrected: bool,
+}
+
+impl Default for VpcConfig {
+    fn default() -> Self {
+        Self {
+            replicates: default_vpc_replicates(),
+            bins: Vec::new(),
+            n_bins: default_vpc_n_bins(),
+            percentiles: default_vpc_percentiles(),
+            confidence: default_vpc_confidence(),
+            prediction_corrected: false,
+        }
+    }
+}
+
+fn default_vpc_replicates() -> usize {
+    100
+}
+
+fn default_vpc_n_bins() -> usize {
+    8
+}
+
+fn default_vpc_percentiles() -> Vec<f64> {
+    vec![5.0, 50.0, 95.0]
+}
+
+fn default_vpc_confidence() -> f64 {
+    0.95
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExposureConfig {
+    #[serde(default)]
+    pub intervals: Vec<(f64, f64)>, // Windows for AUC, Cmax and Tmax; first dose to last time point if empty
+    pub threshold: Option<f64>,     // Concentration for time above threshold
+    pub tau: Option<f64>,           // Dosing interval for average steady-state concentration
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AucMethod {
+    Linear,
+    #[default]
+    LinearUpLogDown,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum IntegrationMethod {
@@ -136,10 +1025,31 @@ impl Config {
             }
         };
         
+        let mut config = config;
+        config.convert_parameter_units()?;
         config.validate()?;
         Ok(config)
     }
     
+    /// Convert thetas and bounds declared with their own `unit` to the model
+    /// units given by `units`, checking that the dimensions agree.
+    pub fn convert_parameter_units(&mut self) -> PKResult<()> {
+        let units = self.units.clone();
+        for (name, param) in self.model.parameters.iter_mut() {
+            let Some(unit) = param.unit.take() else {
+                continue;
+            };
+            let expected = units.parameter_unit(name);
+            let factor = crate::units::convert(&unit, &expected).map_err(|_| PKError::Validation(format!(
+                "Parameter {} is given in {} but must be convertible to {}",
+                name, unit, if expected.is_empty() { "a dimensionless value" } else { &expected }
+            )))?;
+            param.theta *= factor;
+            param.bounds = param.bounds.map(|(lower, upper)| (lower * factor, upper * factor));
+        }
+        Ok(())
+    }
+    
     pub fn validate(&self) -> PKResult<()> {
         // Validate compartments
         if ![1, 2, 3].contains(&self.model.compartments) {
@@ -154,6 +1064,12 @@ impl Config {
         // Validate dosing
         self.validate_dosing()?;
         
+        self.population.demographics.validate()?;
+        
+        if let Some(study) = &self.study {
+            self.validate_study(study)?;
+        }
+        
         // Validate simulation parameters
         if self.simulation.time_points.is_empty() {
             return Err(PKError::Validation(
@@ -161,16 +1077,154 @@ impl Config {
             ));
         }
         
+        self.units.validate()?;
+        self.output.validate()?;
+        
+        if let Some(vpc) = &self.vpc {
+            if vpc.replicates == 0 || vpc.n_bins == 0 {
+                return Err(PKError::Validation(
+                    "VPC replicates and number of bins must be positive".to_string()
+                ));
+            }
+            if !(0.0..1.0).contains(&vpc.confidence) {
+                return Err(PKError::Validation(
+                    "VPC confidence must be between 0 and 1".to_string()
+                ));
+            }
+            if vpc.percentiles.iter().any(|p| !(0.0..=100.0).contains(p)) {
+                return Err(PKError::Validation(
+                    "VPC percentiles must be between 0 and 100".to_string()
+                ));
+            }
+        }
+        
+        if let Some(estimation) = &self.estimation {
+            if estimation.max_iterations == 0 || estimation.tolerance <= 0.0 {
+                return Err(PKError::Validation(
+                    "Estimation max_iterations and tolerance must be positive".to_string()
+                ));
+            }
+            if estimation.saem.accumulation_iterations == 0 {
+                return Err(PKError::Validation(
+                    "SAEM needs at least one accumulation iteration".to_string()
+                ));
+            }
+        }
+        
+        if let Some(map) = &self.map {
+            if map.samples == 0 {
+                return Err(PKError::Validation(
+                    "MAP samples must be positive".to_string()
+                ));
+            }
+            if !(0.0..1.0).contains(&map.coverage) {
+                return Err(PKError::Validation(
+                    "MAP coverage must be between 0 and 1".to_string()
+                ));
+            }
+        }
+        
+        if let Some(optimization) = &self.dose_optimization {
+            optimization.validate(&self.dosing)?;
+        }
+        
+        if let Some(pta) = &self.pta {
+            if pta.mics.iter().any(|mic| *mic <= 0.0) || pta.targets.is_empty() {
+                return Err(PKError::Validation(
+                    "PTA needs positive MICs and at least one target".to_string()
+                ));
+            }
+            if pta.unbound_fraction <= 0.0 || pta.unbound_fraction > 1.0 {
+                return Err(PKError::Validation(
+                    "PTA unbound fraction must be between 0 and 1".to_string()
+                ));
+            }
+            if pta.interval.is_some_and(|(start, end)| start >= end) {
+                return Err(PKError::Validation(
+                    "PTA interval must end after it starts".to_string()
+                ));
+            }
+        }
+        
+        if self.simulation.lloq.is_some_and(|lloq| lloq <= 0.0) {
+            return Err(PKError::Validation("LLOQ must be positive".to_string()));
+        }
+        if self.simulation.lloq.is_none()
+            && matches!(self.simulation.blq_method, BlqMethod::HalfLloq | BlqMethod::Drop) {
+            return Err(PKError::Validation(
+                "BLQ methods half_lloq and drop need simulation.lloq".to_string()
+            ));
+        }
+        
+        if let Some(trial) = &self.trial {
+            trial.validate(&self.simulation.time_points)?;
+        }
+        
+        if let Some(design) = &self.design {
+            design.validate(self.study.as_ref(), &self.simulation.time_points)?;
+        }
+        
+        if let Some(sensitivity) = &self.sensitivity {
+            sensitivity.validate(&self.model)?;
+        }
+        
+        if let Some(summary) = &self.summary {
+            if summary.percentiles.iter().any(|p| !(0.0..=100.0).contains(p)) {
+                return Err(PKError::Validation(
+                    "Summary percentiles must be between 0 and 100".to_string()
+                ));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Configuration of one arm of the study: the top-level configuration
+    /// with the arm's dosing, sampling times, demographics and design windows.
+    pub fn arm(&self, arm: &ArmConfig) -> Config {
+        let mut config = self.clone();
+        config.study = None;
+        if let Some(dosing) = &arm.dosing {
+            config.dosing = dosing.clone();
+        }
+        if let Some(time_points) = &arm.time_points {
+            config.simulation.time_points = time_points.clone();
+        }
+        if let Some(demographics) = &arm.demographics {
+            config.population.demographics = demographics.clone();
+        }
+        if let Some(design) = &mut config.design {
+            if let Some(windows) = design.arm_windows.remove(&arm.name) {
+                design.windows = windows;
+            }
+            design.arm_windows.clear();
+        }
+        config
+    }
+    
+    fn validate_study(&self, study: &StudyConfig) -> PKResult<()> {
+        if study.arms.is_empty() {
+            return Err(PKError::Validation("A study needs at least one arm".to_string()));
+        }
+        for (i, arm) in study.arms.iter().enumerate() {
+            if arm.name.is_empty() || study.arms[..i].iter().any(|other| other.name == arm.name) {
+                return Err(PKError::Validation(
+                    "Study arms need distinct, non-empty names".to_string()
+                ));
+            }
+            if arm.n_subjects == 0 {
+                return Err(PKError::Validation(
+                    format!("Arm '{}' needs at least one subject", arm.name)
+                ));
+            }
+            self.arm(arm).validate()?;
+        }
         Ok(())
     }
     
     fn validate_model_parameters(&self) -> PKResult<()> {
-        let required_params = match self.model.compartments {
-            1 => vec!["CL", "V"],
-            2 => vec!["CL", "V1", "Q", "V2"],
-            3 => vec!["CL", "V1", "Q2", "V2", "Q3", "V3"],
-            _ => return Err(PKError::InvalidModel("Invalid compartment number".to_string())),
-        };
+        let required_params = self.model.parameterization()
+            .parameter_names(self.model.compartments)?;
         
         // Add KA for oral dosing
         let mut all_params = required_params;
@@ -178,21 +1232,26 @@ impl Config {
             all_params.push("KA");
         }
         
+        let thetas: HashMap<String, f64> = self.model.parameters.iter()
+            .map(|(name, param_config)| (name.clone(), param_config.theta))
+            .collect();
+        
         for param in all_params {
-            if !self.model.parameters.contains_key(param) {
-                return Err(PKError::InvalidModel(
+            let theta = crate::models::parameterization::lookup(&thetas, param)
+                .ok_or_else(|| PKError::InvalidModel(
                     format!("Missing required parameter: {}", param)
-                ));
-            }
+                ))?;
             
-            let param_config = &self.model.parameters[param];
-            if param_config.theta <= 0.0 {
+            if theta <= 0.0 {
                 return Err(PKError::Validation(
                     format!("Parameter {} must be positive", param)
                 ));
             }
         }
         
+        // Typical values must map to a valid clearance/volume model
+        self.model.parameterization().to_canonical(self.model.compartments, &thetas)?;
+        
         Ok(())
     }
     
@@ -210,17 +1269,14 @@ impl Config {
         }
         
         // Validate route-specific parameters
-        match self.dosing.route {
-            DosingRoute::IvInfusion => {
-                if self.dosing.additional.as_ref()
-                    .and_then(|a| a.duration)
-                    .unwrap_or(0.0) <= 0.0 {
-                    return Err(PKError::InvalidDosing(
-                        "Infusion duration must be specified and positive".to_string()
-                    ));
-                }
-            },
-            _ => {}
+        if let DosingRoute::IvInfusion = self.dosing.route {
+            if self.dosing.additional.as_ref()
+                .and_then(|a| a.duration)
+                .unwrap_or(0.0) <= 0.0 {
+                return Err(PKError::InvalidDosing(
+                    "Infusion duration must be specified and positive".to_string()
+                ));
+            }
         }
         
         Ok(())
@@ -3,6 +3,9 @@ use std::collections::HashMap;
 use crate::config::*;
 use crate::error::{PKError, PKResult};
 
+/// Supported `$SUBROUTINES` models, quoted in parse errors.
+const SUPPORTED_ADVANS: &str = "Use ADVAN1-4, ADVAN11 or ADVAN12";
+
 pub fn parse_control_stream<P: AsRef<Path>>(path: P) -> PKResult<Config> {
     let content = std::fs::read_to_string(path)?;
     let mut parser = ControlStreamParser::new(&content);
@@ -45,13 +48,7 @@ impl ControlStreamParser {
         while self.current_line < self.lines.len() {
             let line = &self.lines[self.current_line];
             
-            if line.starts_with("$PROBLEM") {
-                self.current_line += 1;
-                continue;
-            } else if line.starts_with("$INPUT") {
-                self.current_line += 1;
-                continue;
-            } else if line.starts_with("$DATA") {
+            if line.starts_with("$PROBLEM") || line.starts_with("$INPUT") || line.starts_with("$DATA") {
                 self.current_line += 1;
                 continue;
             } else if line.starts_with("$SUBROUTINES") || line.starts_with("$SUBROUTINE") {
@@ -90,6 +87,8 @@ impl ControlStreamParser {
                         error_model: ErrorModel::Proportional { sigma: 0.1 },
                         integration_method: IntegrationMethod::Analytical,
                         tolerance: None,
+                        lloq: None,
+                        blq_method: BlqMethod::default(),
                     });
                 }
                 self.parse_simulation_block(simulation_config.as_mut().unwrap())?;
@@ -109,12 +108,14 @@ impl ControlStreamParser {
             additional: None,
         });
         
-        let population_config = population_config.unwrap_or_else(|| PopulationConfig {
+        let population_config = population_config.unwrap_or(PopulationConfig {
             demographics: DemographicsConfig {
                 weight_mean: 70.0,
                 weight_sd: 15.0,
                 age_mean: 45.0,
                 age_sd: 12.0,
+                weight_bounds: None,
+                age_bounds: None,
             },
             covariates: None,
         });
@@ -124,6 +125,8 @@ impl ControlStreamParser {
             error_model: ErrorModel::Proportional { sigma: 0.1 },
             integration_method: IntegrationMethod::Analytical,
             tolerance: None,
+            lloq: None,
+            blq_method: BlqMethod::default(),
         });
         
         Ok(Config {
@@ -131,6 +134,21 @@ impl ControlStreamParser {
             dosing: dosing_config,
             population: population_config,
             simulation: simulation_config,
+            nca: None,
+            exposure: None,
+            summary: None,
+            vpc: None,
+            units: UnitsConfig::default(),
+            report: None,
+            output: OutputConfig::default(),
+            estimation: None,
+            map: None,
+            dose_optimization: None,
+            pta: None,
+            trial: None,
+            study: None,
+            design: None,
+            sensitivity: None,
         })
     }
     
@@ -138,24 +156,50 @@ impl ControlStreamParser {
         let line = &self.lines[self.current_line];
         self.current_line += 1;
         
-        let compartments = if line.contains("ADVAN1") {
-            1
-        } else if line.contains("ADVAN3") {
-            2
-        } else if line.contains("ADVAN11") {
-            3
-        } else {
-            return Err(PKError::InvalidModel(
-                "Unsupported ADVAN subroutine. Use ADVAN1, ADVAN3, or ADVAN11".to_string()
-            ));
-        };
+        let mut compartments = None;
+        let mut parameterization = None;
+        
+        for token in line.split_whitespace().skip(1) {
+            let token = token.to_uppercase();
+            if let Some(advan) = token.strip_prefix("ADVAN") {
+                compartments = Some(match advan {
+                    "1" | "2" => 1,
+                    "3" | "4" => 2,
+                    "11" | "12" => 3,
+                    _ => return Err(PKError::InvalidModel(format!(
+                        "Unsupported ADVAN subroutine: {}. {}", token, SUPPORTED_ADVANS
+                    ))),
+                });
+            } else if let Some(trans) = token.strip_prefix("TRANS") {
+                let number = trans.parse::<u8>()
+                    .map_err(|_| PKError::InvalidModel(format!("Invalid TRANS subroutine: {}", token)))?;
+                parameterization = Some(Parameterization::from_trans(number)?);
+            }
+        }
+        
+        let compartments = compartments.ok_or_else(|| PKError::InvalidModel(
+            format!("Missing ADVAN subroutine. {}", SUPPORTED_ADVANS)
+        ))?;
+        
+        if let Some(parameterization) = &parameterization {
+            parameterization.parameter_names(compartments)?;
+        }
         
         Ok(ModelConfig {
             compartments,
             parameters: HashMap::new(),
+            parameterization,
         })
     }
     
+    /// THETA/OMEGA names in record order: structural parameters, then KA.
+    fn theta_names(model_config: &ModelConfig) -> PKResult<Vec<&'static str>> {
+        let mut names = model_config.parameterization()
+            .parameter_names(model_config.compartments)?;
+        names.push("KA");
+        Ok(names)
+    }
+    
     fn parse_pk_block(&mut self, _model_config: &mut ModelConfig) -> PKResult<()> {
         self.current_line += 1;
         
@@ -174,12 +218,7 @@ impl ControlStreamParser {
     fn parse_theta_block(&mut self, model_config: &mut ModelConfig) -> PKResult<()> {
         self.current_line += 1;
         
-        let param_names = match model_config.compartments {
-            1 => vec!["CL", "V", "KA"],
-            2 => vec!["CL", "V1", "Q", "V2", "KA"],
-            3 => vec!["CL", "V1", "Q2", "V2", "Q3", "V3", "KA"],
-            _ => return Err(PKError::InvalidModel("Invalid compartment number".to_string())),
-        };
+        let param_names = Self::theta_names(model_config)?;
         
         let mut param_index = 0;
         
@@ -199,11 +238,11 @@ impl ControlStreamParser {
                     ParameterConfig {
                         theta: theta_value.1,
                         omega: None,
-                        bounds: if theta_value.0.is_some() && theta_value.2.is_some() {
-                            Some((theta_value.0.unwrap(), theta_value.2.unwrap()))
-                        } else {
-                            None
+                        bounds: match (theta_value.0, theta_value.2) {
+                            (Some(lower), Some(upper)) => Some((lower, upper)),
+                            _ => None,
                         },
+                        unit: None,
                     }
                 );
                 param_index += 1;
@@ -243,12 +282,7 @@ impl ControlStreamParser {
     fn parse_omega_block(&mut self, model_config: &mut ModelConfig) -> PKResult<()> {
         self.current_line += 1;
         
-        let param_names = match model_config.compartments {
-            1 => vec!["CL", "V", "KA"],
-            2 => vec!["CL", "V1", "Q", "V2", "KA"],
-            3 => vec!["CL", "V1", "Q2", "V2", "Q3", "V3", "KA"],
-            _ => return Err(PKError::InvalidModel("Invalid compartment number".to_string())),
-        };
+        let param_names = Self::theta_names(model_config)?;
         
         let mut param_index = 0;
         
@@ -334,6 +368,8 @@ impl ControlStreamParser {
             error_model,
             integration_method: IntegrationMethod::Analytical,
             tolerance: None,
+            lloq: None,
+            blq_method: BlqMethod::default(),
         })
     }
     
@@ -451,6 +487,8 @@ impl ControlStreamParser {
                 weight_sd,
                 age_mean,
                 age_sd,
+                weight_bounds: None,
+                age_bounds: None,
             },
             covariates: if covariates.is_empty() { None } else { Some(covariates) },
         })
@@ -468,6 +506,18 @@ impl ControlStreamParser {
             
             if line.to_uppercase().contains("TIME_POINTS") {
                 sim_config.time_points = self.extract_time_values(line)?;
+            } else if line.to_uppercase().contains("BLQ_METHOD") {
+                sim_config.blq_method = match self.extract_keyword_value(line, "BLQ_METHOD")?.as_str() {
+                    "FLAG" => BlqMethod::Flag,
+                    "HALF_LLOQ" => BlqMethod::HalfLloq,
+                    "DROP" => BlqMethod::Drop,
+                    "KEEP" => BlqMethod::Keep,
+                    other => return Err(PKError::Validation(format!(
+                        "Unknown BLQ_METHOD {}. Use FLAG, HALF_LLOQ, DROP or KEEP", other
+                    ))),
+                };
+            } else if line.to_uppercase().contains("LLOQ") {
+                sim_config.lloq = Some(self.extract_numeric_value(line, "LLOQ")?);
             } else if line.to_uppercase().contains("METHOD") {
                 if line.to_uppercase().contains("RK4") {
                     sim_config.integration_method = IntegrationMethod::Rk4;
@@ -496,6 +546,16 @@ impl ControlStreamParser {
             ))
     }
     
+    /// Value of a `KEYWORD = VALUE` line as one uppercase token.
+    fn extract_keyword_value(&self, line: &str, keyword: &str) -> PKResult<String> {
+        match line.split_once('=') {
+            Some((key, value)) if key.trim().eq_ignore_ascii_case(keyword) => Ok(value.trim().to_uppercase()),
+            _ => Err(PKError::Validation(
+                format!("Invalid {} specification: {}", keyword, line)
+            )),
+        }
+    }
+    
     fn extract_time_values(&self, line: &str) -> PKResult<Vec<f64>> {
         let parts: Vec<&str> = line.split('=').collect();
         if parts.len() != 2 {
@@ -578,8 +638,8 @@ impl ControlStreamParser {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs;
-    use std::path::PathBuf;
+    
+    
     
     #[test]
     fn test_parse_simple_control_stream() {
@@ -612,6 +672,46 @@ $SIGMA
         assert_eq!(config.model.parameters["KA"].theta, 1.5);
     }
     
+    #[test]
+    fn test_parse_trans_parameterization() {
+        let content = r#"
+$PROBLEM Two compartment micro-constant model
+$SUBROUTINES ADVAN3 TRANS1
+$THETA
+0.2   ; K
+0.1   ; K12
+0.2   ; K21
+10.0  ; V
+$OMEGA
+0.09
+$SIGMA
+0.0225
+"#;
+        
+        let mut parser = ControlStreamParser::new(content);
+        let config = parser.parse().unwrap();
+        
+        assert_eq!(config.model.compartments, 2);
+        assert_eq!(config.model.parameterization, Some(Parameterization::Trans1));
+        assert_eq!(config.model.parameters["K12"].theta, 0.1);
+        assert_eq!(config.model.parameters["V"].theta, 10.0);
+        assert!(config.model.parameters["K"].omega.is_some());
+        config.validate().unwrap();
+    }
+    
+    #[test]
+    fn test_parse_advan11_is_three_compartment() {
+        let mut parser = ControlStreamParser::new("$SUBROUTINES ADVAN11 TRANS4");
+        let model = parser.parse_subroutines().unwrap();
+        assert_eq!(model.compartments, 3);
+        assert_eq!(model.parameterization, Some(Parameterization::Trans4));
+        
+        let mut parser = ControlStreamParser::new("$SUBROUTINES ADVAN1 TRANS6");
+        assert!(parser.parse_subroutines().is_err());
+        let mut parser = ControlStreamParser::new("$SUBROUTINES ADVAN3 TRANS2");
+        assert!(parser.parse_subroutines().is_err());
+    }
+    
     #[test]
     fn test_parse_theta_with_bounds() {
         let parser = ControlStreamParser::new("");
@@ -679,7 +779,7 @@ MODEL = PROPORTIONAL
 0.0225
 "#;
         let mut parser = ControlStreamParser::new(content_prop);
-        parser.current_line = 1; // Skip to $SIGMA
+        parser.current_line = 0; // Point at $SIGMA
         let config = parser.parse_sigma_block().unwrap();
         assert!(matches!(config.error_model, ErrorModel::Proportional { .. }));
         
@@ -689,8 +789,25 @@ MODEL = COMBINED
 0.0144, 0.0025
 "#;
         let mut parser = ControlStreamParser::new(content_combined);
-        parser.current_line = 1; // Skip to $SIGMA
+        parser.current_line = 0; // Point at $SIGMA
         let config = parser.parse_sigma_block().unwrap();
         assert!(matches!(config.error_model, ErrorModel::Combined { .. }));
     }
+    
+    #[test]
+    fn test_parse_blq_method() {
+        let blq_method = |line: &str| {
+            let content = format!("$SIGMA\n0.0225\n$SIMULATION\nLLOQ = 0.1\n{}\n", line);
+            let mut parser = ControlStreamParser::new(&content);
+            let mut config = parser.parse_sigma_block()?;
+            parser.parse_simulation_block(&mut config)?;
+            Ok::<_, PKError>(config.blq_method)
+        };
+        
+        assert_eq!(blq_method("BLQ_METHOD = HALF_LLOQ").unwrap(), BlqMethod::HalfLloq);
+        assert_eq!(blq_method("BLQ_METHOD=keep").unwrap(), BlqMethod::Keep);
+        // Whole values only
+        assert!(blq_method("BLQ_METHOD = KEEP_DROPPED").is_err());
+        assert!(blq_method("BLQ_METHOD = FLAGGED").is_err());
+    }
 }
\ No newline at end of file
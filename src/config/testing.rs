@@ -0,0 +1,53 @@
+//! Configuration shared by the unit tests, so that each test states only the
+//! fields it exercises.
+
+use super::Config;
+use serde_json::Value;
+
+/// One-compartment model with CL 2 L/h (omega 30%) and V 10 L (omega 20%),
+/// a single 100 mg IV bolus, adult demographics and a 10% proportional
+/// error, sampled at 1, 4 and 12 h.
+const BASE: &str = r#"{
+    "model": { "compartments": 1, "parameters": {
+        "CL": { "theta": 2.0, "omega": 30.0 }, "V": { "theta": 10.0, "omega": 20.0 }
+    } },
+    "dosing": { "route": "ivbolus", "amount": 100.0, "times": [0.0] },
+    "population": { "demographics": {
+        "weight_mean": 70.0, "weight_sd": 10.0, "age_mean": 40.0, "age_sd": 10.0
+    } },
+    "simulation": {
+        "time_points": [1.0, 4.0, 12.0],
+        "error_model": { "type": "proportional", "sigma": 0.1 },
+        "integration_method": "analytical"
+    }
+}"#;
+
+/// The base configuration with each of `patches` merged in turn as a JSON
+/// merge patch: objects are merged key by key, `null` removes a key and any
+/// other value replaces the base one. An object with a `type`, i.e. an enum
+/// variant such as the error model, replaces the base one as a whole.
+///
+/// A module usually keeps its fixed overrides in one patch and passes the
+/// fields a test varies as a second one.
+pub(crate) fn config_with(patches: &[&str]) -> Config {
+    let mut config: Value = serde_json::from_str(BASE).unwrap();
+    for patch in patches {
+        merge(&mut config, serde_json::from_str(patch).unwrap());
+    }
+    serde_json::from_value(config).unwrap()
+}
+
+fn merge(base: &mut Value, patch: Value) {
+    match (base, patch) {
+        (Value::Object(base), Value::Object(patch)) if !patch.contains_key("type") => {
+            for (key, value) in patch {
+                if value.is_null() {
+                    base.remove(&key);
+                } else {
+                    merge(base.entry(key).or_insert(Value::Null), value);
+                }
+            }
+        },
+        (base, patch) => *base = patch,
+    }
+}
@@ -0,0 +1,236 @@
+use crate::config::{DosingConfig, DosingRoute};
+use crate::models::{DoseEvent, DoseRoute};
+use crate::error::{PKError, PKResult};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// Columns with a fixed meaning; any other numeric column is read as a
+/// covariate.
+const RESERVED_COLUMNS: [&str; 13] = [
+    "ID", "TIME", "AMT", "EVID", "MDV", "CMT", "DV", "RATE", "ADDL", "II", "SS", "PRED", "IPRED",
+];
+
+/// Observed concentration record.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObservedPoint {
+    pub time: f64,
+    pub dv: f64,
+}
+
+/// Dosing history, observations and covariates of one subject.
+#[derive(Debug, Clone)]
+pub struct SubjectData {
+    pub id: usize,
+    pub doses: Vec<DoseEvent>,
+    pub observations: Vec<ObservedPoint>,
+    /// First non-missing value of each covariate column
+    pub covariates: HashMap<String, f64>,
+}
+
+impl SubjectData {
+    pub fn observation_times(&self) -> Vec<f64> {
+        self.observations.iter().map(|obs| obs.time).collect()
+    }
+}
+
+/// NONMEM-format dataset grouped by subject in order of first appearance.
+#[derive(Debug, Clone)]
+pub struct Dataset {
+    pub subjects: Vec<SubjectData>,
+}
+
+impl Dataset {
+    /// Read a comma-separated NONMEM-style dataset with a header row.
+    ///
+    /// `ID`, `TIME` and `DV` are required; `AMT`, `EVID`, `MDV`, `RATE`,
+    /// `ADDL` and `II` are optional. `.` marks a missing value and rows whose
+    /// first field starts with `#` or `C` are comments. Doses are given by
+    /// `EVID=1`, or by a positive `AMT` when there is no `EVID` column. The
+    /// route comes from `dosing` unless `RATE` is positive, which makes the
+    /// dose a zero-order infusion lasting `AMT/RATE`.
+    pub fn from_file<P: AsRef<Path>>(path: P, dosing: &DosingConfig) -> PKResult<Self> {
+        let reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .comment(Some(b'#'))
+            .from_path(path)?;
+        Self::from_reader(reader, dosing)
+    }
+
+    pub fn from_reader<R: std::io::Read>(mut reader: csv::Reader<R>, dosing: &DosingConfig) -> PKResult<Self> {
+        let headers: Vec<String> = reader.headers()?
+            .iter()
+            .map(|h| h.to_uppercase())
+            .collect();
+        let column = |name: &str| headers.iter().position(|h| h == name);
+        let required = |name: &str| column(name).ok_or_else(|| PKError::Validation(
+            format!("Dataset is missing the {} column", name)
+        ));
+
+        let (id_col, time_col, dv_col) = (required("ID")?, required("TIME")?, required("DV")?);
+        let (amt_col, evid_col, mdv_col) = (column("AMT"), column("EVID"), column("MDV"));
+        let (rate_col, addl_col, ii_col) = (column("RATE"), column("ADDL"), column("II"));
+        let covariate_cols: Vec<(usize, &String)> = headers.iter()
+            .enumerate()
+            .filter(|(_, h)| !RESERVED_COLUMNS.contains(&h.as_str()))
+            .collect();
+
+        let default_route = match dosing.route {
+            DosingRoute::Oral => DoseRoute::Oral,
+            DosingRoute::IvBolus => DoseRoute::IvBolus,
+            DosingRoute::IvInfusion => DoseRoute::IvInfusion,
+        };
+        let default_duration = dosing.additional.as_ref().and_then(|a| a.duration);
+
+        let mut subjects: Vec<SubjectData> = Vec::new();
+        let mut index: BTreeMap<usize, usize> = BTreeMap::new();
+
+        for (row, record) in reader.records().enumerate() {
+            let record = record?;
+            let line = row + 2;
+            if record.get(0).is_some_and(|f| f.starts_with('C')) {
+                continue;
+            }
+
+            let field = |col: Option<usize>| -> PKResult<Option<f64>> {
+                match col.and_then(|c| record.get(c)) {
+                    None | Some("") | Some(".") => Ok(None),
+                    Some(text) => text.parse::<f64>().map(Some).map_err(|_| PKError::Validation(
+                        format!("Invalid number '{}' on line {}", text, line)
+                    )),
+                }
+            };
+
+            let id = field(Some(id_col))?.ok_or_else(|| PKError::Validation(
+                format!("Missing ID on line {}", line)
+            ))? as usize;
+            let time = field(Some(time_col))?.ok_or_else(|| PKError::Validation(
+                format!("Missing TIME on line {}", line)
+            ))?;
+            let amt = field(amt_col)?.unwrap_or(0.0);
+            let evid = field(evid_col)?.map(|e| e as u8);
+            let mdv = field(mdv_col)?.unwrap_or(0.0) != 0.0;
+
+            let position = *index.entry(id).or_insert_with(|| {
+                subjects.push(SubjectData {
+                    id,
+                    doses: Vec::new(),
+                    observations: Vec::new(),
+                    covariates: HashMap::new(),
+                });
+                subjects.len() - 1
+            });
+            let subject = &mut subjects[position];
+
+            for &(col, name) in &covariate_cols {
+                if let Some(value) = field(Some(col))? {
+                    subject.covariates.entry(name.clone()).or_insert(value);
+                }
+            }
+
+            let is_dose = match evid {
+                Some(evid) => evid == 1 || evid == 4,
+                None => amt > 0.0,
+            };
+
+            if is_dose {
+                let rate = field(rate_col)?.unwrap_or(0.0);
+                let (route, duration) = if rate > 0.0 {
+                    (DoseRoute::IvInfusion, Some(amt / rate))
+                } else if default_route == DoseRoute::IvInfusion {
+                    (DoseRoute::IvInfusion, default_duration)
+                } else {
+                    (default_route.clone(), None)
+                };
+
+                let additional = field(addl_col)?.unwrap_or(0.0) as usize;
+                let interval = field(ii_col)?.unwrap_or(0.0);
+                if additional > 0 && interval <= 0.0 {
+                    return Err(PKError::InvalidDosing(
+                        format!("ADDL without a positive II on line {}", line)
+                    ));
+                }
+
+                for k in 0..=additional {
+                    subject.doses.push(DoseEvent {
+                        time: time + k as f64 * interval,
+                        amount: amt,
+                        route: route.clone(),
+                        duration,
+                    });
+                }
+            } else if evid.unwrap_or(0) == 0 && !mdv {
+                if let Some(dv) = field(Some(dv_col))? {
+                    subject.observations.push(ObservedPoint { time, dv });
+                }
+            }
+        }
+
+        for subject in &mut subjects {
+            subject.doses.sort_by(|a, b| a.time.total_cmp(&b.time));
+            subject.observations.sort_by(|a, b| a.time.total_cmp(&b.time));
+        }
+
+        if subjects.is_empty() {
+            return Err(PKError::Validation("Dataset contains no records".to_string()));
+        }
+
+        Ok(Self { subjects })
+    }
+
+    pub fn n_observations(&self) -> usize {
+        self.subjects.iter().map(|s| s.observations.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oral_dosing() -> DosingConfig {
+        DosingConfig {
+            route: DosingRoute::Oral,
+            amount: 100.0,
+            times: vec![0.0],
+            additional: None,
+        }
+    }
+
+    fn read(text: &str) -> PKResult<Dataset> {
+        let reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(text.as_bytes());
+        Dataset::from_reader(reader, &oral_dosing())
+    }
+
+    #[test]
+    fn test_read_nonmem_dataset() {
+        let dataset = read(
+            "ID,TIME,AMT,EVID,MDV,DV,RATE,ADDL,II,WT\n\
+             1,0,100,1,1,.,0,1,12,72\n\
+             1,1,.,0,0,4.5,.,.,.,72\n\
+             1,2,.,0,1,.,.,.,.,72\n\
+             C,comment,,,,,,,,\n\
+             2,0,200,1,1,.,100,.,.,60\n\
+             2,4,.,0,0,3.1,.,.,.,60\n"
+        ).unwrap();
+
+        assert_eq!(dataset.subjects.len(), 2);
+        let first = &dataset.subjects[0];
+        assert_eq!(first.doses.len(), 2);
+        assert_eq!(first.doses[1].time, 12.0);
+        assert_eq!(first.doses[0].route, DoseRoute::Oral);
+        assert_eq!(first.observations, vec![ObservedPoint { time: 1.0, dv: 4.5 }]);
+        assert_eq!(first.covariates["WT"], 72.0);
+
+        let second = &dataset.subjects[1];
+        assert_eq!(second.doses[0].route, DoseRoute::IvInfusion);
+        assert_eq!(second.doses[0].duration, Some(2.0));
+        assert_eq!(dataset.n_observations(), 2);
+    }
+
+    #[test]
+    fn test_missing_required_column() {
+        assert!(read("ID,TIME,AMT\n1,0,100\n").is_err());
+    }
+}
@@ -1,6 +1,6 @@
 use crate::config::{DosingConfig, DosingRoute};
 use crate::models::{DoseEvent, DoseRoute as ModelDoseRoute};
-use crate::error::{PKError, PKResult};
+use crate::error::PKResult;
 
 pub struct DosingRegimen {
     pub events: Vec<DoseEvent>,
@@ -1,5 +1,4 @@
 use thiserror::Error;
-use rand_distr::NormalError;
 
 #[derive(Error, Debug)]
 pub enum PKError {
@@ -12,6 +11,12 @@ pub enum PKError {
     #[error("CSV error: {0}")]
     Csv(#[from] csv::Error),
     
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] arrow_schema::ArrowError),
+    
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+    
     #[error("Invalid model configuration: {0}")]
     InvalidModel(String),
     
@@ -0,0 +1,363 @@
+use super::{ParameterKind, PopulationParameters, Problem, Subject};
+use crate::config::Config;
+use crate::dosing::DosingRegimen;
+use crate::error::{PKError, PKResult};
+use crate::simulation::Demographics;
+use crate::solver::Matrix;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+/// Relative step of the finite-difference derivatives of the predictions.
+const STEP: f64 = 1e-5;
+
+/// Smallest increase of `ln det` the search accepts as an improvement.
+const MIN_IMPROVEMENT: f64 = 1e-8;
+
+/// Sampling times of a group of subjects that share dosing, demographics and
+/// sampling windows: the whole population, or one arm of a study.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DesignGroup {
+    pub arm: Option<String>,
+    pub n_subjects: usize,
+    pub windows: Vec<(f64, f64)>,
+    pub initial_times: Vec<f64>,
+    pub optimal_times: Vec<f64>,
+}
+
+/// Expected precision of one population parameter under the configured and
+/// the optimized design.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DesignParameter {
+    pub name: String,
+    pub kind: ParameterKind,
+    pub value: f64,
+    /// `None` where the design does not identify the parameters
+    pub initial_rse_percent: Option<f64>,
+    pub optimal_rse_percent: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DesignResult {
+    pub groups: Vec<DesignGroup>,
+    pub parameters: Vec<DesignParameter>,
+    /// `ln det` of the population Fisher information, `None` if singular
+    pub initial_log_det: Option<f64>,
+    pub optimal_log_det: Option<f64>,
+    /// D-efficiency of the configured design relative to the optimized one,
+    /// `(det initial / det optimal)^(1/p)` for `p` parameters
+    pub efficiency: Option<f64>,
+    pub iterations: usize,
+}
+
+/// The subjects of one group as they enter the Fisher information.
+struct Group {
+    arm: Option<String>,
+    n_subjects: usize,
+    subject: Subject,
+    windows: Vec<(f64, f64)>,
+}
+
+/// Evaluate the configured sampling design and search the sampling windows
+/// for the D-optimal one, i.e. the times that maximize `ln det` of the
+/// population Fisher information (see [`information`]).
+///
+/// Groups are the arms of the study, or `n_subjects` subjects without one,
+/// each represented by a typical subject with the mean demographics of the
+/// group. The search is a coordinate exchange: each sampling time in turn
+/// moves to the best of `grid_points` equally spaced times in its window,
+/// until a pass over all times no longer improves the criterion.
+pub fn optimize(config: &Config, n_subjects: usize) -> PKResult<DesignResult> {
+    let settings = config.design.clone().unwrap_or_default();
+    let groups = groups(config, n_subjects)?;
+    let problem = Problem::with_subjects(config, groups.iter().map(|g| g.subject.clone()).collect())?;
+    let parameters = problem.initial();
+
+    let initial: Vec<Vec<f64>> = groups.iter().map(|g| g.subject.times.clone()).collect();
+    let mut times = initial.clone();
+    let mut informations = groups.iter().zip(&times)
+        .map(|(group, times)| information(&problem, &group.subject, &parameters, times))
+        .collect::<PKResult<Vec<_>>>()?;
+    let initial_fim = total(&groups, &informations);
+    let mut best = initial_fim.log_det_spd().unwrap_or(f64::NEG_INFINITY);
+
+    let mut iterations = 0;
+    while iterations < settings.max_iterations {
+        iterations += 1;
+        let mut improved = false;
+        for (g, group) in groups.iter().enumerate() {
+            for (s, &(lower, upper)) in group.windows.iter().enumerate() {
+                for c in 0..settings.grid_points {
+                    let time = lower + (upper - lower) * c as f64 / (settings.grid_points - 1) as f64;
+                    if time == times[g][s] {
+                        continue;
+                    }
+                    let mut candidate = times[g].clone();
+                    candidate[s] = time;
+                    let info = information(&problem, &group.subject, &parameters, &candidate)?;
+                    let previous = std::mem::replace(&mut informations[g], info);
+                    match total(&groups, &informations).log_det_spd() {
+                        Some(log_det) if log_det > best + MIN_IMPROVEMENT => {
+                            best = log_det;
+                            times[g] = candidate;
+                            improved = true;
+                        },
+                        _ => informations[g] = previous,
+                    }
+                }
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+    let optimal_fim = total(&groups, &informations);
+    info!("Sampling design search finished after {} passes with ln det {:.4}", iterations, best);
+
+    let initial_rse = relative_standard_errors(&initial_fim, &parameters);
+    let optimal_rse = relative_standard_errors(&optimal_fim, &parameters);
+    let values = parameters.values();
+    let initial_log_det = initial_fim.log_det_spd();
+    let optimal_log_det = optimal_fim.log_det_spd();
+
+    Ok(DesignResult {
+        groups: groups.into_iter().zip(initial).zip(times)
+            .map(|((group, initial_times), optimal_times)| DesignGroup {
+                arm: group.arm,
+                n_subjects: group.n_subjects,
+                windows: group.windows,
+                initial_times,
+                optimal_times,
+            })
+            .collect(),
+        parameters: problem.parameter_names().into_iter().enumerate()
+            .map(|(i, (name, kind))| DesignParameter {
+                name,
+                kind,
+                value: values[i],
+                initial_rse_percent: initial_rse.as_ref().map(|rse| rse[i]),
+                optimal_rse_percent: optimal_rse.as_ref().map(|rse| rse[i]),
+            })
+            .collect(),
+        initial_log_det,
+        optimal_log_det,
+        efficiency: initial_log_det.zip(optimal_log_det)
+            .map(|(initial, optimal)| ((initial - optimal) / values.len() as f64).exp()),
+        iterations,
+    })
+}
+
+/// Population Fisher information of the configured sampling design, in the
+/// order of [`Problem::parameter_names`].
+pub fn fisher_information(config: &Config, n_subjects: usize) -> PKResult<Matrix> {
+    let groups = groups(config, n_subjects)?;
+    let problem = Problem::with_subjects(config, groups.iter().map(|g| g.subject.clone()).collect())?;
+    let parameters = problem.initial();
+    let informations = groups.iter()
+        .map(|group| information(&problem, &group.subject, &parameters, &group.subject.times))
+        .collect::<PKResult<Vec<_>>>()?;
+    Ok(total(&groups, &informations))
+}
+
+fn groups(config: &Config, n_subjects: usize) -> PKResult<Vec<Group>> {
+    let arms: Vec<(Option<String>, usize, Config)> = match &config.study {
+        Some(study) => study.arms.iter()
+            .map(|arm| (Some(arm.name.clone()), arm.n_subjects, config.arm(arm)))
+            .collect(),
+        None => vec![(None, n_subjects, config.clone())],
+    };
+    arms.into_iter().enumerate()
+        .map(|(i, (arm, n_subjects, config))| {
+            let windows = config.design.clone().unwrap_or_default().windows(&config.simulation.time_points);
+            let demographics = &config.population.demographics;
+            let subject = Subject {
+                id: i + 1,
+                doses: DosingRegimen::from_config(&config.dosing)?.events,
+                // Configured times outside their windows start at the nearest bound
+                times: config.simulation.time_points.iter().zip(&windows)
+                    .map(|(&time, &(lower, upper))| time.clamp(lower, upper))
+                    .collect(),
+                dv: Vec::new(),
+                demographics: Demographics { weight: demographics.weight_mean, age: demographics.age_mean },
+            };
+            Ok(Group { arm, n_subjects, subject, windows })
+        })
+        .collect()
+}
+
+/// Information of all groups: each group's information times its size.
+fn total(groups: &[Group], informations: &[Matrix]) -> Matrix {
+    let size = informations[0].rows();
+    groups.iter().zip(informations)
+        .fold(Matrix::zeros(size, size), |sum, (group, info)| sum.add(&info.scale(group.n_subjects as f64)))
+}
+
+/// Fisher information of one subject sampled at `times` under the
+/// first-order (FO) approximation: the observations are normal with mean
+/// `f = f(θ, η = 0)` and covariance `V = G Ω Gᵀ + Σ(f)`, with `G = ∂f/∂η`
+/// and `Σ(f)` the residual variances. The block of the THETAs is
+/// `Fᵀ V⁻¹ F` with `F = ∂f/∂θ`, that of the OMEGAs and SIGMAs
+/// `½ tr(V⁻¹ ∂V/∂λᵢ V⁻¹ ∂V/∂λⱼ)`, and the blocks are taken as independent
+/// (block-diagonal information, as in PFIM and PopED).
+fn information(problem: &Problem, subject: &Subject, parameters: &PopulationParameters, times: &[f64]) -> PKResult<Matrix> {
+    let n = times.len();
+    let (p, k) = (parameters.thetas.len(), parameters.omegas.len());
+    let zero = vec![0.0; k];
+    let predictions = problem.predict_at(subject, parameters, &zero, times)?;
+
+    let derivative = |plus: Vec<f64>, minus: Vec<f64>, step: f64| -> Vec<f64> {
+        plus.iter().zip(&minus).map(|(a, b)| (a - b) / (2.0 * step)).collect()
+    };
+    let mut f_theta = Vec::with_capacity(p);
+    for i in 0..p {
+        let step = STEP * parameters.thetas[i].abs().max(STEP);
+        let mut shifted = parameters.clone();
+        shifted.thetas[i] += step;
+        let plus = problem.predict_at(subject, &shifted, &zero, times)?;
+        shifted.thetas[i] -= 2.0 * step;
+        let minus = problem.predict_at(subject, &shifted, &zero, times)?;
+        f_theta.push(derivative(plus, minus, step));
+    }
+    let mut g_eta = Vec::with_capacity(k);
+    for i in 0..k {
+        let mut etas = zero.clone();
+        etas[i] = STEP;
+        let plus = problem.predict_at(subject, parameters, &etas, times)?;
+        etas[i] = -STEP;
+        let minus = problem.predict_at(subject, parameters, &etas, times)?;
+        g_eta.push(derivative(plus, minus, STEP));
+    }
+
+    // Derivatives of V with respect to each OMEGA and SIGMA
+    let mut d_variance: Vec<Matrix> = g_eta.iter()
+        .map(|g| Matrix::from_rows(&(0..n).map(|a| (0..n).map(|b| g[a] * g[b]).collect()).collect::<Vec<_>>()))
+        .collect();
+    for j in 0..parameters.sigmas.len() {
+        let step = STEP * parameters.sigmas[j];
+        let mut sigmas = parameters.sigmas.clone();
+        let mut diagonal = Matrix::zeros(n, n);
+        for (a, &f) in predictions.iter().enumerate() {
+            sigmas[j] = parameters.sigmas[j] + step;
+            let plus = problem.residual_variance(&sigmas, f).0;
+            sigmas[j] = parameters.sigmas[j] - step;
+            let minus = problem.residual_variance(&sigmas, f).0;
+            diagonal.set(a, a, (plus - minus) / (2.0 * step));
+        }
+        d_variance.push(diagonal);
+    }
+
+    let mut variance = Matrix::zeros(n, n);
+    for a in 0..n {
+        for b in 0..n {
+            let random: f64 = (0..k).map(|i| g_eta[i][a] * parameters.omegas[i] * g_eta[i][b]).sum();
+            let residual = if a == b { problem.residual_variance(&parameters.sigmas, predictions[a]).0 } else { 0.0 };
+            variance.set(a, b, random + residual);
+        }
+    }
+    let Some(inverse) = variance.inverse() else {
+        return Err(PKError::Simulation(format!(
+            "The variance of the observations of group {} is singular", subject.id
+        )));
+    };
+
+    let size = p + d_variance.len();
+    let mut fim = Matrix::zeros(size, size);
+    for (i, column) in f_theta.iter().enumerate() {
+        let weighted = inverse.mul_vec(column);
+        for (j, other) in f_theta.iter().enumerate() {
+            fim.set(i, j, other.iter().zip(&weighted).map(|(a, b)| a * b).sum());
+        }
+    }
+    let products: Vec<Matrix> = d_variance.iter().map(|d| inverse.mul(d)).collect();
+    for (i, a) in products.iter().enumerate() {
+        for (j, b) in products.iter().enumerate() {
+            let trace: f64 = (0..n).flat_map(|r| (0..n).map(move |c| (r, c)))
+                .map(|(r, c)| a.get(r, c) * b.get(c, r))
+                .sum();
+            fim.set(p + i, p + j, 0.5 * trace);
+        }
+    }
+    Ok(fim)
+}
+
+/// Expected RSE (%) of each parameter, `100 · √(FIM⁻¹)ᵢᵢ / |value|`, or
+/// `None` if the information is singular.
+fn relative_standard_errors(fim: &Matrix, parameters: &PopulationParameters) -> Option<Vec<f64>> {
+    fim.cholesky()?;
+    let covariance = fim.inverse()?;
+    Some(parameters.values().iter().enumerate()
+        .map(|(i, value)| 100.0 * covariance.get(i, i).max(0.0).sqrt() / value.abs())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testing::config_with;
+    use approx::assert_relative_eq;
+
+    /// V 20 L sampled at 0.5, 1 and 2 h with a combined error and an empty design.
+    const DESIGN: &str = r#"{
+        "model": { "parameters": { "V": { "theta": 20.0 } } },
+        "simulation": {
+            "time_points": [0.5, 1.0, 2.0],
+            "error_model": { "type": "combined", "sigma_prop": 0.1, "sigma_add": 0.05 }
+        },
+        "design": {}
+    }"#;
+
+    #[test]
+    fn test_fixed_effect_information_matches_analytical() {
+        let mut config = config_with(&[DESIGN]);
+        config.model.parameters.values_mut().for_each(|p| p.omega = None);
+        config.simulation.error_model = crate::config::ErrorModel::Additive { sigma: 0.2 };
+        let fim = fisher_information(&config, 10).unwrap();
+
+        // C = D/V · exp(-CL/V · t): ∂C/∂CL = -t/V · C, ∂C/∂V = C/V · (CL·t/V - 1)
+        let (cl, v) = (2.0, 20.0);
+        let mut expected = [[0.0; 2]; 2];
+        for t in [0.5, 1.0, 2.0] {
+            let c = 100.0 / v * f64::exp(-cl / v * t);
+            let gradient = [-t / v * c, c / v * (cl * t / v - 1.0)];
+            for i in 0..2 {
+                for j in 0..2 {
+                    expected[i][j] += 10.0 * gradient[i] * gradient[j] / 0.04;
+                }
+            }
+        }
+        for i in 0..2 {
+            for j in 0..2 {
+                assert_relative_eq!(fim.get(i, j), expected[i][j], max_relative = 1e-6);
+            }
+        }
+        // SIGMA: ½ n (2σ / σ²)² per observation
+        assert_relative_eq!(fim.get(2, 2), 10.0 * 3.0 * 2.0 / 0.04, max_relative = 1e-6);
+    }
+
+    #[test]
+    fn test_optimized_design_improves_precision() {
+        let config = config_with(&[DESIGN, r#"{ "design": { "windows": [[0.25, 1.0], [1.0, 8.0], [8.0, 48.0]] } }"#]);
+        config.validate().unwrap();
+        let result = optimize(&config, 50).unwrap();
+
+        let group = &result.groups[0];
+        assert_eq!(group.initial_times, [0.5, 1.0, 8.0]);
+        for (time, (lower, upper)) in group.optimal_times.iter().zip(&group.windows) {
+            assert!(lower <= time && time <= upper);
+        }
+        // A late sample identifies the variability of CL
+        assert!(group.optimal_times[2] > 8.0);
+        assert!(result.optimal_log_det.unwrap() > result.initial_log_det.unwrap());
+        assert!(result.efficiency.unwrap() < 1.0);
+        let cl = result.parameters.iter().find(|p| p.name == "CL" && p.kind == ParameterKind::Omega).unwrap();
+        assert!(cl.optimal_rse_percent.unwrap() < 0.5 * cl.initial_rse_percent.unwrap());
+
+        // Without windows the design is only evaluated
+        let fixed = optimize(&config_with(&[DESIGN]), 50).unwrap();
+        assert_eq!(fixed.groups[0].optimal_times, fixed.groups[0].initial_times);
+        assert_eq!(fixed.efficiency, Some(1.0));
+
+        let mut invalid = config;
+        invalid.design.as_mut().unwrap().windows.pop();
+        assert!(invalid.validate().is_err());
+    }
+}
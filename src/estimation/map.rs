@@ -0,0 +1,248 @@
+use super::{PopulationParameters, Problem, Subject, SubjectFit};
+use crate::config::{Config, MapConfig};
+use crate::data::Dataset;
+use crate::error::{PKError, PKResult};
+use crate::simulation::statistics::quantile;
+use crate::solver::Matrix;
+use log::info;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::StandardNormal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// MAP estimate of one individual parameter with its posterior interval.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapParameter {
+    pub name: String,
+    /// Typical value for the subject's covariates
+    pub typical: f64,
+    pub estimate: f64,
+    pub lower: f64,
+    pub upper: f64,
+    /// ETA and its posterior SD, if the parameter has an omega
+    pub eta: Option<f64>,
+    pub eta_sd: Option<f64>,
+}
+
+/// Predicted concentration at one time: the population prediction, the
+/// individual prediction at the MAP ETAs and its posterior interval.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapPrediction {
+    pub time: f64,
+    /// Measured concentration, if there is one at this time
+    pub dv: Option<f64>,
+    pub pred: f64,
+    pub ipred: f64,
+    pub lower: f64,
+    pub upper: f64,
+}
+
+/// MAP estimate of one subject.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapEstimate {
+    pub id: usize,
+    /// `Σ (y - f)²/V + ln V + ηᵀ Ω⁻¹ η` at the estimate, -2 log posterior up
+    /// to a constant
+    pub objective: f64,
+    pub parameters: Vec<MapParameter>,
+    pub predictions: Vec<MapPrediction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapResult {
+    pub coverage: f64,
+    pub samples: usize,
+    pub individuals: Vec<MapEstimate>,
+}
+
+/// Maximum a posteriori ETAs of every subject of `dataset`, with the
+/// configured THETAs, omegas and error model as the population model.
+///
+/// The posterior of the ETAs is approximated by a normal distribution around
+/// the MAP estimate with the inverse Gauss-Newton Hessian of
+/// `-log p(η | y)` as covariance. Intervals of the individual parameters and
+/// the predicted profile are quantiles over draws from this distribution.
+pub fn estimate(config: &Config, dataset: &Dataset, seed: Option<u64>) -> PKResult<MapResult> {
+    let settings = config.map.clone().unwrap_or_default();
+    let problem = Problem::new(config, dataset)?;
+    let parameters = problem.initial();
+    let mut rng = match seed {
+        Some(s) => StdRng::seed_from_u64(s),
+        None => StdRng::from_entropy(),
+    };
+    let times = if settings.prediction_times.is_empty() {
+        &config.simulation.time_points
+    } else {
+        &settings.prediction_times
+    };
+
+    let individuals = problem.subjects().iter()
+        .map(|subject| estimate_subject(&problem, subject, &parameters, times, &settings, &mut rng))
+        .collect::<PKResult<Vec<_>>>()?;
+    info!("MAP estimates of {} subjects", individuals.len());
+
+    Ok(MapResult { coverage: settings.coverage, samples: settings.samples, individuals })
+}
+
+/// Normal approximation of the posterior of one subject's ETAs.
+struct Posterior {
+    fit: SubjectFit,
+    covariance: Matrix,
+    factor: Matrix,
+}
+
+impl Posterior {
+    fn new(problem: &Problem, subject: &Subject, parameters: &PopulationParameters) -> PKResult<Self> {
+        let fit = problem.fit_subject(subject, parameters, &vec![0.0; problem.eta_names.len()])?;
+        let posterior = problem.linearize(subject, parameters, &fit.etas)?.half_hessian.inverse()
+            .and_then(|covariance| covariance.cholesky().map(|factor| (covariance, factor)));
+        let Some((covariance, factor)) = posterior else {
+            return Err(PKError::Simulation(format!(
+                "The posterior of subject {} is not positive definite", subject.id
+            )));
+        };
+        Ok(Self { fit, covariance, factor })
+    }
+
+    fn sample(&self, rng: &mut StdRng) -> Vec<f64> {
+        let z: Vec<f64> = (0..self.fit.etas.len()).map(|_| rng.sample(StandardNormal)).collect();
+        self.fit.etas.iter().zip(self.factor.mul_vec(&z)).map(|(eta, d)| eta + d).collect()
+    }
+}
+
+/// Draws of one subject's individual parameters from its posterior.
+#[derive(Debug, Clone)]
+pub struct PosteriorDraws {
+    pub id: usize,
+    pub parameters: Vec<HashMap<String, f64>>,
+}
+
+/// `samples` draws of the individual parameters of every subject of
+/// `dataset` from the posterior of [`estimate`].
+pub fn posterior_parameters(
+    config: &Config,
+    dataset: &Dataset,
+    samples: usize,
+    seed: Option<u64>,
+) -> PKResult<Vec<PosteriorDraws>> {
+    let problem = Problem::new(config, dataset)?;
+    let parameters = problem.initial();
+    let mut rng = match seed {
+        Some(s) => StdRng::seed_from_u64(s),
+        None => StdRng::from_entropy(),
+    };
+    problem.subjects().iter()
+        .map(|subject| {
+            let posterior = Posterior::new(&problem, subject, &parameters)?;
+            let draws = (0..samples)
+                .map(|_| problem.individual_parameters(subject, &parameters, &posterior.sample(&mut rng)))
+                .collect();
+            Ok(PosteriorDraws { id: subject.id, parameters: draws })
+        })
+        .collect()
+}
+
+fn estimate_subject(
+    problem: &Problem,
+    subject: &Subject,
+    parameters: &PopulationParameters,
+    prediction_times: &[f64],
+    settings: &MapConfig,
+    rng: &mut StdRng,
+) -> PKResult<MapEstimate> {
+    let n_etas = problem.eta_names.len();
+    let posterior = Posterior::new(problem, subject, parameters)?;
+    let (fit, covariance) = (&posterior.fit, &posterior.covariance);
+
+    // The measured times are part of the profile, to compare DV and IPRED
+    let mut times: Vec<f64> = prediction_times.iter().chain(&subject.times).copied().collect();
+    times.sort_by(|a, b| a.total_cmp(b));
+    times.dedup();
+
+    let mut parameter_draws = vec![Vec::with_capacity(settings.samples); problem.theta_names.len()];
+    let mut prediction_draws = vec![Vec::with_capacity(settings.samples); times.len()];
+    for _ in 0..settings.samples {
+        let etas = posterior.sample(rng);
+        let individual = problem.individual_parameters(subject, parameters, &etas);
+        for (draws, name) in parameter_draws.iter_mut().zip(&problem.theta_names) {
+            draws.push(individual[name]);
+        }
+        for (draws, c) in prediction_draws.iter_mut().zip(problem.predict_at(subject, parameters, &etas, &times)?) {
+            draws.push(c);
+        }
+    }
+    let (p_lower, p_upper) = (0.5 - settings.coverage / 2.0, 0.5 + settings.coverage / 2.0);
+
+    let individual = problem.individual_parameters(subject, parameters, &fit.etas);
+    let typical = problem.typical_values(subject, parameters);
+    let estimates = problem.theta_names.iter().enumerate()
+        .map(|(i, name)| {
+            let k = problem.eta_of_theta[i];
+            MapParameter {
+                name: name.clone(),
+                typical: typical[i],
+                estimate: individual[name],
+                lower: quantile(&parameter_draws[i], p_lower),
+                upper: quantile(&parameter_draws[i], p_upper),
+                eta: k.map(|k| fit.etas[k]),
+                eta_sd: k.map(|k| covariance.get(k, k).sqrt()),
+            }
+        })
+        .collect();
+
+    let pred = problem.predict_at(subject, parameters, &vec![0.0; n_etas], &times)?;
+    let ipred = problem.predict_at(subject, parameters, &fit.etas, &times)?;
+    let predictions = times.iter().enumerate()
+        .map(|(j, &time)| MapPrediction {
+            time,
+            dv: subject.times.iter().position(|&t| t == time).map(|i| subject.dv[i]),
+            pred: pred[j],
+            ipred: ipred[j],
+            lower: quantile(&prediction_draws[j], p_lower),
+            upper: quantile(&prediction_draws[j], p_upper),
+        })
+        .collect();
+
+    Ok(MapEstimate { id: subject.id, objective: fit.objective, parameters: estimates, predictions })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{config, simulated_dataset};
+    use super::*;
+
+    #[test]
+    fn test_map_estimate_narrows_with_observations() {
+        let mut config = config(0.1);
+        config.map = Some(MapConfig { samples: 400, ..Default::default() });
+        let dataset = simulated_dataset(&config, 2, 8);
+        let result = estimate(&config, &dataset, Some(3)).unwrap();
+        assert_eq!(result.individuals.len(), 2);
+
+        let individual = &result.individuals[0];
+        let observed = &dataset.subjects[0].observations;
+        assert_eq!(individual.predictions.iter().filter(|p| p.dv.is_some()).count(), observed.len());
+        for parameter in &individual.parameters {
+            assert!(parameter.lower < parameter.estimate && parameter.estimate < parameter.upper);
+            // Six observations leave much less uncertainty than the prior
+            // omegas of 20 and 30%
+            let eta_sd = parameter.eta_sd.unwrap();
+            assert!(eta_sd > 0.0 && eta_sd < 0.15);
+        }
+        for prediction in &individual.predictions {
+            assert!(prediction.lower <= prediction.ipred && prediction.ipred <= prediction.upper);
+        }
+
+        let again = estimate(&config, &dataset, Some(3)).unwrap();
+        assert_eq!(again.individuals[0].parameters[0].lower, individual.parameters[0].lower);
+    }
+
+    #[test]
+    fn test_map_rejects_dataset_without_observations() {
+        let config = config(0.1);
+        let text = "ID,TIME,AMT,EVID,DV\n1,0,100,1,.\n2,0,100,1,.\n";
+        let dataset = Dataset::from_reader(csv::Reader::from_reader(text.as_bytes()), &config.dosing).unwrap();
+        assert!(matches!(estimate(&config, &dataset, Some(1)), Err(PKError::Validation(_))));
+    }
+}
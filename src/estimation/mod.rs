@@ -0,0 +1,629 @@
+pub mod design;
+pub mod foce;
+pub mod map;
+pub mod optimize;
+pub mod saem;
+
+use crate::config::{Config, ErrorModel, EstimationMethod};
+use crate::data::Dataset;
+use crate::models::{DoseEvent, ModelParameters};
+use crate::simulation::{apply_covariate_effects, Demographics};
+use crate::solver::{EventSolver, Matrix};
+use crate::vpc::subject_demographics;
+use crate::error::{PKError, PKResult};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+pub use saem::{IterationRecord, SaemPhase};
+
+/// Residual variances are floored here so that observations with a zero
+/// prediction under a proportional error model stay finite.
+const MIN_VARIANCE: f64 = 1e-12;
+
+/// Warn about fitted subjects that lack a covariate with a configured effect;
+/// they are fitted at the population mean, which biases the THETAs.
+fn warn_missing_covariates(config: &Config, dataset: &Dataset) {
+    let Some(covariates) = &config.population.covariates else { return };
+    for column in ["WT", "AGE"] {
+        if !covariates.keys().any(|key| key.ends_with(&format!("_{}", column))) {
+            continue;
+        }
+        let missing: Vec<String> = dataset.subjects.iter()
+            .filter(|subject| !subject.observations.is_empty() && !subject.covariates.contains_key(column))
+            .map(|subject| subject.id.to_string())
+            .collect();
+        if !missing.is_empty() {
+            warn!(
+                "Subjects without {} are fitted at the population mean despite its covariate effect: {}",
+                column, missing.join(", ")
+            );
+        }
+    }
+}
+
+/// Kind of a population parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParameterKind {
+    Theta,
+    Omega,
+    Sigma,
+}
+
+/// Population parameters on their natural scale. OMEGAs are the variances of
+/// the ETAs (diagonal), SIGMAs the standard deviations of the error model in
+/// the order of its formula.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PopulationParameters {
+    pub thetas: Vec<f64>,
+    pub omegas: Vec<f64>,
+    pub sigmas: Vec<f64>,
+}
+
+impl PopulationParameters {
+    /// THETAs, OMEGAs and SIGMAs in one vector, in the order of
+    /// [`Problem::parameter_names`].
+    pub fn values(&self) -> Vec<f64> {
+        self.thetas.iter().chain(&self.omegas).chain(&self.sigmas).copied().collect()
+    }
+}
+
+/// One subject of the dataset as used in the fit.
+#[derive(Debug, Clone)]
+pub struct Subject {
+    pub id: usize,
+    pub doses: Vec<DoseEvent>,
+    pub times: Vec<f64>,
+    pub dv: Vec<f64>,
+    pub demographics: Demographics,
+}
+
+/// Conditional estimate of one subject's ETAs and its contribution to the
+/// FOCE-I objective function.
+#[derive(Debug, Clone)]
+pub struct SubjectFit {
+    pub etas: Vec<f64>,
+    /// `Σ (y - f)²/V + ln V + ηᵀ Ω⁻¹ η` at the estimate
+    pub objective: f64,
+    /// Objective plus `ln|Ω| + ln|Ω⁻¹ + Σ g gᵀ/V + ½ dV dVᵀ/V²|`
+    pub ofv: f64,
+    /// Individual predictions at the observation times
+    pub ipred: Vec<f64>,
+}
+
+/// Value of the objective at a set of ETAs with its gradient and the
+/// first-order approximation of half its Hessian.
+struct Linearization {
+    objective: f64,
+    gradient: Vec<f64>,
+    half_hessian: Matrix,
+    predictions: Vec<f64>,
+}
+
+/// A model fitted to a dataset: the configured model and error model with
+/// the data rearranged per subject.
+///
+/// Every configured THETA is estimated, with an ETA for each parameter that
+/// has an omega and the SDs of the error model. Individual parameters are
+/// `P = TV(P) · exp(η)` with `TV(P)` the THETA adjusted for the subject's
+/// covariates. Parameter bounds are not applied during estimation.
+pub struct Problem {
+    config: Config,
+    theta_names: Vec<String>,
+    /// ETA index of each THETA, if the parameter has an omega
+    eta_of_theta: Vec<Option<usize>>,
+    eta_names: Vec<String>,
+    sigma_names: Vec<&'static str>,
+    subjects: Vec<Subject>,
+    concentration_factor: f64,
+}
+
+impl Problem {
+    pub fn new(config: &Config, dataset: &Dataset) -> PKResult<Self> {
+        let demographics = &config.population.demographics;
+        let subjects: Vec<Subject> = dataset.subjects.iter()
+            .filter(|subject| !subject.observations.is_empty())
+            .map(|subject| Subject {
+                id: subject.id,
+                doses: subject.doses.clone(),
+                times: subject.observations.iter().map(|o| o.time).collect(),
+                dv: subject.observations.iter().map(|o| o.dv).collect(),
+                demographics: subject_demographics(subject, config).unwrap_or(Demographics {
+                    weight: demographics.weight_mean,
+                    age: demographics.age_mean,
+                }),
+            })
+            .collect();
+        if subjects.is_empty() {
+            return Err(PKError::Validation("The dataset has no observations to fit".to_string()));
+        }
+        warn_missing_covariates(config, dataset);
+        Self::with_subjects(config, subjects)
+    }
+
+    /// The configured model for `subjects` that do not come from a dataset.
+    fn with_subjects(config: &Config, subjects: Vec<Subject>) -> PKResult<Self> {
+        let mut theta_names: Vec<String> = config.model.parameters.keys().cloned().collect();
+        theta_names.sort();
+        let mut eta_names = Vec::new();
+        let eta_of_theta = theta_names.iter()
+            .map(|name| {
+                config.model.parameters[name].omega.map(|_| {
+                    eta_names.push(name.clone());
+                    eta_names.len() - 1
+                })
+            })
+            .collect();
+
+        let sigma_names = match config.simulation.error_model {
+            ErrorModel::Proportional { .. } => vec!["PROPORTIONAL"],
+            ErrorModel::Additive { .. } => vec!["ADDITIVE"],
+            ErrorModel::Combined { .. } => vec!["PROPORTIONAL", "ADDITIVE"],
+        };
+
+        let problem = Self {
+            config: config.clone(),
+            theta_names,
+            eta_of_theta,
+            eta_names,
+            sigma_names,
+            subjects,
+            concentration_factor: config.units.concentration_factor()?,
+        };
+
+        // Parameters are estimated on the log scale, so none may start at 0
+        let zero = problem.parameter_names().into_iter().zip(problem.initial().values())
+            .find(|((_, kind), value)| *kind != ParameterKind::Theta && *value <= 0.0);
+        match zero {
+            Some(((name, ParameterKind::Omega), _)) => Err(PKError::Validation(format!(
+                "The omega of {} must be positive to be estimated; remove it to fit {} without an ETA", name, name
+            ))),
+            Some(((name, _), _)) => Err(PKError::Validation(format!(
+                "The {} SD of the error model must be positive to be estimated", name.to_lowercase()
+            ))),
+            None => Ok(problem),
+        }
+    }
+
+    pub fn subjects(&self) -> &[Subject] {
+        &self.subjects
+    }
+
+    pub fn eta_names(&self) -> &[String] {
+        &self.eta_names
+    }
+
+    pub fn n_observations(&self) -> usize {
+        self.subjects.iter().map(|s| s.dv.len()).sum()
+    }
+
+    /// Starting values from the configuration.
+    pub fn initial(&self) -> PopulationParameters {
+        let parameters = &self.config.model.parameters;
+        PopulationParameters {
+            thetas: self.theta_names.iter().map(|name| parameters[name].theta).collect(),
+            omegas: self.eta_names.iter()
+                .map(|name| (parameters[name].omega.unwrap_or(0.0) / 100.0).powi(2))
+                .collect(),
+            sigmas: match self.config.simulation.error_model {
+                ErrorModel::Proportional { sigma } | ErrorModel::Additive { sigma } => vec![sigma],
+                ErrorModel::Combined { sigma_prop, sigma_add } => vec![sigma_prop, sigma_add],
+            },
+        }
+    }
+
+    /// Names and kinds of the parameters in the order of [`Problem::to_vector`].
+    pub fn parameter_names(&self) -> Vec<(String, ParameterKind)> {
+        self.theta_names.iter().map(|n| (n.clone(), ParameterKind::Theta))
+            .chain(self.eta_names.iter().map(|n| (n.clone(), ParameterKind::Omega)))
+            .chain(self.sigma_names.iter().map(|n| (n.to_string(), ParameterKind::Sigma)))
+            .collect()
+    }
+
+    /// Unconstrained vector of the log parameters, for the optimizer.
+    pub fn to_vector(&self, parameters: &PopulationParameters) -> Vec<f64> {
+        parameters.values().into_iter().map(f64::ln).collect()
+    }
+
+    pub fn from_vector(&self, x: &[f64]) -> PopulationParameters {
+        let (thetas, rest) = x.split_at(self.theta_names.len());
+        let (omegas, sigmas) = rest.split_at(self.eta_names.len());
+        let exp = |v: &[f64]| v.iter().map(|u| u.exp()).collect();
+        PopulationParameters { thetas: exp(thetas), omegas: exp(omegas), sigmas: exp(sigmas) }
+    }
+
+    /// Typical values `TV(P)` of `subject`, in the order of the THETAs.
+    pub fn typical_values(&self, subject: &Subject, parameters: &PopulationParameters) -> Vec<f64> {
+        self.theta_names.iter().zip(&parameters.thetas)
+            .map(|(name, &theta)| apply_covariate_effects(&self.config, theta, name, &subject.demographics))
+            .collect()
+    }
+
+    /// Individual parameters `TV(P) · exp(η)` of `subject` by name.
+    pub fn individual_parameters(&self, subject: &Subject, parameters: &PopulationParameters, etas: &[f64]) -> HashMap<String, f64> {
+        self.theta_names.iter().zip(self.typical_values(subject, parameters)).enumerate()
+            .map(|(i, (name, typical))| {
+                let eta = self.eta_of_theta[i].map_or(0.0, |k| etas[k]);
+                (name.clone(), typical * eta.exp())
+            })
+            .collect()
+    }
+
+    /// Individual predictions of `subject` at its observation times.
+    pub fn predict(&self, subject: &Subject, parameters: &PopulationParameters, etas: &[f64]) -> PKResult<Vec<f64>> {
+        self.predict_at(subject, parameters, etas, &subject.times)
+    }
+
+    /// Individual predictions of `subject` at `times`.
+    pub fn predict_at(&self, subject: &Subject, parameters: &PopulationParameters, etas: &[f64], times: &[f64]) -> PKResult<Vec<f64>> {
+        let individual = self.individual_parameters(subject, parameters, etas);
+        let model = ModelParameters::from_individual(&self.config.model, &individual)?;
+        let predictions = EventSolver::new(model).solve(&subject.doses, times)?;
+        Ok(predictions.into_iter().map(|c| c * self.concentration_factor).collect())
+    }
+
+    /// Residual variance at prediction `f` and its derivative with respect to
+    /// `f` (interaction).
+    fn residual_variance(&self, sigmas: &[f64], f: f64) -> (f64, f64) {
+        let (variance, derivative) = match self.config.simulation.error_model {
+            ErrorModel::Proportional { .. } => ((sigmas[0] * f).powi(2), 2.0 * sigmas[0].powi(2) * f),
+            ErrorModel::Additive { .. } => (sigmas[0].powi(2), 0.0),
+            ErrorModel::Combined { .. } => {
+                ((sigmas[0] * f).powi(2) + sigmas[1].powi(2), 2.0 * sigmas[0].powi(2) * f)
+            },
+        };
+        if variance < MIN_VARIANCE { (MIN_VARIANCE, 0.0) } else { (variance, derivative) }
+    }
+
+    /// `Σ (y - f)²/V + ln V + ηᵀ Ω⁻¹ η`, infinite where the model cannot be
+    /// solved.
+    fn objective(&self, subject: &Subject, parameters: &PopulationParameters, etas: &[f64]) -> f64 {
+        match self.predict(subject, parameters, etas) {
+            Ok(predictions) => self.objective_at(subject, parameters, etas, &predictions),
+            Err(_) => f64::INFINITY,
+        }
+    }
+
+    fn objective_at(&self, subject: &Subject, parameters: &PopulationParameters, etas: &[f64], predictions: &[f64]) -> f64 {
+        let residual: f64 = subject.dv.iter().zip(predictions)
+            .map(|(y, &f)| {
+                let (variance, _) = self.residual_variance(&parameters.sigmas, f);
+                (y - f).powi(2) / variance + variance.ln()
+            })
+            .sum();
+        let prior: f64 = etas.iter().zip(&parameters.omegas).map(|(eta, omega)| eta * eta / omega).sum();
+        let value = residual + prior;
+        if value.is_finite() { value } else { f64::INFINITY }
+    }
+
+    /// Objective, gradient and Gauss-Newton half Hessian at `etas`, with
+    /// forward-difference sensitivities of the predictions.
+    fn linearize(&self, subject: &Subject, parameters: &PopulationParameters, etas: &[f64]) -> PKResult<Linearization> {
+        const STEP: f64 = 1e-6;
+        let k = etas.len();
+        let predictions = self.predict(subject, parameters, etas)?;
+
+        let mut sensitivities = Vec::with_capacity(k);
+        for i in 0..k {
+            let mut shifted = etas.to_vec();
+            shifted[i] += STEP;
+            let perturbed = self.predict(subject, parameters, &shifted)?;
+            sensitivities.push(perturbed.iter().zip(&predictions).map(|(a, b)| (a - b) / STEP).collect::<Vec<f64>>());
+        }
+
+        let mut gradient: Vec<f64> = (0..k).map(|i| 2.0 * etas[i] / parameters.omegas[i]).collect();
+        let mut half_hessian = Matrix::zeros(k, k);
+        for i in 0..k {
+            half_hessian.set(i, i, 1.0 / parameters.omegas[i]);
+        }
+        for (j, (&y, &f)) in subject.dv.iter().zip(&predictions).enumerate() {
+            let (variance, dv_df) = self.residual_variance(&parameters.sigmas, f);
+            let residual = y - f;
+            let g: Vec<f64> = sensitivities.iter().map(|s| s[j]).collect();
+            for (a, &g_a) in g.iter().enumerate() {
+                gradient[a] += -2.0 * residual * g_a / variance
+                    + (1.0 - residual * residual / variance) * dv_df * g_a / variance;
+                for (b, &g_b) in g.iter().enumerate() {
+                    let value = half_hessian.get(a, b)
+                        + g_a * g_b / variance
+                        + 0.5 * dv_df * dv_df * g_a * g_b / (variance * variance);
+                    half_hessian.set(a, b, value);
+                }
+            }
+        }
+
+        Ok(Linearization {
+            objective: self.objective_at(subject, parameters, etas, &predictions),
+            gradient,
+            half_hessian,
+            predictions,
+        })
+    }
+
+    /// Conditional mode of the ETAs of `subject` (the empirical Bayes
+    /// estimate) by damped Gauss-Newton from `start`, and its FOCE-I
+    /// objective function contribution.
+    pub fn fit_subject(&self, subject: &Subject, parameters: &PopulationParameters, start: &[f64]) -> PKResult<SubjectFit> {
+        const MAX_ITERATIONS: usize = 100;
+        const STEP_TOLERANCE: f64 = 1e-8;
+
+        let mut etas = start.to_vec();
+        let mut current = self.linearize(subject, parameters, &etas)?;
+        if !current.objective.is_finite() {
+            etas = vec![0.0; etas.len()];
+            current = self.linearize(subject, parameters, &etas)?;
+        }
+
+        for _ in 0..MAX_ITERATIONS {
+            let rhs = Matrix::from_rows(&current.gradient.iter().map(|g| vec![-0.5 * g]).collect::<Vec<_>>());
+            let Some(step) = current.half_hessian.solve(&rhs) else { break };
+            let step: Vec<f64> = (0..etas.len()).map(|i| step.get(i, 0)).collect();
+
+            let mut alpha = 1.0;
+            let mut accepted = None;
+            for _ in 0..30 {
+                let trial: Vec<f64> = etas.iter().zip(&step).map(|(e, s)| e + alpha * s).collect();
+                if self.objective(subject, parameters, &trial) <= current.objective {
+                    accepted = Some(trial);
+                    break;
+                }
+                alpha *= 0.5;
+            }
+            let Some(trial) = accepted else { break };
+
+            let size = step.iter().fold(0.0f64, |m, s| m.max((alpha * s).abs()));
+            etas = trial;
+            current = self.linearize(subject, parameters, &etas)?;
+            if size < STEP_TOLERANCE {
+                break;
+            }
+        }
+
+        let log_det_omega: f64 = parameters.omegas.iter().map(|o| o.ln()).sum();
+        let log_det_hessian = if etas.is_empty() {
+            0.0
+        } else {
+            current.half_hessian.log_det_spd().unwrap_or(f64::INFINITY)
+        };
+
+        Ok(SubjectFit {
+            ofv: current.objective + log_det_omega + log_det_hessian,
+            objective: current.objective,
+            etas,
+            ipred: current.predictions,
+        })
+    }
+
+    /// FOCE-I objective function value, `-2 log L` up to a constant, with the
+    /// ETAs of every subject re-estimated from `starts`.
+    pub fn ofv(&self, parameters: &PopulationParameters, starts: &[Vec<f64>]) -> PKResult<(f64, Vec<SubjectFit>)> {
+        let fits = self.subjects.iter().zip(starts)
+            .map(|(subject, start)| self.fit_subject(subject, parameters, start))
+            .collect::<PKResult<Vec<_>>>()?;
+        let ofv = fits.iter().map(|f| f.ofv).sum();
+        Ok((ofv, fits))
+    }
+
+    /// FOCE-I objective function value at the log parameters `x`. `starts`
+    /// are the ETAs the conditional estimation starts from and are replaced
+    /// by the new estimates.
+    fn ofv_at(&self, x: &[f64], starts: &mut [Vec<f64>]) -> PKResult<f64> {
+        let (ofv, fits) = self.ofv(&self.from_vector(x), starts)?;
+        if ofv.is_finite() {
+            for (start, fit) in starts.iter_mut().zip(fits) {
+                *start = fit.etas;
+            }
+        }
+        Ok(ofv)
+    }
+
+    /// Covariance of the log parameters at `x`: `2·R⁻¹` with `R` the
+    /// finite-difference Hessian of the FOCE-I objective function. `None`
+    /// if `R` is not positive definite.
+    fn covariance(&self, x: &[f64], starts: &mut [Vec<f64>]) -> PKResult<Option<Matrix>> {
+        let r = optimize::hessian(&mut |x: &[f64]| self.ofv_at(x, starts), x, 1e-3)?;
+        let covariance = r.cholesky().and_then(|_| r.inverse()).map(|inverse| inverse.scale(2.0));
+        if covariance.is_none() {
+            warn!("Covariance step failed: the Hessian of the objective is not positive definite");
+        }
+        Ok(covariance)
+    }
+}
+
+/// Estimate of one population parameter. OMEGAs are reported as variances
+/// with the equivalent CV% (`100·√ω²`), the unit used in the configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParameterEstimate {
+    pub name: String,
+    pub kind: ParameterKind,
+    pub initial: f64,
+    pub estimate: f64,
+    pub se: Option<f64>,
+    pub rse_percent: Option<f64>,
+    pub cv_percent: Option<f64>,
+}
+
+/// Empirical Bayes estimates of one subject.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndividualEstimate {
+    pub id: usize,
+    pub etas: BTreeMap<String, f64>,
+    /// Contribution to the objective function value
+    pub ofv: f64,
+    pub times: Vec<f64>,
+    pub dv: Vec<f64>,
+    pub ipred: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EstimationResult {
+    pub method: EstimationMethod,
+    pub objective_function_value: f64,
+    pub iterations: usize,
+    pub converged: bool,
+    pub n_subjects: usize,
+    pub n_observations: usize,
+    pub estimates: Vec<ParameterEstimate>,
+    pub individuals: Vec<IndividualEstimate>,
+    /// Parameter values per iteration, for methods that record them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<IterationRecord>,
+}
+
+impl EstimationResult {
+    /// Assemble the result from the final parameters and subject fits, with
+    /// standard errors on the natural scale from the covariance of the log
+    /// parameters, if computed.
+    fn new(
+        problem: &Problem,
+        method: EstimationMethod,
+        final_parameters: &PopulationParameters,
+        fits: &[SubjectFit],
+        log_covariance: Option<&Matrix>,
+        iterations: usize,
+        converged: bool,
+    ) -> Self {
+        let initial = problem.initial().values();
+        let estimate = final_parameters.values();
+        let estimates = problem.parameter_names().into_iter().enumerate()
+            .map(|(i, (name, kind))| {
+                let value = estimate[i];
+                let se = log_covariance.map(|c| value * c.get(i, i).max(0.0).sqrt());
+                ParameterEstimate {
+                    name,
+                    kind,
+                    initial: initial[i],
+                    estimate: value,
+                    se,
+                    rse_percent: se.map(|se| 100.0 * se / value),
+                    cv_percent: (kind == ParameterKind::Omega).then(|| 100.0 * value.sqrt()),
+                }
+            })
+            .collect();
+
+        let individuals = problem.subjects.iter().zip(fits)
+            .map(|(subject, fit)| IndividualEstimate {
+                id: subject.id,
+                etas: problem.eta_names.iter().cloned().zip(fit.etas.iter().copied()).collect(),
+                ofv: fit.ofv,
+                times: subject.times.clone(),
+                dv: subject.dv.clone(),
+                ipred: fit.ipred.clone(),
+            })
+            .collect();
+
+        Self {
+            method,
+            objective_function_value: fits.iter().map(|f| f.ofv).sum(),
+            iterations,
+            converged,
+            n_subjects: problem.subjects.len(),
+            n_observations: problem.n_observations(),
+            estimates,
+            individuals,
+            history: Vec::new(),
+        }
+    }
+
+    /// Write the estimates into `config` as its THETAs, omegas (CV%) and
+    /// error model, e.g. to simulate from the fitted model.
+    pub fn apply(&self, config: &mut Config) {
+        let mut sigmas = Vec::new();
+        for estimate in &self.estimates {
+            match estimate.kind {
+                ParameterKind::Theta => {
+                    if let Some(param) = config.model.parameters.get_mut(&estimate.name) {
+                        param.theta = estimate.estimate;
+                    }
+                },
+                ParameterKind::Omega => {
+                    if let Some(param) = config.model.parameters.get_mut(&estimate.name) {
+                        param.omega = estimate.cv_percent;
+                    }
+                },
+                ParameterKind::Sigma => sigmas.push(estimate.estimate),
+            }
+        }
+        config.simulation.error_model = match (&config.simulation.error_model, sigmas.as_slice()) {
+            (ErrorModel::Proportional { .. }, [sigma]) => ErrorModel::Proportional { sigma: *sigma },
+            (ErrorModel::Additive { .. }, [sigma]) => ErrorModel::Additive { sigma: *sigma },
+            (ErrorModel::Combined { .. }, [sigma_prop, sigma_add]) => {
+                ErrorModel::Combined { sigma_prop: *sigma_prop, sigma_add: *sigma_add }
+            },
+            (model, _) => model.clone(),
+        };
+    }
+}
+
+/// Fit the configured model to `dataset` with the configured method. The
+/// seed makes the stochastic methods reproducible.
+pub fn estimate(config: &Config, dataset: &Dataset, seed: Option<u64>) -> PKResult<EstimationResult> {
+    let settings = config.estimation.clone().unwrap_or_default();
+    let problem = Problem::new(config, dataset)?;
+    match settings.method {
+        EstimationMethod::Focei => foce::estimate(&problem, &settings),
+        EstimationMethod::Saem => saem::estimate(&problem, &settings, seed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testing::config_with;
+    use crate::output::save_nonmem_dataset;
+    use crate::simulation::Simulator;
+    use approx::assert_relative_eq;
+
+    /// The shared test configuration sampled at six times up to 12 h, with a
+    /// proportional error of `sigma`.
+    pub(crate) fn config(sigma: f64) -> Config {
+        let mut config = config_with(&[r#"{ "simulation": { "time_points": [0.5, 1.0, 2.0, 4.0, 8.0, 12.0] } }"#]);
+        config.simulation.error_model = ErrorModel::Proportional { sigma };
+        config
+    }
+
+    /// Dataset simulated from `config` through the NONMEM export.
+    pub(crate) fn simulated_dataset(config: &Config, n: usize, seed: u64) -> Dataset {
+        let mut simulator = Simulator::new(config.clone(), Some(seed)).unwrap();
+        let results = simulator.simulate_population(n).unwrap();
+        let path = std::env::temp_dir().join(format!("pk_estimation_{}_{}.csv", std::process::id(), seed));
+        save_nonmem_dataset(&results, &simulator, &path).unwrap();
+        let dataset = Dataset::from_file(&path, &config.dosing).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        dataset
+    }
+
+    #[test]
+    fn test_conditional_etas_recover_individual() {
+        let config = config(0.01);
+        let dataset = simulated_dataset(&config, 3, 4);
+        let problem = Problem::new(&config, &dataset).unwrap();
+        let parameters = problem.initial();
+
+        // Noise-free observations of a subject with CL and V 20% above typical
+        let mut subject = problem.subjects()[0].clone();
+        subject.demographics = Demographics { weight: 70.0, age: 40.0 };
+        let truth = [1.2f64.ln(), 1.2f64.ln()];
+        subject.dv = problem.predict(&subject, &parameters, &truth).unwrap();
+
+        let fit = problem.fit_subject(&subject, &parameters, &[0.0, 0.0]).unwrap();
+        // Shrunk slightly towards zero by the prior
+        assert_relative_eq!(fit.etas[0], truth[0], epsilon = 0.01);
+        assert_relative_eq!(fit.etas[1], truth[1], epsilon = 0.01);
+        assert!(fit.etas[0] < truth[0]);
+        assert!(fit.ofv.is_finite());
+    }
+
+    #[test]
+    fn test_zero_omega_or_sigma_is_rejected() {
+        let dataset = simulated_dataset(&config(0.1), 2, 5);
+        let zero_omega = config_with(&[r#"{ "model": { "parameters": { "V": { "omega": 0.0 } } } }"#]);
+        let err = Problem::new(&zero_omega, &dataset).err().unwrap();
+        assert!(err.to_string().contains("omega of V"));
+
+        let zero_sigma = config_with(&[r#"{ "simulation": { "error_model": { "type": "combined", "sigma_prop": 0.1, "sigma_add": 0.0 } } }"#]);
+        let err = Problem::new(&zero_sigma, &dataset).err().unwrap();
+        assert!(err.to_string().contains("additive SD"));
+    }
+}
@@ -0,0 +1,183 @@
+use crate::error::PKResult;
+use crate::solver::Matrix;
+
+/// Largest change of any coordinate in one quasi-Newton step. The estimation
+/// works on log parameters, so this limits a step to a factor of e.
+const MAX_STEP: f64 = 1.0;
+
+/// Point found by [`minimize`].
+#[derive(Debug, Clone)]
+pub struct Minimum {
+    pub x: Vec<f64>,
+    pub value: f64,
+    pub iterations: usize,
+    pub converged: bool,
+}
+
+/// Central finite-difference gradient of `f` at `x`.
+pub fn gradient<F>(f: &mut F, x: &[f64], step: f64) -> PKResult<Vec<f64>>
+where
+    F: FnMut(&[f64]) -> PKResult<f64>,
+{
+    let mut point = x.to_vec();
+    (0..x.len())
+        .map(|i| {
+            point[i] = x[i] + step;
+            let upper = f(&point)?;
+            point[i] = x[i] - step;
+            let lower = f(&point)?;
+            point[i] = x[i];
+            Ok((upper - lower) / (2.0 * step))
+        })
+        .collect()
+}
+
+/// Central finite-difference Hessian of `f` at `x`.
+pub fn hessian<F>(f: &mut F, x: &[f64], step: f64) -> PKResult<Matrix>
+where
+    F: FnMut(&[f64]) -> PKResult<f64>,
+{
+    let n = x.len();
+    let center = f(x)?;
+    let mut point = x.to_vec();
+    let shifted = |point: &mut Vec<f64>, moves: &[(usize, f64)], f: &mut F| -> PKResult<f64> {
+        for &(i, d) in moves {
+            point[i] += d;
+        }
+        let value = f(point);
+        point.copy_from_slice(x);
+        value
+    };
+
+    let mut result = Matrix::zeros(n, n);
+    for i in 0..n {
+        let upper = shifted(&mut point, &[(i, step)], f)?;
+        let lower = shifted(&mut point, &[(i, -step)], f)?;
+        result.set(i, i, (upper - 2.0 * center + lower) / (step * step));
+        for j in 0..i {
+            let pp = shifted(&mut point, &[(i, step), (j, step)], f)?;
+            let pm = shifted(&mut point, &[(i, step), (j, -step)], f)?;
+            let mp = shifted(&mut point, &[(i, -step), (j, step)], f)?;
+            let mm = shifted(&mut point, &[(i, -step), (j, -step)], f)?;
+            let value = (pp - pm - mp + mm) / (4.0 * step * step);
+            result.set(i, j, value);
+            result.set(j, i, value);
+        }
+    }
+    Ok(result)
+}
+
+/// Minimize `f` by BFGS with finite-difference gradients and a backtracking
+/// line search. Converged when no gradient element exceeds
+/// `tolerance · max(1, |f|)`.
+///
+/// Errors from `f` during the line search are treated as an infinite value,
+/// so the search backs away from parameters the model cannot be solved for.
+pub fn minimize<F>(mut f: F, x0: &[f64], max_iterations: usize, tolerance: f64) -> PKResult<Minimum>
+where
+    F: FnMut(&[f64]) -> PKResult<f64>,
+{
+    const GRADIENT_STEP: f64 = 1e-4;
+    let n = x0.len();
+    let dot = |a: &[f64], b: &[f64]| -> f64 { a.iter().zip(b).map(|(x, y)| x * y).sum() };
+
+    let mut x = x0.to_vec();
+    let mut value = f(&x)?;
+    let mut g = gradient(&mut f, &x, GRADIENT_STEP)?;
+    let mut inverse_hessian = Matrix::identity(n);
+    let mut fresh = true;
+
+    for iteration in 0..max_iterations {
+        let limit = tolerance * value.abs().max(1.0);
+        if g.iter().all(|gi| gi.abs() <= limit) {
+            return Ok(Minimum { x, value, iterations: iteration, converged: true });
+        }
+
+        let mut direction: Vec<f64> = inverse_hessian.mul_vec(&g).iter().map(|d| -d).collect();
+        if dot(&direction, &g) >= 0.0 {
+            inverse_hessian = Matrix::identity(n);
+            fresh = true;
+            direction = g.iter().map(|d| -d).collect();
+        }
+        let largest = direction.iter().fold(0.0f64, |m, d| m.max(d.abs()));
+        if largest > MAX_STEP {
+            direction.iter_mut().for_each(|d| *d *= MAX_STEP / largest);
+        }
+
+        let slope = dot(&direction, &g);
+        let mut alpha = 1.0;
+        let mut accepted = None;
+        for _ in 0..40 {
+            let trial: Vec<f64> = x.iter().zip(&direction).map(|(xi, d)| xi + alpha * d).collect();
+            let trial_value = f(&trial).unwrap_or(f64::INFINITY);
+            if trial_value.is_finite() && trial_value <= value + 1e-4 * alpha * slope {
+                accepted = Some((trial, trial_value));
+                break;
+            }
+            alpha *= 0.5;
+        }
+
+        let Some((next, next_value)) = accepted else {
+            if fresh {
+                // Even steepest descent cannot improve: at the resolution
+                // of the finite differences this is the minimum.
+                return Ok(Minimum { x, value, iterations: iteration, converged: false });
+            }
+            inverse_hessian = Matrix::identity(n);
+            fresh = true;
+            continue;
+        };
+
+        let next_g = gradient(&mut f, &next, GRADIENT_STEP)?;
+        let s: Vec<f64> = next.iter().zip(&x).map(|(a, b)| a - b).collect();
+        let y: Vec<f64> = next_g.iter().zip(&g).map(|(a, b)| a - b).collect();
+        let sy = dot(&s, &y);
+        if sy > 1e-12 {
+            if fresh {
+                inverse_hessian = Matrix::identity(n).scale(sy / dot(&y, &y));
+            }
+            // H ← (I - ρ s yᵀ) H (I - ρ y sᵀ) + ρ s sᵀ
+            let rho = 1.0 / sy;
+            let hy = inverse_hessian.mul_vec(&y);
+            let yhy = dot(&y, &hy);
+            for i in 0..n {
+                for j in 0..n {
+                    let value = inverse_hessian.get(i, j)
+                        - rho * (s[i] * hy[j] + hy[i] * s[j])
+                        + (rho * rho * yhy + rho) * s[i] * s[j];
+                    inverse_hessian.set(i, j, value);
+                }
+            }
+            fresh = false;
+        }
+
+        x = next;
+        value = next_value;
+        g = next_g;
+    }
+
+    let limit = tolerance * value.abs().max(1.0);
+    let converged = g.iter().all(|gi| gi.abs() <= limit);
+    Ok(Minimum { x, value, iterations: max_iterations, converged })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_minimize_rosenbrock() {
+        let rosenbrock = |x: &[f64]| Ok((1.0 - x[0]).powi(2) + 100.0 * (x[1] - x[0] * x[0]).powi(2));
+        let minimum = minimize(rosenbrock, &[-1.2, 1.0], 500, 1e-6).unwrap();
+        assert!(minimum.converged);
+        assert_relative_eq!(minimum.x[0], 1.0, epsilon = 1e-3);
+        assert_relative_eq!(minimum.x[1], 1.0, epsilon = 1e-3);
+
+        let mut quadratic = |x: &[f64]| Ok(x[0] * x[0] + 3.0 * x[0] * x[1] + 5.0 * x[1] * x[1]);
+        let h = hessian(&mut quadratic, &[0.3, -0.2], 1e-3).unwrap();
+        assert_relative_eq!(h.get(0, 0), 2.0, epsilon = 1e-6);
+        assert_relative_eq!(h.get(0, 1), 3.0, epsilon = 1e-6);
+        assert_relative_eq!(h.get(1, 1), 10.0, epsilon = 1e-6);
+    }
+}
@@ -0,0 +1,382 @@
+use super::optimize::minimize;
+use super::{EstimationResult, PopulationParameters, Problem, Subject};
+use crate::config::{EstimationConfig, EstimationMethod};
+use crate::error::PKResult;
+use log::{info, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::StandardNormal;
+use serde::{Deserialize, Serialize};
+
+/// Acceptance rate the random-walk kernels adapt their step size towards.
+const TARGET_ACCEPTANCE: f64 = 0.4;
+
+/// During burn-in an omega shrinks by at most this factor per iteration, so
+/// the chains keep exploring before the variances settle.
+const OMEGA_ANNEALING: f64 = 0.95;
+
+/// Estimates are considered stable when none changed by more than this
+/// (relative) over the last tenth of the accumulation phase.
+const STABLE_CHANGE: f64 = 0.02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SaemPhase {
+    BurnIn,
+    Accumulation,
+}
+
+/// State of one SAEM iteration, for convergence diagnostics.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IterationRecord {
+    pub iteration: usize,
+    pub phase: SaemPhase,
+    pub step_size: f64,
+    /// -2 log-likelihood of the data and the sampled ETAs
+    pub complete_likelihood: f64,
+    /// Parameter values in the order of the estimates
+    pub values: Vec<f64>,
+    /// Acceptance rates of the prior, random-walk and component-wise kernels
+    pub acceptance: [f64; 3],
+}
+
+/// Current ETA sample of one subject with its `Σ (y - f)²/V + ln V`.
+struct Chain {
+    etas: Vec<f64>,
+    residual: f64,
+    predictions: Vec<f64>,
+}
+
+/// Accepted and proposed moves of one kernel in an iteration.
+#[derive(Default)]
+struct Acceptance {
+    accepted: usize,
+    proposed: usize,
+}
+
+impl Acceptance {
+    fn record(&mut self, accepted: bool) {
+        self.proposed += 1;
+        self.accepted += accepted as usize;
+    }
+
+    fn rate(&self) -> f64 {
+        if self.proposed == 0 { 0.0 } else { self.accepted as f64 / self.proposed as f64 }
+    }
+}
+
+struct Sampler<'a> {
+    problem: &'a Problem,
+    rng: StdRng,
+    /// Random-walk step of all ETAs together and of each ETA, in omega SDs
+    walk_scale: f64,
+    component_scales: Vec<f64>,
+}
+
+impl Sampler<'_> {
+    fn residual(&self, subject: &Subject, parameters: &PopulationParameters, etas: &[f64]) -> (f64, Vec<f64>) {
+        match self.problem.predict(subject, parameters, etas) {
+            Ok(predictions) => (self.problem.objective_at(subject, parameters, &[], &predictions), predictions),
+            Err(_) => (f64::INFINITY, Vec::new()),
+        }
+    }
+
+    fn prior(etas: &[f64], omegas: &[f64]) -> f64 {
+        etas.iter().zip(omegas).map(|(eta, omega)| eta * eta / omega).sum()
+    }
+
+    /// Metropolis-Hastings acceptance of a move changing `-2 log p` by `delta`.
+    fn accept(&mut self, delta: f64) -> bool {
+        delta.is_finite() && (delta <= 0.0 || self.rng.gen::<f64>().ln() < -0.5 * delta)
+    }
+
+    fn try_move(
+        &mut self,
+        subject: &Subject,
+        parameters: &PopulationParameters,
+        chain: &mut Chain,
+        proposal: Vec<f64>,
+        with_prior: bool,
+    ) -> bool {
+        let (residual, predictions) = self.residual(subject, parameters, &proposal);
+        let mut delta = residual - chain.residual;
+        if with_prior {
+            delta += Self::prior(&proposal, &parameters.omegas) - Self::prior(&chain.etas, &parameters.omegas);
+        }
+        let accepted = self.accept(delta);
+        if accepted {
+            *chain = Chain { etas: proposal, residual, predictions };
+        }
+        accepted
+    }
+
+    /// Run the kernels on one subject's chain.
+    fn sample(
+        &mut self,
+        subject: &Subject,
+        parameters: &PopulationParameters,
+        chain: &mut Chain,
+        settings: &EstimationConfig,
+        acceptance: &mut [Acceptance; 3],
+    ) {
+        let sds: Vec<f64> = parameters.omegas.iter().map(|o| o.sqrt()).collect();
+
+        // Independent proposals from the population distribution: the prior
+        // cancels in the acceptance ratio
+        for _ in 0..settings.saem.prior_kernel {
+            let proposal = sds.iter().map(|sd| sd * self.rng.sample::<f64, _>(StandardNormal)).collect();
+            let accepted = self.try_move(subject, parameters, chain, proposal, false);
+            acceptance[0].record(accepted);
+        }
+
+        for _ in 0..settings.saem.random_walk_kernel {
+            let proposal = chain.etas.iter().zip(&sds)
+                .map(|(eta, sd)| eta + self.walk_scale * sd * self.rng.sample::<f64, _>(StandardNormal))
+                .collect();
+            let accepted = self.try_move(subject, parameters, chain, proposal, true);
+            acceptance[1].record(accepted);
+        }
+
+        for _ in 0..settings.saem.componentwise_kernel {
+            for k in 0..sds.len() {
+                let mut proposal = chain.etas.clone();
+                proposal[k] += self.component_scales[k] * sds[k] * self.rng.sample::<f64, _>(StandardNormal);
+                let accepted = self.try_move(subject, parameters, chain, proposal, true);
+                acceptance[2].record(accepted);
+            }
+        }
+    }
+}
+
+/// Stochastic approximation expectation maximization.
+///
+/// Each iteration samples every subject's ETAs with the MCMC kernels and
+/// updates the population parameters from the samples with step size 1
+/// during burn-in and 1/k in the k-th accumulation iteration. THETAs with an
+/// ETA and the OMEGAs follow in closed form from the sufficient statistics
+/// `mean(ln θ + η)` and `mean((ln θ + η)²)`; THETAs without an ETA and the
+/// error model are updated towards the maximum of the likelihood given the
+/// samples. The reported objective function value, conditional ETAs and
+/// standard errors are those of FOCE-I at the final estimates.
+pub fn estimate(problem: &Problem, settings: &EstimationConfig, seed: Option<u64>) -> PKResult<EstimationResult> {
+    let saem = &settings.saem;
+    let n_etas = problem.eta_names.len();
+    let mut sampler = Sampler {
+        problem,
+        rng: match seed {
+            Some(s) => StdRng::seed_from_u64(s),
+            None => StdRng::from_entropy(),
+        },
+        walk_scale: 1.0,
+        component_scales: vec![1.0; n_etas],
+    };
+
+    let theta_of_eta: Vec<usize> = (0..n_etas)
+        .map(|k| problem.eta_of_theta.iter().position(|e| *e == Some(k)).unwrap())
+        .collect();
+    let fixed_thetas: Vec<usize> = (0..problem.theta_names.len())
+        .filter(|&i| problem.eta_of_theta[i].is_none())
+        .collect();
+
+    let mut parameters = problem.initial();
+    let mut s1: Vec<f64> = theta_of_eta.iter().map(|&i| parameters.thetas[i].ln()).collect();
+    let mut s2: Vec<f64> = s1.iter().zip(&parameters.omegas).map(|(m, omega)| m * m + omega).collect();
+    let mut chains: Vec<Chain> = problem.subjects.iter()
+        .map(|subject| {
+            let etas = vec![0.0; n_etas];
+            let (residual, predictions) = sampler.residual(subject, &parameters, &etas);
+            Chain { etas, residual, predictions }
+        })
+        .collect();
+
+    let n_iterations = saem.burn_in_iterations + saem.accumulation_iterations;
+    let mut history = Vec::with_capacity(n_iterations);
+    info!("SAEM: {} burn-in and {} accumulation iterations", saem.burn_in_iterations, saem.accumulation_iterations);
+
+    for iteration in 1..=n_iterations {
+        let (phase, step_size) = if iteration <= saem.burn_in_iterations {
+            (SaemPhase::BurnIn, 1.0)
+        } else {
+            (SaemPhase::Accumulation, 1.0 / (iteration - saem.burn_in_iterations) as f64)
+        };
+
+        // Simulation: the targets changed with the parameters
+        let mut acceptance: [Acceptance; 3] = Default::default();
+        for (subject, chain) in problem.subjects.iter().zip(chains.iter_mut()) {
+            let (residual, predictions) = sampler.residual(subject, &parameters, &chain.etas);
+            chain.residual = residual;
+            chain.predictions = predictions;
+            sampler.sample(subject, &parameters, chain, settings, &mut acceptance);
+        }
+        sampler.walk_scale *= 1.0 + 0.4 * (acceptance[1].rate() - TARGET_ACCEPTANCE);
+        for scale in &mut sampler.component_scales {
+            *scale *= 1.0 + 0.4 * (acceptance[2].rate() - TARGET_ACCEPTANCE);
+        }
+
+        // Stochastic approximation and maximization
+        let n = chains.len() as f64;
+        for (k, &i) in theta_of_eta.iter().enumerate() {
+            let log_theta = parameters.thetas[i].ln();
+            let mean = chains.iter().map(|c| log_theta + c.etas[k]).sum::<f64>() / n;
+            let square = chains.iter().map(|c| (log_theta + c.etas[k]).powi(2)).sum::<f64>() / n;
+            s1[k] += step_size * (mean - s1[k]);
+            s2[k] += step_size * (square - s2[k]);
+        }
+        let mut updated = parameters.clone();
+        for (k, &i) in theta_of_eta.iter().enumerate() {
+            updated.thetas[i] = s1[k].exp();
+            let mut omega = (s2[k] - s1[k] * s1[k]).max(1e-8);
+            if phase == SaemPhase::BurnIn {
+                omega = omega.max(OMEGA_ANNEALING * parameters.omegas[k]);
+            }
+            updated.omegas[k] = omega;
+        }
+        // ETAs are relative to the THETAs, so they move with them
+        for chain in &mut chains {
+            for (k, &i) in theta_of_eta.iter().enumerate() {
+                chain.etas[k] += parameters.thetas[i].ln() - updated.thetas[i].ln();
+            }
+        }
+        maximize_residual(problem, &mut updated, &fixed_thetas, &chains, step_size)?;
+        parameters = updated;
+
+        let complete_likelihood = problem.subjects.iter().zip(&chains)
+            .map(|(subject, chain)| {
+                let prior = Sampler::prior(&chain.etas, &parameters.omegas)
+                    + parameters.omegas.iter().map(|o| o.ln()).sum::<f64>();
+                sampler.residual(subject, &parameters, &chain.etas).0 + prior
+            })
+            .sum();
+        history.push(IterationRecord {
+            iteration,
+            phase,
+            step_size,
+            complete_likelihood,
+            values: parameters.values(),
+            acceptance: [acceptance[0].rate(), acceptance[1].rate(), acceptance[2].rate()],
+        });
+        if iteration % 50 == 0 {
+            info!("SAEM iteration {}/{}: -2LL {:.4}", iteration, n_iterations, complete_likelihood);
+        }
+    }
+
+    let converged = is_stable(&history, saem.accumulation_iterations);
+    if !converged {
+        warn!("SAEM estimates still changed by more than {}% at the end of the accumulation phase", STABLE_CHANGE * 100.0);
+    }
+
+    let mut etas: Vec<Vec<f64>> = chains.into_iter().map(|c| c.etas).collect();
+    let x = problem.to_vector(&parameters);
+    problem.ofv_at(&x, &mut etas)?;
+    let covariance = if settings.covariance { problem.covariance(&x, &mut etas)? } else { None };
+    let (ofv, fits) = problem.ofv(&parameters, &etas)?;
+    info!("SAEM: FOCE-I objective function value {:.4} at the estimates", ofv);
+
+    let mut result = EstimationResult::new(
+        problem,
+        EstimationMethod::Saem,
+        &parameters,
+        &fits,
+        covariance.as_ref(),
+        n_iterations,
+        converged,
+    );
+    result.history = history;
+    Ok(result)
+}
+
+/// Move the THETAs without an ETA and the error model SDs by `step_size`
+/// towards the maximum of the likelihood of the data given the sampled ETAs.
+fn maximize_residual(
+    problem: &Problem,
+    parameters: &mut PopulationParameters,
+    fixed_thetas: &[usize],
+    chains: &[Chain],
+    step_size: f64,
+) -> PKResult<()> {
+    let current: Vec<f64> = fixed_thetas.iter().map(|&i| parameters.thetas[i])
+        .chain(parameters.sigmas.iter().copied())
+        .map(f64::ln)
+        .collect();
+    let with = |x: &[f64]| {
+        let mut candidate = parameters.clone();
+        for (&i, value) in fixed_thetas.iter().zip(x) {
+            candidate.thetas[i] = value.exp();
+        }
+        candidate.sigmas = x[fixed_thetas.len()..].iter().map(|v| v.exp()).collect();
+        candidate
+    };
+
+    let objective = |x: &[f64]| -> PKResult<f64> {
+        let candidate = with(x);
+        let mut total = 0.0;
+        for (subject, chain) in problem.subjects.iter().zip(chains) {
+            total += if fixed_thetas.is_empty() {
+                problem.objective_at(subject, &candidate, &[], &chain.predictions)
+            } else {
+                let predictions = problem.predict(subject, &candidate, &chain.etas)?;
+                problem.objective_at(subject, &candidate, &[], &predictions)
+            };
+        }
+        Ok(total)
+    };
+    let optimum = minimize(objective, &current, 50, 1e-6)?;
+
+    let x: Vec<f64> = current.iter().zip(&optimum.x).map(|(c, o)| c + step_size * (o - c)).collect();
+    *parameters = with(&x);
+    Ok(())
+}
+
+/// Whether every estimate changed by less than [`STABLE_CHANGE`] over the
+/// last tenth of the accumulation phase.
+fn is_stable(history: &[IterationRecord], accumulation_iterations: usize) -> bool {
+    let window = (accumulation_iterations / 10).max(1);
+    if history.len() <= window {
+        return false;
+    }
+    let last = &history[history.len() - 1].values;
+    let earlier = &history[history.len() - 1 - window].values;
+    last.iter().zip(earlier).all(|(a, b)| ((a - b) / b).abs() <= STABLE_CHANGE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{config, simulated_dataset};
+    use super::super::ParameterKind;
+    use super::*;
+
+    #[test]
+    fn test_saem_recovers_simulated_parameters() {
+        let truth = config(0.1);
+        let dataset = simulated_dataset(&truth, 40, 21);
+
+        let mut start = truth.clone();
+        start.model.parameters.get_mut("CL").unwrap().theta = 4.0;
+        start.model.parameters.get_mut("V").unwrap().theta = 6.0;
+        start.simulation.error_model = crate::config::ErrorModel::Proportional { sigma: 0.3 };
+
+        let mut settings = EstimationConfig { covariance: false, ..Default::default() };
+        settings.saem.burn_in_iterations = 100;
+        settings.saem.accumulation_iterations = 60;
+        let problem = Problem::new(&start, &dataset).unwrap();
+        let result = estimate(&problem, &settings, Some(5)).unwrap();
+
+        assert_eq!(result.history.len(), 160);
+        assert_eq!(result.history[99].phase, SaemPhase::BurnIn);
+        assert_eq!(result.history[100].phase, SaemPhase::Accumulation);
+        assert_eq!(result.history[101].step_size, 0.5);
+        assert!(result.history.iter().all(|r| r.acceptance.iter().all(|a| (0.0..=1.0).contains(a))));
+
+        let value = |name: &str, kind| {
+            result.estimates.iter().find(|e| e.name == name && e.kind == kind).unwrap().estimate
+        };
+        assert!((value("CL", ParameterKind::Theta) - 2.0).abs() < 0.2);
+        assert!((value("V", ParameterKind::Theta) - 10.0).abs() < 1.0);
+        assert!((value("CL", ParameterKind::Omega).sqrt() - 0.3).abs() < 0.1);
+        assert!((value("PROPORTIONAL", ParameterKind::Sigma) - 0.1).abs() < 0.02);
+
+        // Same seed, same run
+        let again = estimate(&problem, &settings, Some(5)).unwrap();
+        assert_eq!(again.history.last().unwrap().values, result.history.last().unwrap().values);
+    }
+}
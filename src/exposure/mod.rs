@@ -0,0 +1,321 @@
+use crate::config::{Config, ExposureConfig};
+use crate::models::{DoseEvent, DoseRoute, ModelParameters};
+use crate::nca::NcaDosing;
+use crate::solver::{infusion_duration, EventSolver, ProfilePoint, SegmentState};
+use crate::simulation::PatientResult;
+use crate::error::PKResult;
+use serde::{Deserialize, Serialize};
+
+/// Grid points per smooth segment used to bracket extrema before refinement.
+const GRID_RESOLUTION: usize = 64;
+/// Bisection stops once the bracket is this narrow relative to its time.
+const TIME_TOLERANCE: f64 = 1e-12;
+
+/// Exposure metrics of one individual computed from the model solution
+/// rather than from the observation grid.
+pub struct ExposureProfile<'a> {
+    solver: EventSolver,
+    doses: &'a [DoseEvent],
+}
+
+impl<'a> ExposureProfile<'a> {
+    pub fn new(params: ModelParameters, doses: &'a [DoseEvent]) -> Self {
+        Self {
+            solver: EventSolver::new(params),
+            doses,
+        }
+    }
+
+    fn evaluate(&mut self, times: &[f64]) -> PKResult<Vec<ProfilePoint>> {
+        self.solver.solve_profile(self.doses, &[], times)
+    }
+
+    /// Exact AUC between `start` and `end`.
+    pub fn auc(&mut self, start: f64, end: f64) -> PKResult<f64> {
+        let points = self.evaluate(&[start, end])?;
+        Ok(points[1].cumulative_auc - points[0].cumulative_auc)
+    }
+
+    /// Maximum concentration and its time within `[start, end]`, with
+    /// interior peaks located where dC/dt = 0.
+    pub fn peak(&mut self, start: f64, end: f64) -> PKResult<(f64, f64)> {
+        let segments = self.segments(start, end)?;
+        let (tmax, cmax) = segments.iter()
+            .flat_map(|segment| &segment.points)
+            .map(|(t, point)| (*t, point.concentration))
+            .fold((start, f64::NEG_INFINITY), |best, (t, c)| if c > best.1 { (t, c) } else { best });
+        Ok((cmax.max(0.0), tmax))
+    }
+
+    /// Total time within `[start, end]` with concentration above `threshold`.
+    pub fn time_above(&mut self, threshold: f64, start: f64, end: f64) -> PKResult<f64> {
+        let segments = self.segments(start, end)?;
+        let mut total = 0.0;
+
+        for segment in &segments {
+            // Concentration is monotonic between consecutive points
+            for pair in segment.points.windows(2) {
+                let ((t1, p1), (t2, p2)) = (pair[0], pair[1]);
+                let (above1, above2) = (p1.concentration > threshold, p2.concentration > threshold);
+                total += match (above1, above2) {
+                    (true, true) => t2 - t1,
+                    (false, false) => 0.0,
+                    _ => {
+                        let crossing = bisect(&segment.start, t1, t2, |p| p.concentration - threshold);
+                        if above1 { crossing - t1 } else { t2 - crossing }
+                    },
+                };
+            }
+        }
+
+        Ok(total)
+    }
+
+    /// Smooth pieces of the profile between dosing events, each sampled on a
+    /// grid refined with its local extrema so that the concentration is
+    /// monotonic between consecutive points. The last point of a piece ending
+    /// at a dose is the left limit just before the dose.
+    ///
+    /// The dose history is solved once for the starts of all pieces; points
+    /// within a piece are advanced from its start.
+    fn segments(&mut self, start: f64, end: f64) -> PKResult<Vec<Segment>> {
+        let mut bounds = vec![start, end];
+        for dose in self.doses {
+            bounds.push(dose.time);
+            if dose.route == DoseRoute::IvInfusion {
+                bounds.push(dose.time + infusion_duration(dose)?);
+            }
+        }
+        bounds.retain(|t| *t >= start && *t <= end);
+        bounds.sort_by(|a, b| a.total_cmp(b));
+        bounds.dedup();
+
+        let states = self.solver.segment_states(self.doses, &bounds)?;
+        let mut segments = Vec::new();
+        for (window, state) in bounds.windows(2).zip(&states) {
+            let (a, b) = (window[0], window[1]);
+            let b_left = (b - 1e-9 * b.abs().max(1.0)).max(a);
+            let step = (b - a) / GRID_RESOLUTION as f64;
+            let mut grid = vec![state.clone()];
+            grid.extend(state.steps(step, GRID_RESOLUTION - 1));
+            grid.push(state.advance(b_left - a));
+
+            let mut points: Vec<(f64, ProfilePoint)> = Vec::with_capacity(grid.len());
+            for node in &grid {
+                let point = node.point();
+                if let Some(&(t_prev, prev)) = points.last() {
+                    if prev.slope.signum() * point.slope.signum() < 0.0 {
+                        let t = bisect(state, t_prev, node.time, |p| p.slope);
+                        points.push((t, state.advance(t - a).point()));
+                    }
+                }
+                points.push((node.time, point));
+            }
+            segments.push(Segment { start: state.clone(), points });
+        }
+
+        if segments.is_empty() {
+            let state = self.solver.segment_states(self.doses, &[start])?.remove(0);
+            segments.push(Segment { points: vec![(start, state.point())], start: state });
+        }
+
+        Ok(segments)
+    }
+}
+
+/// One smooth piece of the profile and the state it starts from.
+struct Segment {
+    start: SegmentState,
+    points: Vec<(f64, ProfilePoint)>,
+}
+
+/// Root of `f` on `[lo, hi]` within the piece starting at `segment`,
+/// assuming a sign change.
+fn bisect<F: Fn(&ProfilePoint) -> f64>(segment: &SegmentState, mut lo: f64, mut hi: f64, f: F) -> f64 {
+    let at = |t: f64| f(&segment.advance(t - segment.time).point());
+    let f_lo = at(lo);
+    while hi - lo > TIME_TOLERANCE * hi.abs().max(1.0) {
+        let mid = 0.5 * (lo + hi);
+        if mid <= lo || mid >= hi {
+            break;
+        }
+        if at(mid).signum() == f_lo.signum() {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    0.5 * (lo + hi)
+}
+
+/// Average concentration at steady state, `Dose / (CL * tau)`.
+pub fn average_steady_state_concentration(params: &ModelParameters, dose: f64, tau: f64) -> f64 {
+    dose / (params.cl * tau)
+}
+
+/// Model-based exposure metrics over one interval.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IntervalExposure {
+    pub start: f64,
+    pub end: f64,
+    pub auc: f64,
+    pub cmax: f64,
+    pub tmax: f64,
+    pub time_above_threshold: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubjectExposure {
+    pub patient_id: usize,
+    pub intervals: Vec<IntervalExposure>,
+    pub cavg_ss: Option<f64>,
+}
+
+/// Exposure intervals to evaluate: the configured ones or, by default, from
+/// the first dose to the last simulated time point of any study arm.
+pub fn exposure_intervals(config: &Config, settings: &ExposureConfig) -> Vec<(f64, f64)> {
+    if !settings.intervals.is_empty() {
+        return settings.intervals.clone();
+    }
+
+    // Dosing and sampling times of each arm
+    let schedules: Vec<(&[f64], &[f64])> = match &config.study {
+        Some(study) => study.arms.iter()
+            .map(|arm| (
+                arm.dosing.as_ref().unwrap_or(&config.dosing).times.as_slice(),
+                arm.time_points.as_deref().unwrap_or(&config.simulation.time_points),
+            ))
+            .collect(),
+        None => vec![(&config.dosing.times, &config.simulation.time_points)],
+    };
+    let first_dose = schedules.iter().flat_map(|s| s.0).copied().fold(f64::INFINITY, f64::min);
+    let last_time = schedules.iter().flat_map(|s| s.1).copied().fold(f64::NEG_INFINITY, f64::max);
+    vec![(first_dose.min(last_time), last_time)]
+}
+
+/// Model-based exposure of every simulated subject, from the doses each one
+/// received: missed, delayed and post-dropout doses are reflected.
+pub fn analyze_population(results: &[PatientResult], config: &Config) -> PKResult<Vec<SubjectExposure>> {
+    let settings = config.exposure.clone().unwrap_or_default();
+    let intervals = exposure_intervals(config, &settings);
+    let tau = NcaDosing::from_config(&config.dosing, settings.tau).tau;
+    // The model works in amount/volume; thresholds and results use the output unit
+    let factor = config.units.concentration_factor()?;
+
+    results.iter()
+        .map(|result| {
+            let params = ModelParameters::from_individual(&config.model, &result.parameters)?;
+            let mut profile = ExposureProfile::new(params.clone(), &result.doses);
+
+            let intervals = intervals.iter()
+                .map(|&(start, end)| {
+                    let (cmax, tmax) = profile.peak(start, end)?;
+                    Ok(IntervalExposure {
+                        start,
+                        end,
+                        auc: profile.auc(start, end)? * factor,
+                        cmax: cmax * factor,
+                        tmax,
+                        time_above_threshold: settings.threshold
+                            .map(|threshold| profile.time_above(threshold / factor, start, end))
+                            .transpose()?,
+                    })
+                })
+                .collect::<PKResult<Vec<_>>>()?;
+
+            Ok(SubjectExposure {
+                patient_id: result.patient_id,
+                intervals,
+                cavg_ss: tau.map(|tau| average_steady_state_concentration(&params, config.dosing.amount, tau) * factor),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testing::config_with;
+    use crate::dosing::DosingRegimen;
+    use crate::models::create_model;
+    use crate::simulation::Simulator;
+    use approx::assert_relative_eq;
+    use std::collections::HashMap;
+
+    fn params(values: &[(&str, f64)], compartments: u8) -> ModelParameters {
+        let mut model = create_model(compartments).unwrap();
+        let map: HashMap<String, f64> = values.iter().map(|(k, v)| (k.to_string(), *v)).collect();
+        model.set_parameters(&map).unwrap();
+        model.model_parameters().clone()
+    }
+
+    fn oral(time: f64) -> DoseEvent {
+        DoseEvent { time, amount: 100.0, route: DoseRoute::Oral, duration: None }
+    }
+
+    #[test]
+    fn test_oral_peak_matches_analytical_tmax() {
+        let (ka, ke): (f64, f64) = (1.0, 0.2);
+        let doses = [oral(0.0)];
+        let mut profile = ExposureProfile::new(params(&[("CL", 2.0), ("V", 10.0), ("KA", ka)], 1), &doses);
+
+        let (cmax, tmax) = profile.peak(0.0, 24.0).unwrap();
+        let expected_tmax = (ka / ke).ln() / (ka - ke);
+        let expected_cmax = 100.0 * ka / (10.0 * (ka - ke))
+            * ((-ke * expected_tmax).exp() - (-ka * expected_tmax).exp());
+        assert_relative_eq!(tmax, expected_tmax, epsilon = 1e-8);
+        assert_relative_eq!(cmax, expected_cmax, epsilon = 1e-10);
+    }
+
+    #[test]
+    fn test_auc_is_exact() {
+        let doses = [DoseEvent { time: 0.0, amount: 100.0, route: DoseRoute::IvBolus, duration: None }];
+        let mut profile = ExposureProfile::new(params(&[("CL", 2.0), ("V", 10.0)], 1), &doses);
+        let auc = profile.auc(0.0, 10.0).unwrap();
+        assert_relative_eq!(auc, 50.0 * (1.0 - (-2.0f64).exp()), epsilon = 1e-10);
+
+        // Steady-state AUC over one interval equals single-dose AUC to infinity
+        let p = params(&[("CL", 2.0), ("V", 10.0)], 1);
+        assert_relative_eq!(average_steady_state_concentration(&p, 100.0, 12.0), 50.0 / 12.0);
+    }
+
+    #[test]
+    fn test_time_above_threshold() {
+        // C = 10 exp(-0.2 t) after each bolus; above 5 for ln(2)/0.2 per dose
+        let doses: Vec<_> = [0.0, 24.0].iter()
+            .map(|&t| DoseEvent { time: t, amount: 100.0, route: DoseRoute::IvBolus, duration: None })
+            .collect();
+        let mut profile = ExposureProfile::new(params(&[("CL", 2.0), ("V", 10.0)], 1), &doses);
+        let above = profile.time_above(5.0, 0.0, 48.0).unwrap();
+        let second_peak = 10.0 + 10.0 * (-4.8f64).exp();
+        let expected = std::f64::consts::LN_2 / 0.2 + (second_peak / 5.0).ln() / 0.2;
+        assert_relative_eq!(above, expected, epsilon = 1e-6);
+
+        let (cmax, tmax) = profile.peak(1.0, 48.0).unwrap();
+        assert_eq!(tmax, 24.0);
+        assert!(cmax > 10.0);
+    }
+
+    #[test]
+    fn test_exposure_follows_received_doses() {
+        let config = config_with(&[r#"{
+            "model": { "parameters": { "CL": { "omega": null }, "V": { "omega": null } } },
+            "dosing": { "times": [0.0, 12.0, 24.0] },
+            "simulation": { "time_points": [36.0] }
+        }"#]);
+        let mut simulator = Simulator::new(config.clone(), Some(1)).unwrap();
+        let nominal = DosingRegimen::from_config(&config.dosing).unwrap().events;
+        let missed: Vec<_> = nominal.iter().filter(|dose| dose.time != 12.0).cloned().collect();
+        let results = [
+            simulator.simulate_subject(1, None, &nominal, &[36.0]).unwrap(),
+            simulator.simulate_subject(2, None, &missed, &[36.0]).unwrap(),
+        ];
+
+        let exposures = analyze_population(&results, &config).unwrap();
+        let (full, partial) = (&exposures[0].intervals[0], &exposures[1].intervals[0]);
+        assert_eq!((full.start, full.end), (0.0, 36.0));
+        // The missed dose takes its AUC over the remaining 24 h with it
+        let missed_auc = 100.0 / 2.0 * (1.0 - (-0.2f64 * 24.0).exp());
+        assert_relative_eq!(full.auc - partial.auc, missed_auc, max_relative = 1e-9);
+    }
+}
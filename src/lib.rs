@@ -0,0 +1,16 @@
+pub mod config;
+pub mod models;
+pub mod dosing;
+pub mod data;
+pub mod solver;
+pub mod simulation;
+pub mod nca;
+pub mod exposure;
+pub mod pta;
+pub mod vpc;
+pub mod estimation;
+pub mod dose_optimization;
+pub mod sensitivity;
+pub mod output;
+pub mod units;
+pub mod error;
@@ -1,17 +1,12 @@
 use clap::Parser;
-use log::{info, warn, error};
+use log::info;
 use std::path::PathBuf;
 
-mod config;
-mod models;
-mod dosing;
-mod simulation;
-mod output;
-mod error;
-
-use crate::config::Config;
-use crate::simulation::Simulator;
-use crate::error::PKError;
+use pk_simulation::config::Config;
+use pk_simulation::data::Dataset;
+use pk_simulation::pta::MicDistribution;
+use pk_simulation::simulation::Simulator;
+use pk_simulation::error::PKError;
 
 #[derive(Parser)]
 #[command(name = "pk_simulation")]
@@ -25,7 +20,8 @@ struct Cli {
     #[arg(short, long)]
     output: PathBuf,
     
-    /// Number of patients to simulate
+    /// Number of patients to simulate; a study design sets the size of
+    /// each arm instead
     #[arg(short, long, default_value = "100")]
     patients: usize,
     
@@ -33,6 +29,57 @@ struct Cli {
     #[arg(short, long)]
     seed: Option<u64>,
     
+    /// Observed NONMEM-format dataset; runs a visual predictive check
+    /// against it instead of a population simulation
+    #[arg(long, conflicts_with_all = ["stream", "report", "mic_distribution"])]
+    vpc: Option<PathBuf>,
+    
+    /// Observed NONMEM-format dataset; estimates the population parameters
+    /// from it, starting from the configured values
+    #[arg(long, conflicts_with_all = ["vpc", "stream", "report", "mic_distribution"])]
+    estimate: Option<PathBuf>,
+    
+    /// NONMEM-format dataset of measured concentrations; estimates each
+    /// subject's individual parameters (MAP) under the configured model
+    #[arg(long, conflicts_with_all = ["vpc", "estimate", "stream", "report", "mic_distribution"])]
+    map: Option<PathBuf>,
+    
+    /// Rank the configured candidate regimens by probability of target
+    /// attainment over `--patients` simulated subjects, or with `--map` for
+    /// each patient of the dataset
+    #[arg(long, conflicts_with_all = ["vpc", "estimate", "stream", "report", "mic_distribution"])]
+    optimize_dose: bool,
+    
+    /// Evaluate the configured sampling design for `--patients` subjects
+    /// (or the study arms) and search its windows for the D-optimal one
+    #[arg(long, conflicts_with_all = ["vpc", "estimate", "map", "optimize_dose", "stream", "report", "mic_distribution"])]
+    optimize_design: bool,
+    
+    /// Local and global sensitivity of the concentrations and exposure
+    /// metrics of a typical subject to the THETAs and covariates
+    #[arg(long, conflicts_with_all = ["vpc", "estimate", "map", "optimize_dose", "optimize_design", "stream", "report", "mic_distribution"])]
+    sensitivity: bool,
+    
+    /// MIC distribution (CSV with MIC and COUNT columns) for the cumulative
+    /// fraction of response of the configured PTA analysis
+    #[arg(long, conflicts_with = "stream")]
+    mic_distribution: Option<PathBuf>,
+    
+    /// Output table format: `csv`, `parquet` or `arrow` for all tables, or
+    /// `TABLE=FORMAT` for one table; may be repeated
+    #[arg(long = "format", value_name = "[TABLE=]FORMAT")]
+    formats: Vec<String>,
+    
+    /// Write an HTML report with the sections chosen in the configuration
+    #[arg(long)]
+    report: bool,
+    
+    /// Write each subject's rows as soon as it is simulated and summarize
+    /// online, keeping memory bounded; PTA and the report need all subjects
+    /// and cannot be combined with it
+    #[arg(long, conflicts_with = "report")]
+    stream: bool,
+    
     /// Verbose logging
     #[arg(short, long)]
     verbose: bool,
@@ -59,21 +106,113 @@ fn main() -> Result<(), PKError> {
     }
     
     // Load configuration
-    let config = Config::from_file(&cli.config)?;
+    let mut config = Config::from_file(&cli.config)?;
     info!("Loaded configuration from {:?}", cli.config);
+    for spec in &cli.formats {
+        config.output.set_format(spec)?;
+    }
+    config.output.validate()?;
+    let mic_distribution = cli.mic_distribution.as_ref().map(MicDistribution::from_file).transpose()?;
+    if mic_distribution.is_some() && config.pta.is_none() {
+        return Err(PKError::Validation("--mic-distribution needs a pta section in the configuration".to_string()));
+    }
     
     // Create simulator
     let mut simulator = Simulator::new(config, cli.seed)?;
     
+    if let Some(dataset_path) = &cli.vpc {
+        let dataset = Dataset::from_file(dataset_path, &simulator.config().dosing)?;
+        info!("Loaded {} subjects from {:?}", dataset.subjects.len(), dataset_path);
+        
+        let vpc = pk_simulation::vpc::run_vpc(&mut simulator, &dataset)?;
+        std::fs::create_dir_all(&cli.output)?;
+        pk_simulation::output::save_vpc_results(&vpc, simulator.config(), &cli.output)?;
+        return Ok(());
+    }
+    
+    if let Some(dataset_path) = &cli.estimate {
+        let dataset = Dataset::from_file(dataset_path, &simulator.config().dosing)?;
+        info!("Loaded {} subjects from {:?}", dataset.subjects.len(), dataset_path);
+        
+        let result = pk_simulation::estimation::estimate(simulator.config(), &dataset, cli.seed)?;
+        std::fs::create_dir_all(&cli.output)?;
+        pk_simulation::output::save_estimation_results(&result, simulator.config(), &cli.output)?;
+        return Ok(());
+    }
+    
+    if cli.optimize_dose {
+        let results = match &cli.map {
+            Some(dataset_path) => {
+                let dataset = Dataset::from_file(dataset_path, &simulator.config().dosing)?;
+                info!("Loaded {} subjects from {:?}", dataset.subjects.len(), dataset_path);
+                pk_simulation::dose_optimization::optimize_individuals(simulator.config(), &dataset, cli.seed)?
+            },
+            None => vec![pk_simulation::dose_optimization::optimize_population(&mut simulator, cli.patients)?],
+        };
+        std::fs::create_dir_all(&cli.output)?;
+        pk_simulation::output::save_dose_optimization_results(&results, simulator.config(), &cli.output)?;
+        return Ok(());
+    }
+    
+    if cli.optimize_design {
+        let result = pk_simulation::estimation::design::optimize(simulator.config(), cli.patients)?;
+        std::fs::create_dir_all(&cli.output)?;
+        pk_simulation::output::save_design_results(&result, simulator.config(), &cli.output)?;
+        return Ok(());
+    }
+    
+    if cli.sensitivity {
+        let result = pk_simulation::sensitivity::analyze(simulator.config(), cli.seed)?;
+        std::fs::create_dir_all(&cli.output)?;
+        pk_simulation::output::save_sensitivity_results(&result, simulator.config(), &cli.output)?;
+        return Ok(());
+    }
+    
+    if let Some(dataset_path) = &cli.map {
+        let dataset = Dataset::from_file(dataset_path, &simulator.config().dosing)?;
+        info!("Loaded {} subjects from {:?}", dataset.subjects.len(), dataset_path);
+        
+        let result = pk_simulation::estimation::map::estimate(simulator.config(), &dataset, cli.seed)?;
+        std::fs::create_dir_all(&cli.output)?;
+        pk_simulation::output::save_map_results(&result, simulator.config(), &cli.output)?;
+        return Ok(());
+    }
+    
+    if cli.stream {
+        std::fs::create_dir_all(&cli.output)?;
+        let mut output = pk_simulation::output::StreamingOutput::new(simulator.config(), &cli.output)?;
+        if simulator.config().study.is_some() {
+            simulator.simulate_study_with(|result| output.add(&result))?;
+        } else {
+            simulator.simulate_population_with(cli.patients, |result| output.add(&result))?;
+        }
+        output.finish()?;
+        info!("Results saved to {:?}", cli.output);
+        return Ok(());
+    }
+    
     // Run simulation
-    let results = simulator.simulate_population(cli.patients)?;
+    let results = if simulator.config().study.is_some() {
+        simulator.simulate_study()?
+    } else {
+        simulator.simulate_population(cli.patients)?
+    };
     info!("Simulation completed for {} patients", results.len());
     
     // Create output directory if it doesn't exist
     std::fs::create_dir_all(&cli.output)?;
     
     // Save results
-    crate::output::save_results(&results, &cli.output)?;
+    pk_simulation::output::save_results(&results, simulator.config(), &cli.output)?;
+    pk_simulation::output::save_nca_results(&results, simulator.config(), &cli.output)?;
+    pk_simulation::output::save_exposure_results(&results, simulator.config(), &cli.output)?;
+    if simulator.config().pta.is_some() {
+        pk_simulation::output::save_pta_results(&results, simulator.config(), mic_distribution.as_ref(), &cli.output)?;
+    }
+    pk_simulation::output::save_nonmem_dataset(&results, &simulator, cli.output.join("nonmem_dataset.csv"))?;
+    if cli.report {
+        pk_simulation::output::generate_report(&results, simulator.config(), &cli.output)?;
+    }
     info!("Results saved to {:?}", cli.output);
     
     Ok(())
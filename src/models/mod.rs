@@ -1,18 +1,22 @@
 pub mod one_compartment;
 pub mod two_compartment;
 pub mod three_compartment;
+pub mod parameterization;
 
 use crate::error::{PKError, PKResult};
 use crate::config::ModelConfig;
+use crate::solver::{infusion_duration, Matrix};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 pub trait PKModel {
     fn calculate_concentration(&self, time: f64, dose_history: &[DoseEvent]) -> PKResult<f64>;
     fn get_parameter_names(&self) -> Vec<&'static str>;
     fn set_parameters(&mut self, params: &HashMap<String, f64>) -> PKResult<()>;
+    fn model_parameters(&self) -> &ModelParameters;
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DoseEvent {
     pub time: f64,
     pub amount: f64,
@@ -20,7 +24,7 @@ pub struct DoseEvent {
     pub duration: Option<f64>, // For infusions
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DoseRoute {
     Oral,
     IvBolus,
@@ -54,25 +58,154 @@ impl ModelParameters {
     pub fn from_config(config: &ModelConfig) -> PKResult<Self> {
         let mut params = Self::new(config.compartments);
         
-        for (name, param_config) in &config.parameters {
-            match name.as_str() {
-                "CL" => params.cl = param_config.theta,
-                "V" | "V1" => params.v1 = param_config.theta,
-                "KA" => params.ka = Some(param_config.theta),
-                "Q" | "Q2" => params.q2 = Some(param_config.theta),
-                "V2" => params.v2 = Some(param_config.theta),
-                "Q3" => params.q3 = Some(param_config.theta),
-                "V3" => params.v3 = Some(param_config.theta),
-                _ => return Err(PKError::InvalidModel(
-                    format!("Unknown parameter: {}", name)
-                )),
-            }
+        let thetas: HashMap<String, f64> = config.parameters.iter()
+            .map(|(name, param_config)| (name.clone(), param_config.theta))
+            .collect();
+        let canonical = config.parameterization().to_canonical(config.compartments, &thetas)?;
+        
+        for (name, value) in &canonical {
+            params.set_parameter(name, *value)?;
         }
         
         Ok(params)
     }
+    
+    /// Structural parameters of one individual from values sampled in the
+    /// configured parameterization.
+    pub fn from_individual(config: &ModelConfig, params: &HashMap<String, f64>) -> PKResult<Self> {
+        let canonical = config.parameterization().to_canonical(config.compartments, params)?;
+        let mut model = create_model(config.compartments)?;
+        model.set_parameters(&canonical)?;
+        Ok(model.model_parameters().clone())
+    }
+    
+    pub fn set_parameter(&mut self, name: &str, value: f64) -> PKResult<()> {
+        if value <= 0.0 {
+            return Err(PKError::Validation(format!("{} must be positive", name)));
+        }
+        
+        match name {
+            "CL" => self.cl = value,
+            "V" | "V1" => self.v1 = value,
+            "KA" => self.ka = Some(value),
+            "Q" | "Q2" => self.q2 = Some(value),
+            "V2" => self.v2 = Some(value),
+            "Q3" => self.q3 = Some(value),
+            "V3" => self.v3 = Some(value),
+            _ => return Err(PKError::InvalidModel(
+                format!("Unknown parameter: {}", name)
+            )),
+        }
+        
+        Ok(())
+    }
+    
+    /// Number of amount compartments in the linear system: depot, central
+    /// and any peripheral compartments.
+    pub fn state_size(&self) -> usize {
+        2 + self.q2.is_some() as usize + self.q3.is_some() as usize
+    }
+    
+    /// First-order rate matrix `K` such that `dA/dt = K * A` for the amounts
+    /// `[depot, central, peripheral 2, peripheral 3]`. Without KA the depot
+    /// is left unconnected; oral doses then fail in [`ModelParameters::ka`].
+    pub fn rate_matrix(&self) -> PKResult<Matrix> {
+        let n = self.state_size();
+        let mut k = Matrix::zeros(n, n);
+        
+        if let Some(ka) = self.ka {
+            k.set(DEPOT, DEPOT, -ka);
+            k.set(CENTRAL, DEPOT, ka);
+        }
+        k.set(CENTRAL, CENTRAL, -self.cl / self.v1);
+        
+        let peripherals = [("Q2", self.q2, "V2", self.v2), ("Q3", self.q3, "V3", self.v3)];
+        for (idx, (q_name, q, v_name, v)) in peripherals.into_iter().enumerate() {
+            if let Some(q) = q {
+                let v = v.ok_or_else(|| PKError::InvalidModel(
+                    format!("{} requires {}", q_name, v_name)
+                ))?;
+                let p = CENTRAL + 1 + idx;
+                let k1p = q / self.v1;
+                let kp1 = q / v;
+                k.set(CENTRAL, CENTRAL, k.get(CENTRAL, CENTRAL) - k1p);
+                k.set(CENTRAL, p, kp1);
+                k.set(p, CENTRAL, k1p);
+                k.set(p, p, -kp1);
+            }
+        }
+        
+        Ok(k)
+    }
+    
+    /// Absorption rate constant, required to dose into the depot.
+    pub fn ka(&self) -> PKResult<f64> {
+        self.ka.ok_or_else(|| PKError::InvalidModel(
+            "KA is required for oral dosing".to_string()
+        ))
+    }
+    
+    /// Exponential of the rate matrix augmented with a zero-order input into
+    /// the central compartment, `exp([[K, r], [0, 0]] dt)`. Applied to
+    /// `[A; 1]` it gives the amounts after `dt` including the infused drug.
+    pub fn transition_matrix(&self, rate: f64, dt: f64) -> PKResult<Matrix> {
+        let k = self.rate_matrix()?;
+        let n = k.rows();
+        let mut augmented = Matrix::zeros(n + 1, n + 1);
+        for i in 0..n {
+            for j in 0..n {
+                augmented.set(i, j, k.get(i, j));
+            }
+        }
+        augmented.set(CENTRAL, n, rate);
+        Ok(augmented.scale(dt).expm())
+    }
 }
 
+/// Central concentration `t` time units after a single dose, from the exact
+/// solution of the linear system. The matrix exponential has no removable
+/// singularities, so coinciding rate constants (KA close to ALPHA, BETA or
+/// GAMMA) need no special-case branches.
+pub fn single_dose_concentration(params: &ModelParameters, dose: &DoseEvent, t: f64) -> PKResult<f64> {
+    if t < 0.0 {
+        return Ok(0.0);
+    }
+    
+    let n = params.state_size();
+    let mut amounts = vec![0.0; n + 1];
+    amounts[n] = 1.0;
+    
+    let amounts = match dose.route {
+        DoseRoute::Oral | DoseRoute::IvBolus => {
+            let target = if dose.route == DoseRoute::Oral {
+                params.ka()?;
+                DEPOT
+            } else {
+                CENTRAL
+            };
+            amounts[target] = dose.amount;
+            params.transition_matrix(0.0, t)?.mul_vec(&amounts)
+        },
+        DoseRoute::IvInfusion => {
+            let duration = infusion_duration(dose)?;
+            let rate = dose.amount / duration;
+            let end_of_infusion = params.transition_matrix(rate, t.min(duration))?.mul_vec(&amounts);
+            if t > duration {
+                params.transition_matrix(0.0, t - duration)?.mul_vec(&end_of_infusion)
+            } else {
+                end_of_infusion
+            }
+        },
+    };
+    
+    Ok((amounts[CENTRAL] / params.v1).max(0.0))
+}
+
+/// State index of the absorption (depot) compartment.
+pub const DEPOT: usize = 0;
+/// State index of the central compartment.
+pub const CENTRAL: usize = 1;
+
 pub fn create_model(compartments: u8) -> PKResult<Box<dyn PKModel>> {
     match compartments {
         1 => Ok(Box::new(one_compartment::OneCompartmentModel::new())),
@@ -1,5 +1,6 @@
 use super::{PKModel, DoseEvent, DoseRoute, ModelParameters};
 use crate::error::{PKError, PKResult};
+use crate::solver::infusion_duration;
 use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
@@ -7,6 +8,12 @@ pub struct OneCompartmentModel {
     params: ModelParameters,
 }
 
+impl Default for OneCompartmentModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl OneCompartmentModel {
     pub fn new() -> Self {
         Self {
@@ -14,8 +21,8 @@ impl OneCompartmentModel {
         }
     }
     
-    fn calculate_oral_concentration(&self, time: f64, dose_events: &[DoseEvent]) -> f64 {
-        let ka = self.params.ka.unwrap_or(1.0);
+    fn calculate_oral_concentration(&self, time: f64, dose_events: &[DoseEvent]) -> PKResult<f64> {
+        let ka = self.params.ka()?;
         let ke = self.params.cl / self.params.v1;
         let mut concentration = 0.0;
         
@@ -43,10 +50,10 @@ impl OneCompartmentModel {
             }
         }
         
-        concentration.max(0.0)
+        Ok(concentration.max(0.0))
     }
     
-    fn calculate_iv_concentration(&self, time: f64, dose_events: &[DoseEvent]) -> f64 {
+    fn calculate_iv_concentration(&self, time: f64, dose_events: &[DoseEvent]) -> PKResult<f64> {
         let ke = self.params.cl / self.params.v1;
         let mut concentration = 0.0;
         
@@ -60,7 +67,7 @@ impl OneCompartmentModel {
                         concentration += conc_contrib;
                     },
                     DoseRoute::IvInfusion => {
-                        let duration = dose.duration.unwrap_or(1.0);
+                        let duration = infusion_duration(dose)?;
                         let rate = dose.amount / duration;
                         
                         if t <= duration {
@@ -79,7 +86,7 @@ impl OneCompartmentModel {
             }
         }
         
-        concentration.max(0.0)
+        Ok(concentration.max(0.0))
     }
 }
 
@@ -90,9 +97,9 @@ impl PKModel for OneCompartmentModel {
         }
         
         let concentration = match dose_history[0].route {
-            DoseRoute::Oral => self.calculate_oral_concentration(time, dose_history),
+            DoseRoute::Oral => self.calculate_oral_concentration(time, dose_history)?,
             DoseRoute::IvBolus | DoseRoute::IvInfusion => 
-                self.calculate_iv_concentration(time, dose_history),
+                self.calculate_iv_concentration(time, dose_history)?,
         };
         
         Ok(concentration)
@@ -102,6 +109,10 @@ impl PKModel for OneCompartmentModel {
         vec!["CL", "V"]
     }
     
+    fn model_parameters(&self) -> &ModelParameters {
+        &self.params
+    }
+    
     fn set_parameters(&mut self, params: &HashMap<String, f64>) -> PKResult<()> {
         for (name, &value) in params {
             match name.as_str() {
@@ -152,11 +163,11 @@ mod tests {
             duration: None,
         };
         
-        let conc_0 = model.calculate_concentration(0.0, &[dose.clone()]).unwrap();
+        let conc_0 = model.calculate_concentration(0.0, std::slice::from_ref(&dose)).unwrap();
         assert_relative_eq!(conc_0, 10.0, epsilon = 1e-6);
         
         let conc_5 = model.calculate_concentration(5.0, &[dose]).unwrap();
-        let expected = 10.0 * (-0.2 * 5.0).exp(); // ke = CL/V = 0.2
+        let expected = 10.0 * (-0.2_f64 * 5.0).exp(); // ke = CL/V = 0.2
         assert_relative_eq!(conc_5, expected, epsilon = 1e-6);
     }
     
@@ -177,8 +188,8 @@ mod tests {
         };
         
         let conc_1 = model.calculate_concentration(1.0, &[dose]).unwrap();
-        let ke = 0.2;
-        let ka = 1.0;
+        let ke: f64 = 0.2;
+        let ka: f64 = 1.0;
         let expected = (100.0 * ka / 10.0) * ((-ke).exp() - (-ka).exp()) / (ka - ke);
         assert_relative_eq!(conc_1, expected, epsilon = 1e-6);
     }
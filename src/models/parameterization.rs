@@ -0,0 +1,279 @@
+use crate::config::Parameterization;
+use crate::error::{PKError, PKResult};
+use std::collections::HashMap;
+
+impl Parameterization {
+    /// NONMEM TRANS number, e.g. `4` for TRANS4.
+    pub fn from_trans(number: u8) -> PKResult<Self> {
+        match number {
+            1 => Ok(Parameterization::Trans1),
+            2 => Ok(Parameterization::Trans2),
+            3 => Ok(Parameterization::Trans3),
+            4 => Ok(Parameterization::Trans4),
+            5 => Ok(Parameterization::Trans5),
+            6 => Ok(Parameterization::Trans6),
+            _ => Err(PKError::InvalidModel(
+                format!("Unsupported TRANS{} parameterization", number)
+            )),
+        }
+    }
+
+    /// Clearance/volume form used when no parameterization is given.
+    pub fn default_for(compartments: u8) -> Self {
+        if compartments == 1 {
+            Parameterization::Trans2
+        } else {
+            Parameterization::Trans4
+        }
+    }
+
+    /// Structural parameter names in THETA order (absorption not included).
+    pub fn parameter_names(&self, compartments: u8) -> PKResult<Vec<&'static str>> {
+        use Parameterization::*;
+
+        let names = match (compartments, self) {
+            (1, Trans1) => vec!["K", "V"],
+            (1, Trans2) | (1, Trans4) => vec!["CL", "V"],
+            (2, Trans1) => vec!["K", "K12", "K21", "V"],
+            (2, Trans4) => vec!["CL", "V1", "Q", "V2"],
+            (2, Trans3) => vec!["CL", "V", "Q", "VSS"],
+            (2, Trans5) => vec!["AOB", "ALPHA", "BETA", "V"],
+            (2, Trans6) => vec!["ALPHA", "BETA", "K21", "V"],
+            (3, Trans1) => vec!["K", "K12", "K21", "K13", "K31", "V"],
+            (3, Trans4) => vec!["CL", "V1", "Q2", "V2", "Q3", "V3"],
+            (3, Trans6) => vec!["ALPHA", "BETA", "GAMMA", "K21", "K31", "V"],
+            _ => return Err(PKError::InvalidModel(format!(
+                "{:?} is not available for a {}-compartment model", self, compartments
+            ))),
+        };
+
+        Ok(names)
+    }
+
+    /// Convert individual parameters to the canonical clearance/volume form
+    /// (`CL`, `V`/`V1`, `Q2`, `V2`, `Q3`, `V3`) understood by the models.
+    /// Parameters outside the structural set (e.g. `KA`) are passed through.
+    pub fn to_canonical(
+        &self,
+        compartments: u8,
+        params: &HashMap<String, f64>,
+    ) -> PKResult<HashMap<String, f64>> {
+        use Parameterization::*;
+
+        let names = self.parameter_names(compartments)?;
+        let get = |name: &str| lookup(params, name).ok_or_else(|| PKError::InvalidModel(
+            format!("Missing required parameter: {}", name)
+        ));
+
+        // Micro constants (k10, k12, k21, k13, k31) and central volume
+        let (cl, v1, micro) = match (compartments, self) {
+            (1, Trans2) | (1, Trans4) => (get("CL")?, get("V")?, None),
+            (1, Trans1) => (get("K")? * get("V")?, get("V")?, None),
+            (_, Trans4) => {
+                let mut canonical = passthrough(params, &names);
+                canonical.insert("CL".to_string(), get("CL")?);
+                canonical.insert("V1".to_string(), get("V1")?);
+                canonical.insert("Q2".to_string(), get("Q2")?);
+                canonical.insert("V2".to_string(), get("V2")?);
+                if compartments == 3 {
+                    canonical.insert("Q3".to_string(), get("Q3")?);
+                    canonical.insert("V3".to_string(), get("V3")?);
+                }
+                return Ok(canonical);
+            },
+            (2, Trans3) => {
+                let (cl, v1, q, vss) = (get("CL")?, get("V")?, get("Q")?, get("VSS")?);
+                if vss <= v1 {
+                    return Err(PKError::InvalidModel("VSS must exceed V".to_string()));
+                }
+                (cl, v1, Some([cl / v1, q / v1, q / (vss - v1), 0.0, 0.0]))
+            },
+            (2, Trans1) => {
+                let v = get("V")?;
+                (get("K")? * v, v, Some([get("K")?, get("K12")?, get("K21")?, 0.0, 0.0]))
+            },
+            (2, Trans5) => {
+                let (aob, alpha, beta) = (get("AOB")?, get("ALPHA")?, get("BETA")?);
+                let k21 = (aob * beta + alpha) / (aob + 1.0);
+                let k = alpha * beta / k21;
+                let k12 = alpha + beta - k21 - k;
+                let v = get("V")?;
+                (k * v, v, Some([k, k12, k21, 0.0, 0.0]))
+            },
+            (2, Trans6) => {
+                let (alpha, beta, k21) = (get("ALPHA")?, get("BETA")?, get("K21")?);
+                let k = alpha * beta / k21;
+                let k12 = alpha + beta - k21 - k;
+                let v = get("V")?;
+                (k * v, v, Some([k, k12, k21, 0.0, 0.0]))
+            },
+            (3, Trans1) => {
+                let v = get("V")?;
+                let k = get("K")?;
+                (k * v, v, Some([k, get("K12")?, get("K21")?, get("K13")?, get("K31")?]))
+            },
+            (3, Trans6) => {
+                let (alpha, beta, gamma) = (get("ALPHA")?, get("BETA")?, get("GAMMA")?);
+                let (k21, k31) = (get("K21")?, get("K31")?);
+                if k21 == k31 {
+                    return Err(PKError::InvalidModel("K21 and K31 must differ".to_string()));
+                }
+                let k = alpha * beta * gamma / (k21 * k31);
+                // Match the remaining coefficients of the characteristic cubic:
+                //   k12 + k13         = alpha + beta + gamma - k - k21 - k31
+                //   k12 k31 + k13 k21 = ab + ag + bg - k (k21 + k31) - k21 k31
+                let s1 = alpha + beta + gamma - k - k21 - k31;
+                let s2 = alpha * beta + alpha * gamma + beta * gamma - k * (k21 + k31) - k21 * k31;
+                let k12 = (s2 - k21 * s1) / (k31 - k21);
+                let k13 = s1 - k12;
+                let v = get("V")?;
+                (k * v, v, Some([k, k12, k21, k13, k31]))
+            },
+            _ => unreachable!("parameter_names rejects unsupported combinations"),
+        };
+
+        let mut canonical = passthrough(params, &names);
+        canonical.insert("CL".to_string(), cl);
+        if let Some([_, k12, k21, k13, k31]) = micro {
+            canonical.insert("V1".to_string(), v1);
+            canonical.insert("Q2".to_string(), k12 * v1);
+            canonical.insert("V2".to_string(), k12 * v1 / k21);
+            if compartments == 3 {
+                canonical.insert("Q3".to_string(), k13 * v1);
+                canonical.insert("V3".to_string(), k13 * v1 / k31);
+            }
+        } else {
+            canonical.insert("V".to_string(), v1);
+        }
+
+        for (name, value) in &canonical {
+            if !value.is_finite() || *value <= 0.0 {
+                return Err(PKError::Validation(format!(
+                    "{:?} parameters give non-positive {} ({})", self, name, value
+                )));
+            }
+        }
+
+        Ok(canonical)
+    }
+}
+
+/// Look a parameter up, accepting the `V`/`V1` and `Q`/`Q2` aliases.
+pub fn lookup(params: &HashMap<String, f64>, name: &str) -> Option<f64> {
+    let aliases: &[&str] = match name {
+        "V" | "V1" => &["V", "V1"],
+        "Q" | "Q2" => &["Q", "Q2"],
+        _ => &[],
+    };
+
+    params.get(name)
+        .or_else(|| aliases.iter().find_map(|alias| params.get(*alias)))
+        .copied()
+}
+
+fn passthrough(params: &HashMap<String, f64>, structural: &[&str]) -> HashMap<String, f64> {
+    params.iter()
+        .filter(|(name, _)| !structural.iter().any(|s| lookup_matches(s, name)))
+        .map(|(name, value)| (name.clone(), *value))
+        .collect()
+}
+
+fn lookup_matches(structural: &str, name: &str) -> bool {
+    structural == name || matches!(
+        (structural, name),
+        ("V", "V1") | ("V1", "V") | ("Q", "Q2") | ("Q2", "Q")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::create_model;
+    use crate::models::three_compartment::ThreeCompartmentModel;
+    use crate::models::two_compartment::TwoCompartmentModel;
+    use crate::models::PKModel;
+    use approx::assert_relative_eq;
+
+    fn map(values: &[(&str, f64)]) -> HashMap<String, f64> {
+        values.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+    }
+
+    #[test]
+    fn test_trans1_two_compartment() {
+        let params = map(&[("K", 0.2), ("K12", 0.1), ("K21", 0.2), ("V", 10.0), ("KA", 1.0)]);
+        let canonical = Parameterization::Trans1.to_canonical(2, &params).unwrap();
+        assert_relative_eq!(canonical["CL"], 2.0, epsilon = 1e-12);
+        assert_relative_eq!(canonical["V1"], 10.0, epsilon = 1e-12);
+        assert_relative_eq!(canonical["Q2"], 1.0, epsilon = 1e-12);
+        assert_relative_eq!(canonical["V2"], 5.0, epsilon = 1e-12);
+        assert_eq!(canonical["KA"], 1.0);
+        assert!(!canonical.contains_key("K12"));
+    }
+
+    #[test]
+    fn test_trans3_vss() {
+        let params = map(&[("CL", 2.0), ("V", 10.0), ("Q", 1.0), ("VSS", 15.0)]);
+        let canonical = Parameterization::Trans3.to_canonical(2, &params).unwrap();
+        assert_relative_eq!(canonical["V2"], 5.0, epsilon = 1e-12);
+        assert_relative_eq!(canonical["Q2"], 1.0, epsilon = 1e-12);
+
+        let params = map(&[("CL", 2.0), ("V", 10.0), ("Q", 1.0), ("VSS", 8.0)]);
+        assert!(matches!(Parameterization::Trans3.to_canonical(2, &params), Err(PKError::InvalidModel(_))));
+    }
+
+    #[test]
+    fn test_macro_constants_round_trip_two_compartment() {
+        let mut model = TwoCompartmentModel::new();
+        model.set_parameters(&map(&[("CL", 2.0), ("V1", 10.0), ("Q2", 1.0), ("V2", 5.0)])).unwrap();
+        let (alpha, beta) = model.hybrid_constants();
+        let k21 = 0.2;
+
+        let trans6 = map(&[("ALPHA", alpha), ("BETA", beta), ("K21", k21), ("V", 10.0)]);
+        let canonical = Parameterization::Trans6.to_canonical(2, &trans6).unwrap();
+        assert_relative_eq!(canonical["CL"], 2.0, max_relative = 1e-9);
+        assert_relative_eq!(canonical["Q2"], 1.0, max_relative = 1e-9);
+        assert_relative_eq!(canonical["V2"], 5.0, max_relative = 1e-9);
+
+        // AOB = A/B of the bolus disposition coefficients
+        let aob = (alpha - k21) / (k21 - beta);
+        let trans5 = map(&[("AOB", aob), ("ALPHA", alpha), ("BETA", beta), ("V", 10.0)]);
+        let canonical = Parameterization::Trans5.to_canonical(2, &trans5).unwrap();
+        assert_relative_eq!(canonical["CL"], 2.0, max_relative = 1e-9);
+        assert_relative_eq!(canonical["V2"], 5.0, max_relative = 1e-9);
+    }
+
+    #[test]
+    fn test_macro_constants_round_trip_three_compartment() {
+        let mut model = ThreeCompartmentModel::new();
+        let values = map(&[("CL", 2.0), ("V1", 10.0), ("Q2", 1.0), ("V2", 5.0), ("Q3", 0.5), ("V3", 3.0)]);
+        model.set_parameters(&values).unwrap();
+        let (alpha, beta, gamma) = model.hybrid_constants();
+
+        let trans6 = map(&[
+            ("ALPHA", alpha), ("BETA", beta), ("GAMMA", gamma),
+            ("K21", 0.2), ("K31", 0.5 / 3.0), ("V", 10.0),
+        ]);
+        let canonical = Parameterization::Trans6.to_canonical(3, &trans6).unwrap();
+        for (name, value) in &values {
+            assert_relative_eq!(canonical[name], *value, max_relative = 1e-8);
+        }
+
+        let mut converted = create_model(3).unwrap();
+        converted.set_parameters(&canonical).unwrap();
+        assert!(converted.model_parameters().q3.is_some());
+    }
+
+    #[test]
+    fn test_unsupported_combination() {
+        assert!(Parameterization::Trans5.parameter_names(3).is_err());
+        assert!(Parameterization::Trans3.parameter_names(1).is_err());
+        // TRANS2 is defined for ADVAN1/2 only
+        assert!(Parameterization::Trans2.parameter_names(2).is_err());
+        assert!(Parameterization::Trans2.parameter_names(3).is_err());
+
+        let trans6 = map(&[
+            ("ALPHA", 1.0), ("BETA", 0.2), ("GAMMA", 0.05), ("K21", 0.1), ("K31", 0.1), ("V", 10.0),
+        ]);
+        assert!(matches!(Parameterization::Trans6.to_canonical(3, &trans6), Err(PKError::InvalidModel(_))));
+    }
+}
@@ -1,4 +1,4 @@
-use super::{PKModel, DoseEvent, DoseRoute, ModelParameters};
+use super::{PKModel, DoseEvent, ModelParameters, single_dose_concentration};
 use crate::error::{PKError, PKResult};
 use std::collections::HashMap;
 
@@ -7,6 +7,12 @@ pub struct ThreeCompartmentModel {
     params: ModelParameters,
 }
 
+impl Default for ThreeCompartmentModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ThreeCompartmentModel {
     pub fn new() -> Self {
         Self {
@@ -14,121 +20,38 @@ impl ThreeCompartmentModel {
         }
     }
     
-    fn calculate_hybrid_constants(&self) -> (f64, f64, f64) {
+    /// Disposition rate constants `(alpha, beta, gamma)` in decreasing order,
+    /// the roots of the characteristic cubic of the three-compartment system.
+    pub fn hybrid_constants(&self) -> (f64, f64, f64) {
         let k10 = self.params.cl / self.params.v1;
         let k12 = self.params.q2.unwrap_or(0.0) / self.params.v1;
         let k21 = self.params.q2.unwrap_or(0.0) / self.params.v2.unwrap_or(1.0);
         let k13 = self.params.q3.unwrap_or(0.0) / self.params.v1;
         let k31 = self.params.q3.unwrap_or(0.0) / self.params.v3.unwrap_or(1.0);
         
-        // For 3-compartment model, we need to solve cubic equation
-        // Simplified approach using numerical methods or approximations
+        // lambda^3 - a lambda^2 + b lambda - c = 0
         let a = k10 + k12 + k21 + k13 + k31;
-        let b = k10 * (k21 + k31) + k12 * k31 + k13 * k21;
+        let b = k10 * (k21 + k31) + k12 * k31 + k13 * k21 + k21 * k31;
         let c = k10 * k21 * k31;
         
-        // Approximate solution for the three exponential terms
-        // This is a simplified version - full implementation would solve cubic
-        let alpha = a / 3.0 + ((a*a - 3.0*b)/9.0).sqrt();
-        let beta = a / 3.0;
-        let gamma = a / 3.0 - ((a*a - 3.0*b)/9.0).sqrt();
-        
-        (alpha, beta, gamma)
-    }
-    
-    fn calculate_iv_bolus_concentration(&self, time: f64, dose_events: &[DoseEvent]) -> f64 {
-        let (alpha, beta, gamma) = self.calculate_hybrid_constants();
-        let mut concentration = 0.0;
-        
-        for dose in dose_events {
-            if dose.time <= time && dose.route == DoseRoute::IvBolus {
-                let t = time - dose.time;
-                
-                // Simplified coefficients (in practice, these would be derived from
-                // the full solution of the 3-compartment differential equations)
-                let a_coeff = 0.4;
-                let b_coeff = 0.4;
-                let c_coeff = 0.2;
-                
-                let conc_contrib = (dose.amount / self.params.v1) * 
-                    (a_coeff * (-alpha * t).exp() + 
-                     b_coeff * (-beta * t).exp() + 
-                     c_coeff * (-gamma * t).exp());
-                
-                concentration += conc_contrib;
-            }
-        }
-        
-        concentration.max(0.0)
-    }
-    
-    fn calculate_iv_infusion_concentration(&self, time: f64, dose_events: &[DoseEvent]) -> f64 {
-        let (alpha, beta, gamma) = self.calculate_hybrid_constants();
-        let mut concentration = 0.0;
+        // All roots are real and positive; use the trigonometric solution of
+        // the depressed cubic x^3 + p x + q = 0 with lambda = x + a/3
+        let p = b - a * a / 3.0;
+        let q = -2.0 * a * a * a / 27.0 + a * b / 3.0 - c;
         
-        for dose in dose_events {
-            if dose.time <= time && dose.route == DoseRoute::IvInfusion {
-                let t = time - dose.time;
-                let duration = dose.duration.unwrap_or(1.0);
-                let rate = dose.amount / duration;
-                
-                let a_coeff = 0.4;
-                let b_coeff = 0.4;
-                let c_coeff = 0.2;
-                
-                if t <= duration {
-                    // During infusion
-                    let term1 = a_coeff * (1.0 - (-alpha * t).exp()) / alpha;
-                    let term2 = b_coeff * (1.0 - (-beta * t).exp()) / beta;
-                    let term3 = c_coeff * (1.0 - (-gamma * t).exp()) / gamma;
-                    let conc_contrib = (rate / self.params.v1) * (term1 + term2 + term3);
-                    concentration += conc_contrib;
-                } else {
-                    // After infusion
-                    let term1_end = a_coeff * (1.0 - (-alpha * duration).exp()) / alpha;
-                    let term2_end = b_coeff * (1.0 - (-beta * duration).exp()) / beta;
-                    let term3_end = c_coeff * (1.0 - (-gamma * duration).exp()) / gamma;
-                    let conc_end = (rate / self.params.v1) * (term1_end + term2_end + term3_end);
-                    
-                    let decay_term1 = a_coeff * (-alpha * (t - duration)).exp();
-                    let decay_term2 = b_coeff * (-beta * (t - duration)).exp();
-                    let decay_term3 = c_coeff * (-gamma * (t - duration)).exp();
-                    let conc_contrib = conc_end * (decay_term1 + decay_term2 + decay_term3);
-                    concentration += conc_contrib;
-                }
-            }
+        if p.abs() < 1e-300 {
+            let root = a / 3.0 - q.cbrt();
+            return (root, root, root);
         }
         
-        concentration.max(0.0)
-    }
-    
-    fn calculate_oral_concentration(&self, time: f64, dose_events: &[DoseEvent]) -> f64 {
-        let ka = self.params.ka.unwrap_or(1.0);
-        let (alpha, beta, gamma) = self.calculate_hybrid_constants();
-        let mut concentration = 0.0;
-        
-        for dose in dose_events {
-            if dose.time <= time && dose.route == DoseRoute::Oral {
-                let t = time - dose.time;
-                let bioavailability = 1.0;
-                
-                let a_coeff = 0.4;
-                let b_coeff = 0.4;
-                let c_coeff = 0.2;
-                
-                let term_ka = ka * bioavailability * dose.amount / self.params.v1;
-                
-                let term1 = a_coeff * (-alpha * t).exp() / (ka - alpha);
-                let term2 = b_coeff * (-beta * t).exp() / (ka - beta);
-                let term3 = c_coeff * (-gamma * t).exp() / (ka - gamma);
-                let term4 = (-ka * t).exp() / ((alpha - ka) * (beta - ka) * (gamma - ka));
-                
-                let conc_contrib = term_ka * (term1 + term2 + term3 + term4);
-                concentration += conc_contrib;
-            }
-        }
+        let m = 2.0 * (-p / 3.0).sqrt();
+        let phi = (3.0 * q / (p * m)).clamp(-1.0, 1.0).acos() / 3.0;
+        let mut roots = [0.0, 1.0, 2.0].map(|k: f64| {
+            a / 3.0 + m * (phi - 2.0 * std::f64::consts::PI * k / 3.0).cos()
+        });
+        roots.sort_by(|x, y| y.total_cmp(x));
         
-        concentration.max(0.0)
+        (roots[0], roots[1], roots[2].max(0.0))
     }
 }
 
@@ -138,39 +61,23 @@ impl PKModel for ThreeCompartmentModel {
             return Ok(0.0);
         }
         
-        let mut total_concentration = 0.0;
-        
-        // Group doses by route
-        let oral_doses: Vec<_> = dose_history.iter()
-            .filter(|d| d.route == DoseRoute::Oral)
-            .cloned()
-            .collect();
-        let iv_bolus_doses: Vec<_> = dose_history.iter()
-            .filter(|d| d.route == DoseRoute::IvBolus)
-            .cloned()
-            .collect();
-        let iv_infusion_doses: Vec<_> = dose_history.iter()
-            .filter(|d| d.route == DoseRoute::IvInfusion)
-            .cloned()
-            .collect();
-        
-        if !oral_doses.is_empty() {
-            total_concentration += self.calculate_oral_concentration(time, &oral_doses);
-        }
-        if !iv_bolus_doses.is_empty() {
-            total_concentration += self.calculate_iv_bolus_concentration(time, &iv_bolus_doses);
-        }
-        if !iv_infusion_doses.is_empty() {
-            total_concentration += self.calculate_iv_infusion_concentration(time, &iv_infusion_doses);
-        }
-        
-        Ok(total_concentration)
+        // Superposition of single-dose responses; each uses the exact
+        // matrix-exponential solution, which stays finite when KA is close
+        // to ALPHA, BETA or GAMMA
+        dose_history.iter()
+            .filter(|dose| dose.time <= time)
+            .map(|dose| single_dose_concentration(&self.params, dose, time - dose.time))
+            .sum()
     }
     
     fn get_parameter_names(&self) -> Vec<&'static str> {
         vec!["CL", "V1", "Q2", "V2", "Q3", "V3"]
     }
     
+    fn model_parameters(&self) -> &ModelParameters {
+        &self.params
+    }
+    
     fn set_parameters(&mut self, params: &HashMap<String, f64>) -> PKResult<()> {
         for (name, &value) in params {
             match name.as_str() {
@@ -229,6 +136,8 @@ impl PKModel for ThreeCompartmentModel {
 mod tests {
     use super::*;
     use approx::assert_relative_eq;
+    use proptest::prelude::*;
+    use crate::models::DoseRoute;
     
     #[test]
     fn test_three_compartment_iv_bolus() {
@@ -249,13 +158,78 @@ mod tests {
             duration: None,
         };
         
-        let conc_0 = model.calculate_concentration(0.0, &[dose.clone()]).unwrap();
+        let conc_0 = model.calculate_concentration(0.0, std::slice::from_ref(&dose)).unwrap();
         assert_relative_eq!(conc_0, 10.0, epsilon = 1e-6);
         
         // Test that concentration decreases over time
-        let conc_1 = model.calculate_concentration(1.0, &[dose.clone()]).unwrap();
+        let conc_1 = model.calculate_concentration(1.0, std::slice::from_ref(&dose)).unwrap();
         let conc_5 = model.calculate_concentration(5.0, &[dose]).unwrap();
         assert!(conc_1 > conc_5);
         assert!(conc_5 > 0.0);
     }
-}
\ No newline at end of file
+    
+    fn model(values: [f64; 7]) -> ThreeCompartmentModel {
+        let names = ["CL", "V1", "Q2", "V2", "Q3", "V3", "KA"];
+        let params = names.iter()
+            .zip(values)
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        let mut model = ThreeCompartmentModel::new();
+        model.set_parameters(&params).unwrap();
+        model
+    }
+    
+    fn dose(route: DoseRoute) -> DoseEvent {
+        DoseEvent {
+            time: 0.0,
+            amount: 100.0,
+            route,
+            duration: None,
+        }
+    }
+    
+    #[test]
+    fn test_three_compartment_iv_bolus_matches_macro_constants() {
+        let model = model([2.0, 10.0, 1.0, 5.0, 0.5, 3.0, 1.0]);
+        let (alpha, beta, gamma) = model.hybrid_constants();
+        let (k21, k31) = (1.0 / 5.0, 0.5 / 3.0);
+        let lambdas = [alpha, beta, gamma];
+        
+        assert!(alpha > beta && beta > gamma && gamma > 0.0);
+        
+        for &t in &[0.0, 0.5, 4.0, 24.0, 96.0] {
+            let expected: f64 = (0..3).map(|i| {
+                let (j, k) = ((i + 1) % 3, (i + 2) % 3);
+                let li = lambdas[i];
+                10.0 * (k21 - li) * (k31 - li) / ((lambdas[j] - li) * (lambdas[k] - li)) * (-li * t).exp()
+            }).sum();
+            let conc = model.calculate_concentration(t, &[dose(DoseRoute::IvBolus)]).unwrap();
+            assert_relative_eq!(conc, expected, max_relative = 1e-9);
+        }
+    }
+    
+    proptest! {
+        #[test]
+        fn prop_oral_finite_when_ka_matches_disposition(
+            cl in 0.01f64..100.0,
+            v1 in 0.5f64..500.0,
+            q2 in 0.01f64..100.0,
+            v2 in 0.5f64..1000.0,
+            q3 in 0.01f64..100.0,
+            v3 in 0.5f64..1000.0,
+            t in 0.0f64..200.0,
+            root in 0usize..3,
+        ) {
+            let (alpha, beta, gamma) = model([cl, v1, q2, v2, q3, v3, 1.0]).hybrid_constants();
+            let ka = [alpha, beta, gamma][root];
+            
+            let exact = model([cl, v1, q2, v2, q3, v3, ka])
+                .calculate_concentration(t, &[dose(DoseRoute::Oral)]).unwrap();
+            let nearby = model([cl, v1, q2, v2, q3, v3, ka * (1.0 + 1e-7)])
+                .calculate_concentration(t, &[dose(DoseRoute::Oral)]).unwrap();
+            
+            prop_assert!(exact.is_finite() && exact >= 0.0);
+            prop_assert!((exact - nearby).abs() <= 1e-5 * exact.max(1e-12));
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use super::{PKModel, DoseEvent, DoseRoute, ModelParameters};
+use super::{PKModel, DoseEvent, ModelParameters, single_dose_concentration};
 use crate::error::{PKError, PKResult};
 use std::collections::HashMap;
 
@@ -7,6 +7,12 @@ pub struct TwoCompartmentModel {
     params: ModelParameters,
 }
 
+impl Default for TwoCompartmentModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TwoCompartmentModel {
     pub fn new() -> Self {
         Self {
@@ -14,7 +20,9 @@ impl TwoCompartmentModel {
         }
     }
     
-    fn calculate_hybrid_constants(&self) -> (f64, f64, f64) {
+    /// Disposition rate constants `(alpha, beta)` with `alpha > beta`, the
+    /// roots of `lambda^2 - (k10 + k12 + k21) lambda + k10 k21 = 0`.
+    pub fn hybrid_constants(&self) -> (f64, f64) {
         let k10 = self.params.cl / self.params.v1;
         let k12 = self.params.q2.unwrap_or(0.0) / self.params.v1;
         let k21 = self.params.q2.unwrap_or(0.0) / self.params.v2.unwrap_or(1.0);
@@ -22,97 +30,14 @@ impl TwoCompartmentModel {
         let a = k10 + k12 + k21;
         let b = k10 * k21;
         
-        let discriminant = a * a - 4.0 * b;
+        let discriminant = (a * a - 4.0 * b).max(0.0);
         let sqrt_disc = discriminant.sqrt();
         
         let alpha = (a + sqrt_disc) / 2.0;
-        let beta = (a - sqrt_disc) / 2.0;
+        // Vieta form avoids cancellation when beta << alpha
+        let beta = if alpha > 0.0 { b / alpha } else { 0.0 };
         
-        (alpha, beta, k21)
-    }
-    
-    fn calculate_iv_bolus_concentration(&self, time: f64, dose_events: &[DoseEvent]) -> f64 {
-        let (alpha, beta, k21) = self.calculate_hybrid_constants();
-        let mut concentration = 0.0;
-        
-        for dose in dose_events {
-            if dose.time <= time && dose.route == DoseRoute::IvBolus {
-                let t = time - dose.time;
-                
-                let a_coeff = (alpha - k21) / (alpha - beta);
-                let b_coeff = (k21 - beta) / (alpha - beta);
-                
-                let conc_contrib = (dose.amount / self.params.v1) * 
-                    (a_coeff * (-alpha * t).exp() + b_coeff * (-beta * t).exp());
-                
-                concentration += conc_contrib;
-            }
-        }
-        
-        concentration.max(0.0)
-    }
-    
-    fn calculate_iv_infusion_concentration(&self, time: f64, dose_events: &[DoseEvent]) -> f64 {
-        let (alpha, beta, k21) = self.calculate_hybrid_constants();
-        let mut concentration = 0.0;
-        
-        for dose in dose_events {
-            if dose.time <= time && dose.route == DoseRoute::IvInfusion {
-                let t = time - dose.time;
-                let duration = dose.duration.unwrap_or(1.0);
-                let rate = dose.amount / duration;
-                
-                let a_coeff = (alpha - k21) / (alpha - beta);
-                let b_coeff = (k21 - beta) / (alpha - beta);
-                
-                if t <= duration {
-                    // During infusion
-                    let term1 = a_coeff * (1.0 - (-alpha * t).exp()) / alpha;
-                    let term2 = b_coeff * (1.0 - (-beta * t).exp()) / beta;
-                    let conc_contrib = (rate / self.params.v1) * (term1 + term2);
-                    concentration += conc_contrib;
-                } else {
-                    // After infusion
-                    let term1_end = a_coeff * (1.0 - (-alpha * duration).exp()) / alpha;
-                    let term2_end = b_coeff * (1.0 - (-beta * duration).exp()) / beta;
-                    let conc_end = (rate / self.params.v1) * (term1_end + term2_end);
-                    
-                    let decay_term1 = a_coeff * (-alpha * (t - duration)).exp();
-                    let decay_term2 = b_coeff * (-beta * (t - duration)).exp();
-                    let conc_contrib = conc_end * (decay_term1 + decay_term2);
-                    concentration += conc_contrib;
-                }
-            }
-        }
-        
-        concentration.max(0.0)
-    }
-    
-    fn calculate_oral_concentration(&self, time: f64, dose_events: &[DoseEvent]) -> f64 {
-        let ka = self.params.ka.unwrap_or(1.0);
-        let (alpha, beta, k21) = self.calculate_hybrid_constants();
-        let mut concentration = 0.0;
-        
-        for dose in dose_events {
-            if dose.time <= time && dose.route == DoseRoute::Oral {
-                let t = time - dose.time;
-                let bioavailability = 1.0;
-                
-                let a_coeff = (alpha - k21) / (alpha - beta);
-                let b_coeff = (k21 - beta) / (alpha - beta);
-                
-                let term_ka = ka * bioavailability * dose.amount / self.params.v1;
-                
-                let term1 = a_coeff * (-alpha * t).exp() / (ka - alpha);
-                let term2 = b_coeff * (-beta * t).exp() / (ka - beta);
-                let term3 = (-ka * t).exp() / ((alpha - ka) * (beta - ka));
-                
-                let conc_contrib = term_ka * (term1 + term2 + term3);
-                concentration += conc_contrib;
-            }
-        }
-        
-        concentration.max(0.0)
+        (alpha, beta)
     }
 }
 
@@ -122,39 +47,23 @@ impl PKModel for TwoCompartmentModel {
             return Ok(0.0);
         }
         
-        let mut total_concentration = 0.0;
-        
-        // Group doses by route
-        let oral_doses: Vec<_> = dose_history.iter()
-            .filter(|d| d.route == DoseRoute::Oral)
-            .cloned()
-            .collect();
-        let iv_bolus_doses: Vec<_> = dose_history.iter()
-            .filter(|d| d.route == DoseRoute::IvBolus)
-            .cloned()
-            .collect();
-        let iv_infusion_doses: Vec<_> = dose_history.iter()
-            .filter(|d| d.route == DoseRoute::IvInfusion)
-            .cloned()
-            .collect();
-        
-        if !oral_doses.is_empty() {
-            total_concentration += self.calculate_oral_concentration(time, &oral_doses);
-        }
-        if !iv_bolus_doses.is_empty() {
-            total_concentration += self.calculate_iv_bolus_concentration(time, &iv_bolus_doses);
-        }
-        if !iv_infusion_doses.is_empty() {
-            total_concentration += self.calculate_iv_infusion_concentration(time, &iv_infusion_doses);
-        }
-        
-        Ok(total_concentration)
+        // Superposition of single-dose responses; each uses the exact
+        // matrix-exponential solution, which stays finite when KA is close
+        // to ALPHA or BETA
+        dose_history.iter()
+            .filter(|dose| dose.time <= time)
+            .map(|dose| single_dose_concentration(&self.params, dose, time - dose.time))
+            .sum()
     }
     
     fn get_parameter_names(&self) -> Vec<&'static str> {
         vec!["CL", "V1", "Q2", "V2"]
     }
     
+    fn model_parameters(&self) -> &ModelParameters {
+        &self.params
+    }
+    
     fn set_parameters(&mut self, params: &HashMap<String, f64>) -> PKResult<()> {
         for (name, &value) in params {
             match name.as_str() {
@@ -201,6 +110,8 @@ impl PKModel for TwoCompartmentModel {
 mod tests {
     use super::*;
     use approx::assert_relative_eq;
+    use proptest::prelude::*;
+    use crate::models::DoseRoute;
     
     #[test]
     fn test_two_compartment_iv_bolus() {
@@ -219,13 +130,82 @@ mod tests {
             duration: None,
         };
         
-        let conc_0 = model.calculate_concentration(0.0, &[dose.clone()]).unwrap();
+        let conc_0 = model.calculate_concentration(0.0, std::slice::from_ref(&dose)).unwrap();
         assert_relative_eq!(conc_0, 10.0, epsilon = 1e-6);
         
         // Test that concentration decreases over time
-        let conc_1 = model.calculate_concentration(1.0, &[dose.clone()]).unwrap();
+        let conc_1 = model.calculate_concentration(1.0, std::slice::from_ref(&dose)).unwrap();
         let conc_5 = model.calculate_concentration(5.0, &[dose]).unwrap();
         assert!(conc_1 > conc_5);
         assert!(conc_5 > 0.0);
     }
-}
\ No newline at end of file
+    
+    fn oral_model(cl: f64, v1: f64, q: f64, v2: f64, ka: f64) -> TwoCompartmentModel {
+        let mut model = TwoCompartmentModel::new();
+        let params = HashMap::from([
+            ("CL".to_string(), cl),
+            ("V1".to_string(), v1),
+            ("Q2".to_string(), q),
+            ("V2".to_string(), v2),
+            ("KA".to_string(), ka),
+        ]);
+        model.set_parameters(&params).unwrap();
+        model
+    }
+    
+    fn oral_dose() -> DoseEvent {
+        DoseEvent {
+            time: 0.0,
+            amount: 100.0,
+            route: DoseRoute::Oral,
+            duration: None,
+        }
+    }
+    
+    #[test]
+    fn test_zero_infusion_duration_is_an_error() {
+        let model = oral_model(2.0, 10.0, 1.0, 5.0, 1.5);
+        let dose = DoseEvent { duration: Some(0.0), route: DoseRoute::IvInfusion, ..oral_dose() };
+        assert!(model.calculate_concentration(1.0, &[dose]).is_err());
+    }
+    
+    #[test]
+    fn test_two_compartment_oral_matches_macro_constants() {
+        let model = oral_model(2.0, 10.0, 1.0, 5.0, 1.5);
+        let (alpha, beta) = model.hybrid_constants();
+        let (ka, k21) = (1.5, 1.0 / 5.0);
+        
+        for &t in &[0.5, 2.0, 8.0, 24.0] {
+            let expected = (ka * 100.0 / 10.0) * (
+                (k21 - alpha) / ((ka - alpha) * (beta - alpha)) * (-alpha * t).exp() +
+                (k21 - beta) / ((ka - beta) * (alpha - beta)) * (-beta * t).exp() +
+                (k21 - ka) / ((alpha - ka) * (beta - ka)) * (-ka * t).exp()
+            );
+            let conc = model.calculate_concentration(t, &[oral_dose()]).unwrap();
+            assert_relative_eq!(conc, expected, max_relative = 1e-9);
+        }
+    }
+    
+    proptest! {
+        #[test]
+        fn prop_oral_finite_when_ka_matches_disposition(
+            cl in 0.01f64..100.0,
+            v1 in 0.5f64..500.0,
+            q in 0.01f64..100.0,
+            v2 in 0.5f64..1000.0,
+            t in 0.0f64..200.0,
+            use_alpha in any::<bool>(),
+        ) {
+            let (alpha, beta) = oral_model(cl, v1, q, v2, 1.0).hybrid_constants();
+            let ka = if use_alpha { alpha } else { beta };
+            
+            let exact = oral_model(cl, v1, q, v2, ka)
+                .calculate_concentration(t, &[oral_dose()]).unwrap();
+            let nearby = oral_model(cl, v1, q, v2, ka * (1.0 + 1e-7))
+                .calculate_concentration(t, &[oral_dose()]).unwrap();
+            
+            prop_assert!(exact.is_finite() && exact >= 0.0);
+            prop_assert!((exact - nearby).abs() <= 1e-5 * exact.max(1e-12));
+        }
+    }
+}
@@ -1,186 +1,801 @@
-use crate::simulation::{PatientResult, PopulationSummary};
-use crate::error::{PKError, PKResult};
+pub mod nonmem;
+pub mod plot;
+pub mod report;
+pub mod stream;
+pub mod table;
+
+use crate::simulation::{by_arm, PatientResult, PopulationSummary};
+use crate::config::{Config, UnitsConfig};
+use crate::nca::{self, NcaDosing};
+use crate::exposure;
+use crate::pta::{self, MicDistribution};
+use crate::vpc::VpcResult;
+use crate::estimation::EstimationResult;
+use crate::estimation::design::DesignResult;
+use crate::estimation::map::MapResult;
+use crate::dose_optimization::DoseOptimizationResult;
+use crate::sensitivity::{Response, SensitivityResult};
+use crate::error::PKResult;
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
 use std::fs::File;
-use log::info;
+use log::{debug, info};
+
+pub use nonmem::save_nonmem_dataset;
+pub use report::generate_report;
+pub use stream::StreamingOutput;
+pub use table::{Table, Values};
 
-pub fn save_results<P: AsRef<Path>>(results: &[PatientResult], output_dir: P) -> PKResult<()> {
+pub fn save_results<P: AsRef<Path>>(results: &[PatientResult], config: &Config, output_dir: P) -> PKResult<()> {
     let output_path = output_dir.as_ref();
     
     // Save individual patient data
-    save_patient_data(results, &output_path.join("individual_data.csv"))?;
+    write_table(patient_table(results, config), "individual_data", config, output_path)?;
     
     // Save concentration-time data
-    save_concentration_data(results, &output_path.join("concentrations.csv"))?;
+    write_table(concentration_table(results, config), "concentrations", config, output_path)?;
     
-    // Save population summary
-    let summary = PopulationSummary::from_results(results);
-    save_population_summary(&summary, &output_path.join("population_summary.json"))?;
+    // Save population summary, per arm for a study
+    let extra_percentiles = config.summary.as_ref()
+        .map(|s| s.percentiles.as_slice())
+        .unwrap_or_default();
+    let summaries: Vec<(Option<String>, PopulationSummary)> = by_arm(results, config)?.iter()
+        .map(|group| (group.arm.map(str::to_string), PopulationSummary::with_percentiles(group.results, extra_percentiles)))
+        .collect();
+    save_summaries(&summaries, config, output_path)?;
     
     // Save parameters
-    save_parameter_data(results, &output_path.join("parameters.csv"))?;
+    if let Some(first) = results.first() {
+        let columns = ParameterColumns::new(first, config);
+        write_table(parameter_table(results, &columns, config), "parameters", config, output_path)?;
+    }
     
     info!("All results saved to {:?}", output_path);
     Ok(())
 }
 
-fn save_patient_data<P: AsRef<Path>>(results: &[PatientResult], path: P) -> PKResult<()> {
-    let mut writer = csv::Writer::from_path(path)?;
+/// Per-subject rows, with `DROPOUT` (1 if the subject dropped out) and
+/// `TIME_IN_STUDY`, up to dropout or the last scheduled sample of the
+/// subject's arm, when dropout is simulated.
+fn patient_table(results: &[PatientResult], config: &Config) -> Table {
+    let units = &config.units;
+    let mut table = Table::new();
+    table.push("PATIENT_ID", "", Values::UInt(results.iter().map(|r| r.patient_id as u64).collect()));
+    push_arm(&mut table, config, results.iter());
+    table.push("WEIGHT", "kg", Values::Float(results.iter().map(|r| r.demographics.weight).collect()));
+    table.push("AGE", "years", Values::Float(results.iter().map(|r| r.demographics.age).collect()));
+    table.push("CMAX", &units.concentration(), Values::Float(results.iter().map(|r| r.get_max_concentration()).collect()));
+    table.push("AUC", &units.auc(), Values::Float(results.iter().map(|r| r.get_auc()).collect()));
+    table.push("TMAX", &units.time, Values::OptionalFloat(results.iter().map(|r| r.get_time_to_max()).collect()));
+    if config.trial.as_ref().is_some_and(|t| t.dropout.is_some()) {
+        table.push("DROPOUT", "", Values::UInt(
+            results.iter().map(|r| r.dropout_time.is_some() as u64).collect()
+        ));
+        let last_time = |time_points: &[f64]| time_points.iter().copied().fold(0.0, f64::max);
+        let end = |r: &PatientResult| config.study.iter()
+            .flat_map(|study| &study.arms)
+            .find(|arm| r.arm.as_deref() == Some(arm.name.as_str()))
+            .and_then(|arm| arm.time_points.as_deref())
+            .map_or_else(|| last_time(&config.simulation.time_points), last_time);
+        table.push("TIME_IN_STUDY", &units.time, Values::Float(
+            results.iter().map(|r| r.dropout_time.unwrap_or_else(|| end(r))).collect()
+        ));
+    }
+    table
+}
+
+/// Concentration-time rows with the residual errors `EPS_1`, `EPS_2`, ...
+/// drawn for each observation, the scheduled `NOMINAL_TIME` when sampling
+/// windows are simulated and the `BLQ` flag when an LLOQ is set.
+fn concentration_table(results: &[PatientResult], config: &Config) -> Table {
+    let units = &config.units;
+    let rows = || results.iter().flat_map(|r| r.observations.iter().map(move |obs| (r, obs)));
+    let concentration = units.concentration();
     
-    // Write header
-    writer.write_record(&[
-        "PATIENT_ID", "WEIGHT", "AGE", "CMAX", "AUC", "TMAX"
-    ])?;
+    let mut table = Table::new();
+    table.push("PATIENT_ID", "", Values::UInt(rows().map(|(r, _)| r.patient_id as u64).collect()));
+    push_arm(&mut table, config, rows().map(|(r, _)| r));
+    table.push("TIME", &units.time, Values::Float(rows().map(|(_, obs)| obs.time).collect()));
+    if config.trial.as_ref().is_some_and(|t| t.sampling.is_some()) {
+        table.push("NOMINAL_TIME", &units.time, Values::Float(rows().map(|(_, obs)| obs.nominal_time).collect()));
+    }
+    table.push("CONCENTRATION", &concentration, Values::Float(rows().map(|(_, obs)| obs.concentration).collect()));
+    table.push("PREDICTED_CONCENTRATION", &concentration, Values::Float(
+        rows().map(|(_, obs)| obs.predicted_concentration).collect()
+    ));
+    for i in 0..config.simulation.error_model.n_eps() {
+        table.push(&format!("EPS_{}", i + 1), "", Values::OptionalFloat(
+            rows().map(|(_, obs)| obs.eps.get(i).copied()).collect()
+        ));
+    }
+    if config.simulation.lloq.is_some() {
+        table.push("BLQ", "", Values::UInt(rows().map(|(_, obs)| obs.blq as u64).collect()));
+    }
+    table
+}
+
+/// Columns of the parameters table, in a fixed order so that runs can be
+/// diffed: the individual values of every parameter in name order, then for
+/// the parameters of the model configuration the typical value `TV_<P>`, the
+/// covariate-adjusted typical value `COV_<P>`, the random effect `ETA_<P>`
+/// (parameters with an omega) and `CLAMPED_<P>`, 1 if the value was clamped
+/// to its bounds.
+struct ParameterColumns {
+    individual: Vec<String>,
+    configured: Vec<(String, f64)>,
+    etas: Vec<String>,
+}
+
+impl ParameterColumns {
+    fn new(first: &PatientResult, config: &Config) -> Self {
+        let mut individual: Vec<String> = first.parameters.keys().cloned().collect();
+        individual.sort();
+        let mut configured: Vec<(String, f64)> = config.model.parameters.iter()
+            .map(|(name, param)| (name.clone(), param.theta))
+            .collect();
+        configured.sort_by(|a, b| a.0.cmp(&b.0));
+        let etas = configured.iter()
+            .filter(|(name, _)| config.model.parameters[name].omega.is_some())
+            .map(|(name, _)| name.clone())
+            .collect();
+        Self { individual, configured, etas }
+    }
+}
+
+fn parameter_table(results: &[PatientResult], columns: &ParameterColumns, config: &Config) -> Table {
+    let units = &config.units;
+    // Values a subject does not have are missing
+    let values = |map: fn(&PatientResult) -> &HashMap<String, f64>, name: &str| {
+        Values::OptionalFloat(results.iter().map(|r| map(r).get(name).copied()).collect())
+    };
     
-    // Write data
-    for result in results {
-        let cmax = result.get_max_concentration();
-        let auc = result.get_auc();
-        let tmax = result.get_time_to_max().unwrap_or(0.0);
-        
-        writer.write_record(&[
-            result.patient_id.to_string(),
-            result.demographics.weight.to_string(),
-            result.demographics.age.to_string(),
-            cmax.to_string(),
-            auc.to_string(),
-            tmax.to_string(),
-        ])?;
+    let mut table = Table::new();
+    table.push("PATIENT_ID", "", Values::UInt(results.iter().map(|r| r.patient_id as u64).collect()));
+    push_arm(&mut table, config, results.iter());
+    for name in &columns.individual {
+        table.push(name, &units.parameter_unit(name), values(|r| &r.parameters, name));
+    }
+    for (name, theta) in &columns.configured {
+        table.push(&format!("TV_{}", name), &units.parameter_unit(name), Values::Float(vec![*theta; results.len()]));
+    }
+    for (name, _) in &columns.configured {
+        table.push(&format!("COV_{}", name), &units.parameter_unit(name), values(|r| &r.typical_parameters, name));
+    }
+    for name in &columns.etas {
+        table.push(&format!("ETA_{}", name), "", values(|r| &r.etas, name));
+    }
+    for (name, _) in &columns.configured {
+        table.push(&format!("CLAMPED_{}", name), "", Values::UInt(
+            results.iter().map(|r| r.clamped.contains(name) as u64).collect()
+        ));
+    }
+    table
+}
+
+/// `ARM` column with the arm of each row's subject, when a study is
+/// configured.
+fn push_arm<'a, I>(table: &mut Table, config: &Config, subjects: I)
+where
+    I: Iterator<Item = &'a PatientResult>,
+{
+    if config.study.is_some() {
+        table.push("ARM", "", Values::Text(subjects.map(|r| r.arm.clone().unwrap_or_default()).collect()));
     }
+}
+
+/// Write one JSON value per arm: the value itself without a study, an
+/// object keyed by arm name for a study.
+fn save_json_by_arm<T: serde::Serialize, P: AsRef<Path>>(entries: &[(Option<&str>, T)], path: P) -> PKResult<()> {
+    let file = File::create(path)?;
+    match entries {
+        [(None, value)] => serde_json::to_writer_pretty(file, value)?,
+        _ => {
+            let by_arm: BTreeMap<&str, &T> = entries.iter()
+                .map(|(arm, value)| (arm.unwrap_or_default(), value))
+                .collect();
+            serde_json::to_writer_pretty(file, &by_arm)?
+        },
+    }
+    Ok(())
+}
+
+/// Write the population summary (`population_summary.json`), keyed by arm
+/// for a study, and the prediction intervals of every arm.
+fn save_summaries(summaries: &[(Option<String>, PopulationSummary)], config: &Config, output_path: &Path) -> PKResult<()> {
+    let entries: Vec<(Option<&str>, &PopulationSummary)> = summaries.iter()
+        .map(|(arm, summary)| (arm.as_deref(), summary))
+        .collect();
+    save_json_by_arm(&entries, output_path.join("population_summary.json"))?;
     
-    writer.flush()?;
+    let mut table = Table::new();
+    for (arm, summary) in summaries {
+        table.append(prediction_interval_table(summary, arm.as_deref(), &config.units))?;
+    }
+    write_table(table, "prediction_intervals", config, output_path)
+}
+
+/// Write `table` as the output table `name`, in its configured format.
+fn write_table(table: Table, name: &str, config: &Config, output_path: &Path) -> PKResult<()> {
+    let path = table.write(&output_path.join(name), config.output.format_for(name), config.output.compression)?;
+    debug!("Wrote {:?}", path);
     Ok(())
 }
 
-fn save_concentration_data<P: AsRef<Path>>(results: &[PatientResult], path: P) -> PKResult<()> {
-    let mut writer = csv::Writer::from_path(path)?;
+/// Long-format percentiles of observed and predicted concentrations over time,
+/// one row per time point, variable and percentile, led by the `ARM` of a
+/// study arm.
+fn prediction_interval_table(summary: &PopulationSummary, arm: Option<&str>, units: &UnitsConfig) -> Table {
+    let rows: Vec<(f64, &str, f64, f64)> = summary.concentration_bands.iter()
+        .flat_map(|band| {
+            [("OBSERVED", &band.observed), ("PREDICTED", &band.predicted)].into_iter()
+                .flat_map(move |(variable, distribution)| {
+                    distribution.percentiles.iter().map(move |p| (band.time, variable, p.percentile, p.value))
+                })
+        })
+        .collect();
     
-    // Write header
-    writer.write_record(&[
-        "PATIENT_ID", "TIME", "CONCENTRATION", "PREDICTED_CONCENTRATION"
-    ])?;
+    let mut table = Table::new();
+    if let Some(arm) = arm {
+        table.push("ARM", "", Values::Text(vec![arm.to_string(); rows.len()]));
+    }
+    table.push("TIME", &units.time, Values::Float(rows.iter().map(|r| r.0).collect()));
+    table.push("VARIABLE", "", Values::Text(rows.iter().map(|r| r.1.to_string()).collect()));
+    table.push("PERCENTILE", "", Values::Float(rows.iter().map(|r| r.2).collect()));
+    table.push("VALUE", &units.concentration(), Values::Float(rows.iter().map(|r| r.3).collect()));
+    table
+}
+
+/// Write per-subject NCA parameters (`nca.csv`) and their population summary
+/// (`nca_summary.json`), per arm for a study. Each subject is analyzed with
+/// the doses it received.
+pub fn save_nca_results<P: AsRef<Path>>(results: &[PatientResult], config: &Config, output_dir: P) -> PKResult<()> {
+    let output_path = output_dir.as_ref();
+    let arms = by_arm(results, config)?.into_iter()
+        .map(|group| Ok((group.arm, nca::analyze_population(group.results, &group.config)?)))
+        .collect::<PKResult<Vec<_>>>()?;
     
-    // Write data
-    for result in results {
-        for obs in &result.observations {
-            writer.write_record(&[
-                result.patient_id.to_string(),
-                obs.time.to_string(),
-                obs.concentration.to_string(),
-                obs.predicted_concentration.to_string(),
-            ])?;
-        }
+    let mut table = Table::new();
+    for (arm, nca_results) in &arms {
+        table.append(nca_table(*arm, nca_results, config))?;
     }
+    write_table(table, "nca", config, output_path)?;
+    
+    let summaries: Vec<_> = arms.iter()
+        .map(|(arm, nca_results)| (*arm, nca::summarize(nca_results)))
+        .collect();
+    save_json_by_arm(&summaries, output_path.join("nca_summary.json"))?;
     
-    writer.flush()?;
     Ok(())
 }
 
-fn save_parameter_data<P: AsRef<Path>>(results: &[PatientResult], path: P) -> PKResult<()> {
-    if results.is_empty() {
-        return Ok(());
+/// NCA rows of the subjects of one arm; parameters that could not be
+/// estimated are missing.
+fn nca_table(arm: Option<&str>, nca_results: &[(usize, nca::NcaResult)], config: &Config) -> Table {
+    let mut table = Table::new();
+    let Some((_, first)) = nca_results.first() else {
+        return table;
+    };
+    table.push("PATIENT_ID", "", Values::UInt(nca_results.iter().map(|(id, _)| *id as u64).collect()));
+    if config.study.is_some() {
+        table.push("ARM", "", Values::Text(vec![arm.unwrap_or_default().to_string(); nca_results.len()]));
+    }
+    for (k, (name, _)) in first.metrics().iter().enumerate() {
+        table.push(name, &nca::metric_unit(name, &config.units), Values::OptionalFloat(
+            nca_results.iter().map(|(_, result)| result.metrics()[k].1).collect()
+        ));
+    }
+    table
+}
+
+/// Write model-based exposure metrics of each subject (`exposure.csv`): AUC,
+/// Cmax and Tmax per interval, time above the threshold when one is set and
+/// the average steady-state concentration when a dosing interval applies.
+/// Each subject is evaluated on the doses it received, so missed, delayed
+/// and post-dropout doses count; study arms share the same intervals.
+pub fn save_exposure_results<P: AsRef<Path>>(results: &[PatientResult], config: &Config, output_dir: P) -> PKResult<()> {
+    let mut table = Table::new();
+    for group in by_arm(results, config)? {
+        let exposures = exposure::analyze_population(group.results, &group.config)?;
+        table.append(exposure_table(group.arm, &exposures, config))?;
+    }
+    write_table(table, "exposure", config, output_dir.as_ref())
+}
+
+/// Exposure rows of the subjects of one arm. `CAVG_SS` is present when any
+/// arm has a dosing interval, and missing in the arms without one.
+fn exposure_table(arm: Option<&str>, exposures: &[exposure::SubjectExposure], config: &Config) -> Table {
+    let units = &config.units;
+    let mut table = Table::new();
+    let Some(first) = exposures.first() else {
+        return table;
+    };
+    table.push("PATIENT_ID", "", Values::UInt(exposures.iter().map(|e| e.patient_id as u64).collect()));
+    if config.study.is_some() {
+        table.push("ARM", "", Values::Text(vec![arm.unwrap_or_default().to_string(); exposures.len()]));
+    }
+    for (k, interval) in first.intervals.iter().enumerate() {
+        let suffix = format!("{}_{}", interval.start, interval.end);
+        let values = |metric: fn(&exposure::IntervalExposure) -> f64| {
+            Values::Float(exposures.iter().map(|e| metric(&e.intervals[k])).collect())
+        };
+        table.push(&format!("AUC_{}", suffix), &units.auc(), values(|i| i.auc));
+        table.push(&format!("CMAX_{}", suffix), &units.concentration(), values(|i| i.cmax));
+        table.push(&format!("TMAX_{}", suffix), &units.time, values(|i| i.tmax));
+        if interval.time_above_threshold.is_some() {
+            table.push(&format!("TIME_ABOVE_{}", suffix), &units.time, Values::OptionalFloat(
+                exposures.iter().map(|e| e.intervals[k].time_above_threshold).collect()
+            ));
+        }
+    }
+    if has_dosing_interval(config) {
+        table.push("CAVG_SS", &units.concentration(), Values::OptionalFloat(exposures.iter().map(|e| e.cavg_ss).collect()));
     }
+    table
+}
+
+/// Whether the exposure of any arm is evaluated at steady state over a
+/// dosing interval.
+fn has_dosing_interval(config: &Config) -> bool {
+    let tau = config.exposure.as_ref().and_then(|settings| settings.tau);
+    let has_tau = |dosing: &crate::config::DosingConfig| NcaDosing::from_config(dosing, tau).tau.is_some();
+    match &config.study {
+        Some(study) => study.arms.iter().any(|arm| has_tau(arm.dosing.as_ref().unwrap_or(&config.dosing))),
+        None => has_tau(&config.dosing),
+    }
+}
+
+/// Write the probability of target attainment per target and MIC
+/// (`pta.csv`), the cumulative fraction of response over `distribution`
+/// (`cfr.csv`) and the full result (`pta.json`), per arm for a study.
+pub fn save_pta_results<P: AsRef<Path>>(
+    results: &[PatientResult],
+    config: &Config,
+    distribution: Option<&MicDistribution>,
+    output_dir: P,
+) -> PKResult<()> {
+    let output_path = output_dir.as_ref();
+    let arms = by_arm(results, config)?.into_iter()
+        .map(|group| Ok((group.arm, pta::analyze(group.results, &group.config, distribution)?)))
+        .collect::<PKResult<Vec<_>>>()?;
+    let index_name = |target: &crate::config::PtaTarget| -> PKResult<String> {
+        Ok(serde_json::to_value(target.index)?.as_str().unwrap_or_default().to_string())
+    };
+    // Rows lead with the ARM of a study
+    let push_arm = |table: &mut Table, arm: Option<&str>, n_rows: usize| {
+        if config.study.is_some() {
+            table.push("ARM", "", Values::Text(vec![arm.unwrap_or_default().to_string(); n_rows]));
+        }
+    };
     
-    let mut writer = csv::Writer::from_path(path)?;
+    let mut points = Table::new();
+    let mut fractions = Table::new();
+    for (arm, pta) in &arms {
+        let mut table = Table::new();
+        push_arm(&mut table, *arm, pta.points.len());
+        table.push("INDEX", "", Values::Text(pta.points.iter().map(|p| index_name(&p.target)).collect::<PKResult<_>>()?));
+        table.push("TARGET", "", Values::Float(pta.points.iter().map(|p| p.target.value).collect()));
+        table.push("MIC", &config.units.concentration(), Values::Float(pta.points.iter().map(|p| p.mic).collect()));
+        table.push("PTA", "", Values::Float(pta.points.iter().map(|p| p.probability).collect()));
+        points.append(table)?;
+        
+        let fraction_rows = &pta.cumulative_fractions;
+        let mut table = Table::new();
+        push_arm(&mut table, *arm, fraction_rows.len());
+        table.push("INDEX", "", Values::Text(fraction_rows.iter().map(|f| index_name(&f.target)).collect::<PKResult<_>>()?));
+        table.push("TARGET", "", Values::Float(fraction_rows.iter().map(|f| f.target.value).collect()));
+        table.push("CFR", "", Values::Float(fraction_rows.iter().map(|f| f.cfr).collect()));
+        fractions.append(table)?;
+    }
+    write_table(points, "pta", config, output_path)?;
+    if distribution.is_some() {
+        write_table(fractions, "cfr", config, output_path)?;
+    }
     
-    // Get parameter names from first patient
-    let param_names: Vec<String> = results[0].parameters.keys().cloned().collect();
+    save_json_by_arm(&arms, output_path.join("pta.json"))?;
+    Ok(())
+}
+
+/// Write VPC tables: observed and simulated percentiles per bin (`vpc.csv`)
+/// and the binned observations (`vpc_observations.csv`).
+pub fn save_vpc_results<P: AsRef<Path>>(vpc: &VpcResult, config: &Config, output_dir: P) -> PKResult<()> {
+    let output_path = output_dir.as_ref();
+    let units = &config.units;
+    let (time, concentration) = (units.time.as_str(), units.concentration());
+    
+    let percentiles = &vpc.percentiles;
+    let bins = || percentiles.iter().map(|p| &vpc.bins[p.bin]);
+    let mut table = Table::new();
+    table.push("BIN", "", Values::UInt(percentiles.iter().map(|p| p.bin as u64 + 1).collect()));
+    table.push("BIN_LOWER", time, Values::Float(bins().map(|bin| bin.lower).collect()));
+    table.push("BIN_UPPER", time, Values::Float(bins().map(|bin| bin.upper).collect()));
+    table.push("TIME", time, Values::Float(bins().map(|bin| bin.time).collect()));
+    table.push("N_OBS", "", Values::UInt(bins().map(|bin| bin.n_observations as u64).collect()));
+    table.push("PERCENTILE", "", Values::Float(percentiles.iter().map(|p| p.percentile).collect()));
+    table.push("OBSERVED", &concentration, Values::OptionalFloat(percentiles.iter().map(|p| p.observed).collect()));
+    table.push("SIM_LOWER", &concentration, Values::Float(percentiles.iter().map(|p| p.simulated_lower).collect()));
+    table.push("SIM_MEDIAN", &concentration, Values::Float(percentiles.iter().map(|p| p.simulated_median).collect()));
+    table.push("SIM_UPPER", &concentration, Values::Float(percentiles.iter().map(|p| p.simulated_upper).collect()));
+    write_table(table, "vpc", config, output_path)?;
+    
+    // Observations outside every bin have a missing BIN
+    let observations = &vpc.observations;
+    let mut table = Table::new();
+    table.push("ID", "", Values::UInt(observations.iter().map(|obs| obs.id as u64).collect()));
+    table.push("TIME", time, Values::Float(observations.iter().map(|obs| obs.time).collect()));
+    table.push("BIN", "", Values::OptionalUInt(observations.iter().map(|obs| obs.bin.map(|b| b as u64 + 1)).collect()));
+    table.push("DV", &concentration, Values::Float(observations.iter().map(|obs| obs.dv).collect()));
+    table.push("PRED", &concentration, Values::Float(observations.iter().map(|obs| obs.pred).collect()));
+    table.push("PCDV", &concentration, Values::OptionalFloat(observations.iter().map(|obs| obs.pcdv).collect()));
+    write_table(table, "vpc_observations", config, output_path)?;
+    
+    info!("VPC tables saved to {:?}", output_path);
+    Ok(())
+}
+
+/// Write estimation results: the population estimates (`estimates.csv`), the
+/// subjects' ETAs (`individual_etas.csv`) and predictions
+/// (`individual_predictions.csv`), the iterations of SAEM
+/// (`estimation_iterations.csv`), the full result (`estimation.json`) and
+/// the configuration with the estimates filled in (`fitted_config.json`).
+pub fn save_estimation_results<P: AsRef<Path>>(result: &EstimationResult, config: &Config, output_dir: P) -> PKResult<()> {
+    let output_path = output_dir.as_ref();
+    let kind = |kind: &dyn std::fmt::Debug| format!("{:?}", kind).to_uppercase();
+    
+    let estimates = &result.estimates;
+    let mut table = Table::new();
+    table.push("TYPE", "", Values::Text(estimates.iter().map(|e| kind(&e.kind)).collect()));
+    table.push("PARAMETER", "", Values::Text(estimates.iter().map(|e| e.name.clone()).collect()));
+    table.push("INITIAL", "", Values::Float(estimates.iter().map(|e| e.initial).collect()));
+    table.push("ESTIMATE", "", Values::Float(estimates.iter().map(|e| e.estimate).collect()));
+    table.push("SE", "", Values::OptionalFloat(estimates.iter().map(|e| e.se).collect()));
+    table.push("RSE", "%", Values::OptionalFloat(estimates.iter().map(|e| e.rse_percent).collect()));
+    table.push("CV", "%", Values::OptionalFloat(estimates.iter().map(|e| e.cv_percent).collect()));
+    write_table(table, "estimates", config, output_path)?;
+    
+    let individuals = &result.individuals;
+    let mut table = Table::new();
+    table.push("ID", "", Values::UInt(individuals.iter().map(|i| i.id as u64).collect()));
+    table.push("OFV", "", Values::Float(individuals.iter().map(|i| i.ofv).collect()));
+    if let Some(first) = individuals.first() {
+        for name in first.etas.keys() {
+            table.push(&format!("ETA_{}", name), "", Values::OptionalFloat(
+                individuals.iter().map(|i| i.etas.get(name).copied()).collect()
+            ));
+        }
+    }
+    write_table(table, "individual_etas", config, output_path)?;
     
-    // Write header
-    let mut header = vec!["PATIENT_ID".to_string()];
-    header.extend(param_names.clone());
-    writer.write_record(&header)?;
+    let units = &config.units;
+    let concentration = units.concentration();
+    let rows: Vec<(usize, f64, f64, f64)> = individuals.iter()
+        .flat_map(|i| i.times.iter().zip(&i.dv).zip(&i.ipred).map(|((&time, &dv), &ipred)| (i.id, time, dv, ipred)))
+        .collect();
+    let mut table = Table::new();
+    table.push("ID", "", Values::UInt(rows.iter().map(|r| r.0 as u64).collect()));
+    table.push("TIME", &units.time, Values::Float(rows.iter().map(|r| r.1).collect()));
+    table.push("DV", &concentration, Values::Float(rows.iter().map(|r| r.2).collect()));
+    table.push("IPRED", &concentration, Values::Float(rows.iter().map(|r| r.3).collect()));
+    write_table(table, "individual_predictions", config, output_path)?;
     
-    // Write data
-    for result in results {
-        let mut record = vec![result.patient_id.to_string()];
-        for param_name in &param_names {
-            let value = result.parameters.get(param_name).unwrap_or(&0.0);
-            record.push(value.to_string());
+    // Convergence diagnostics of the methods that record their iterations
+    let history = &result.history;
+    if !history.is_empty() {
+        let mut table = Table::new();
+        table.push("ITERATION", "", Values::UInt(history.iter().map(|r| r.iteration as u64).collect()));
+        table.push("PHASE", "", Values::Text(history.iter()
+            .map(|r| Ok(serde_json::to_value(r.phase)?.as_str().unwrap_or_default().to_string()))
+            .collect::<PKResult<_>>()?));
+        table.push("STEP_SIZE", "", Values::Float(history.iter().map(|r| r.step_size).collect()));
+        table.push("COMPLETE_LL", "", Values::Float(history.iter().map(|r| r.complete_likelihood).collect()));
+        for (k, estimate) in estimates.iter().enumerate() {
+            table.push(&format!("{}_{}", kind(&estimate.kind), estimate.name), "", Values::Float(
+                history.iter().map(|r| r.values[k]).collect()
+            ));
+        }
+        for (k, name) in ["ACCEPT_PRIOR", "ACCEPT_RANDOM_WALK", "ACCEPT_COMPONENTWISE"].iter().enumerate() {
+            table.push(name, "", Values::Float(history.iter().map(|r| r.acceptance[k]).collect()));
         }
-        writer.write_record(&record)?;
+        write_table(table, "estimation_iterations", config, output_path)?;
     }
     
-    writer.flush()?;
+    let file = File::create(output_path.join("estimation.json"))?;
+    serde_json::to_writer_pretty(file, result)?;
+    
+    let mut fitted = config.clone();
+    result.apply(&mut fitted);
+    let file = File::create(output_path.join("fitted_config.json"))?;
+    serde_json::to_writer_pretty(file, &fitted)?;
+    
+    info!("Estimation results saved to {:?}", output_path);
     Ok(())
 }
 
-fn save_population_summary<P: AsRef<Path>>(summary: &PopulationSummary, path: P) -> PKResult<()> {
-    let file = File::create(path)?;
-    serde_json::to_writer_pretty(file, summary)?;
+/// Write individual MAP estimates: the individual parameters with their
+/// intervals (`map_parameters.csv`), the predicted profiles
+/// (`map_predictions.csv`) and the full result (`map.json`).
+pub fn save_map_results<P: AsRef<Path>>(result: &MapResult, config: &Config, output_dir: P) -> PKResult<()> {
+    let output_path = output_dir.as_ref();
+    let units = &config.units;
+    
+    let parameters: Vec<_> = result.individuals.iter()
+        .flat_map(|i| i.parameters.iter().map(move |parameter| (i.id, parameter)))
+        .collect();
+    let mut table = Table::new();
+    table.push("ID", "", Values::UInt(parameters.iter().map(|(id, _)| *id as u64).collect()));
+    table.push("PARAMETER", "", Values::Text(parameters.iter().map(|(_, p)| p.name.clone()).collect()));
+    table.push("UNIT", "", Values::Text(parameters.iter().map(|(_, p)| units.parameter_unit(&p.name)).collect()));
+    table.push("TYPICAL", "", Values::Float(parameters.iter().map(|(_, p)| p.typical).collect()));
+    table.push("ESTIMATE", "", Values::Float(parameters.iter().map(|(_, p)| p.estimate).collect()));
+    table.push("LOWER", "", Values::Float(parameters.iter().map(|(_, p)| p.lower).collect()));
+    table.push("UPPER", "", Values::Float(parameters.iter().map(|(_, p)| p.upper).collect()));
+    table.push("ETA", "", Values::OptionalFloat(parameters.iter().map(|(_, p)| p.eta).collect()));
+    table.push("ETA_SD", "", Values::OptionalFloat(parameters.iter().map(|(_, p)| p.eta_sd).collect()));
+    write_table(table, "map_parameters", config, output_path)?;
+    
+    let concentration = units.concentration();
+    let predictions: Vec<_> = result.individuals.iter()
+        .flat_map(|i| i.predictions.iter().map(move |prediction| (i.id, prediction)))
+        .collect();
+    let mut table = Table::new();
+    table.push("ID", "", Values::UInt(predictions.iter().map(|(id, _)| *id as u64).collect()));
+    table.push("TIME", &units.time, Values::Float(predictions.iter().map(|(_, p)| p.time).collect()));
+    table.push("DV", &concentration, Values::OptionalFloat(predictions.iter().map(|(_, p)| p.dv).collect()));
+    table.push("PRED", &concentration, Values::Float(predictions.iter().map(|(_, p)| p.pred).collect()));
+    table.push("IPRED", &concentration, Values::Float(predictions.iter().map(|(_, p)| p.ipred).collect()));
+    table.push("IPRED_LOWER", &concentration, Values::Float(predictions.iter().map(|(_, p)| p.lower).collect()));
+    table.push("IPRED_UPPER", &concentration, Values::Float(predictions.iter().map(|(_, p)| p.upper).collect()));
+    write_table(table, "map_predictions", config, output_path)?;
+    
+    let file = File::create(output_path.join("map.json"))?;
+    serde_json::to_writer_pretty(file, result)?;
+    
+    info!("MAP estimates saved to {:?}", output_path);
     Ok(())
 }
 
-/// Generate a comprehensive report
-pub fn generate_report<P: AsRef<Path>>(results: &[PatientResult], output_dir: P) -> PKResult<()> {
+/// Write the configured and optimized sampling times (`design.csv`), the
+/// expected RSEs of the parameters under both (`design_rse.csv`) and the
+/// full result (`design.json`).
+pub fn save_design_results<P: AsRef<Path>>(result: &DesignResult, config: &Config, output_dir: P) -> PKResult<()> {
     let output_path = output_dir.as_ref();
-    let report_path = output_path.join("simulation_report.md");
-    
-    let summary = PopulationSummary::from_results(results);
-    
-    let report_content = format!(
-        r#"# Population Pharmacokinetics Simulation Report
-
-## Simulation Overview
-- **Number of patients**: {}
-- **Time points**: {:?}
-
-## Population Parameters
-### Clearance (CL)
-- Mean: {:.3} L/h
-- SD: {:.3} L/h
-- CV%: {:.1}%
-
-### Volume of Distribution (V)
-- Mean: {:.3} L
-- SD: {:.3} L
-- CV%: {:.1}%
-
-## Pharmacokinetic Endpoints
-### Maximum Concentration (Cmax)
-- Mean: {:.3} mg/L
-- SD: {:.3} mg/L
-- CV%: {:.1}%
-
-### Area Under the Curve (AUC)
-- Mean: {:.3} mg*h/L
-- SD: {:.3} mg*h/L
-- CV%: {:.1}%
-
-### Time to Maximum Concentration (Tmax)
-- Mean: {:.3} h
-- SD: {:.3} h
-
-## Files Generated
-- `individual_data.csv`: Patient demographics and PK endpoints
-- `concentrations.csv`: Concentration-time data for all patients
-- `parameters.csv`: Individual patient parameters
-- `population_summary.json`: Detailed population statistics
-
-## Notes
-This simulation was generated using NONMEM-inspired algorithms with appropriate
-inter-individual and residual variability models.
-"#,
-        summary.n_patients,
-        if results.is_empty() { vec![] } else { 
-            results[0].observations.iter().map(|o| o.time).collect::<Vec<_>>() 
-        },
-        summary.parameters.cl_mean,
-        summary.parameters.cl_sd,
-        if summary.parameters.cl_mean > 0.0 { summary.parameters.cl_sd / summary.parameters.cl_mean * 100.0 } else { 0.0 },
-        summary.parameters.v_mean,
-        summary.parameters.v_sd,
-        if summary.parameters.v_mean > 0.0 { summary.parameters.v_sd / summary.parameters.v_mean * 100.0 } else { 0.0 },
-        summary.pharmacokinetics.cmax_mean,
-        summary.pharmacokinetics.cmax_sd,
-        if summary.pharmacokinetics.cmax_mean > 0.0 { summary.pharmacokinetics.cmax_sd / summary.pharmacokinetics.cmax_mean * 100.0 } else { 0.0 },
-        summary.pharmacokinetics.auc_mean,
-        summary.pharmacokinetics.auc_sd,
-        if summary.pharmacokinetics.auc_mean > 0.0 { summary.pharmacokinetics.auc_sd / summary.pharmacokinetics.auc_mean * 100.0 } else { 0.0 },
-        summary.pharmacokinetics.tmax_mean,
-        summary.pharmacokinetics.tmax_sd,
-    );
-    
-    std::fs::write(report_path, report_content)?;
+    let time = &config.units.time;
+    
+    let samples: Vec<_> = result.groups.iter()
+        .flat_map(|group| (0..group.windows.len()).map(move |i| (group, i)))
+        .collect();
+    let mut table = Table::new();
+    table.push("ARM", "", Values::Text(samples.iter().map(|(g, _)| g.arm.clone().unwrap_or_default()).collect()));
+    table.push("N_SUBJECTS", "", Values::UInt(samples.iter().map(|(g, _)| g.n_subjects as u64).collect()));
+    table.push("SAMPLE", "", Values::UInt(samples.iter().map(|(_, i)| *i as u64 + 1).collect()));
+    table.push("WINDOW_LOWER", time, Values::Float(samples.iter().map(|(g, i)| g.windows[*i].0).collect()));
+    table.push("WINDOW_UPPER", time, Values::Float(samples.iter().map(|(g, i)| g.windows[*i].1).collect()));
+    table.push("INITIAL_TIME", time, Values::Float(samples.iter().map(|(g, i)| g.initial_times[*i]).collect()));
+    table.push("OPTIMAL_TIME", time, Values::Float(samples.iter().map(|(g, i)| g.optimal_times[*i]).collect()));
+    write_table(table, "design", config, output_path)?;
+    
+    let parameters = &result.parameters;
+    let mut table = Table::new();
+    table.push("TYPE", "", Values::Text(parameters.iter().map(|p| format!("{:?}", p.kind).to_uppercase()).collect()));
+    table.push("PARAMETER", "", Values::Text(parameters.iter().map(|p| p.name.clone()).collect()));
+    table.push("VALUE", "", Values::Float(parameters.iter().map(|p| p.value).collect()));
+    table.push("INITIAL_RSE", "%", Values::OptionalFloat(parameters.iter().map(|p| p.initial_rse_percent).collect()));
+    table.push("OPTIMAL_RSE", "%", Values::OptionalFloat(parameters.iter().map(|p| p.optimal_rse_percent).collect()));
+    write_table(table, "design_rse", config, output_path)?;
+    
+    let file = File::create(output_path.join("design.json"))?;
+    serde_json::to_writer_pretty(file, result)?;
+    
+    info!("Sampling design saved to {:?}", output_path);
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Write the indices of each method that was run, one row per response and
+/// factor (`sensitivity_local.csv`, `sensitivity_morris.csv`,
+/// `sensitivity_sobol.csv`), and the full result (`sensitivity.json`).
+pub fn save_sensitivity_results<P: AsRef<Path>>(result: &SensitivityResult, config: &Config, output_dir: P) -> PKResult<()> {
+    let output_path = output_dir.as_ref();
+    let time = &config.units.time;
+    let Some(first) = result.responses.first() else {
+        return Ok(());
+    };
+    // Rows of every response and factor, led by their key columns
+    let rows: Vec<(&Response, usize)> = result.responses.iter()
+        .flat_map(|response| (0..result.factors.len()).map(move |k| (&response.response, k)))
+        .collect();
+    let table = || {
+        let mut table = Table::new();
+        table.push("METRIC", "", Values::Text(rows.iter().map(|(r, _)| r.metric.clone()).collect()));
+        table.push("TIME", time, Values::OptionalFloat(rows.iter().map(|(r, _)| r.time).collect()));
+        table.push("START", time, Values::OptionalFloat(rows.iter().map(|(r, _)| r.interval.map(|i| i.0)).collect()));
+        table.push("END", time, Values::OptionalFloat(rows.iter().map(|(r, _)| r.interval.map(|i| i.1)).collect()));
+        table.push("VALUE", "", Values::Float(rows.iter().map(|(r, _)| r.nominal).collect()));
+        table.push("FACTOR", "", Values::Text(rows.iter().map(|(_, k)| result.factors[*k].name.clone()).collect()));
+        table
+    };
+    let indices = || result.responses.iter().flat_map(|response| (0..result.factors.len()).map(move |k| (response, k)));
+    
+    if !first.local.is_empty() {
+        let mut table = table();
+        table.push("DERIVATIVE", "", Values::Float(indices().map(|(r, k)| r.local[k].derivative).collect()));
+        table.push("NORMALIZED", "", Values::OptionalFloat(indices().map(|(r, k)| r.local[k].normalized).collect()));
+        write_table(table, "sensitivity_local", config, output_path)?;
+    }
+    
+    if !first.morris.is_empty() {
+        let mut table = table();
+        table.push("MU", "", Values::Float(indices().map(|(r, k)| r.morris[k].mu).collect()));
+        table.push("MU_STAR", "", Values::Float(indices().map(|(r, k)| r.morris[k].mu_star).collect()));
+        table.push("SIGMA", "", Values::Float(indices().map(|(r, k)| r.morris[k].sigma).collect()));
+        write_table(table, "sensitivity_morris", config, output_path)?;
+    }
+    
+    if !first.sobol.is_empty() {
+        let mut table = table();
+        table.push("FIRST_ORDER", "", Values::OptionalFloat(indices().map(|(r, k)| r.sobol[k].first_order).collect()));
+        table.push("TOTAL", "", Values::OptionalFloat(indices().map(|(r, k)| r.sobol[k].total).collect()));
+        write_table(table, "sensitivity_sobol", config, output_path)?;
+    }
+    
+    let file = File::create(output_path.join("sensitivity.json"))?;
+    serde_json::to_writer_pretty(file, result)?;
+    
+    info!("Sensitivity analysis saved to {:?}", output_path);
+    Ok(())
+}
+
+/// Write ranked regimens (`dose_optimization.csv`), one block per patient
+/// for individual optimization, and the full result
+/// (`dose_optimization.json`).
+pub fn save_dose_optimization_results<P: AsRef<Path>>(results: &[DoseOptimizationResult], config: &Config, output_dir: P) -> PKResult<()> {
+    let output_path = output_dir.as_ref();
+    let units = &config.units;
+    
+    let rows: Vec<_> = results.iter()
+        .flat_map(|result| result.regimens.iter().map(move |attainment| (result.id, attainment)))
+        .collect();
+    let mut table = Table::new();
+    table.push("ID", "", Values::OptionalUInt(rows.iter().map(|(id, _)| id.map(|id| id as u64)).collect()));
+    table.push("RANK", "", Values::UInt(rows.iter().map(|(_, a)| a.rank as u64).collect()));
+    table.push("AMOUNT", &units.amount, Values::Float(rows.iter().map(|(_, a)| a.regimen.amount).collect()));
+    table.push("INTERVAL", &units.time, Values::Float(rows.iter().map(|(_, a)| a.regimen.interval).collect()));
+    table.push("DURATION", &units.time, Values::OptionalFloat(rows.iter().map(|(_, a)| a.regimen.duration).collect()));
+    table.push("DAILY_DOSE", &units.amount, Values::Float(rows.iter().map(|(_, a)| a.daily_dose).collect()));
+    table.push("PTA", "", Values::Float(rows.iter().map(|(_, a)| a.probability).collect()));
+    table.push("METRIC_MEDIAN", "", Values::Float(rows.iter().map(|(_, a)| a.metric_median).collect()));
+    table.push("METRIC_P5", "", Values::Float(rows.iter().map(|(_, a)| a.metric_p5).collect()));
+    table.push("METRIC_P95", "", Values::Float(rows.iter().map(|(_, a)| a.metric_p95).collect()));
+    write_table(table, "dose_optimization", config, output_path)?;
+    
+    let file = File::create(output_path.join("dose_optimization.json"))?;
+    serde_json::to_writer_pretty(file, results)?;
+    
+    info!("Dose optimization results saved to {:?}", output_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testing::config_with;
+    use crate::config::OutputFormat;
+    use crate::simulation::Simulator;
+
+    #[test]
+    fn test_parameter_columns_are_stable() {
+        let config = config_with(&[r#"{
+            "model": { "parameters": {
+                "CL": { "omega": 50.0, "bounds": [1.5, 2.5] }, "KA": { "theta": 1.0 }
+            } },
+            "dosing": { "route": "oral" },
+            "simulation": { "time_points": [1.0] }
+        }"#]);
+        let results = Simulator::new(config.clone(), Some(5)).unwrap().simulate_population(20).unwrap();
+
+        let path = std::env::temp_dir().join(format!("pk_parameters_test_{}", std::process::id()));
+        let columns = ParameterColumns::new(&results[0], &config);
+        let path = parameter_table(&results, &columns, &config)
+            .write(&path, config.output.format, config.output.compression)
+            .unwrap();
+        let mut reader = csv::Reader::from_path(&path).unwrap();
+        let header: Vec<String> = reader.headers().unwrap().iter().map(str::to_string).collect();
+        let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(header, [
+            "PATIENT_ID", "CL (L/h)", "KA (1/h)", "V (L)",
+            "TV_CL (L/h)", "TV_KA (1/h)", "TV_V (L)", "COV_CL (L/h)", "COV_KA (1/h)", "COV_V (L)",
+            "ETA_CL", "ETA_V", "CLAMPED_CL", "CLAMPED_KA", "CLAMPED_V",
+        ]);
+        // With a 50% omega about two thirds of clearances fall outside the bounds
+        let clamped: Vec<&csv::StringRecord> = rows.iter().filter(|r| &r[12] == "1").collect();
+        assert!(!clamped.is_empty() && clamped.len() < rows.len());
+        for row in &rows {
+            let cl: f64 = row[1].parse().unwrap();
+            assert!((1.5..=2.5).contains(&cl));
+            assert_eq!(&row[4], "2");
+            assert_eq!(&row[13], "0");
+        }
+    }
+
+    #[test]
+    fn test_missing_values_are_blank() {
+        let config = config_with(&[r#"{
+            "model": { "parameters": { "V": { "omega": null } } },
+            "simulation": { "time_points": [1.0, 4.0] }
+        }"#]);
+        let mut results = Simulator::new(config.clone(), Some(5)).unwrap().simulate_population(2).unwrap();
+        // No quantified sample, no residual error drawn and no ETA
+        results[0].observations.iter_mut().for_each(|obs| obs.censored = true);
+        results[0].observations[0].eps.clear();
+        results[0].etas.clear();
+
+        let read = |table: Table, name: &str| -> Vec<csv::StringRecord> {
+            let path = std::env::temp_dir().join(format!("pk_missing_{}_{}", name, std::process::id()));
+            let path = table.write(&path, OutputFormat::Csv, config.output.compression).unwrap();
+            let rows = csv::Reader::from_path(&path).unwrap().records().map(Result::unwrap).collect();
+            std::fs::remove_file(&path).unwrap();
+            rows
+        };
+        let patients = read(patient_table(&results, &config), "patients");
+        assert_eq!(&patients[0][5], "");
+        assert!(patients[1][5].parse::<f64>().is_ok());
+        let concentrations = read(concentration_table(&results, &config), "concentrations");
+        assert_eq!(&concentrations[0][4], "");
+        assert!(concentrations[1][4].parse::<f64>().is_ok());
+        let columns = ParameterColumns::new(&results[1], &config);
+        let parameters = read(parameter_table(&results, &columns, &config), "parameters");
+        assert_eq!(&parameters[0][7], "");
+        assert!(parameters[1][7].parse::<f64>().is_ok());
+    }
+
+    #[test]
+    fn test_nca_follows_received_doses() {
+        let config = config_with(&[r#"{
+            "model": { "parameters": { "CL": { "omega": null }, "V": { "omega": null } } },
+            "dosing": { "times": [0.0, 12.0, 24.0] },
+            "simulation": { "time_points": [1.0, 4.0, 11.0, 18.0, 23.0, 30.0, 36.0] },
+            "trial": {
+                "dropout": { "hazards": [[0.0, 0.04]] },
+                "adherence": { "missed_probability": 0.3 }
+            }
+        }"#]);
+        let results = Simulator::new(config.clone(), Some(3)).unwrap().simulate_population(20).unwrap();
+        assert!(results.iter().any(|r| r.dropout_time.is_some()));
+        assert!(results.iter().any(|r| r.dropout_time.is_none() && r.doses.len() < 3));
+
+        let dir = std::env::temp_dir().join(format!("pk_nca_received_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        save_nca_results(&results, &config, &dir).unwrap();
+        let mut reader = csv::Reader::from_path(dir.join("nca.csv")).unwrap();
+        let header = reader.headers().unwrap().clone();
+        let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let column = |name: &str| header.iter().position(|h| h.split(' ').next() == Some(name)).unwrap();
+        let (auc_tau, ctrough) = (column("AUC_TAU"), column("CTROUGH"));
+
+        let (mut within, mut past) = (0, 0);
+        for (row, result) in rows.iter().zip(&results) {
+            let times: Vec<f64> = result.quantified().map(|o| o.time).collect();
+            let concs: Vec<f64> = result.quantified().map(|o| o.concentration).collect();
+            let last_dose = result.doses.iter().map(|d| d.time).fold(0.0, f64::max);
+            match times.last() {
+                // The interval of the last received dose, within the samples
+                Some(&last) if last_dose > times[0] && last >= last_dose + 12.0 => {
+                    let method = crate::config::AucMethod::LinearUpLogDown;
+                    let expected = nca::partial_auc(&times, &concs, last_dose, last_dose + 12.0, method, None).unwrap();
+                    assert!((row[auc_tau].parse::<f64>().unwrap() - expected).abs() <= 1e-6 * expected);
+                    let expected = nca::concentration_at(&times, &concs, last_dose + 12.0, method, None).unwrap();
+                    assert!((row[ctrough].parse::<f64>().unwrap() - expected).abs() <= 1e-6 * expected);
+                    within += 1;
+                },
+                // No sample after the last received dose
+                Some(&last) if last <= last_dose => {
+                    assert_eq!((&row[auc_tau], &row[ctrough]), ("", ""));
+                    past += 1;
+                },
+                _ => {},
+            }
+        }
+        assert!(within > 0 && past > 0);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::config::{OutputCompression, OutputFormat};
+use crate::models::DoseRoute;
+use crate::output::table::{Table, TableWriter, Values};
+use crate::simulation::{PatientResult, Simulator};
+use crate::solver::infusion_duration;
+use crate::error::PKResult;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// Write the simulated study as a NONMEM-ready dataset: one dose record
+/// (EVID=1, MDV=1) per administration and one observation record (EVID=0,
+/// MDV=0) per sample, sorted by time with doses first at equal times.
+/// Samples below the LLOQ are flagged in a `BLQ` column, present when an
+/// LLOQ is configured; censored samples, BLQ under the `flag` method, get
+/// MDV=1.
+///
+/// Compartments follow the ADVAN numbering: oral doses go to the depot
+/// (CMT=1) and are observed in the central compartment (CMT=2); IV doses and
+/// observations both use CMT=1. Infusions carry `RATE`. `PRED` is the
+/// typical-subject prediction with the subject's covariates, `IPRED` the
+/// individual prediction and `DV` the prediction with residual error. For a
+/// study, `ARM` is the number of the subject's arm, in configuration order.
+pub fn save_nonmem_dataset<P: AsRef<Path>>(results: &[PatientResult], simulator: &Simulator, path: P) -> PKResult<()> {
+    let eta_names: BTreeSet<&String> = results.iter().flat_map(|r| r.etas.keys()).collect();
+    let eta_names: Vec<String> = eta_names.into_iter().cloned().collect();
+    let mut writer = TableWriter::new(path.as_ref(), OutputFormat::Csv, OutputCompression::None).with_missing(".");
+    for result in results {
+        writer.write(subject_rows(result, simulator, &eta_names)?)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Dose and observation records of one subject, with an `ETA_` column for
+/// each of `eta_names`.
+pub(super) fn subject_rows(result: &PatientResult, simulator: &Simulator, eta_names: &[String]) -> PKResult<Table> {
+    let config = simulator.config();
+    let arms: Vec<&str> = config.study.iter()
+        .flat_map(|study| study.arms.iter().map(|arm| arm.name.as_str()))
+        .collect();
+    let oral = result.doses.iter().any(|d| d.route == DoseRoute::Oral);
+    let observation_cmt = if oral { 2 } else { 1 };
+
+    let times: Vec<f64> = result.observations.iter().map(|o| o.time).collect();
+    let preds = simulator.typical_prediction(&result.demographics, &result.doses, &times)?;
+
+    // (time, is observation, index) in record order
+    let mut rows: Vec<(f64, bool, usize)> = result.doses.iter().enumerate()
+        .map(|(i, dose)| (dose.time, false, i))
+        .chain(result.observations.iter().enumerate().map(|(i, obs)| (obs.time, true, i)))
+        .collect();
+    rows.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+    // Observation and dose values of each record; DV, PRED and IPRED are
+    // missing on dose records
+    let observation = |&(_, is_observation, index): &(f64, bool, usize)| {
+        is_observation.then(|| &result.observations[index])
+    };
+    let dose = |&(_, is_observation, index): &(f64, bool, usize)| {
+        (!is_observation).then(|| &result.doses[index])
+    };
+    let n_rows = rows.len();
+    let mut table = Table::new();
+    table.push("ID", "", Values::UInt(vec![result.patient_id as u64; n_rows]));
+    table.push("TIME", "", Values::Float(rows.iter().map(|row| row.0).collect()));
+    table.push("AMT", "", Values::Float(rows.iter().map(|row| dose(row).map_or(0.0, |d| d.amount)).collect()));
+    table.push("RATE", "", Values::Float(rows.iter()
+        .map(|row| Ok(match dose(row) {
+            Some(d) if d.route == DoseRoute::IvInfusion => d.amount / infusion_duration(d)?,
+            _ => 0.0,
+        }))
+        .collect::<PKResult<_>>()?));
+    table.push("EVID", "", Values::UInt(rows.iter().map(|row| dose(row).is_some() as u64).collect()));
+    table.push("MDV", "", Values::UInt(rows.iter().map(|row| observation(row).is_none_or(|obs| obs.censored) as u64).collect()));
+    table.push("CMT", "", Values::UInt(rows.iter().map(|row| observation(row).map_or(1, |_| observation_cmt)).collect()));
+    table.push("DV", "", Values::OptionalFloat(rows.iter().map(|row| observation(row).map(|obs| obs.concentration)).collect()));
+    table.push("PRED", "", Values::OptionalFloat(rows.iter().map(|row| row.1.then(|| preds[row.2])).collect()));
+    table.push("IPRED", "", Values::OptionalFloat(rows.iter().map(|row| observation(row).map(|obs| obs.predicted_concentration)).collect()));
+    table.push("WT", "", Values::Float(vec![result.demographics.weight; n_rows]));
+    table.push("AGE", "", Values::Float(vec![result.demographics.age; n_rows]));
+    if !arms.is_empty() {
+        let arm = result.arm.as_deref().and_then(|name| arms.iter().position(|&arm| arm == name));
+        table.push("ARM", "", Values::OptionalUInt(vec![arm.map(|i| i as u64 + 1); n_rows]));
+    }
+    for name in eta_names {
+        table.push(&format!("ETA_{}", name), "", Values::OptionalFloat(vec![result.etas.get(name).copied(); n_rows]));
+    }
+    if config.simulation.lloq.is_some() {
+        table.push("BLQ", "", Values::UInt(rows.iter().map(|row| observation(row).is_some_and(|obs| obs.blq) as u64).collect()));
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testing::config_with;
+    use crate::data::Dataset;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_dataset_round_trip() {
+        let config = config_with(&[r#"{
+            "model": { "parameters": { "KA": { "theta": 1.0 } } },
+            "dosing": { "route": "oral", "times": [0.0, 12.0] },
+            "simulation": { "time_points": [0.0, 1.0, 12.0, 24.0] }
+        }"#]);
+        let dosing = config.dosing.clone();
+        let mut simulator = Simulator::new(config, Some(3)).unwrap();
+        let results = simulator.simulate_population(3).unwrap();
+
+        let path = std::env::temp_dir().join(format!("pk_nonmem_test_{}.csv", std::process::id()));
+        save_nonmem_dataset(&results, &simulator, &path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let dataset = Dataset::from_file(&path, &dosing).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let header = text.lines().next().unwrap();
+        assert_eq!(header, "ID,TIME,AMT,RATE,EVID,MDV,CMT,DV,PRED,IPRED,WT,AGE,ETA_CL,ETA_V");
+        // Dose at 12 h precedes the 12 h sample
+        let first_subject: Vec<&str> = text.lines().skip(1).take(6).collect();
+        assert!(first_subject[3].starts_with("1,12,100,0,1,1,1,"));
+        assert!(first_subject[4].starts_with("1,12,0,0,0,0,2,"));
+
+        assert_eq!(dataset.subjects.len(), 3);
+        for (subject, result) in dataset.subjects.iter().zip(&results) {
+            assert_eq!(subject.doses.len(), 2);
+            assert_eq!(subject.observations.len(), 4);
+            assert_relative_eq!(subject.observations[1].dv, result.observations[1].concentration);
+            assert_relative_eq!(subject.covariates["ETA_CL"], result.etas["CL"]);
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! Minimal SVG plotting for the HTML report: line, ribbon, scatter and
+//! histogram layers on linear or logarithmic axes.
+
+use std::fmt::Write;
+
+const WIDTH: f64 = 640.0;
+const HEIGHT: f64 = 400.0;
+const MARGIN_LEFT: f64 = 70.0;
+const MARGIN_RIGHT: f64 = 20.0;
+const MARGIN_TOP: f64 = 36.0;
+const MARGIN_BOTTOM: f64 = 50.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scale {
+    Linear,
+    Log,
+}
+
+#[derive(Debug, Clone)]
+enum Layer {
+    Line { points: Vec<(f64, f64)>, color: String, width: f64, opacity: f64 },
+    Ribbon { lower: Vec<(f64, f64)>, upper: Vec<(f64, f64)>, color: String, opacity: f64 },
+    Points { points: Vec<(f64, f64)>, color: String, radius: f64 },
+    Bars { bins: Vec<(f64, f64, f64)>, color: String },
+}
+
+/// A single chart rendered as an inline `<svg>` element.
+#[derive(Debug, Clone)]
+pub struct Plot {
+    title: String,
+    x_label: String,
+    y_label: String,
+    y_scale: Scale,
+    layers: Vec<Layer>,
+}
+
+impl Plot {
+    pub fn new(title: &str, x_label: &str, y_label: &str) -> Self {
+        Self {
+            title: title.to_string(),
+            x_label: x_label.to_string(),
+            y_label: y_label.to_string(),
+            y_scale: Scale::Linear,
+            layers: Vec::new(),
+        }
+    }
+
+    pub fn y_scale(mut self, scale: Scale) -> Self {
+        self.y_scale = scale;
+        self
+    }
+
+    pub fn line(mut self, points: Vec<(f64, f64)>, color: &str, width: f64, opacity: f64) -> Self {
+        self.layers.push(Layer::Line { points, color: color.to_string(), width, opacity });
+        self
+    }
+
+    /// Shaded band between two curves sampled at the same x values.
+    pub fn ribbon(mut self, lower: Vec<(f64, f64)>, upper: Vec<(f64, f64)>, color: &str, opacity: f64) -> Self {
+        self.layers.push(Layer::Ribbon { lower, upper, color: color.to_string(), opacity });
+        self
+    }
+
+    pub fn points(mut self, points: Vec<(f64, f64)>, color: &str, radius: f64) -> Self {
+        self.layers.push(Layer::Points { points, color: color.to_string(), radius });
+        self
+    }
+
+    /// Histogram bars given as `(lower, upper, count)`.
+    pub fn bars(mut self, bins: Vec<(f64, f64, f64)>, color: &str) -> Self {
+        self.layers.push(Layer::Bars { bins, color: color.to_string() });
+        self
+    }
+
+    fn usable(&self, (x, y): (f64, f64)) -> bool {
+        x.is_finite() && y.is_finite() && (self.y_scale == Scale::Linear || y > 0.0)
+    }
+
+    fn data_range(&self) -> Option<((f64, f64), (f64, f64))> {
+        let mut points: Vec<(f64, f64)> = Vec::new();
+        for layer in &self.layers {
+            match layer {
+                Layer::Line { points: p, .. } | Layer::Points { points: p, .. } => points.extend(p),
+                Layer::Ribbon { lower, upper, .. } => {
+                    points.extend(lower);
+                    points.extend(upper);
+                },
+                Layer::Bars { bins, .. } => {
+                    for &(lo, hi, count) in bins {
+                        points.extend([(lo, 0.0), (hi, count)]);
+                    }
+                },
+            }
+        }
+        let points: Vec<_> = points.into_iter().filter(|&p| self.usable(p)).collect();
+        if points.is_empty() {
+            return None;
+        }
+
+        let fold = |f: fn(&(f64, f64)) -> f64| points.iter().map(f).fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| (lo.min(v), hi.max(v)));
+        let (mut x, mut y) = (fold(|p| p.0), fold(|p| p.1));
+        if x.0 == x.1 {
+            x = (x.0 - 0.5, x.1 + 0.5);
+        }
+        if self.y_scale == Scale::Linear {
+            y.0 = y.0.min(0.0);
+            if y.0 == y.1 {
+                y.1 = y.0 + 1.0;
+            }
+        } else if y.0 == y.1 {
+            y = (y.0 / 10.0, y.1 * 10.0);
+        }
+        Some((x, y))
+    }
+
+    pub fn to_svg(&self) -> String {
+        let mut svg = String::new();
+        let _ = write!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {w} {h}" width="{w}" height="{h}" font-family="sans-serif" font-size="12">"#,
+            w = WIDTH, h = HEIGHT,
+        );
+        let _ = write!(svg, r#"<rect width="{}" height="{}" fill="white"/>"#, WIDTH, HEIGHT);
+        let _ = write!(
+            svg,
+            r#"<text x="{}" y="22" text-anchor="middle" font-size="15" font-weight="bold">{}</text>"#,
+            WIDTH / 2.0, escape(&self.title),
+        );
+
+        let Some(((x_min, x_max), (y_min, y_max))) = self.data_range() else {
+            let _ = write!(svg, r#"<text x="{}" y="{}" text-anchor="middle">No data</text></svg>"#, WIDTH / 2.0, HEIGHT / 2.0);
+            return svg;
+        };
+
+        let x_ticks = linear_ticks(x_min, x_max);
+        let y_ticks = match self.y_scale {
+            Scale::Linear => linear_ticks(y_min, y_max),
+            Scale::Log => log_ticks(y_min, y_max),
+        };
+        // Extend the axes to the outer ticks
+        let (x_min, x_max) = (x_min.min(x_ticks[0]), x_max.max(*x_ticks.last().unwrap()));
+        let (y_min, y_max) = (y_min.min(y_ticks[0]), y_max.max(*y_ticks.last().unwrap()));
+
+        let transform = |v: f64| match self.y_scale {
+            Scale::Linear => v,
+            Scale::Log => v.log10(),
+        };
+        let plot_w = WIDTH - MARGIN_LEFT - MARGIN_RIGHT;
+        let plot_h = HEIGHT - MARGIN_TOP - MARGIN_BOTTOM;
+        let sx = |x: f64| MARGIN_LEFT + (x - x_min) / (x_max - x_min) * plot_w;
+        let sy = |y: f64| MARGIN_TOP + plot_h - (transform(y) - transform(y_min)) / (transform(y_max) - transform(y_min)) * plot_h;
+
+        // Grid and tick labels
+        for &x in &x_ticks {
+            let _ = write!(
+                svg,
+                r##"<line x1="{x:.1}" y1="{t:.1}" x2="{x:.1}" y2="{b:.1}" stroke="#e0e0e0"/><text x="{x:.1}" y="{l:.1}" text-anchor="middle">{v}</text>"##,
+                x = sx(x), t = MARGIN_TOP, b = MARGIN_TOP + plot_h, l = MARGIN_TOP + plot_h + 16.0, v = format_tick(x),
+            );
+        }
+        for &y in &y_ticks {
+            let _ = write!(
+                svg,
+                r##"<line x1="{l:.1}" y1="{y:.1}" x2="{r:.1}" y2="{y:.1}" stroke="#e0e0e0"/><text x="{t:.1}" y="{ty:.1}" text-anchor="end">{v}</text>"##,
+                y = sy(y), l = MARGIN_LEFT, r = MARGIN_LEFT + plot_w, t = MARGIN_LEFT - 6.0, ty = sy(y) + 4.0, v = format_tick(y),
+            );
+        }
+
+        for layer in &self.layers {
+            match layer {
+                Layer::Line { points, color, width, opacity } => {
+                    let path = polyline(points.iter().copied().filter(|&p| self.usable(p)).map(|(x, y)| (sx(x), sy(y))));
+                    if !path.is_empty() {
+                        let _ = write!(
+                            svg,
+                            r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="{}" stroke-opacity="{}"/>"#,
+                            path, color, width, opacity,
+                        );
+                    }
+                },
+                Layer::Ribbon { lower, upper, color, opacity } => {
+                    let pairs: Vec<_> = lower.iter().zip(upper)
+                        .filter(|(&l, &u)| self.usable(l) && self.usable(u))
+                        .collect();
+                    let outline = polyline(
+                        pairs.iter().map(|(l, _)| (sx(l.0), sy(l.1)))
+                            .chain(pairs.iter().rev().map(|(_, u)| (sx(u.0), sy(u.1))))
+                    );
+                    if !outline.is_empty() {
+                        let _ = write!(
+                            svg,
+                            r#"<polygon points="{}" fill="{}" fill-opacity="{}" stroke="none"/>"#,
+                            outline, color, opacity,
+                        );
+                    }
+                },
+                Layer::Points { points, color, radius } => {
+                    for &(x, y) in points.iter().filter(|&&p| self.usable(p)) {
+                        let _ = write!(
+                            svg,
+                            r#"<circle cx="{:.1}" cy="{:.1}" r="{}" fill="{}" fill-opacity="0.6"/>"#,
+                            sx(x), sy(y), radius, color,
+                        );
+                    }
+                },
+                Layer::Bars { bins, color } => {
+                    for &(lo, hi, count) in bins {
+                        let (top, base) = (sy(count), sy(y_min.max(0.0)));
+                        let _ = write!(
+                            svg,
+                            r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="{}" stroke="white"/>"#,
+                            sx(lo), top, (sx(hi) - sx(lo)).max(0.5), (base - top).max(0.0), color,
+                        );
+                    }
+                },
+            }
+        }
+
+        // Axes and labels
+        let _ = write!(
+            svg,
+            r#"<rect x="{}" y="{}" width="{}" height="{}" fill="none" stroke="black"/>"#,
+            MARGIN_LEFT, MARGIN_TOP, plot_w, plot_h,
+        );
+        let _ = write!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="middle">{}</text>"#,
+            MARGIN_LEFT + plot_w / 2.0, HEIGHT - 10.0, escape(&self.x_label),
+        );
+        let _ = write!(
+            svg,
+            r#"<text x="16" y="{y}" text-anchor="middle" transform="rotate(-90 16 {y})">{}</text>"#,
+            escape(&self.y_label), y = MARGIN_TOP + plot_h / 2.0,
+        );
+        svg.push_str("</svg>");
+        svg
+    }
+}
+
+/// Equal-width histogram with Sturges' number of bins, as
+/// `(lower, upper, count)`.
+pub fn histogram(values: &[f64]) -> Vec<(f64, f64, f64)> {
+    let values: Vec<f64> = values.iter().copied().filter(|v| v.is_finite()).collect();
+    if values.is_empty() {
+        return Vec::new();
+    }
+
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if min == max {
+        return vec![(min - 0.5, max + 0.5, values.len() as f64)];
+    }
+
+    let n_bins = ((values.len() as f64).log2().ceil() as usize + 1).max(1);
+    let width = (max - min) / n_bins as f64;
+    let mut counts = vec![0.0; n_bins];
+    for v in values {
+        let bin = (((v - min) / width) as usize).min(n_bins - 1);
+        counts[bin] += 1.0;
+    }
+
+    counts.into_iter()
+        .enumerate()
+        .map(|(i, count)| (min + i as f64 * width, min + (i + 1) as f64 * width, count))
+        .collect()
+}
+
+/// Round tick positions (steps of 1, 2 or 5 times a power of ten) covering
+/// `[min, max]`.
+pub fn linear_ticks(min: f64, max: f64) -> Vec<f64> {
+    let raw_step = (max - min) / 5.0;
+    let magnitude = 10f64.powf(raw_step.log10().floor());
+    let step = [1.0, 2.0, 5.0, 10.0].iter()
+        .map(|m| m * magnitude)
+        .find(|s| *s >= raw_step)
+        .unwrap_or(10.0 * magnitude);
+
+    let start = (min / step).floor() as i64;
+    let end = (max / step).ceil() as i64;
+    (start..=end).map(|i| i as f64 * step).collect()
+}
+
+/// Powers of ten covering `[min, max]` (both positive).
+pub fn log_ticks(min: f64, max: f64) -> Vec<f64> {
+    let start = min.log10().floor() as i32;
+    let end = (max.log10().ceil() as i32).max(start + 1);
+    (start..=end).map(|e| 10f64.powi(e)).collect()
+}
+
+fn format_tick(value: f64) -> String {
+    let rounded = (value * 1e6).round() / 1e6;
+    if rounded != 0.0 && (rounded.abs() >= 1e5 || rounded.abs() < 1e-3) {
+        format!("{:.0e}", rounded)
+    } else {
+        format!("{}", rounded)
+    }
+}
+
+fn polyline<I: Iterator<Item = (f64, f64)>>(points: I) -> String {
+    points.map(|(x, y)| format!("{:.1},{:.1}", x, y)).collect::<Vec<_>>().join(" ")
+}
+
+/// Escape text for inclusion in HTML or SVG.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticks() {
+        assert_eq!(linear_ticks(0.0, 24.0), vec![0.0, 5.0, 10.0, 15.0, 20.0, 25.0]);
+        assert_eq!(log_ticks(0.05, 12.0), vec![0.01, 0.1, 1.0, 10.0, 100.0]);
+    }
+
+    #[test]
+    fn test_histogram_counts_every_value() {
+        let values: Vec<f64> = (0..100).map(|i| i as f64).collect();
+        let bins = histogram(&values);
+        assert_eq!(bins.len(), 8);
+        assert_eq!(bins.iter().map(|b| b.2).sum::<f64>(), 100.0);
+        assert_eq!(bins.last().unwrap().1, 99.0);
+    }
+
+    #[test]
+    fn test_log_scale_skips_non_positive_values() {
+        let svg = Plot::new("C & t", "Time", "Conc")
+            .y_scale(Scale::Log)
+            .line(vec![(0.0, 0.0), (1.0, 10.0), (2.0, 1.0)], "steelblue", 1.0, 1.0)
+            .to_svg();
+        assert!(svg.contains("C &amp; t"));
+        assert_eq!(svg.matches("<polyline").count(), 1);
+        assert!(!svg.contains("NaN") && !svg.contains("inf"));
+    }
+}
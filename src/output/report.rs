@@ -0,0 +1,338 @@
+use super::plot::{escape, histogram, Plot, Scale};
+use crate::config::{Config, DosingRoute, ReportSection, UnitsConfig};
+use crate::nca;
+use crate::units::label;
+use crate::simulation::statistics::MetricSummary;
+use crate::simulation::{by_arm, PatientResult, PopulationSummary};
+use crate::error::PKResult;
+use std::fmt::Write;
+use std::path::Path;
+
+/// Individual profiles drawn in the spaghetti plot.
+const MAX_SPAGHETTI_SUBJECTS: usize = 200;
+
+type Covariate = fn(&PatientResult) -> f64;
+
+const STYLE: &str = "body{font-family:sans-serif;max-width:1000px;margin:2em auto;color:#222}\
+table{border-collapse:collapse;margin:1em 0}th,td{border:1px solid #ccc;padding:4px 10px;text-align:right}\
+th:first-child,td:first-child{text-align:left}th{background:#f3f3f3}\
+.figures{display:flex;flex-wrap:wrap;gap:12px}.figures svg{max-width:100%;height:auto}";
+
+/// Generate a self-contained HTML report (`simulation_report.html`) with
+/// summary tables and embedded SVG figures. Sections and their order come
+/// from the `report` configuration, all sections by default. For a study,
+/// the sections are repeated for each arm with the arm's configuration.
+pub fn generate_report<P: AsRef<Path>>(results: &[PatientResult], config: &Config, output_dir: P) -> PKResult<()> {
+    let report_path = output_dir.as_ref().join("simulation_report.html");
+    let extra_percentiles = config.summary.as_ref()
+        .map(|s| s.percentiles.as_slice())
+        .unwrap_or_default();
+    let sections = config.report.clone().unwrap_or_default().sections;
+
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Population Pharmacokinetics Simulation Report</title><style>{}</style></head><body>",
+        STYLE,
+    );
+    html.push_str("<h1>Population Pharmacokinetics Simulation Report</h1>");
+
+    for group in by_arm(results, config)? {
+        // Sections of an arm go under the arm's heading
+        let level = match group.arm {
+            Some(arm) => {
+                heading(&mut html, 2, &format!("Arm: {}", arm));
+                3
+            },
+            None => 2,
+        };
+        let (results, config) = (group.results, &group.config);
+        let summary = PopulationSummary::with_percentiles(results, extra_percentiles);
+        for section in &sections {
+            match section {
+                ReportSection::Overview => overview_section(&mut html, level, results, &summary, &config.units),
+                ReportSection::Dosing => dosing_section(&mut html, level, config),
+                ReportSection::Parameters => parameter_section(&mut html, level, results, &summary, &config.units),
+                ReportSection::PkEndpoints => endpoint_section(&mut html, level, &summary, &config.units),
+                ReportSection::Nca => nca_section(&mut html, level, results, config)?,
+                ReportSection::Covariates => covariate_section(&mut html, level, results, &summary),
+            }
+        }
+    }
+
+    html.push_str("</body></html>\n");
+    std::fs::write(report_path, html)?;
+    Ok(())
+}
+
+fn heading(html: &mut String, level: usize, title: &str) {
+    let _ = write!(html, "<h{}>{}</h{}>", level, escape(title), level);
+}
+
+fn overview_section(html: &mut String, level: usize, results: &[PatientResult], summary: &PopulationSummary, units: &UnitsConfig) {
+    let n_observations: usize = results.iter().map(|r| r.observations.len()).sum();
+    let times: Vec<String> = summary.concentration_bands.iter().map(|b| b.time.to_string()).collect();
+
+    heading(html, level, "Simulation Overview");
+    html.push_str("<table>");
+    let _ = write!(html, "<tr><td>Number of patients</td><td>{}</td></tr>", summary.n_patients);
+    let _ = write!(html, "<tr><td>Observations</td><td>{}</td></tr>", n_observations);
+    let _ = write!(html, "<tr><td>Time points ({})</td><td>{}</td></tr>", escape(&units.time), times.join(", "));
+    html.push_str("</table>");
+
+    let time_label = format!("Time ({})", units.time);
+    let concentration_label = format!("Concentration ({})", units.concentration());
+
+    html.push_str("<div class=\"figures\">");
+    let mut spaghetti = Plot::new("Individual predicted profiles", &time_label, &concentration_label);
+    for result in results.iter().take(MAX_SPAGHETTI_SUBJECTS) {
+        let points = result.observations.iter().map(|o| (o.time, o.predicted_concentration)).collect();
+        spaghetti = spaghetti.line(points, "steelblue", 1.0, 0.3);
+    }
+    html.push_str(&spaghetti.to_svg());
+
+    let (lower_p, upper_p) = interval_percentiles(summary);
+    let band = |p: f64| -> Vec<(f64, f64)> {
+        summary.concentration_bands.iter()
+            .filter_map(|b| b.observed.percentile(p).map(|v| (b.time, v)))
+            .collect()
+    };
+    for scale in [Scale::Linear, Scale::Log] {
+        let title = format!(
+            "Median and {}-{}th percentile interval ({})",
+            lower_p, upper_p, if scale == Scale::Log { "log" } else { "linear" },
+        );
+        let plot = Plot::new(&title, &time_label, &concentration_label)
+            .y_scale(scale)
+            .ribbon(band(lower_p), band(upper_p), "steelblue", 0.25)
+            .line(band(50.0), "navy", 2.0, 1.0);
+        html.push_str(&plot.to_svg());
+    }
+    html.push_str("</div>");
+}
+
+fn dosing_section(html: &mut String, level: usize, config: &Config) {
+    let dosing = &config.dosing;
+    let units = &config.units;
+    let route = match dosing.route {
+        DosingRoute::Oral => "Oral",
+        DosingRoute::IvBolus => "IV bolus",
+        DosingRoute::IvInfusion => "IV infusion",
+    };
+    let times: Vec<String> = dosing.times.iter().map(|t| t.to_string()).collect();
+
+    heading(html, level, "Dosing");
+    html.push_str("<table>");
+    let _ = write!(html, "<tr><td>Route</td><td>{}</td></tr>", route);
+    let _ = write!(html, "<tr><td>Dose</td><td>{} {}</td></tr>", dosing.amount, escape(&units.amount));
+    let _ = write!(html, "<tr><td>Number of doses</td><td>{}</td></tr>", dosing.times.len());
+    let _ = write!(html, "<tr><td>Dosing times ({})</td><td>{}</td></tr>", escape(&units.time), times.join(", "));
+    if let Some(additional) = &dosing.additional {
+        if let Some(duration) = additional.duration {
+            let _ = write!(html, "<tr><td>Infusion duration</td><td>{} {}</td></tr>", duration, escape(&units.time));
+        }
+        if let Some(lag_time) = additional.lag_time {
+            let _ = write!(html, "<tr><td>Lag time</td><td>{} {}</td></tr>", lag_time, escape(&units.time));
+        }
+        if let Some(bioavailability) = additional.bioavailability {
+            let _ = write!(html, "<tr><td>Bioavailability</td><td>{}</td></tr>", bioavailability);
+        }
+    }
+    html.push_str("</table>");
+}
+
+fn parameter_section(html: &mut String, level: usize, results: &[PatientResult], summary: &PopulationSummary, units: &UnitsConfig) {
+    heading(html, level, "Population Parameters");
+    distribution_table(html, summary.parameter_distributions.iter()
+        .map(|(name, d)| (label(name, &units.parameter_unit(name)), &d.summary)));
+
+    html.push_str("<div class=\"figures\">");
+    for name in summary.parameter_distributions.keys() {
+        let values: Vec<f64> = results.iter().filter_map(|r| r.parameters.get(name).copied()).collect();
+        let label = label(name, &units.parameter_unit(name));
+        let plot = Plot::new(&format!("Distribution of {}", name), &label, "Count")
+            .bars(histogram(&values), "steelblue");
+        html.push_str(&plot.to_svg());
+    }
+    html.push_str("</div>");
+}
+
+fn endpoint_section(html: &mut String, level: usize, summary: &PopulationSummary, units: &UnitsConfig) {
+    heading(html, level, "Pharmacokinetic Endpoints");
+    html.push_str("<p>From the observed profiles.</p>");
+    distribution_table(html, summary.exposure_distributions.iter()
+        .map(|(name, d)| {
+            let unit = match name.as_str() {
+                "CMAX" => units.concentration(),
+                "AUC" => units.auc(),
+                _ => units.time.clone(),
+            };
+            (label(name, &unit), &d.summary)
+        }));
+}
+
+fn nca_section(html: &mut String, level: usize, results: &[PatientResult], config: &Config) -> PKResult<()> {
+    let nca_results = nca::analyze_population(results, config)?;
+    let summaries = nca::summarize(&nca_results);
+
+    heading(html, level, "Non-Compartmental Analysis");
+    let Some((_, first)) = nca_results.first() else {
+        html.push_str("<p>No profiles to analyze.</p>");
+        return Ok(());
+    };
+    let units = &config.units;
+    let rows = first.metrics().into_iter()
+        .filter_map(|(name, _)| summaries.get(name).map(|s| (label(name, &nca::metric_unit(name, units)), s)));
+    distribution_table(html, rows);
+    Ok(())
+}
+
+fn covariate_section(html: &mut String, level: usize, results: &[PatientResult], summary: &PopulationSummary) {
+    heading(html, level, "Covariates");
+    let covariates: [(&str, Covariate); 2] = [
+        ("Weight (kg)", |r| r.demographics.weight),
+        ("Age (years)", |r| r.demographics.age),
+    ];
+
+    let covariate_summaries: Vec<(String, MetricSummary)> = covariates.iter()
+        .map(|(label, covariate)| {
+            let values: Vec<f64> = results.iter().map(covariate).collect();
+            (label.to_string(), MetricSummary::from_values(&values))
+        })
+        .collect();
+    distribution_table(html, covariate_summaries.iter().map(|(label, s)| (label.clone(), s)));
+
+    html.push_str("<div class=\"figures\">");
+    for (label, covariate) in covariates {
+        for name in summary.parameter_distributions.keys() {
+            let points = results.iter()
+                .filter_map(|r| r.parameters.get(name).map(|&v| (covariate(r), v)))
+                .collect();
+            let plot = Plot::new(&format!("{} vs {}", name, label), label, name)
+                .points(points, "steelblue", 3.0);
+            html.push_str(&plot.to_svg());
+        }
+    }
+    html.push_str("</div>");
+}
+
+fn distribution_table<'a, I>(html: &mut String, rows: I)
+where
+    I: Iterator<Item = (String, &'a MetricSummary)>,
+{
+    html.push_str("<table><tr><th></th><th>Mean</th><th>SD</th><th>CV%</th><th>Geo. mean</th><th>Geo. CV%</th><th>Median</th><th>Min</th><th>Max</th></tr>");
+    let optional = |v: Option<f64>| v.map(|v| format!("{:.3}", v)).unwrap_or_else(|| "-".to_string());
+    for (name, s) in rows {
+        let _ = write!(
+            html,
+            "<tr><td>{}</td><td>{:.3}</td><td>{:.3}</td><td>{:.1}</td><td>{}</td><td>{}</td><td>{:.3}</td><td>{:.3}</td><td>{:.3}</td></tr>",
+            escape(&name), s.mean, s.sd, s.cv_percent, optional(s.geometric_mean),
+            optional(s.geometric_cv_percent), s.median, s.min, s.max,
+        );
+    }
+    html.push_str("</table>");
+}
+
+/// Outermost percentiles of the summary, e.g. 5 and 95.
+fn interval_percentiles(summary: &PopulationSummary) -> (f64, f64) {
+    let lower = summary.percentiles.first().copied().unwrap_or(5.0);
+    let upper = summary.percentiles.last().copied().unwrap_or(95.0);
+    (lower, upper)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testing::config_with;
+    use crate::simulation::{Demographics, Observation};
+    use std::collections::HashMap;
+
+    fn results() -> Vec<PatientResult> {
+        (1..=10)
+            .map(|i| PatientResult {
+                patient_id: i,
+                arm: None,
+                demographics: Demographics { weight: 60.0 + i as f64, age: 30.0 + i as f64 },
+                parameters: HashMap::from([("CL".to_string(), 1.0 + 0.1 * i as f64)]),
+                etas: HashMap::new(),
+                typical_parameters: HashMap::new(),
+                clamped: Vec::new(),
+                doses: Vec::new(),
+                observations: [0.0, 1.0, 2.0, 4.0].iter()
+                    .map(|&t: &f64| {
+                        let c = 10.0 * (-0.2 * t).exp() * (1.0 + 0.01 * i as f64);
+                        Observation {
+                            time: t, nominal_time: t, concentration: c, predicted_concentration: c, eps: Vec::new(), blq: false,
+                            censored: false,
+                        }
+                    })
+                    .collect(),
+                dropout_time: None,
+            })
+            .collect()
+    }
+
+    /// Deterministic CL and V sampled at 0, 1, 2 and 4 h.
+    const REPORT: &str = r#"{
+        "model": { "parameters": { "CL": { "omega": null }, "V": { "omega": null } } },
+        "simulation": { "time_points": [0.0, 1.0, 2.0, 4.0] }
+    }"#;
+
+    fn render(config: &Config) -> String {
+        let dir = std::env::temp_dir().join(format!("pk_report_test_{}_{}", std::process::id(), config.units.amount));
+        std::fs::create_dir_all(&dir).unwrap();
+        generate_report(&results(), config, &dir).unwrap();
+        let html = std::fs::read_to_string(dir.join("simulation_report.html")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        html
+    }
+
+    #[test]
+    fn test_report_is_self_contained_html() {
+        let html = render(&config_with(&[REPORT]));
+
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        // Spaghetti, two interval plots, one histogram and two covariate plots
+        assert_eq!(html.matches("<svg").count(), 6);
+        assert!(html.contains("Non-Compartmental Analysis"));
+        assert!(html.contains("CL (L/h)"));
+        assert!(!html.contains("src=\"http"));
+    }
+
+    #[test]
+    fn test_sections_and_units_from_config() {
+        let html = render(&config_with(&[REPORT, r#"{
+            "units": { "amount": "ug", "volume": "mL", "time": "min" },
+            "report": { "sections": ["parameters", "pk_endpoints"] }
+        }"#]));
+
+        assert!(html.contains("CL (mL/min)"));
+        assert!(html.contains("AUC (ug/mL*min)"));
+        assert!(!html.contains("Simulation Overview"));
+        assert!(!html.contains("Non-Compartmental Analysis"));
+        assert!(!html.contains("mg/L"));
+    }
+
+    #[test]
+    fn test_study_report_has_a_block_per_arm() {
+        let config = config_with(&[REPORT, r#"{
+            "study": { "arms": [
+                { "name": "low", "n_subjects": 5 },
+                { "name": "high", "n_subjects": 5, "dosing": { "route": "ivbolus", "amount": 400.0, "times": [0.0] } }
+            ] }
+        }"#]);
+        let results = crate::simulation::Simulator::new(config.clone(), Some(2)).unwrap().simulate_study().unwrap();
+        let dir = std::env::temp_dir().join(format!("pk_report_study_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        generate_report(&results, &config, &dir).unwrap();
+        let html = std::fs::read_to_string(dir.join("simulation_report.html")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(html.contains("<h2>Arm: low</h2>") && html.contains("<h2>Arm: high</h2>"));
+        assert_eq!(html.matches("<h3>Non-Compartmental Analysis</h3>").count(), 2);
+        // Each arm's dosing table shows its own dose
+        let high = &html[html.find("Arm: high").unwrap()..];
+        assert!(high.contains("<td>Dose</td><td>400 mg</td>"));
+        assert!(html[..html.find("Arm: high").unwrap()].contains("<td>Dose</td><td>100 mg</td>"));
+    }
+}
@@ -0,0 +1,270 @@
+use super::{
+    concentration_table, exposure_table, nca_table, parameter_table, patient_table, save_json_by_arm,
+    save_summaries, ParameterColumns,
+};
+use super::nonmem;
+use super::table::TableWriter;
+use crate::config::{Config, OutputCompression, OutputFormat};
+use crate::exposure;
+use crate::nca;
+use crate::simulation::{arm_config, PatientResult, PopulationSummary, Simulator, StreamingSummary};
+use crate::simulation::statistics::{MetricSummary, RunningDistribution};
+use crate::error::{PKError, PKResult};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::slice;
+use log::info;
+
+/// Streaming counterpart of [`save_results`](super::save_results),
+/// [`save_nca_results`](super::save_nca_results),
+/// [`save_exposure_results`](super::save_exposure_results) and
+/// [`save_nonmem_dataset`](super::save_nonmem_dataset): each subject's rows
+/// are written as soon as it is simulated and the population and NCA
+/// summaries are accumulated online, per arm for a study, so memory does not
+/// grow with the number of subjects.
+pub struct StreamingOutput {
+    config: Config,
+    output_dir: PathBuf,
+    individual_data: TableWriter,
+    concentrations: TableWriter,
+    parameters: TableWriter,
+    nca: TableWriter,
+    exposure: TableWriter,
+    nonmem_dataset: TableWriter,
+    /// Typical predictions of the NONMEM dataset, which draw nothing
+    predictor: Simulator,
+    /// Parameter columns, fixed by the first subject
+    parameter_columns: Option<ParameterColumns>,
+    /// ETA columns of the NONMEM dataset, fixed by the first subject
+    eta_names: Option<Vec<String>>,
+    extra_percentiles: Vec<f64>,
+    /// Arms seen so far, in order
+    arms: Vec<ArmOutput>,
+}
+
+/// Configuration and online summaries of one arm, or of the whole population
+/// without a study.
+struct ArmOutput {
+    arm: Option<String>,
+    config: Config,
+    summary: StreamingSummary,
+    nca_summary: BTreeMap<String, RunningDistribution>,
+}
+
+impl StreamingOutput {
+    /// Outputs under `output_dir`. A PTA analysis needs every subject at
+    /// once and is rejected.
+    pub fn new<P: AsRef<Path>>(config: &Config, output_dir: P) -> PKResult<Self> {
+        if config.pta.is_some() {
+            return Err(PKError::Validation(
+                "A PTA analysis needs all subjects at once and cannot be streamed".to_string()
+            ));
+        }
+        let output_dir = output_dir.as_ref().to_path_buf();
+        let writer = |name: &str| TableWriter::new(
+            &output_dir.join(name), config.output.format_for(name), config.output.compression,
+        );
+        let extra_percentiles = config.summary.as_ref()
+            .map(|s| s.percentiles.clone())
+            .unwrap_or_default();
+        
+        Ok(Self {
+            individual_data: writer("individual_data"),
+            concentrations: writer("concentrations"),
+            parameters: writer("parameters"),
+            nca: writer("nca"),
+            exposure: writer("exposure"),
+            nonmem_dataset: TableWriter::new(&output_dir.join("nonmem_dataset"), OutputFormat::Csv, OutputCompression::None)
+                .with_missing("."),
+            predictor: Simulator::new(config.clone(), None)?,
+            parameter_columns: None,
+            eta_names: None,
+            extra_percentiles,
+            arms: Vec::new(),
+            config: config.clone(),
+            output_dir,
+        })
+    }
+    
+    pub fn add(&mut self, result: &PatientResult) -> PKResult<()> {
+        let config = &self.config;
+        let results = slice::from_ref(result);
+        self.individual_data.write(patient_table(results, config))?;
+        self.concentrations.write(concentration_table(results, config))?;
+        let columns = self.parameter_columns
+            .get_or_insert_with(|| ParameterColumns::new(result, config));
+        self.parameters.write(parameter_table(results, columns, config))?;
+        
+        // Subjects come arm by arm
+        if self.arms.last().is_none_or(|arm| arm.arm != result.arm) {
+            let arm_config = arm_config(config, result)?;
+            self.arms.push(ArmOutput {
+                arm: result.arm.clone(),
+                config: arm_config,
+                summary: StreamingSummary::new(&self.extra_percentiles),
+                nca_summary: BTreeMap::new(),
+            });
+        }
+        let arm = self.arms.len() - 1;
+        let arm = &mut self.arms[arm];
+        arm.summary.add(result);
+        
+        let nca_results = nca::analyze_population(results, &arm.config)?;
+        for (_, nca_result) in &nca_results {
+            for (name, value) in nca_result.metrics() {
+                if let Some(value) = value {
+                    arm.nca_summary.entry(name.to_string()).or_default().push(value);
+                }
+            }
+        }
+        self.nca.write(nca_table(arm.arm.as_deref(), &nca_results, config))?;
+        let exposures = exposure::analyze_population(results, &arm.config)?;
+        self.exposure.write(exposure_table(arm.arm.as_deref(), &exposures, config))?;
+        
+        let eta_names = self.eta_names.get_or_insert_with(|| {
+            let mut names: Vec<String> = result.etas.keys().cloned().collect();
+            names.sort();
+            names
+        });
+        self.nonmem_dataset.write(nonmem::subject_rows(result, &self.predictor, eta_names)?)?;
+        Ok(())
+    }
+    
+    /// Close the tables and write the population summary, prediction
+    /// intervals and NCA summary. Returns the summary of each arm, a single
+    /// one without a study.
+    pub fn finish(self) -> PKResult<Vec<(Option<String>, PopulationSummary)>> {
+        self.individual_data.finish()?;
+        self.concentrations.finish()?;
+        self.parameters.finish()?;
+        self.nca.finish()?;
+        self.exposure.finish()?;
+        self.nonmem_dataset.finish()?;
+        
+        let mut summaries: Vec<(Option<String>, PopulationSummary)> = self.arms.iter()
+            .map(|arm| (arm.arm.clone(), arm.summary.finish()))
+            .collect();
+        if summaries.is_empty() {
+            summaries.push((None, StreamingSummary::new(&self.extra_percentiles).finish()));
+        }
+        save_summaries(&summaries, &self.config, &self.output_dir)?;
+        
+        let mut nca_summaries: Vec<(Option<&str>, BTreeMap<&str, MetricSummary>)> = self.arms.iter()
+            .map(|arm| {
+                let summary = arm.nca_summary.iter()
+                    .map(|(name, distribution)| (name.as_str(), distribution.summary(&[]).summary))
+                    .collect();
+                (arm.arm.as_deref(), summary)
+            })
+            .collect();
+        if nca_summaries.is_empty() {
+            nca_summaries.push((None, BTreeMap::new()));
+        }
+        save_json_by_arm(&nca_summaries, self.output_dir.join("nca_summary.json"))?;
+        
+        info!("All results saved to {:?}", self.output_dir);
+        Ok(summaries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testing::config_with;
+    use crate::output::{save_exposure_results, save_nca_results, save_nonmem_dataset, save_results};
+    use approx::assert_relative_eq;
+
+    /// Tables written the same in batch and in streaming
+    const FILES: [&str; 7] = [
+        "individual_data.csv", "concentrations.csv", "parameters.csv", "prediction_intervals.csv",
+        "nca.csv", "exposure.csv", "nonmem_dataset.csv",
+    ];
+
+    fn save_batch(results: &[PatientResult], simulator: &Simulator, dir: &Path) {
+        let config = simulator.config();
+        save_results(results, config, dir).unwrap();
+        save_nca_results(results, config, dir).unwrap();
+        save_exposure_results(results, config, dir).unwrap();
+        save_nonmem_dataset(results, simulator, dir.join("nonmem_dataset.csv")).unwrap();
+    }
+
+    const STREAM: &str = r#"{ "simulation": { "time_points": [0.5, 1.0, 4.0] } }"#;
+
+    #[test]
+    fn test_streaming_matches_batch_output() {
+        let base = std::env::temp_dir().join(format!("pk_stream_test_{}", std::process::id()));
+        let (batch_dir, stream_dir) = (base.join("batch"), base.join("stream"));
+        std::fs::create_dir_all(&batch_dir).unwrap();
+        std::fs::create_dir_all(&stream_dir).unwrap();
+
+        let results = Simulator::new(config_with(&[STREAM]), Some(11)).unwrap().simulate_population(20).unwrap();
+        save_batch(&results, &Simulator::new(config_with(&[STREAM]), None).unwrap(), &batch_dir);
+
+        let mut simulator = Simulator::new(config_with(&[STREAM]), Some(11)).unwrap();
+        let mut output = StreamingOutput::new(simulator.config(), &stream_dir).unwrap();
+        simulator.simulate_population_with(20, |result| output.add(&result)).unwrap();
+        output.finish().unwrap();
+
+        for file in FILES {
+            let batch = std::fs::read_to_string(batch_dir.join(file)).unwrap();
+            let streamed = std::fs::read_to_string(stream_dir.join(file)).unwrap();
+            assert_eq!(batch, streamed, "{}", file);
+        }
+        let nca_summary = |dir: &Path| -> serde_json::Value {
+            serde_json::from_str(&std::fs::read_to_string(dir.join("nca_summary.json")).unwrap()).unwrap()
+        };
+        let (batch, streamed) = (nca_summary(&batch_dir), nca_summary(&stream_dir));
+        assert_eq!(batch["CMAX"]["n"], streamed["CMAX"]["n"]);
+        assert_relative_eq!(
+            batch["AUC_LAST"]["median"].as_f64().unwrap(), streamed["AUC_LAST"]["median"].as_f64().unwrap(),
+            max_relative = 1e-12
+        );
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_streaming_study_summarizes_each_arm() {
+        let base = std::env::temp_dir().join(format!("pk_stream_study_test_{}", std::process::id()));
+        let (batch_dir, stream_dir) = (base.join("batch"), base.join("stream"));
+        std::fs::create_dir_all(&batch_dir).unwrap();
+        std::fs::create_dir_all(&stream_dir).unwrap();
+        let mut config = config_with(&[STREAM]);
+        config.study = serde_json::from_str(r#"{ "arms": [
+            { "name": "placebo", "n_subjects": 5 },
+            { "name": "active", "n_subjects": 5, "time_points": [1.0, 8.0] }
+        ] }"#).unwrap();
+
+        let results = Simulator::new(config.clone(), Some(11)).unwrap().simulate_study().unwrap();
+        save_batch(&results, &Simulator::new(config.clone(), None).unwrap(), &batch_dir);
+
+        let mut simulator = Simulator::new(config.clone(), Some(11)).unwrap();
+        let mut output = StreamingOutput::new(simulator.config(), &stream_dir).unwrap();
+        simulator.simulate_study_with(|result| output.add(&result)).unwrap();
+        let summaries = output.finish().unwrap();
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[1].0.as_deref(), Some("active"));
+        assert_eq!(summaries[1].1.n_patients, 5);
+
+        for file in FILES {
+            let batch = std::fs::read_to_string(batch_dir.join(file)).unwrap();
+            let streamed = std::fs::read_to_string(stream_dir.join(file)).unwrap();
+            assert_eq!(batch, streamed, "{}", file);
+            if file != "nonmem_dataset.csv" {
+                assert!(batch.starts_with("PATIENT_ID,ARM,") || batch.starts_with("ARM,"), "{}", file);
+            }
+        }
+        let summary: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(batch_dir.join("population_summary.json")).unwrap()
+        ).unwrap();
+        assert_eq!(summary["active"]["concentration_bands"].as_array().unwrap().len(), 2);
+        assert_eq!(summary["placebo"]["concentration_bands"].as_array().unwrap().len(), 3);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_streaming_rejects_pta() {
+        let mut config = config_with(&[STREAM]);
+        config.pta = serde_json::from_str(r#"{ "targets": [{ "index": "auc_mic", "value": 100.0 }], "mics": [1.0] }"#).unwrap();
+        assert!(StreamingOutput::new(&config, std::env::temp_dir()).is_err());
+    }
+}
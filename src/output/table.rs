@@ -0,0 +1,426 @@
+use crate::config::{OutputCompression, OutputFormat};
+use crate::error::{PKError, PKResult};
+use crate::units::label;
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, UInt64Array};
+use arrow_schema::{DataType, Field, Schema};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Typed values of one column. Missing values of the optional columns are
+/// written as an empty CSV cell and as null in the columnar formats.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Values {
+    UInt(Vec<u64>),
+    Float(Vec<f64>),
+    Text(Vec<String>),
+    OptionalUInt(Vec<Option<u64>>),
+    OptionalFloat(Vec<Option<f64>>),
+}
+
+impl Values {
+    fn len(&self) -> usize {
+        match self {
+            Values::UInt(v) => v.len(),
+            Values::Float(v) => v.len(),
+            Values::Text(v) => v.len(),
+            Values::OptionalUInt(v) => v.len(),
+            Values::OptionalFloat(v) => v.len(),
+        }
+    }
+
+    fn format(&self, row: usize, missing: &str) -> String {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| missing.to_string());
+        match self {
+            Values::UInt(v) => v[row].to_string(),
+            Values::Float(v) => v[row].to_string(),
+            Values::Text(v) => v[row].clone(),
+            Values::OptionalUInt(v) => optional(v[row].map(|v| v.to_string())),
+            Values::OptionalFloat(v) => optional(v[row].map(|v| v.to_string())),
+        }
+    }
+
+    /// Move the values of `other`, of the same type, to the end.
+    fn extend(&mut self, other: Values) -> Result<(), Values> {
+        match (self, other) {
+            (Values::UInt(a), Values::UInt(b)) => a.extend(b),
+            (Values::Float(a), Values::Float(b)) => a.extend(b),
+            (Values::Text(a), Values::Text(b)) => a.extend(b),
+            (Values::OptionalUInt(a), Values::OptionalUInt(b)) => a.extend(b),
+            (Values::OptionalFloat(a), Values::OptionalFloat(b)) => a.extend(b),
+            (_, other) => return Err(other),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Column {
+    name: String,
+    unit: String,
+    values: Values,
+}
+
+/// A column-oriented output table that can be written as CSV, Parquet or
+/// Arrow IPC.
+///
+/// CSV headers carry the unit in parentheses, e.g. `CMAX (mg/L)`; the
+/// columnar formats keep the bare name and store the unit in the field
+/// metadata under `unit`.
+#[derive(Debug, Clone, Default)]
+pub struct Table {
+    columns: Vec<Column>,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, name: &str, unit: &str, values: Values) {
+        debug_assert!(self.columns.first().is_none_or(|c| c.values.len() == values.len()));
+        self.columns.push(Column { name: name.to_string(), unit: unit.to_string(), values });
+    }
+
+    pub fn n_rows(&self) -> usize {
+        self.columns.first().map_or(0, |c| c.values.len())
+    }
+
+    /// The rows, leaving the columns without values.
+    fn take_rows(&mut self) -> Table {
+        let columns = self.columns.iter_mut()
+            .map(|column| {
+                let empty = match &column.values {
+                    Values::UInt(_) => Values::UInt(Vec::new()),
+                    Values::Float(_) => Values::Float(Vec::new()),
+                    Values::Text(_) => Values::Text(Vec::new()),
+                    Values::OptionalUInt(_) => Values::OptionalUInt(Vec::new()),
+                    Values::OptionalFloat(_) => Values::OptionalFloat(Vec::new()),
+                };
+                Column { name: column.name.clone(), unit: column.unit.clone(), values: std::mem::replace(&mut column.values, empty) }
+            })
+            .collect();
+        Table { columns }
+    }
+
+    /// Append the rows of `other`, which must have the same columns with
+    /// the same types; the table is left unchanged otherwise. A table
+    /// without columns has no rows to append.
+    pub fn append(&mut self, other: Table) -> PKResult<()> {
+        if other.columns.is_empty() {
+            return Ok(());
+        }
+        if self.columns.is_empty() {
+            *self = other;
+            return Ok(());
+        }
+        let names = |table: &Table| table.columns.iter().map(|c| c.name.as_str()).collect::<Vec<_>>().join(", ");
+        if self.columns.len() != other.columns.len()
+            || self.columns.iter().zip(&other.columns).any(|(a, b)| a.name != b.name) {
+            return Err(PKError::Validation(format!(
+                "Cannot append rows with columns {} to a table with columns {}", names(&other), names(self)
+            )));
+        }
+        if let Some(column) = self.columns.iter().zip(&other.columns)
+            .find(|(a, b)| std::mem::discriminant(&a.values) != std::mem::discriminant(&b.values))
+            .map(|(a, _)| a.name.clone()) {
+            return Err(PKError::Validation(format!("Column {} changed type", column)));
+        }
+        for (column, other) in self.columns.iter_mut().zip(other.columns) {
+            // Types were checked above
+            let _ = column.values.extend(other.values);
+        }
+        Ok(())
+    }
+
+    /// Write the table to `path` with the extension of `format` and return
+    /// the file written.
+    pub fn write(self, path: &Path, format: OutputFormat, compression: OutputCompression) -> PKResult<PathBuf> {
+        let mut writer = TableWriter::new(path, format, compression);
+        writer.write(self)?;
+        writer.finish()
+    }
+
+    fn schema(&self) -> Arc<Schema> {
+        let fields: Vec<Field> = self.columns.iter()
+            .map(|column| {
+                let (data_type, nullable) = match &column.values {
+                    Values::UInt(_) => (DataType::UInt64, false),
+                    Values::Float(_) => (DataType::Float64, false),
+                    Values::Text(_) => (DataType::Utf8, false),
+                    Values::OptionalUInt(_) => (DataType::UInt64, true),
+                    Values::OptionalFloat(_) => (DataType::Float64, true),
+                };
+                let field = Field::new(&column.name, data_type, nullable);
+                if column.unit.is_empty() {
+                    field
+                } else {
+                    field.with_metadata(HashMap::from([("unit".to_string(), column.unit.clone())]))
+                }
+            })
+            .collect();
+        Arc::new(Schema::new(fields))
+    }
+
+    fn record_batch(self) -> PKResult<RecordBatch> {
+        let schema = self.schema();
+        let arrays: Vec<ArrayRef> = self.columns.into_iter()
+            .map(|column| -> ArrayRef {
+                match column.values {
+                    Values::UInt(v) => Arc::new(UInt64Array::from(v)),
+                    Values::Float(v) => Arc::new(Float64Array::from(v)),
+                    Values::Text(v) => Arc::new(StringArray::from(v)),
+                    Values::OptionalUInt(v) => Arc::new(UInt64Array::from(v)),
+                    Values::OptionalFloat(v) => Arc::new(Float64Array::from(v)),
+                }
+            })
+            .collect();
+        Ok(RecordBatch::try_new(schema, arrays)?)
+    }
+}
+
+/// Rows buffered before they are written as one record batch.
+const BATCH_ROWS: usize = 65_536;
+
+enum Sink {
+    Csv(csv::Writer<File>),
+    Parquet(parquet::arrow::ArrowWriter<File>),
+    Arrow(arrow_ipc::writer::FileWriter<File>),
+}
+
+/// Writes a table incrementally: rows are appended with [`TableWriter::write`]
+/// and flushed in batches, so the whole table never has to be in memory. The
+/// file is created on the first flush, from the columns of the first rows.
+pub struct TableWriter {
+    path: PathBuf,
+    format: OutputFormat,
+    compression: OutputCompression,
+    /// CSV cell of a missing value
+    missing: String,
+    pending: Option<Table>,
+    sink: Option<Sink>,
+}
+
+impl TableWriter {
+    /// Writer for `path` with the extension of `format`.
+    pub fn new(path: &Path, format: OutputFormat, compression: OutputCompression) -> Self {
+        Self {
+            path: path.with_extension(format.extension()),
+            format,
+            compression,
+            missing: String::new(),
+            pending: None,
+            sink: None,
+        }
+    }
+
+    /// Write missing values to CSV as `missing` instead of an empty cell.
+    pub fn with_missing(mut self, missing: &str) -> Self {
+        self.missing = missing.to_string();
+        self
+    }
+
+    /// Append rows, which must have the columns of the rows written before.
+    pub fn write(&mut self, rows: Table) -> PKResult<()> {
+        match &mut self.pending {
+            Some(pending) => pending.append(rows)?,
+            None => self.pending = Some(rows),
+        }
+        if self.pending.as_ref().is_some_and(|p| p.n_rows() >= BATCH_ROWS) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flush the remaining rows, close the file and return its path. Nothing
+    /// is written if no rows were ever given.
+    pub fn finish(mut self) -> PKResult<PathBuf> {
+        self.flush()?;
+        match self.sink.take() {
+            Some(Sink::Csv(mut writer)) => writer.flush()?,
+            Some(Sink::Parquet(writer)) => {
+                writer.close()?;
+            },
+            Some(Sink::Arrow(mut writer)) => writer.finish()?,
+            None => {},
+        }
+        Ok(self.path)
+    }
+
+    fn flush(&mut self) -> PKResult<()> {
+        let Some(mut columns) = self.pending.take() else {
+            return Ok(());
+        };
+        let table = columns.take_rows();
+        // Later rows are checked against the columns written
+        self.pending = Some(columns);
+        if self.sink.is_none() {
+            self.sink = Some(self.open(&table)?);
+        } else if table.n_rows() == 0 {
+            return Ok(());
+        }
+        match self.sink.as_mut() {
+            Some(Sink::Csv(writer)) => {
+                for row in 0..table.n_rows() {
+                    writer.write_record(table.columns.iter().map(|c| c.values.format(row, &self.missing)))?;
+                }
+            },
+            Some(Sink::Parquet(writer)) => writer.write(&table.record_batch()?)?,
+            Some(Sink::Arrow(writer)) => writer.write(&table.record_batch()?)?,
+            None => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn open(&self, table: &Table) -> PKResult<Sink> {
+        let file = File::create(&self.path)?;
+        let schema = table.schema();
+        Ok(match self.format {
+            OutputFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(file);
+                writer.write_record(table.columns.iter().map(|c| label(&c.name, &c.unit)))?;
+                Sink::Csv(writer)
+            },
+            OutputFormat::Parquet => {
+                use parquet::basic::{Compression, ZstdLevel};
+                use parquet::file::properties::WriterProperties;
+
+                let compression = match self.compression {
+                    OutputCompression::None => Compression::UNCOMPRESSED,
+                    OutputCompression::Snappy => Compression::SNAPPY,
+                    OutputCompression::Zstd => Compression::ZSTD(ZstdLevel::default()),
+                };
+                let properties = WriterProperties::builder().set_compression(compression).build();
+                Sink::Parquet(parquet::arrow::ArrowWriter::try_new(file, schema, Some(properties))?)
+            },
+            OutputFormat::Arrow => {
+                use arrow_ipc::writer::{FileWriter, IpcWriteOptions};
+                use arrow_ipc::CompressionType;
+
+                // Snappy is rejected for Arrow output by `OutputConfig::validate`
+                let compression = match self.compression {
+                    OutputCompression::Zstd => Some(CompressionType::ZSTD),
+                    OutputCompression::None | OutputCompression::Snappy => None,
+                };
+                let options = IpcWriteOptions::default().try_with_compression(compression)?;
+                Sink::Arrow(FileWriter::try_new_with_options(file, &schema, options)?)
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::Array;
+
+    fn table() -> Table {
+        let mut table = Table::new();
+        table.push("PATIENT_ID", "", Values::UInt(vec![1, 1, 2]));
+        table.push("TIME", "h", Values::Float(vec![0.0, 1.5, 0.0]));
+        table.push("VARIABLE", "", Values::Text(vec!["A".into(), "B".into(), "A".into()]));
+        table
+    }
+
+    fn read_back(path: &Path, format: OutputFormat) -> RecordBatch {
+        let file = File::open(path).unwrap();
+        let mut batches: Vec<RecordBatch> = match format {
+            OutputFormat::Parquet => parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(file)
+                .unwrap().build().unwrap().map(Result::unwrap).collect(),
+            OutputFormat::Arrow => arrow_ipc::reader::FileReader::try_new(file, None)
+                .unwrap().map(Result::unwrap).collect(),
+            OutputFormat::Csv => unreachable!(),
+        };
+        assert_eq!(batches.len(), 1);
+        batches.remove(0)
+    }
+
+    #[test]
+    fn test_columnar_round_trip() {
+        let dir = std::env::temp_dir().join(format!("pk_table_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for (format, compression) in [
+            (OutputFormat::Parquet, OutputCompression::Zstd),
+            (OutputFormat::Parquet, OutputCompression::Snappy),
+            (OutputFormat::Arrow, OutputCompression::Zstd),
+            (OutputFormat::Arrow, OutputCompression::None),
+        ] {
+            let path = table().write(&dir.join("table"), format, compression).unwrap();
+            let batch = read_back(&path, format);
+            assert_eq!(batch.num_rows(), 3);
+
+            let schema = batch.schema();
+            assert_eq!(schema.field(0).data_type(), &DataType::UInt64);
+            assert_eq!(schema.field(1).name(), "TIME");
+            assert_eq!(schema.field(1).metadata()["unit"], "h");
+            let time = batch.column(1).as_any().downcast_ref::<Float64Array>().unwrap();
+            assert_eq!(time.value(1), 1.5);
+            let variable = batch.column(2).as_any().downcast_ref::<StringArray>().unwrap();
+            assert_eq!(variable.value(1), "B");
+            assert_eq!(variable.null_count(), 0);
+        }
+
+        let path = table().write(&dir.join("table"), OutputFormat::Csv, OutputCompression::Zstd).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text, "PATIENT_ID,TIME (h),VARIABLE\n1,0,A\n1,1.5,B\n2,0,A\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_append_checks_columns() {
+        let mut rows = table();
+        assert!(rows.append(table()).is_ok());
+        assert_eq!(rows.n_rows(), 6);
+
+        let mut renamed = Table::new();
+        renamed.push("PATIENT_ID", "", Values::UInt(vec![3]));
+        assert!(rows.append(renamed).is_err());
+        let mut retyped = table();
+        retyped.columns[1].values = Values::OptionalFloat(vec![Some(0.0), None, Some(2.0)]);
+        assert!(rows.append(retyped).is_err());
+        assert_eq!(rows.n_rows(), 6);
+    }
+
+    #[test]
+    fn test_missing_values() {
+        let dir = std::env::temp_dir().join(format!("pk_table_missing_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut rows = Table::new();
+        rows.push("ID", "", Values::OptionalUInt(vec![Some(1), None]));
+        rows.push("CL", "L/h", Values::OptionalFloat(vec![None, Some(2.5)]));
+
+        let path = rows.clone().write(&dir.join("missing"), OutputFormat::Parquet, OutputCompression::Zstd).unwrap();
+        let batch = read_back(&path, OutputFormat::Parquet);
+        assert!(batch.schema().field(1).is_nullable());
+        assert_eq!(batch.column(0).null_count(), 1);
+        assert!(batch.column(1).is_null(0));
+
+        let mut writer = TableWriter::new(&dir.join("missing"), OutputFormat::Csv, OutputCompression::None).with_missing(".");
+        writer.write(rows).unwrap();
+        let text = std::fs::read_to_string(writer.finish().unwrap()).unwrap();
+        assert_eq!(text, "ID,CL (L/h)\n1,.\n.,2.5\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_format_overrides() {
+        let mut output = crate::config::OutputConfig::default();
+        output.set_format("parquet").unwrap();
+        output.set_format("concentrations=arrow").unwrap();
+        assert_eq!(output.format_for("parameters"), OutputFormat::Parquet);
+        assert_eq!(output.format_for("concentrations"), OutputFormat::Arrow);
+        assert!(output.validate().is_ok());
+
+        output.compression = OutputCompression::Snappy;
+        assert!(output.validate().is_err());
+        assert!(output.set_format("xlsx").is_err());
+        output.set_format("nca=csv").unwrap();
+        assert_eq!(output.format_for("nca"), OutputFormat::Csv);
+        output.set_format("nonmem_dataset=parquet").unwrap();
+        assert!(output.validate().is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use crate::config::{Config, ExposureConfig, PkPdIndex, PtaTarget};
+use crate::exposure::{exposure_intervals, ExposureProfile};
+use crate::models::ModelParameters;
+use crate::simulation::PatientResult;
+use crate::error::{PKError, PKResult};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Distribution of MICs over isolates, e.g. from EUCAST, with the relative
+/// frequency of each MIC.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MicDistribution {
+    pub mics: Vec<f64>,
+    /// Frequencies normalized to sum to one
+    pub frequencies: Vec<f64>,
+}
+
+impl MicDistribution {
+    /// Read a CSV file with a header row and the columns `MIC` and `COUNT`
+    /// (or `FREQUENCY`).
+    pub fn from_file<P: AsRef<Path>>(path: P) -> PKResult<Self> {
+        let reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .comment(Some(b'#'))
+            .from_path(path)?;
+        Self::from_reader(reader)
+    }
+
+    pub fn from_reader<R: std::io::Read>(mut reader: csv::Reader<R>) -> PKResult<Self> {
+        let headers: Vec<String> = reader.headers()?.iter().map(|h| h.to_uppercase()).collect();
+        let column = |names: &[&str]| headers.iter().position(|h| names.contains(&h.as_str()));
+        let (Some(mic_col), Some(count_col)) = (column(&["MIC"]), column(&["COUNT", "FREQUENCY"])) else {
+            return Err(PKError::Validation(
+                "MIC distribution needs a MIC and a COUNT or FREQUENCY column".to_string()
+            ));
+        };
+
+        let mut mics = Vec::new();
+        let mut counts = Vec::new();
+        for (row, record) in reader.records().enumerate() {
+            let record = record?;
+            let field = |col: usize| -> PKResult<f64> {
+                let text = record.get(col).unwrap_or_default();
+                text.parse::<f64>().map_err(|_| PKError::Validation(
+                    format!("Invalid number '{}' on line {} of the MIC distribution", text, row + 2)
+                ))
+            };
+            let (mic, count) = (field(mic_col)?, field(count_col)?);
+            if mic <= 0.0 || count < 0.0 {
+                return Err(PKError::Validation(format!(
+                    "MICs must be positive and counts not negative (line {} of the MIC distribution)", row + 2
+                )));
+            }
+            mics.push(mic);
+            counts.push(count);
+        }
+
+        let total: f64 = counts.iter().sum();
+        if total <= 0.0 {
+            return Err(PKError::Validation("The MIC distribution is empty".to_string()));
+        }
+        Ok(Self { mics, frequencies: counts.iter().map(|c| c / total).collect() })
+    }
+}
+
+/// Fraction of subjects attaining one target at one MIC.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PtaPoint {
+    pub target: PtaTarget,
+    pub mic: f64,
+    pub probability: f64,
+}
+
+/// Cumulative fraction of response: the PTA of one target averaged over the
+/// MIC distribution.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CumulativeFraction {
+    pub target: PtaTarget,
+    pub cfr: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PtaResult {
+    pub n_subjects: usize,
+    pub start: f64,
+    pub end: f64,
+    pub points: Vec<PtaPoint>,
+    pub cumulative_fractions: Vec<CumulativeFraction>,
+}
+
+/// Value of a PK/PD index at a MIC from a subject's unbound exposure.
+struct SubjectIndices {
+    auc_24: f64,
+    cmax: f64,
+    /// Fraction of the window above each MIC, in the order of the MICs
+    ft_above: Vec<f64>,
+}
+
+impl SubjectIndices {
+    fn value(&self, index: PkPdIndex, mic_index: usize, mic: f64) -> f64 {
+        match index {
+            PkPdIndex::FtAboveMic => self.ft_above[mic_index],
+            PkPdIndex::AucMic => self.auc_24 / mic,
+            PkPdIndex::CmaxMic => self.cmax / mic,
+        }
+    }
+}
+
+/// Probability of target attainment of the simulated subjects for every
+/// target at the configured MICs and those of `distribution`, with the
+/// cumulative fraction of response over `distribution` if given. Indices
+/// are computed from the model profiles of the doses each subject received
+/// over the evaluation window, with the AUC scaled to 24 hours.
+pub fn analyze(
+    results: &[PatientResult],
+    config: &Config,
+    distribution: Option<&MicDistribution>,
+) -> PKResult<PtaResult> {
+    let Some(settings) = &config.pta else {
+        return Err(PKError::Validation("PTA needs a pta section in the configuration".to_string()));
+    };
+    let (start, end) = settings.interval
+        .unwrap_or_else(|| exposure_intervals(config, &ExposureConfig::default())[0]);
+    // Unbound concentrations in the output unit, from the model's amount/volume
+    let factor = config.units.concentration_factor()? * settings.unbound_fraction;
+    let day = 24.0 * crate::units::convert("h", &config.units.time)?;
+
+    let mut mics: Vec<f64> = settings.mics.iter()
+        .chain(distribution.map(|d| d.mics.as_slice()).unwrap_or_default())
+        .copied()
+        .collect();
+    mics.sort_by(|a, b| a.total_cmp(b));
+    mics.dedup();
+
+    let subjects = results.iter()
+        .map(|result| {
+            let params = ModelParameters::from_individual(&config.model, &result.parameters)?;
+            let mut profile = ExposureProfile::new(params, &result.doses);
+            Ok(SubjectIndices {
+                auc_24: profile.auc(start, end)? * factor * day / (end - start),
+                cmax: profile.peak(start, end)?.0 * factor,
+                ft_above: mics.iter()
+                    .map(|mic| Ok(profile.time_above(mic / factor, start, end)? / (end - start)))
+                    .collect::<PKResult<Vec<f64>>>()?,
+            })
+        })
+        .collect::<PKResult<Vec<_>>>()?;
+    let n = subjects.len().max(1) as f64;
+
+    let mut points = Vec::new();
+    for target in &settings.targets {
+        for (i, &mic) in mics.iter().enumerate() {
+            let hits = subjects.iter().filter(|s| s.value(target.index, i, mic) >= target.value).count();
+            points.push(PtaPoint { target: *target, mic, probability: hits as f64 / n });
+        }
+    }
+
+    let cumulative_fractions = distribution
+        .map(|distribution| {
+            settings.targets.iter()
+                .map(|target| {
+                    let pta = |mic: f64| points.iter()
+                        .find(|p| p.target == *target && p.mic == mic)
+                        .map_or(0.0, |p| p.probability);
+                    CumulativeFraction {
+                        target: *target,
+                        cfr: distribution.mics.iter().zip(&distribution.frequencies)
+                            .map(|(&mic, frequency)| frequency * pta(mic))
+                            .sum(),
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(PtaResult { n_subjects: results.len(), start, end, points, cumulative_fractions })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testing::config_with;
+    use crate::simulation::Simulator;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_pta_decreases_with_mic_and_cfr_weights_it() {
+        let config = config_with(&[r#"{
+            "model": { "parameters": { "V": { "theta": 20.0, "omega": null } } },
+            "dosing": { "amount": 500.0, "times": [0.0, 12.0] },
+            "simulation": { "time_points": [24.0] },
+            "pta": {
+                "mics": [0.5, 2.0, 8.0],
+                "targets": [{ "index": "auc_mic", "value": 100.0 }, { "index": "ft_above_mic", "value": 0.5 }],
+                "unbound_fraction": 0.5
+            }
+        }"#]);
+        let results = Simulator::new(config.clone(), Some(2)).unwrap().simulate_population(100).unwrap();
+
+        let csv = "MIC,COUNT\n0.5,30\n1,50\n2,20\n";
+        let distribution = MicDistribution::from_reader(csv::Reader::from_reader(csv.as_bytes())).unwrap();
+        assert_eq!(distribution.frequencies, [0.3, 0.5, 0.2]);
+
+        let result = analyze(&results, &config, Some(&distribution)).unwrap();
+        assert_eq!((result.start, result.end), (0.0, 24.0));
+        // The distribution's MIC of 1 is evaluated as well
+        assert_eq!(result.points.len(), 2 * 4);
+
+        // The typical unbound AUC over the window is about 200, part of the
+        // second dose falling after it: borderline at MIC 2
+        let auc = |mic: f64| result.points.iter()
+            .find(|p| p.target.index == PkPdIndex::AucMic && p.mic == mic)
+            .unwrap()
+            .probability;
+        assert!(auc(0.5) == 1.0 && auc(1.0) > 0.9 && auc(8.0) == 0.0);
+        assert!(auc(2.0) > 0.2 && auc(2.0) < 0.8);
+        let pta: Vec<f64> = result.points.iter().map(|p| p.probability).collect();
+        assert!(pta[..4].windows(2).all(|w| w[0] >= w[1]));
+
+        let cfr = &result.cumulative_fractions[0];
+        assert_relative_eq!(cfr.cfr, 0.3 * auc(0.5) + 0.5 * auc(1.0) + 0.2 * auc(2.0), epsilon = 1e-12);
+    }
+}
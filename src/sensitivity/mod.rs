@@ -0,0 +1,458 @@
+use crate::config::{Config, SensitivityConfig, SensitivityMethod};
+use crate::dosing::DosingRegimen;
+use crate::exposure::{average_steady_state_concentration, exposure_intervals, ExposureProfile};
+use crate::models::{DoseEvent, ModelParameters};
+use crate::nca::NcaDosing;
+use crate::simulation::{apply_covariate_effects, Demographics};
+use crate::solver::EventSolver;
+use crate::error::PKResult;
+use log::info;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Relative step of the finite-difference derivatives.
+const STEP: f64 = 1e-4;
+
+/// Input varied in the analysis: a THETA, `WT` or `AGE`, with its nominal
+/// value and the range of the global methods.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Factor {
+    pub name: String,
+    pub nominal: f64,
+    pub lower: f64,
+    pub upper: f64,
+}
+
+/// Model output whose sensitivity is analyzed: the concentration at a time
+/// point, or an exposure metric over an interval (or at steady state).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    /// `CONCENTRATION`, `AUC`, `CMAX`, `TMAX`, `TIME_ABOVE` or `CAVG_SS`
+    pub metric: String,
+    pub time: Option<f64>,
+    pub interval: Option<(f64, f64)>,
+    /// Value at the nominal factors
+    pub nominal: f64,
+}
+
+/// Derivative of a response at the nominal factors and the normalized
+/// sensitivity `∂ln y / ∂ln x`, undefined where the response is zero.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalSensitivity {
+    pub derivative: f64,
+    pub normalized: Option<f64>,
+}
+
+/// Statistics of the elementary effects, per unit of the factor's range.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MorrisSensitivity {
+    pub mu: f64,
+    pub mu_star: f64,
+    pub sigma: f64,
+}
+
+/// Sobol indices, undefined where the response does not vary.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SobolSensitivity {
+    pub first_order: Option<f64>,
+    pub total: Option<f64>,
+}
+
+/// Indices of one response for each factor, in the order of the factors,
+/// for the methods that were run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseSensitivity {
+    pub response: Response,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub local: Vec<LocalSensitivity>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub morris: Vec<MorrisSensitivity>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sobol: Vec<SobolSensitivity>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SensitivityResult {
+    pub factors: Vec<Factor>,
+    pub responses: Vec<ResponseSensitivity>,
+}
+
+/// Responses of the typical subject for a set of factor values.
+struct Evaluator<'a> {
+    config: &'a Config,
+    factors: Vec<Factor>,
+    doses: Vec<DoseEvent>,
+    intervals: Vec<(f64, f64)>,
+    threshold: Option<f64>,
+    tau: Option<f64>,
+    /// Model concentrations (amount/volume) to output concentration units
+    concentration_factor: f64,
+}
+
+impl<'a> Evaluator<'a> {
+    fn new(config: &'a Config, settings: &SensitivityConfig) -> PKResult<Self> {
+        let exposure = config.exposure.clone().unwrap_or_default();
+        Ok(Self {
+            config,
+            factors: factors(config, settings),
+            doses: DosingRegimen::from_config(&config.dosing)?.events,
+            intervals: exposure_intervals(config, &exposure),
+            threshold: exposure.threshold,
+            tau: NcaDosing::from_config(&config.dosing, exposure.tau).tau,
+            concentration_factor: config.units.concentration_factor()?,
+        })
+    }
+
+    /// The responses, in the order of [`Evaluator::evaluate`], with their
+    /// values at the nominal factors.
+    fn responses(&self) -> PKResult<Vec<Response>> {
+        let nominal: Vec<f64> = self.factors.iter().map(|f| f.nominal).collect();
+        let values = self.evaluate(&nominal)?;
+
+        let mut responses: Vec<Response> = self.config.simulation.time_points.iter()
+            .map(|&time| Response { metric: "CONCENTRATION".to_string(), time: Some(time), interval: None, nominal: 0.0 })
+            .collect();
+        for &interval in &self.intervals {
+            let mut metrics = vec!["AUC", "CMAX", "TMAX"];
+            if self.threshold.is_some() {
+                metrics.push("TIME_ABOVE");
+            }
+            responses.extend(metrics.into_iter().map(|metric| Response {
+                metric: metric.to_string(), time: None, interval: Some(interval), nominal: 0.0,
+            }));
+        }
+        if self.tau.is_some() {
+            responses.push(Response { metric: "CAVG_SS".to_string(), time: None, interval: None, nominal: 0.0 });
+        }
+        for (response, value) in responses.iter_mut().zip(values) {
+            response.nominal = value;
+        }
+        Ok(responses)
+    }
+
+    /// Concentrations at the time points and exposure metrics of a typical
+    /// subject with the configured THETAs and mean demographics, except for
+    /// the factors set to `values`. The structural parameters come from the
+    /// model implementation of the configured compartments.
+    fn evaluate(&self, values: &[f64]) -> PKResult<Vec<f64>> {
+        let demographics = &self.config.population.demographics;
+        let mut demographics = Demographics { weight: demographics.weight_mean, age: demographics.age_mean };
+        let mut thetas: HashMap<String, f64> = self.config.model.parameters.iter()
+            .map(|(name, parameter)| (name.clone(), parameter.theta))
+            .collect();
+        for (factor, &value) in self.factors.iter().zip(values) {
+            match factor.name.as_str() {
+                "WT" => demographics.weight = value,
+                "AGE" => demographics.age = value,
+                name => { thetas.insert(name.to_string(), value); },
+            }
+        }
+        let typical: HashMap<String, f64> = thetas.iter()
+            .map(|(name, &theta)| (name.clone(), apply_covariate_effects(self.config, theta, name, &demographics)))
+            .collect();
+        let params = ModelParameters::from_individual(&self.config.model, &typical)?;
+
+        let factor = self.concentration_factor;
+        let mut responses: Vec<f64> = EventSolver::new(params.clone())
+            .solve(&self.doses, &self.config.simulation.time_points)?
+            .into_iter()
+            .map(|c| c * factor)
+            .collect();
+        let mut profile = ExposureProfile::new(params.clone(), &self.doses);
+        for &(start, end) in &self.intervals {
+            let (cmax, tmax) = profile.peak(start, end)?;
+            responses.extend([profile.auc(start, end)? * factor, cmax * factor, tmax]);
+            if let Some(threshold) = self.threshold {
+                responses.push(profile.time_above(threshold / factor, start, end)?);
+            }
+        }
+        if let Some(tau) = self.tau {
+            responses.push(average_steady_state_concentration(&params, self.config.dosing.amount, tau) * factor);
+        }
+        Ok(responses)
+    }
+
+    /// Values of the factors at a point of the unit hypercube.
+    fn scale(&self, unit: &[f64]) -> Vec<f64> {
+        self.factors.iter().zip(unit).map(|(f, u)| f.lower + u * (f.upper - f.lower)).collect()
+    }
+}
+
+/// The configured factors, or all THETAs and the covariates with configured
+/// effects, with their ranges.
+fn factors(config: &Config, settings: &SensitivityConfig) -> Vec<Factor> {
+    let names: Vec<String> = if settings.factors.is_empty() {
+        let mut names: Vec<String> = config.model.parameters.keys().cloned().collect();
+        names.sort();
+        let covariates = config.population.covariates.as_ref();
+        for covariate in SensitivityConfig::COVARIATES {
+            let suffix = format!("_{}", covariate);
+            if covariates.is_some_and(|c| c.keys().any(|key| key.ends_with(&suffix))) {
+                names.push(covariate.to_string());
+            }
+        }
+        names
+    } else {
+        settings.factors.clone()
+    };
+
+    let demographics = &config.population.demographics;
+    names.into_iter()
+        .map(|name| {
+            let (nominal, default) = match name.as_str() {
+                "WT" => (demographics.weight_mean, 2.0 * demographics.weight_sd),
+                "AGE" => (demographics.age_mean, 2.0 * demographics.age_sd),
+                theta => {
+                    let theta = config.model.parameters[theta].theta;
+                    (theta, 0.5 * theta)
+                },
+            };
+            let (lower, upper) = settings.ranges.get(&name).copied().unwrap_or((nominal - default, nominal + default));
+            Factor { name, nominal, lower, upper }
+        })
+        .collect()
+}
+
+/// Sensitivity of the concentrations at the simulation time points and the
+/// model-based exposure metrics (see [`crate::exposure`]) of a typical
+/// subject to the configured factors, by the configured methods:
+///
+/// - local: central differences at the nominal values;
+/// - Morris: `trajectories` one-at-a-time trajectories on a grid of
+///   `levels` levels over the factor ranges, summarized by the mean, mean
+///   absolute value and SD of the elementary effects;
+/// - Sobol: first-order indices (Saltelli) and total indices (Jansen) from
+///   `samples` uniform draws over the ranges, `samples · (factors + 2)`
+///   model evaluations.
+///
+/// The seed makes the global methods reproducible.
+pub fn analyze(config: &Config, seed: Option<u64>) -> PKResult<SensitivityResult> {
+    let settings = config.sensitivity.clone().unwrap_or_default();
+    let evaluator = Evaluator::new(config, &settings)?;
+    let mut rng = match seed {
+        Some(s) => StdRng::seed_from_u64(s),
+        None => StdRng::from_entropy(),
+    };
+
+    let mut responses: Vec<ResponseSensitivity> = evaluator.responses()?.into_iter()
+        .map(|response| ResponseSensitivity { response, local: Vec::new(), morris: Vec::new(), sobol: Vec::new() })
+        .collect();
+    for method in &settings.methods {
+        match method {
+            SensitivityMethod::Local => {
+                for (response, local) in responses.iter_mut().zip(local(&evaluator)?) {
+                    response.local = local;
+                }
+            },
+            SensitivityMethod::Morris => {
+                for (response, morris) in responses.iter_mut().zip(morris(&evaluator, &settings, &mut rng)?) {
+                    response.morris = morris;
+                }
+            },
+            SensitivityMethod::Sobol => {
+                for (response, sobol) in responses.iter_mut().zip(sobol(&evaluator, &settings, &mut rng)?) {
+                    response.sobol = sobol;
+                }
+            },
+        }
+    }
+    info!("Sensitivity of {} responses to {} factors", responses.len(), evaluator.factors.len());
+
+    Ok(SensitivityResult { factors: evaluator.factors, responses })
+}
+
+/// Local sensitivities per response and factor.
+fn local(evaluator: &Evaluator) -> PKResult<Vec<Vec<LocalSensitivity>>> {
+    let nominal: Vec<f64> = evaluator.factors.iter().map(|f| f.nominal).collect();
+    let center = evaluator.evaluate(&nominal)?;
+    let mut columns = Vec::with_capacity(nominal.len());
+    for (i, &x) in nominal.iter().enumerate() {
+        let step = STEP * x.abs().max(STEP);
+        let mut shifted = nominal.clone();
+        shifted[i] = x + step;
+        let plus = evaluator.evaluate(&shifted)?;
+        shifted[i] = x - step;
+        let minus = evaluator.evaluate(&shifted)?;
+        let column: Vec<LocalSensitivity> = plus.iter().zip(&minus).zip(&center)
+            .map(|((plus, minus), &y)| {
+                let derivative = (plus - minus) / (2.0 * step);
+                LocalSensitivity { derivative, normalized: (y != 0.0).then(|| derivative * x / y) }
+            })
+            .collect();
+        columns.push(column);
+    }
+    Ok(transpose(columns, center.len()))
+}
+
+/// Morris elementary effects per response and factor. Each trajectory
+/// starts at a random grid point and moves every factor once, in random
+/// order, by `Δ = levels / (2 (levels - 1))` up or, at the top of the
+/// range, down.
+fn morris(evaluator: &Evaluator, settings: &SensitivityConfig, rng: &mut StdRng) -> PKResult<Vec<Vec<MorrisSensitivity>>> {
+    let k = evaluator.factors.len();
+    let last = (settings.levels - 1) as f64;
+    let delta = settings.levels as f64 / (2.0 * last);
+
+    let mut effects: Vec<Vec<f64>> = vec![Vec::with_capacity(settings.trajectories); k];
+    let mut n_responses = 0;
+    for _ in 0..settings.trajectories {
+        let mut unit: Vec<f64> = (0..k).map(|_| rng.gen_range(0..settings.levels) as f64 / last).collect();
+        let mut previous = evaluator.evaluate(&evaluator.scale(&unit))?;
+        n_responses = previous.len();
+        let mut order: Vec<usize> = (0..k).collect();
+        order.shuffle(rng);
+        for i in order {
+            let step = if unit[i] + delta <= 1.0 + 1e-9 { delta } else { -delta };
+            unit[i] += step;
+            let next = evaluator.evaluate(&evaluator.scale(&unit))?;
+            effects[i].extend(next.iter().zip(&previous).map(|(b, a)| (b - a) / step));
+            previous = next;
+        }
+    }
+
+    let r = settings.trajectories as f64;
+    let columns = effects.into_iter()
+        .map(|effects| {
+            (0..n_responses)
+                .map(|j| {
+                    let values: Vec<f64> = effects.iter().skip(j).step_by(n_responses).copied().collect();
+                    let mu = values.iter().sum::<f64>() / r;
+                    let variance = values.iter().map(|e| (e - mu).powi(2)).sum::<f64>() / (r - 1.0).max(1.0);
+                    MorrisSensitivity {
+                        mu,
+                        mu_star: values.iter().map(|e| e.abs()).sum::<f64>() / r,
+                        sigma: variance.sqrt(),
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    Ok(transpose(columns, n_responses))
+}
+
+/// Sobol indices per response and factor from the sample matrices A and B
+/// and, for each factor, A with that factor's column from B.
+fn sobol(evaluator: &Evaluator, settings: &SensitivityConfig, rng: &mut StdRng) -> PKResult<Vec<Vec<SobolSensitivity>>> {
+    let k = evaluator.factors.len();
+    let n = settings.samples;
+    let mut draw = || -> Vec<Vec<f64>> { (0..n).map(|_| (0..k).map(|_| rng.gen::<f64>()).collect()).collect() };
+    let (a, b) = (draw(), draw());
+    let evaluate = |samples: &[Vec<f64>]| -> PKResult<Vec<Vec<f64>>> {
+        samples.iter().map(|unit| evaluator.evaluate(&evaluator.scale(unit))).collect()
+    };
+    let (y_a, y_b) = (evaluate(&a)?, evaluate(&b)?);
+    let n_responses = y_a[0].len();
+
+    // Mean and variance of each response over both samples
+    let moments: Vec<(f64, f64)> = (0..n_responses)
+        .map(|j| {
+            let values = || y_a.iter().chain(&y_b).map(|y| y[j]);
+            let mean = values().sum::<f64>() / (2 * n) as f64;
+            (mean, values().map(|v| (v - mean).powi(2)).sum::<f64>() / (2 * n - 1) as f64)
+        })
+        .collect();
+
+    let mut columns = Vec::with_capacity(k);
+    for i in 0..k {
+        let mixed: Vec<Vec<f64>> = a.iter().zip(&b)
+            .map(|(a, b)| {
+                let mut unit = a.clone();
+                unit[i] = b[i];
+                unit
+            })
+            .collect();
+        let y_ab = evaluate(&mixed)?;
+        let column: Vec<SobolSensitivity> = (0..n_responses)
+            .map(|j| {
+                let (mean, variance) = moments[j];
+                // Centering the responses reduces the variance of the estimate
+                let first: f64 = (0..n).map(|s| (y_b[s][j] - mean) * (y_ab[s][j] - y_a[s][j])).sum::<f64>() / n as f64;
+                let total: f64 = (0..n).map(|s| (y_a[s][j] - y_ab[s][j]).powi(2)).sum::<f64>() / (2 * n) as f64;
+                let defined = variance > 0.0;
+                SobolSensitivity {
+                    first_order: defined.then(|| first / variance),
+                    total: defined.then(|| total / variance),
+                }
+            })
+            .collect();
+        columns.push(column);
+    }
+    Ok(transpose(columns, n_responses))
+}
+
+/// Per-factor columns of indices to per-response rows.
+fn transpose<T>(columns: Vec<Vec<T>>, n_responses: usize) -> Vec<Vec<T>> {
+    let mut rows: Vec<Vec<T>> = (0..n_responses).map(|_| Vec::with_capacity(columns.len())).collect();
+    for column in columns {
+        for (row, value) in rows.iter_mut().zip(column) {
+            row.push(value);
+        }
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testing::config_with;
+    use approx::assert_relative_eq;
+
+    /// A deterministic V of 20 L and CL scaled by weight; tests add the sensitivity settings.
+    const FACTORS: &str = r#"{
+        "model": { "parameters": { "V": { "theta": 20.0, "omega": null } } },
+        "population": {
+            "covariates": { "CL_WT": { "reference": 70.0, "effect": 0.75, "model": "power" } }
+        }
+    }"#;
+
+    #[test]
+    fn test_local_sensitivity_matches_analytical() {
+        let config = config_with(&[FACTORS, r#"{ "sensitivity": { "methods": ["local"] } }"#]);
+        config.validate().unwrap();
+        let result = analyze(&config, Some(1)).unwrap();
+
+        let names: Vec<&str> = result.factors.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["CL", "V", "WT"]);
+        // C = D/V · exp(-CL/V · t): ∂ln C/∂ln CL = -CL·t/V, ∂ln C/∂ln V = CL·t/V - 1
+        for response in result.responses.iter().filter(|r| r.response.metric == "CONCENTRATION") {
+            let k_t = 0.1 * response.response.time.unwrap();
+            assert_relative_eq!(response.local[0].normalized.unwrap(), -k_t, max_relative = 1e-6);
+            assert_relative_eq!(response.local[1].normalized.unwrap(), k_t - 1.0, max_relative = 1e-6);
+            // Through CL ∝ WT^0.75
+            assert_relative_eq!(response.local[2].normalized.unwrap(), -0.75 * k_t, max_relative = 1e-6);
+            assert!(response.morris.is_empty() && response.sobol.is_empty());
+        }
+        let cmax = result.responses.iter().find(|r| r.response.metric == "CMAX").unwrap();
+        assert_relative_eq!(cmax.response.nominal, 5.0, max_relative = 1e-9);
+    }
+
+    #[test]
+    fn test_global_sensitivity_ranks_factors() {
+        let mut config = config_with(&[FACTORS, r#"{ "sensitivity": {
+            "methods": ["morris", "sobol"], "factors": ["CL", "V"], "trajectories": 10, "samples": 2000
+        } }"#]);
+        config.exposure = Some(crate::config::ExposureConfig { intervals: vec![(0.0, 240.0)], ..Default::default() });
+        config.validate().unwrap();
+        let result = analyze(&config, Some(3)).unwrap();
+
+        // The peak after a bolus depends on V only
+        let cmax = result.responses.iter().find(|r| r.response.metric == "CMAX").unwrap();
+        assert_relative_eq!(cmax.morris[0].mu_star, 0.0, epsilon = 1e-9);
+        assert!(cmax.morris[1].mu_star > 0.0 && cmax.morris[1].mu < 0.0);
+        assert_relative_eq!(cmax.sobol[0].total.unwrap(), 0.0, epsilon = 1e-9);
+        assert!((cmax.sobol[1].first_order.unwrap() - 1.0).abs() < 0.1);
+        // The AUC to 240 h, close to D/CL, depends mostly on CL
+        let auc = result.responses.iter().find(|r| r.response.metric == "AUC").unwrap();
+        assert!(auc.sobol[0].total.unwrap() > auc.sobol[1].total.unwrap());
+
+        let again = analyze(&config, Some(3)).unwrap();
+        assert_eq!(again.responses[0].sobol[0].first_order, result.responses[0].sobol[0].first_order);
+
+        let mut invalid = config;
+        invalid.sensitivity.as_mut().unwrap().factors.push("HT".to_string());
+        assert!(invalid.validate().is_err());
+    }
+}
@@ -1,12 +1,32 @@
+use crate::models::DoseEvent;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PatientResult {
     pub patient_id: usize,
+    /// Study arm of the subject, when a study design is configured
+    #[serde(default)]
+    pub arm: Option<String>,
     pub demographics: Demographics,
     pub parameters: HashMap<String, f64>,
+    /// Random effects by parameter name, for parameters with an omega
+    #[serde(default)]
+    pub etas: HashMap<String, f64>,
+    /// Typical values adjusted for the subject's covariates, before random
+    /// effects and bounds
+    #[serde(default)]
+    pub typical_parameters: HashMap<String, f64>,
+    /// Parameters whose value was clamped to their bounds, in name order
+    #[serde(default)]
+    pub clamped: Vec<String>,
+    #[serde(default)]
+    pub doses: Vec<DoseEvent>,
     pub observations: Vec<Observation>,
+    /// Time the subject dropped out, if before the last nominal sample;
+    /// later doses and samples are not part of the result
+    #[serde(default)]
+    pub dropout_time: Option<f64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,13 +38,32 @@ pub struct Demographics {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Observation {
     pub time: f64,
+    /// Scheduled sampling time, `time` unless sampling windows are simulated
+    #[serde(default)]
+    pub nominal_time: f64,
     pub concentration: f64,
     pub predicted_concentration: f64,
+    /// Residual error draws EPS(1), EPS(2), ... in the order of the error
+    /// model's formula
+    #[serde(default)]
+    pub eps: Vec<f64>,
+    /// Below the lower limit of quantification
+    #[serde(default)]
+    pub blq: bool,
+    /// Left out of the NCA and summaries, and MDV=1 in the NONMEM dataset:
+    /// BLQ under the `flag` method
+    #[serde(default)]
+    pub censored: bool,
 }
 
 impl PatientResult {
+    /// Observations that are not censored, which the NCA and summaries use.
+    pub fn quantified(&self) -> impl Iterator<Item = &Observation> {
+        self.observations.iter().filter(|obs| !obs.censored)
+    }
+    
     pub fn get_max_concentration(&self) -> f64 {
-        self.observations.iter()
+        self.quantified()
             .map(|obs| obs.concentration)
             .fold(0.0, f64::max)
     }
@@ -33,7 +72,8 @@ impl PatientResult {
         // Simple trapezoidal rule for AUC calculation
         let mut auc = 0.0;
         
-        for window in self.observations.windows(2) {
+        let observations: Vec<&Observation> = self.quantified().collect();
+        for window in observations.windows(2) {
             let dt = window[1].time - window[0].time;
             let avg_conc = (window[0].concentration + window[1].concentration) / 2.0;
             auc += dt * avg_conc;
@@ -43,7 +83,7 @@ impl PatientResult {
     }
     
     pub fn get_time_to_max(&self) -> Option<f64> {
-        self.observations.iter()
+        self.quantified()
             .max_by(|a, b| a.concentration.partial_cmp(&b.concentration).unwrap())
             .map(|obs| obs.time)
     }
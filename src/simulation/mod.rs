@@ -1,23 +1,32 @@
 pub mod population;
 pub mod individual;
 pub mod variability;
+pub mod statistics;
+pub mod trial;
+pub mod study;
 use crate::config::{ErrorModel,CovariateModel,Config};
-use crate::models::{create_model, PKModel};
+use crate::models::{create_model, DoseEvent, ModelParameters};
+use crate::models::parameterization::lookup;
 use crate::dosing::DosingRegimen;
+use crate::solver::EventSolver;
 use crate::error::{PKError, PKResult};
 use rand::{Rng, SeedableRng};
 use rand::rngs::StdRng;
 // Corrected: Import the Distribution trait
 use rand_distr::{Normal, Distribution};
 use log::{info, debug};
+use std::collections::HashMap;
 
 pub use population::*;
 pub use individual::*;
 pub use variability::*;
+pub use study::*;
 
 pub struct Simulator {
     config: Config,
     rng: StdRng,
+    /// Model concentrations (amount/volume) to output concentration units
+    concentration_factor: f64,
 }
 
 impl Simulator {
@@ -27,170 +36,356 @@ impl Simulator {
             None => StdRng::from_entropy(),
         };
         
-        Ok(Self { config, rng })
+        let concentration_factor = config.units.concentration_factor()?;
+        Ok(Self { config, rng, concentration_factor })
+    }
+    
+    pub fn config(&self) -> &Config {
+        &self.config
     }
     
     pub fn simulate_population(&mut self, n_patients: usize) -> PKResult<Vec<PatientResult>> {
+        let mut results = Vec::with_capacity(n_patients);
+        self.simulate_population_with(n_patients, |result| {
+            results.push(result);
+            Ok(())
+        })?;
+        Ok(results)
+    }
+    
+    /// Simulate the population, handing each subject to `on_result` as soon
+    /// as it is simulated instead of collecting them.
+    pub fn simulate_population_with<F>(&mut self, n_patients: usize, mut on_result: F) -> PKResult<()>
+    where
+        F: FnMut(PatientResult) -> PKResult<()>,
+    {
         info!("Starting population simulation for {} patients", n_patients);
         
         // Clone the dosing config to avoid borrowing conflicts
         let dosing_config = self.config.dosing.clone();
         let dosing_regimen = DosingRegimen::from_config(&dosing_config)?;
         
-        let mut results = Vec::with_capacity(n_patients);
-
         for patient_id in 1..=n_patients {
             if patient_id % 10 == 0 || patient_id <= 10 {
                 info!("Simulating patient {}/{}", patient_id, n_patients);
             }
             
             let patient_result = self.simulate_individual(patient_id, &dosing_regimen)?;
-            results.push(patient_result);
+            on_result(patient_result)?;
         }
         
         info!("Population simulation completed");
-        Ok(results)
+        Ok(())
     }
     
+    /// Simulate one subject of the study, with the configured trial
+    /// execution: missed and delayed doses, sampling windows and dropout,
+    /// and observations below the LLOQ reported by the configured method.
+    /// Trial draws come before the subject's own, and only when configured,
+    /// so that a seed gives the same subjects without a trial section.
     fn simulate_individual(&mut self, patient_id: usize, dosing_regimen: &DosingRegimen) -> PKResult<PatientResult> {
+        let nominal_times = self.config.simulation.time_points.clone();
+        let trial = self.config.trial.clone().unwrap_or_default();
+        
+        let dropout_time = trial.dropout.as_ref()
+            .and_then(|dropout| trial::sample_dropout_time(dropout, &mut self.rng))
+            .filter(|&t| nominal_times.iter().any(|&nominal| nominal > t));
+        let doses = match &trial.adherence {
+            Some(adherence) => trial::apply_adherence(adherence, &dosing_regimen.events, &mut self.rng),
+            None => dosing_regimen.events.clone(),
+        };
+        let samples = match &trial.sampling {
+            Some(sampling) => trial::sample_times(sampling, &nominal_times, &mut self.rng),
+            None => nominal_times.iter().map(|&time| (time, time)).collect(),
+        };
+        let time_points: Vec<f64> = samples.iter().map(|&(_, time)| time).collect();
+        
+        let mut result = self.simulate_subject(patient_id, None, &doses, &time_points)?;
+        for (obs, &(nominal, _)) in result.observations.iter_mut().zip(&samples) {
+            obs.nominal_time = nominal;
+        }
+        if let Some(end) = dropout_time {
+            result.observations.retain(|obs| obs.time <= end);
+            result.doses.retain(|dose| dose.time <= end);
+            result.dropout_time = Some(end);
+        }
+        let simulation = &self.config.simulation;
+        censor_blq(&mut result.observations, simulation.lloq, simulation.blq_method);
+        Ok(result)
+    }
+    
+    /// Simulate one subject with its own dosing history and sampling times.
+    /// Demographics are sampled from the population unless given.
+    /// Observations below the LLOQ are flagged but kept as simulated, one
+    /// per sampling time.
+    pub fn simulate_subject(
+        &mut self,
+        patient_id: usize,
+        demographics: Option<Demographics>,
+        doses: &[DoseEvent],
+        time_points: &[f64],
+    ) -> PKResult<PatientResult> {
         debug!("Simulating patient {}", patient_id);
         
-        // Clone necessary config values to avoid borrowing conflicts
         let model_compartments = self.config.model.compartments;
-        let time_points = self.config.simulation.time_points.clone();
+        let demographics = match demographics {
+            Some(demographics) => demographics,
+            None => self.generate_demographics()?,
+        };
+        let sampled = self.generate_individual_parameters(&demographics)?;
+        let individual_params = sampled.values;
         
-        let (demographics, individual_params) = self.generate_individual_parameters()?;
+        let parameterization = self.config.model.parameterization();
+        let canonical_params = parameterization.to_canonical(model_compartments, &individual_params)?;
         
         let mut model = create_model(model_compartments)?;
-        model.set_parameters(&individual_params)?;
+        model.set_parameters(&canonical_params)?;
         
+        let mut solver = EventSolver::new(model.model_parameters().clone());
+        let predictions: Vec<f64> = solver.solve(doses, time_points)?
+            .into_iter()
+            .map(|c| c * self.concentration_factor)
+            .collect();
+        
+        let lloq = self.config.simulation.lloq;
         let mut observations = Vec::new();
-        for &time in &time_points {
-            let dose_history = dosing_regimen.get_events_before(time);
-            let predicted_conc = model.calculate_concentration(time, &dose_history)?;
-            let observed_conc = self.add_residual_variability(predicted_conc)?;
+        for (&time, &predicted_conc) in time_points.iter().zip(&predictions) {
+            let (observed_conc, eps) = self.add_residual_variability(predicted_conc)?;
             
             observations.push(Observation {
                 time,
+                nominal_time: time,
                 concentration: observed_conc,
                 predicted_concentration: predicted_conc,
+                eps,
+                blq: lloq.is_some_and(|lloq| observed_conc < lloq),
+                censored: false,
             });
         }
         
         Ok(PatientResult {
             patient_id,
+            arm: None,
             demographics,
-            parameters: individual_params,
+            parameters: merge_parameters(individual_params, canonical_params),
+            etas: sampled.etas,
+            typical_parameters: sampled.typical,
+            clamped: sampled.clamped,
+            doses: doses.to_vec(),
             observations,
+            dropout_time: None,
         })
     }
-
-    fn generate_individual_parameters(&mut self) -> PKResult<(Demographics, std::collections::HashMap<String, f64>)> {
-        let mut params = std::collections::HashMap::new();
+    
+    /// Individual parameters of a subject drawn from the population, before
+    /// conversion to the canonical parameterization. Demographics are
+    /// sampled unless given.
+    pub fn sample_parameters(&mut self, demographics: Option<Demographics>) -> PKResult<HashMap<String, f64>> {
+        let demographics = match demographics {
+            Some(demographics) => demographics,
+            None => self.generate_demographics()?,
+        };
+        Ok(self.generate_individual_parameters(&demographics)?.values)
+    }
+    
+    /// Population prediction (PRED): concentrations of a typical subject with
+    /// the given demographics, i.e. covariate effects but no random effects.
+    pub fn typical_prediction(
+        &self,
+        demographics: &Demographics,
+        doses: &[DoseEvent],
+        time_points: &[f64],
+    ) -> PKResult<Vec<f64>> {
+        let typical_params: HashMap<String, f64> = self.config.model.parameters.iter()
+            .map(|(name, param_config)| {
+                let value = apply_covariate_effects(&self.config, param_config.theta, name, demographics);
+                (name.clone(), value)
+            })
+            .collect();
+        
+        let params = ModelParameters::from_individual(&self.config.model, &typical_params)?;
+        let predictions = EventSolver::new(params).solve(doses, time_points)?;
+        Ok(predictions.into_iter().map(|c| c * self.concentration_factor).collect())
+    }
+    
+    /// Individual parameter values and how they were derived. Parameters
+    /// are visited in name order so that a seed gives the same subjects on
+    /// every run.
+    fn generate_individual_parameters(&mut self, demographics: &Demographics) -> PKResult<SampledParameters> {
+        let mut sampled = SampledParameters::default();
         
         // Clone the parameters to avoid borrowing conflicts
-        let model_parameters = self.config.model.parameters.clone();
-        let demographics = self.generate_demographics()?;
+        let mut model_parameters: Vec<_> = self.config.model.parameters.clone().into_iter().collect();
+        model_parameters.sort_by(|a, b| a.0.cmp(&b.0));
         
         for (name, param_config) in &model_parameters {
-            let mut value = param_config.theta;
-            
             // Apply covariate effects
-            value = self.apply_covariate_effects(value, name, &demographics);
+            let typical = apply_covariate_effects(&self.config, param_config.theta, name, demographics);
+            let mut value = typical;
             
             if let Some(omega) = param_config.omega {
                 let omega_sd = omega / 100.0;
                 let normal_dist = Normal::new(0.0, omega_sd).map_err(|_| PKError::Random)?;
-                let eta: f64 = self.rng.sample(normal_dist); // This now works
+                let eta: f64 = self.rng.sample(normal_dist);
                 value *= eta.exp();
+                sampled.etas.insert(name.clone(), eta);
             }
             
             if let Some((lower, upper)) = param_config.bounds {
+                if value < lower || value > upper {
+                    sampled.clamped.push(name.clone());
+                }
                 value = value.max(lower).min(upper);
             }
             
-            params.insert(name.clone(), value);
+            sampled.typical.insert(name.clone(), typical);
+            sampled.values.insert(name.clone(), value);
         }
         
-        Ok((demographics, params))
+        Ok(sampled)
     }
     
+    /// Weight and age from their normal distributions, redrawn until they
+    /// fall within their bounds.
     fn generate_demographics(&mut self) -> PKResult<Demographics> {
-        // Clone demographic config to avoid borrowing conflicts
-        let demo_config = self.config.population.demographics.clone();
-        
-        let weight_dist = Normal::new(demo_config.weight_mean, demo_config.weight_sd)
-            .map_err(|_| PKError::Random)?;
-        let weight = self.rng.sample(weight_dist); // This now works
-        
-        let age_dist = Normal::new(demo_config.age_mean, demo_config.age_sd)
-            .map_err(|_| PKError::Random)?;
-        let age = self.rng.sample(age_dist); // This now works
-        
-        Ok(Demographics {
-            weight: weight.max(30.0).min(200.0),
-            age: age.max(18.0).min(100.0),
-        })
+        let demographics = &self.config.population.demographics;
+        let weight = truncated_normal(&mut self.rng, demographics.weight_mean, demographics.weight_sd, demographics.weight_bounds())?;
+        let age = truncated_normal(&mut self.rng, demographics.age_mean, demographics.age_sd, demographics.age_bounds())?;
+        Ok(Demographics { weight, age })
     }
 
-    fn add_residual_variability(&mut self, predicted: f64) -> PKResult<f64> {
+    /// Observation with residual error and the EPS values drawn for it.
+    fn add_residual_variability(&mut self, predicted: f64) -> PKResult<(f64, Vec<f64>)> {
         match &self.config.simulation.error_model {
             ErrorModel::Proportional { sigma } => {
                 apply_proportional_error(predicted, *sigma, &mut self.rng)
             },
             ErrorModel::Additive { sigma } => {
-                if predicted <= 0.0 {
-                    return Ok(0.0);
-                }
-                let normal = Normal::new(0.0, *sigma).map_err(|_| PKError::Random)?;
-                let epsilon = normal.sample(&mut self.rng); // This now works
-                Ok((predicted + epsilon).max(0.0))
+                apply_additive_error(predicted, *sigma, &mut self.rng)
             },
             ErrorModel::Combined { sigma_prop, sigma_add } => {
                 apply_combined_error(predicted, *sigma_add, *sigma_prop, &mut self.rng)
             },
         }
     }
+}
+
+/// Draws of a truncated normal distribution are redrawn at most this often.
+const MAX_TRUNCATED_DRAWS: usize = 1000;
+
+/// Normal draw within `[lower, upper]`.
+fn truncated_normal(rng: &mut StdRng, mean: f64, sd: f64, (lower, upper): (f64, f64)) -> PKResult<f64> {
+    let normal = Normal::new(mean, sd).map_err(|_| PKError::Random)?;
+    for _ in 0..MAX_TRUNCATED_DRAWS {
+        let value = normal.sample(rng);
+        if (lower..=upper).contains(&value) {
+            return Ok(value);
+        }
+    }
+    Err(PKError::Simulation(format!(
+        "Demographics with mean {} and SD {} fall outside their bounds [{}, {}]", mean, sd, lower, upper
+    )))
+}
+
+/// Typical value of `param_name` for a subject: `base_value` times the
+/// configured weight and age effects.
+pub fn apply_covariate_effects(config: &Config, base_value: f64, param_name: &str, demographics: &Demographics) -> f64 {
+    let mut value = base_value;
     
-    fn apply_covariate_effects(&self, base_value: f64, param_name: &str, demographics: &Demographics) -> f64 {
-        let mut value = base_value;
-        
-        if let Some(covariates) = &self.config.population.covariates {
-            // Weight effect
-            if let Some(wt_config) = covariates.get(&format!("{}_WT", param_name)) {
-                value *= self.apply_covariate_effect(
-                    demographics.weight, 
-                    wt_config.reference, 
-                    wt_config.effect, 
-                    &wt_config.model
-                );
-            }
-            
-            // Age effect
-            if let Some(age_config) = covariates.get(&format!("{}_AGE", param_name)) {
-                value *= self.apply_covariate_effect(
-                    demographics.age, 
-                    age_config.reference, 
-                    age_config.effect, 
-                    &age_config.model
-                );
-            }
+    if let Some(covariates) = &config.population.covariates {
+        // Weight effect
+        if let Some(wt_config) = covariates.get(&format!("{}_WT", param_name)) {
+            value *= apply_covariate_effect(
+                demographics.weight, 
+                wt_config.reference, 
+                wt_config.effect, 
+                &wt_config.model
+            );
         }
         
-        value
+        // Age effect
+        if let Some(age_config) = covariates.get(&format!("{}_AGE", param_name)) {
+            value *= apply_covariate_effect(
+                demographics.age, 
+                age_config.reference, 
+                age_config.effect, 
+                &age_config.model
+            );
+        }
     }
     
-    fn apply_covariate_effect(&self, covariate_value: f64, reference: f64, effect: f64, model: &CovariateModel) -> f64 {
-        match model {
-            CovariateModel::Power => {
-                (covariate_value / reference).powf(effect)
-            },
-            CovariateModel::Exponential => {
-                (effect * (covariate_value - reference)).exp()
-            },
-            CovariateModel::Linear => {
-                1.0 + effect * (covariate_value - reference)
+    value
+}
+
+fn apply_covariate_effect(covariate_value: f64, reference: f64, effect: f64, model: &CovariateModel) -> f64 {
+    match model {
+        CovariateModel::Power => {
+            (covariate_value / reference).powf(effect)
+        },
+        CovariateModel::Exponential => {
+            (effect * (covariate_value - reference)).exp()
+        },
+        CovariateModel::Linear => {
+            1.0 + effect * (covariate_value - reference)
+        },
+    }
+}
+
+/// Parameters drawn for one subject by `generate_individual_parameters`.
+#[derive(Default)]
+struct SampledParameters {
+    values: HashMap<String, f64>,
+    typical: HashMap<String, f64>,
+    etas: HashMap<String, f64>,
+    clamped: Vec<String>,
+}
+
+/// Sampled parameters plus any derived clearance/volume parameters that the
+/// configured parameterization did not declare directly.
+fn merge_parameters(
+    mut sampled: HashMap<String, f64>,
+    canonical: HashMap<String, f64>,
+) -> HashMap<String, f64> {
+    for (name, value) in canonical {
+        if lookup(&sampled, &name).is_none() {
+            sampled.insert(name, value);
+        }
+    }
+    sampled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testing::config_with;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_random_effects_reproduce_subject() {
+        let config = config_with(&[r#"{
+            "population": {
+                "covariates": { "CL_WT": { "effect": 0.75, "reference": 70.0, "model": "power" } }
             },
+            "simulation": {
+                "time_points": [0.5, 2.0, 8.0],
+                "error_model": { "type": "combined", "sigma_prop": 0.1, "sigma_add": 0.05 }
+            }
+        }"#]);
+        let results = Simulator::new(config, Some(9)).unwrap().simulate_population(5).unwrap();
+
+        for result in &results {
+            for name in ["CL", "V"] {
+                let expected = result.typical_parameters[name] * result.etas[name].exp();
+                assert_relative_eq!(result.parameters[name], expected, max_relative = 1e-12);
+            }
+            for obs in &result.observations {
+                assert_eq!(obs.eps.len(), 2);
+                let dv = obs.predicted_concentration * (1.0 + obs.eps[0]) + obs.eps[1];
+                assert_eq!(obs.concentration, dv);
+            }
         }
+        // Weight enters the covariate-adjusted clearance only
+        assert_ne!(results[0].typical_parameters["CL"], 2.0);
+        assert_eq!(results[0].typical_parameters["V"], 10.0);
     }
-}
\ No newline at end of file
+}
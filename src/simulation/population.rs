@@ -1,11 +1,35 @@
-use super::{PatientResult, Demographics};
+use super::PatientResult;
+use super::statistics::{mean, std_dev, DistributionSummary, RunningDistribution, RunningStats};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Percentiles always included in population summaries.
+pub const DEFAULT_PERCENTILES: [f64; 3] = [5.0, 50.0, 95.0];
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PopulationSummary {
     pub n_patients: usize,
     pub parameters: ParameterSummary,
     pub pharmacokinetics: PKSummary,
+    pub percentiles: Vec<f64>,
+    /// Distribution of every individual parameter
+    pub parameter_distributions: BTreeMap<String, DistributionSummary>,
+    /// Distribution of the sampled ETAs of each parameter, to compare with
+    /// the configured omegas
+    #[serde(default)]
+    pub eta_distributions: BTreeMap<String, DistributionSummary>,
+    /// Distribution of CMAX, AUC and TMAX
+    pub exposure_distributions: BTreeMap<String, DistributionSummary>,
+    /// Observed and predicted concentrations at each time point
+    pub concentration_bands: Vec<TimePointSummary>,
+}
+
+/// Prediction-interval summary of the concentrations at one time point.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TimePointSummary {
+    pub time: f64,
+    pub observed: DistributionSummary,
+    pub predicted: DistributionSummary,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -28,7 +52,14 @@ pub struct PKSummary {
 
 impl PopulationSummary {
     pub fn from_results(results: &[PatientResult]) -> Self {
+        Self::with_percentiles(results, &[])
+    }
+    
+    /// Summary reporting `extra_percentiles` (0-100) in addition to
+    /// [`DEFAULT_PERCENTILES`].
+    pub fn with_percentiles(results: &[PatientResult], extra_percentiles: &[f64]) -> Self {
         let n = results.len();
+        let percentiles = summary_percentiles(extra_percentiles);
         
         // Calculate parameter statistics
         let cl_values: Vec<f64> = results.iter()
@@ -49,6 +80,19 @@ impl PopulationSummary {
             .filter_map(|r| r.get_time_to_max())
             .collect();
         
+        let parameter_distributions = named_distributions(results, |r| &r.parameters, &percentiles);
+        let eta_distributions = named_distributions(results, |r| &r.etas, &percentiles);
+        
+        let exposure_distributions = [
+            ("CMAX", &cmax_values),
+            ("AUC", &auc_values),
+            ("TMAX", &tmax_values),
+        ].into_iter()
+            .map(|(name, values)| (name.to_string(), DistributionSummary::from_values(values, &percentiles)))
+            .collect();
+        
+        let concentration_bands = concentration_bands(results, &percentiles);
+        
         Self {
             n_patients: n,
             parameters: ParameterSummary {
@@ -65,26 +109,259 @@ impl PopulationSummary {
                 tmax_mean: mean(&tmax_values),
                 tmax_sd: std_dev(&tmax_values),
             },
+            percentiles,
+            parameter_distributions,
+            eta_distributions,
+            exposure_distributions,
+            concentration_bands,
         }
     }
 }
 
-fn mean(values: &[f64]) -> f64 {
-    if values.is_empty() {
-        0.0
-    } else {
-        values.iter().sum::<f64>() / values.len() as f64
+/// Distribution of each named value of the subjects, e.g. their parameters
+/// or ETAs, over the subjects that have it.
+fn named_distributions(
+    results: &[PatientResult],
+    values: fn(&PatientResult) -> &HashMap<String, f64>,
+    percentiles: &[f64],
+) -> BTreeMap<String, DistributionSummary> {
+    let names: BTreeSet<&String> = results.iter()
+        .flat_map(|r| values(r).keys())
+        .collect();
+    names.into_iter()
+        .map(|name| {
+            let named: Vec<f64> = results.iter()
+                .filter_map(|r| values(r).get(name).copied())
+                .collect();
+            (name.clone(), DistributionSummary::from_values(&named, percentiles))
+        })
+        .collect()
+}
+
+/// [`DEFAULT_PERCENTILES`] and `extra_percentiles`, sorted and deduplicated.
+fn summary_percentiles(extra_percentiles: &[f64]) -> Vec<f64> {
+    let mut percentiles: Vec<f64> = DEFAULT_PERCENTILES.iter()
+        .chain(extra_percentiles)
+        .copied()
+        .collect();
+    percentiles.sort_by(|a, b| a.total_cmp(b));
+    percentiles.dedup();
+    percentiles
+}
+
+/// Builds a [`PopulationSummary`] one subject at a time with online
+/// accumulators, so memory does not grow with the number of subjects.
+/// Percentiles are exact for small populations and sketched beyond that.
+#[derive(Debug, Clone, Default)]
+pub struct StreamingSummary {
+    percentiles: Vec<f64>,
+    n_patients: usize,
+    cl: RunningStats,
+    v: RunningStats,
+    cmax: RunningDistribution,
+    auc: RunningDistribution,
+    tmax: RunningDistribution,
+    parameters: BTreeMap<String, RunningDistribution>,
+    etas: BTreeMap<String, RunningDistribution>,
+    /// (nominal time, observed, predicted), sorted by time
+    bands: Vec<(f64, RunningDistribution, RunningDistribution)>,
+}
+
+impl StreamingSummary {
+    pub fn new(extra_percentiles: &[f64]) -> Self {
+        Self { percentiles: summary_percentiles(extra_percentiles), ..Self::default() }
+    }
+    
+    pub fn add(&mut self, result: &PatientResult) {
+        self.n_patients += 1;
+        self.cl.push(*result.parameters.get("CL").unwrap_or(&0.0));
+        self.v.push(*result.parameters.get("V").or_else(|| result.parameters.get("V1")).unwrap_or(&0.0));
+        self.cmax.push(result.get_max_concentration());
+        self.auc.push(result.get_auc());
+        if let Some(tmax) = result.get_time_to_max() {
+            self.tmax.push(tmax);
+        }
+        
+        for (distributions, values) in [(&mut self.parameters, &result.parameters), (&mut self.etas, &result.etas)] {
+            for (name, value) in values {
+                distributions.entry(name.clone()).or_default().push(*value);
+            }
+        }
+        
+        for obs in &result.observations {
+            let index = match self.bands.binary_search_by(|band| band.0.total_cmp(&obs.nominal_time)) {
+                Ok(index) => index,
+                Err(index) => {
+                    self.bands.insert(index, (obs.nominal_time, RunningDistribution::default(), RunningDistribution::default()));
+                    index
+                },
+            };
+            if !obs.censored {
+                self.bands[index].1.push(obs.concentration);
+            }
+            self.bands[index].2.push(obs.predicted_concentration);
+        }
+    }
+    
+    pub fn finish(&self) -> PopulationSummary {
+        let percentiles = &self.percentiles;
+        let summaries = |distributions: &BTreeMap<String, RunningDistribution>| distributions.iter()
+            .map(|(name, distribution)| (name.clone(), distribution.summary(percentiles)))
+            .collect();
+        PopulationSummary {
+            n_patients: self.n_patients,
+            parameters: ParameterSummary {
+                cl_mean: self.cl.mean(),
+                cl_sd: self.cl.std_dev(),
+                v_mean: self.v.mean(),
+                v_sd: self.v.std_dev(),
+            },
+            pharmacokinetics: PKSummary {
+                cmax_mean: self.cmax.stats().mean(),
+                cmax_sd: self.cmax.stats().std_dev(),
+                auc_mean: self.auc.stats().mean(),
+                auc_sd: self.auc.stats().std_dev(),
+                tmax_mean: self.tmax.stats().mean(),
+                tmax_sd: self.tmax.stats().std_dev(),
+            },
+            percentiles: percentiles.clone(),
+            parameter_distributions: summaries(&self.parameters),
+            eta_distributions: summaries(&self.etas),
+            exposure_distributions: [("CMAX", &self.cmax), ("AUC", &self.auc), ("TMAX", &self.tmax)].into_iter()
+                .map(|(name, distribution)| (name.to_string(), distribution.summary(percentiles)))
+                .collect(),
+            concentration_bands: self.bands.iter()
+                .map(|(time, observed, predicted)| TimePointSummary {
+                    time: *time,
+                    observed: observed.summary(percentiles),
+                    predicted: predicted.summary(percentiles),
+                })
+                .collect(),
+        }
     }
 }
 
-fn std_dev(values: &[f64]) -> f64 {
-    if values.len() < 2 {
-        0.0
-    } else {
-        let mean_val = mean(values);
-        let variance = values.iter()
-            .map(|v| (v - mean_val).powi(2))
-            .sum::<f64>() / (values.len() - 1) as f64;
-        variance.sqrt()
+/// Group observations of all subjects by nominal time and summarize each
+/// group, so subjects need not share a sampling schedule. Censored
+/// observations count for the predictions only.
+fn concentration_bands(results: &[PatientResult], percentiles: &[f64]) -> Vec<TimePointSummary> {
+    let mut samples: Vec<(f64, Option<f64>, f64)> = results.iter()
+        .flat_map(|r| r.observations.iter())
+        .map(|obs| (obs.nominal_time, (!obs.censored).then_some(obs.concentration), obs.predicted_concentration))
+        .collect();
+    samples.sort_by(|a, b| a.0.total_cmp(&b.0));
+    
+    samples.chunk_by(|a, b| a.0 == b.0)
+        .map(|group| {
+            let observed: Vec<f64> = group.iter().filter_map(|s| s.1).collect();
+            let predicted: Vec<f64> = group.iter().map(|s| s.2).collect();
+            TimePointSummary {
+                time: group[0].0,
+                observed: DistributionSummary::from_values(&observed, percentiles),
+                predicted: DistributionSummary::from_values(&predicted, percentiles),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BlqMethod;
+    use crate::simulation::{censor_blq, Demographics, Observation};
+    use approx::assert_relative_eq;
+    use std::collections::HashMap;
+
+    fn patient(id: usize, cl: f64, concentrations: &[(f64, f64)]) -> PatientResult {
+        PatientResult {
+            patient_id: id,
+            arm: None,
+            demographics: Demographics { weight: 70.0, age: 40.0 },
+            parameters: HashMap::from([("CL".to_string(), cl), ("KA".to_string(), 1.0)]),
+            etas: HashMap::new(),
+            typical_parameters: HashMap::new(),
+            clamped: Vec::new(),
+            doses: Vec::new(),
+            observations: concentrations.iter()
+                .map(|&(time, c)| Observation {
+                    time, nominal_time: time, concentration: c, predicted_concentration: c * 2.0, eps: Vec::new(),
+                    blq: false, censored: false,
+                })
+                .collect(),
+            dropout_time: None,
+        }
+    }
+
+    #[test]
+    fn test_percentile_bands_by_time() {
+        let results: Vec<_> = (1..=5)
+            .map(|i| patient(i, i as f64, &[(0.0, 0.0), (1.0, i as f64)]))
+            .collect();
+        let summary = PopulationSummary::with_percentiles(&results, &[25.0, 50.0]);
+        
+        assert_eq!(summary.percentiles, vec![5.0, 25.0, 50.0, 95.0]);
+        assert_eq!(summary.concentration_bands.len(), 2);
+        
+        let band = &summary.concentration_bands[1];
+        assert_eq!(band.time, 1.0);
+        assert_relative_eq!(band.observed.percentile(50.0).unwrap(), 3.0);
+        assert_relative_eq!(band.observed.percentile(25.0).unwrap(), 2.0);
+        assert_relative_eq!(band.predicted.percentile(95.0).unwrap(), 9.6, epsilon = 1e-12);
+        
+        assert!(summary.parameter_distributions.contains_key("KA"));
+        let cl = &summary.parameter_distributions["CL"];
+        assert_relative_eq!(cl.percentile(5.0).unwrap(), 1.2, epsilon = 1e-12);
+        assert!(cl.summary.geometric_cv_percent.is_some());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_censored_observations_left_out() {
+        let mut results: Vec<_> = (1..=4)
+            .map(|i| patient(i, 1.0, &[(0.0, -0.2), (1.0, i as f64), (2.0, 0.5)]))
+            .collect();
+        for result in &mut results {
+            censor_blq(&mut result.observations, Some(1.5), BlqMethod::Flag);
+        }
+        let summary = PopulationSummary::with_percentiles(&results, &[]);
+        let mut streaming = StreamingSummary::new(&[]);
+        results.iter().for_each(|r| streaming.add(r));
+        
+        // Subject 1 has no quantified sample
+        assert_eq!(results[0].get_max_concentration(), 0.0);
+        assert_eq!(results[3].get_auc(), 0.0);
+        assert_eq!(results[3].get_time_to_max(), Some(1.0));
+        for summary in [summary, streaming.finish()] {
+            let bands = &summary.concentration_bands;
+            assert_eq!(bands[0].observed.summary.n, 0);
+            assert_eq!(bands[0].predicted.summary.n, 4);
+            assert_eq!(bands[1].observed.summary.n, 3);
+            assert_relative_eq!(bands[1].observed.summary.min, 2.0);
+        }
+    }
+
+    #[test]
+    fn test_streaming_summary_matches_batch() {
+        let results: Vec<_> = (1..=7)
+            .map(|i| patient(i, 1.0 + i as f64 * 0.3, &[(0.5, i as f64), (2.0, 10.0 / i as f64)]))
+            .collect();
+        let batch = PopulationSummary::with_percentiles(&results, &[10.0]);
+        let mut streaming = StreamingSummary::new(&[10.0]);
+        results.iter().for_each(|r| streaming.add(r));
+        let streamed = streaming.finish();
+        
+        assert_eq!(streamed.n_patients, 7);
+        assert_eq!(streamed.percentiles, batch.percentiles);
+        assert_relative_eq!(streamed.parameters.cl_sd, batch.parameters.cl_sd, epsilon = 1e-12);
+        assert_relative_eq!(streamed.pharmacokinetics.auc_mean, batch.pharmacokinetics.auc_mean, epsilon = 1e-12);
+        assert_relative_eq!(
+            streamed.exposure_distributions["TMAX"].summary.median,
+            batch.exposure_distributions["TMAX"].summary.median
+        );
+        assert_eq!(streamed.concentration_bands.len(), 2);
+        for (s, b) in streamed.concentration_bands.iter().zip(&batch.concentration_bands) {
+            assert_eq!(s.time, b.time);
+            assert_relative_eq!(s.observed.percentile(10.0).unwrap(), b.observed.percentile(10.0).unwrap(), epsilon = 1e-12);
+            assert_relative_eq!(s.predicted.summary.mean, b.predicted.summary.mean, epsilon = 1e-12);
+        }
+    }
+}
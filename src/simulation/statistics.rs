@@ -0,0 +1,458 @@
+use serde::{Deserialize, Serialize};
+
+pub fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+}
+
+pub fn std_dev(values: &[f64]) -> f64 {
+    if values.len() < 2 {
+        0.0
+    } else {
+        let mean_val = mean(values);
+        let variance = values.iter()
+            .map(|v| (v - mean_val).powi(2))
+            .sum::<f64>() / (values.len() - 1) as f64;
+        variance.sqrt()
+    }
+}
+
+/// Geometric mean of the positive values; `None` if there are none.
+pub fn geometric_mean(values: &[f64]) -> Option<f64> {
+    let logs: Vec<f64> = values.iter()
+        .filter(|v| **v > 0.0)
+        .map(|v| v.ln())
+        .collect();
+    if logs.is_empty() {
+        None
+    } else {
+        Some(mean(&logs).exp())
+    }
+}
+
+/// Geometric coefficient of variation, `sqrt(exp(s^2) - 1)` with `s` the SD
+/// of the log values, in percent. `None` with fewer than two positive values.
+pub fn geometric_cv_percent(values: &[f64]) -> Option<f64> {
+    let logs: Vec<f64> = values.iter()
+        .filter(|v| **v > 0.0)
+        .map(|v| v.ln())
+        .collect();
+    if logs.len() < 2 {
+        None
+    } else {
+        Some(((std_dev(&logs).powi(2)).exp() - 1.0).sqrt() * 100.0)
+    }
+}
+
+/// Sample quantile with linear interpolation between order statistics
+/// (type 7, the R and NumPy default). `p` is in [0, 1].
+pub fn quantile(values: &[f64], p: f64) -> f64 {
+    let mut sorted: Vec<f64> = values.iter().copied().filter(|v| !v.is_nan()).collect();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    quantile_sorted(&sorted, p)
+}
+
+/// [`quantile`] for data that is already sorted ascending.
+pub fn quantile_sorted(sorted: &[f64], p: f64) -> f64 {
+    match sorted.len() {
+        0 => 0.0,
+        1 => sorted[0],
+        n => {
+            let h = (n - 1) as f64 * p.clamp(0.0, 1.0);
+            let lo = h.floor() as usize;
+            let hi = (lo + 1).min(n - 1);
+            sorted[lo] + (h - lo as f64) * (sorted[hi] - sorted[lo])
+        },
+    }
+}
+
+/// Descriptive statistics of one metric across subjects.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricSummary {
+    pub n: usize,
+    pub mean: f64,
+    pub sd: f64,
+    pub cv_percent: f64,
+    pub geometric_mean: Option<f64>,
+    pub geometric_cv_percent: Option<f64>,
+    pub median: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl MetricSummary {
+    pub fn from_values(values: &[f64]) -> Self {
+        if values.is_empty() {
+            return Self {
+                n: 0, mean: 0.0, sd: 0.0, cv_percent: 0.0,
+                geometric_mean: None, geometric_cv_percent: None,
+                median: 0.0, min: 0.0, max: 0.0,
+            };
+        }
+        
+        let mean_val = mean(values);
+        let sd = std_dev(values);
+        Self {
+            n: values.len(),
+            mean: mean_val,
+            sd,
+            cv_percent: if mean_val != 0.0 { sd / mean_val.abs() * 100.0 } else { 0.0 },
+            geometric_mean: geometric_mean(values),
+            geometric_cv_percent: geometric_cv_percent(values),
+            median: quantile(values, 0.5),
+            min: values.iter().copied().fold(f64::INFINITY, f64::min),
+            max: values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+/// Value of one percentile (0-100) of a distribution.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PercentileValue {
+    pub percentile: f64,
+    pub value: f64,
+}
+
+/// [`MetricSummary`] together with selected percentiles.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DistributionSummary {
+    #[serde(flatten)]
+    pub summary: MetricSummary,
+    pub percentiles: Vec<PercentileValue>,
+}
+
+impl DistributionSummary {
+    pub fn from_values(values: &[f64], percentiles: &[f64]) -> Self {
+        let mut sorted: Vec<f64> = values.iter().copied().filter(|v| !v.is_nan()).collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        
+        Self {
+            summary: MetricSummary::from_values(values),
+            percentiles: percentiles.iter()
+                .map(|&percentile| PercentileValue {
+                    percentile,
+                    value: quantile_sorted(&sorted, percentile / 100.0),
+                })
+                .collect(),
+        }
+    }
+    
+    /// Value of `percentile` if it was computed.
+    pub fn percentile(&self, percentile: f64) -> Option<f64> {
+        self.percentiles.iter()
+            .find(|p| p.percentile == percentile)
+            .map(|p| p.value)
+    }
+}
+
+/// Online mean and variance by Welford's algorithm.
+#[derive(Debug, Clone, Copy, Default)]
+struct Welford {
+    n: usize,
+    mean: f64,
+    m2: f64,
+}
+
+impl Welford {
+    fn push(&mut self, value: f64) {
+        self.n += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.n as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+    
+    fn std_dev(&self) -> f64 {
+        if self.n < 2 { 0.0 } else { (self.m2 / (self.n - 1) as f64).sqrt() }
+    }
+}
+
+/// Streaming counterpart of [`mean`], [`std_dev`], [`geometric_mean`],
+/// [`geometric_cv_percent`] and the range, in constant memory.
+#[derive(Debug, Clone, Copy)]
+pub struct RunningStats {
+    values: Welford,
+    logs: Welford,
+    min: f64,
+    max: f64,
+}
+
+impl Default for RunningStats {
+    fn default() -> Self {
+        Self {
+            values: Welford::default(),
+            logs: Welford::default(),
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+}
+
+impl RunningStats {
+    pub fn push(&mut self, value: f64) {
+        self.values.push(value);
+        if value > 0.0 {
+            self.logs.push(value.ln());
+        }
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+    
+    pub fn count(&self) -> usize {
+        self.values.n
+    }
+    
+    pub fn mean(&self) -> f64 {
+        self.values.mean
+    }
+    
+    pub fn std_dev(&self) -> f64 {
+        self.values.std_dev()
+    }
+    
+    /// [`MetricSummary`] of the values seen so far; the median must come
+    /// from elsewhere, e.g. a [`QuantileSketch`].
+    pub fn summary(&self, median: f64) -> MetricSummary {
+        if self.values.n == 0 {
+            return MetricSummary::from_values(&[]);
+        }
+        let mean_val = self.mean();
+        let sd = self.std_dev();
+        MetricSummary {
+            n: self.values.n,
+            mean: mean_val,
+            sd,
+            cv_percent: if mean_val != 0.0 { sd / mean_val.abs() * 100.0 } else { 0.0 },
+            geometric_mean: (self.logs.n > 0).then(|| self.logs.mean.exp()),
+            geometric_cv_percent: (self.logs.n > 1)
+                .then(|| ((self.logs.std_dev().powi(2)).exp() - 1.0).sqrt() * 100.0),
+            median,
+            min: self.min,
+            max: self.max,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Centroid {
+    mean: f64,
+    weight: f64,
+}
+
+/// Streaming quantile estimator (a merging t-digest) with memory bounded by
+/// the compression, independent of the number of values.
+///
+/// Values are kept exactly until the buffer first fills, so small samples give
+/// the same type-7 quantiles as [`quantile`]; beyond that they are merged into
+/// centroids that are smallest in the tails, where accuracy matters most.
+#[derive(Debug, Clone)]
+pub struct QuantileSketch {
+    compression: f64,
+    buffer: Vec<f64>,
+    centroids: Vec<Centroid>,
+    count: usize,
+    min: f64,
+    max: f64,
+}
+
+impl Default for QuantileSketch {
+    fn default() -> Self {
+        Self::new(200.0)
+    }
+}
+
+impl QuantileSketch {
+    pub fn new(compression: f64) -> Self {
+        Self {
+            compression,
+            buffer: Vec::new(),
+            centroids: Vec::new(),
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+    
+    pub fn push(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.buffer.push(value);
+        if self.buffer.len() >= self.buffer_capacity() {
+            self.centroids = self.merged();
+            self.buffer.clear();
+        }
+    }
+    
+    pub fn count(&self) -> usize {
+        self.count
+    }
+    
+    /// Estimated quantile, `p` in [0, 1].
+    pub fn quantile(&self, p: f64) -> f64 {
+        if self.centroids.is_empty() {
+            return quantile(&self.buffer, p);
+        }
+        
+        // Interpolate between centroid centres, anchored at the extremes
+        let centroids = self.merged();
+        let target = p.clamp(0.0, 1.0) * self.count as f64;
+        let mut previous = (0.0, self.min);
+        let mut cumulative = 0.0;
+        for centroid in centroids.iter().map(|c| Some(*c)).chain(std::iter::once(None)) {
+            let point = match centroid {
+                Some(c) => {
+                    let centre = cumulative + c.weight / 2.0;
+                    cumulative += c.weight;
+                    (centre, c.mean)
+                },
+                None => (self.count as f64, self.max),
+            };
+            if target <= point.0 {
+                let span = point.0 - previous.0;
+                if span <= 0.0 {
+                    return point.1;
+                }
+                return previous.1 + (target - previous.0) / span * (point.1 - previous.1);
+            }
+            previous = point;
+        }
+        self.max
+    }
+    
+    fn buffer_capacity(&self) -> usize {
+        (self.compression * 5.0).ceil() as usize
+    }
+    
+    /// Centroids and buffered values merged under the k1 scale function, so a
+    /// centroid spans at most one unit of `k(q) = δ/2π · asin(2q - 1)`.
+    fn merged(&self) -> Vec<Centroid> {
+        let mut items: Vec<Centroid> = self.centroids.iter().copied()
+            .chain(self.buffer.iter().map(|&mean| Centroid { mean, weight: 1.0 }))
+            .collect();
+        items.sort_by(|a, b| a.mean.total_cmp(&b.mean));
+        
+        let total = self.count as f64;
+        let delta = self.compression;
+        let k = |q: f64| delta / (2.0 * std::f64::consts::PI) * (2.0 * q - 1.0).asin();
+        let q_limit = |q: f64| {
+            let k_next = k(q) + 1.0;
+            if k_next >= delta / 4.0 { 1.0 } else { ((2.0 * std::f64::consts::PI * k_next / delta).sin() + 1.0) / 2.0 }
+        };
+        
+        let mut merged: Vec<Centroid> = Vec::with_capacity(items.len().min(delta as usize * 2));
+        let mut weight_before = 0.0;
+        let mut limit = q_limit(0.0);
+        for item in items {
+            match merged.last_mut() {
+                Some(current) if (weight_before + current.weight + item.weight) / total <= limit => {
+                    current.weight += item.weight;
+                    current.mean += (item.mean - current.mean) * item.weight / current.weight;
+                },
+                _ => {
+                    if let Some(current) = merged.last() {
+                        weight_before += current.weight;
+                        limit = q_limit(weight_before / total);
+                    }
+                    merged.push(item);
+                },
+            }
+        }
+        merged
+    }
+}
+
+/// Streaming counterpart of [`DistributionSummary::from_values`].
+#[derive(Debug, Clone, Default)]
+pub struct RunningDistribution {
+    stats: RunningStats,
+    sketch: QuantileSketch,
+}
+
+impl RunningDistribution {
+    pub fn push(&mut self, value: f64) {
+        self.stats.push(value);
+        self.sketch.push(value);
+    }
+    
+    pub fn stats(&self) -> &RunningStats {
+        &self.stats
+    }
+    
+    pub fn summary(&self, percentiles: &[f64]) -> DistributionSummary {
+        let value = |p: f64| if self.sketch.count() == 0 { 0.0 } else { self.sketch.quantile(p) };
+        DistributionSummary {
+            summary: self.stats.summary(value(0.5)),
+            percentiles: percentiles.iter()
+                .map(|&percentile| PercentileValue { percentile, value: value(percentile / 100.0) })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_quantile_interpolation() {
+        let values = [4.0, 1.0, 3.0, 2.0];
+        assert_relative_eq!(quantile(&values, 0.0), 1.0);
+        assert_relative_eq!(quantile(&values, 0.5), 2.5);
+        assert_relative_eq!(quantile(&values, 0.9), 3.7, epsilon = 1e-12);
+        assert_relative_eq!(quantile(&values, 1.0), 4.0);
+    }
+
+    #[test]
+    fn test_geometric_mean() {
+        assert_relative_eq!(geometric_mean(&[1.0, 10.0, 100.0]).unwrap(), 10.0, epsilon = 1e-12);
+        assert!(geometric_mean(&[0.0, -1.0]).is_none());
+    }
+
+    #[test]
+    fn test_geometric_cv() {
+        // Log values 0 and ln(e) = 1 have SD 1/sqrt(2)
+        let cv = geometric_cv_percent(&[1.0, std::f64::consts::E]).unwrap();
+        assert_relative_eq!(cv, ((0.5f64).exp() - 1.0).sqrt() * 100.0, epsilon = 1e-12);
+        assert!(geometric_cv_percent(&[2.0]).is_none());
+    }
+
+    #[test]
+    fn test_running_distribution_matches_batch() {
+        let values: Vec<f64> = (1..=50).map(|i| (i as f64 * 0.37).sin().abs() * 10.0 + 0.1).collect();
+        let mut running = RunningDistribution::default();
+        values.iter().for_each(|&v| running.push(v));
+        
+        let batch = DistributionSummary::from_values(&values, &[5.0, 50.0, 95.0]);
+        let streamed = running.summary(&[5.0, 50.0, 95.0]);
+        assert_relative_eq!(streamed.summary.mean, batch.summary.mean, epsilon = 1e-12);
+        assert_relative_eq!(streamed.summary.sd, batch.summary.sd, epsilon = 1e-12);
+        assert_relative_eq!(streamed.summary.geometric_cv_percent.unwrap(), batch.summary.geometric_cv_percent.unwrap(), epsilon = 1e-9);
+        assert_relative_eq!(streamed.summary.min, batch.summary.min);
+        // Small samples are kept exactly
+        for (s, b) in streamed.percentiles.iter().zip(&batch.percentiles) {
+            assert_relative_eq!(s.value, b.value, epsilon = 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_quantile_sketch_accuracy() {
+        // Deterministic, non-monotonic sequence covering (0, 1)
+        let values: Vec<f64> = (0..200_000u64)
+            .map(|i| (i.wrapping_mul(2_654_435_761) % 1_000_003) as f64 / 1_000_003.0)
+            .collect();
+        let mut sketch = QuantileSketch::default();
+        values.iter().for_each(|&v| sketch.push(v));
+        
+        assert!(sketch.centroids.len() < 1000);
+        for p in [0.01, 0.05, 0.5, 0.95, 0.99] {
+            assert_relative_eq!(sketch.quantile(p), quantile(&values, p), epsilon = 2e-3);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use super::{PatientResult, Simulator};
+use crate::config::{ArmConfig, Config};
+use crate::dosing::DosingRegimen;
+use crate::exposure::exposure_intervals;
+use crate::error::{PKError, PKResult};
+use log::info;
+
+impl Simulator {
+    pub fn simulate_study(&mut self) -> PKResult<Vec<PatientResult>> {
+        let mut results = Vec::new();
+        self.simulate_study_with(|result| {
+            results.push(result);
+            Ok(())
+        })?;
+        Ok(results)
+    }
+
+    /// Simulate every arm of the configured study in turn, handing each
+    /// subject to `on_result`. Subjects are numbered across arms and carry
+    /// the name of their arm.
+    pub fn simulate_study_with<F>(&mut self, mut on_result: F) -> PKResult<()>
+    where
+        F: FnMut(PatientResult) -> PKResult<()>,
+    {
+        let Some(study) = self.config.study.clone() else {
+            return Err(PKError::Validation("Simulating a study needs a study section in the configuration".to_string()));
+        };
+
+        let mut first_id = 1;
+        for arm in &study.arms {
+            info!("Simulating arm '{}' with {} patients", arm.name, arm.n_subjects);
+            // The arm's configuration stands in for the study's while it is simulated
+            let arm_config = self.config.arm(arm);
+            let study_config = std::mem::replace(&mut self.config, arm_config);
+            let outcome = self.simulate_arm(arm, first_id, &mut on_result);
+            self.config = study_config;
+            outcome?;
+            first_id += arm.n_subjects;
+        }
+
+        info!("Study simulation completed");
+        Ok(())
+    }
+
+    fn simulate_arm<F>(&mut self, arm: &ArmConfig, first_id: usize, on_result: &mut F) -> PKResult<()>
+    where
+        F: FnMut(PatientResult) -> PKResult<()>,
+    {
+        let dosing_regimen = DosingRegimen::from_config(&self.config.dosing)?;
+        for patient_id in first_id..first_id + arm.n_subjects {
+            let mut result = self.simulate_individual(patient_id, &dosing_regimen)?;
+            result.arm = Some(arm.name.clone());
+            on_result(result)?;
+        }
+        Ok(())
+    }
+}
+
+/// Subjects of one arm with the arm's configuration.
+pub struct ArmResults<'a> {
+    /// Arm name, `None` without a study
+    pub arm: Option<&'a str>,
+    pub config: Config,
+    pub results: &'a [PatientResult],
+}
+
+/// Split study results, simulated arm by arm, into their arms. Without a
+/// study the results form a single group with the configuration as is.
+pub fn by_arm<'a>(results: &'a [PatientResult], config: &Config) -> PKResult<Vec<ArmResults<'a>>> {
+    if config.study.is_none() {
+        return Ok(vec![ArmResults { arm: None, config: config.clone(), results }]);
+    }
+    results.chunk_by(|a, b| a.arm == b.arm)
+        .map(|group| Ok(ArmResults { arm: group[0].arm.as_deref(), config: arm_config(config, &group[0])?, results: group }))
+        .collect()
+}
+
+/// Configuration of the arm of `result`, or the configuration as is
+/// without a study. Exposure is evaluated over the same intervals in every
+/// arm so that arms can be compared: the configured ones or the window of
+/// the whole study.
+pub fn arm_config(config: &Config, result: &PatientResult) -> PKResult<Config> {
+    let Some(study) = &config.study else {
+        return Ok(config.clone());
+    };
+    let arm = study.arms.iter()
+        .find(|arm| Some(arm.name.as_str()) == result.arm.as_deref())
+        .ok_or_else(|| PKError::Validation(format!(
+            "Patient {} is not in an arm of the study", result.patient_id
+        )))?;
+    let mut exposure = config.exposure.clone().unwrap_or_default();
+    exposure.intervals = exposure_intervals(config, &exposure);
+    let mut arm_config = config.arm(arm);
+    arm_config.exposure = Some(exposure);
+    Ok(arm_config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testing::config_with;
+
+    #[test]
+    fn test_arms_have_their_own_design() {
+        let config = config_with(&[r#"{
+            "model": { "parameters": { "V": { "theta": 20.0, "omega": null } } },
+            "study": { "arms": [
+                { "name": "low", "n_subjects": 3 },
+                { "name": "high", "n_subjects": 4,
+                  "dosing": { "route": "ivbolus", "amount": 400.0, "times": [0.0, 12.0] },
+                  "time_points": [1.0, 24.0],
+                  "demographics": { "weight_mean": 20.0, "weight_sd": 1.0, "age_mean": 8.0, "age_sd": 1.0 } }
+            ] }
+        }"#]);
+        config.validate().unwrap();
+        let results = Simulator::new(config.clone(), Some(6)).unwrap().simulate_study().unwrap();
+
+        assert_eq!(results.len(), 7);
+        assert!(results.iter().enumerate().all(|(i, r)| r.patient_id == i + 1));
+        let (low, high) = results.split_at(3);
+        for result in low {
+            assert_eq!(result.arm.as_deref(), Some("low"));
+            assert_eq!(result.doses.len(), 1);
+            assert_eq!(result.observations.len(), 3);
+        }
+        for result in high {
+            assert_eq!(result.arm.as_deref(), Some("high"));
+            assert_eq!(result.doses[1].amount, 400.0);
+            assert_eq!(result.observations.len(), 2);
+        }
+        // The arm's children are not turned into adults
+        let mean_age = high.iter().map(|r| r.demographics.age).sum::<f64>() / high.len() as f64;
+        let max_weight = high.iter().map(|r| r.demographics.weight).fold(0.0, f64::max);
+        assert!((mean_age - 8.0).abs() < 2.0 && max_weight < 25.0);
+
+        let groups = by_arm(&results, &config).unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!((groups[1].arm, groups[1].results.len()), (Some("high"), 4));
+        assert_eq!(groups[1].config.dosing.amount, 400.0);
+        // Both arms are evaluated over the whole study
+        for group in &groups {
+            assert_eq!(group.config.exposure.as_ref().unwrap().intervals, [(0.0, 24.0)]);
+        }
+
+        let mut invalid = config;
+        invalid.study.as_mut().unwrap().arms[1].name = "low".to_string();
+        assert!(invalid.validate().is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use crate::config::{AdherenceConfig, DropoutConfig, SamplingConfig};
+use crate::models::DoseEvent;
+use rand::Rng;
+
+/// Dropout time from the piecewise-constant hazard, by inversion of the
+/// cumulative hazard at an exponential draw. `None` if the subject never
+/// drops out, i.e. the last hazard is zero and the draw is not reached.
+pub fn sample_dropout_time<R: Rng>(dropout: &DropoutConfig, rng: &mut R) -> Option<f64> {
+    let mut hazards = dropout.hazards.clone();
+    hazards.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    // -ln(U) with U in (0, 1]
+    let mut remaining = -(1.0 - rng.gen::<f64>()).ln();
+    for (i, &(start, hazard)) in hazards.iter().enumerate() {
+        let end = hazards.get(i + 1).map_or(f64::INFINITY, |next| next.0);
+        if hazard > 0.0 {
+            let cumulative = hazard * (end - start);
+            if remaining <= cumulative {
+                return Some(start + remaining / hazard);
+            }
+            remaining -= cumulative;
+        }
+    }
+    None
+}
+
+/// The doses actually taken: each dose is missed with the missed-dose
+/// probability and otherwise taken late, uniformly up to the maximum delay,
+/// with the delay probability.
+pub fn apply_adherence<R: Rng>(adherence: &AdherenceConfig, doses: &[DoseEvent], rng: &mut R) -> Vec<DoseEvent> {
+    let mut taken = Vec::with_capacity(doses.len());
+    for dose in doses {
+        if rng.gen::<f64>() < adherence.missed_probability {
+            continue;
+        }
+        let mut dose = dose.clone();
+        if rng.gen::<f64>() < adherence.delay_probability {
+            dose.time += rng.gen::<f64>() * adherence.max_delay;
+        }
+        taken.push(dose);
+    }
+    taken.sort_by(|a, b| a.time.total_cmp(&b.time));
+    taken
+}
+
+/// Nominal and actual sampling times, in the order of the actual times.
+/// Actual times are uniform within the window around each nominal time, the
+/// part of it before time 0 left out.
+pub fn sample_times<R: Rng>(sampling: &SamplingConfig, nominal: &[f64], rng: &mut R) -> Vec<(f64, f64)> {
+    let mut samples: Vec<(f64, f64)> = nominal.iter()
+        .map(|&time| {
+            let (lower, upper) = ((time - sampling.window).max(0.0), time + sampling.window);
+            (time, lower + rng.gen::<f64>() * (upper - lower))
+        })
+        .collect();
+    samples.sort_by(|a, b| a.1.total_cmp(&b.1));
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testing::config_with;
+    use crate::config::TrialConfig;
+    use crate::simulation::{PatientResult, Simulator};
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn test_dropout_follows_piecewise_hazard() {
+        let mut rng = StdRng::seed_from_u64(5);
+        // No dropout before 10, then a median of ln 2 / 0.1 ≈ 6.9 afterwards
+        let dropout = DropoutConfig { hazards: vec![(10.0, 0.1), (0.0, 0.0)] };
+        let times: Vec<f64> = (0..4000).map(|_| sample_dropout_time(&dropout, &mut rng).unwrap()).collect();
+        assert!(times.iter().all(|&t| t >= 10.0));
+        let before = times.iter().filter(|&&t| t < 10.0 + 2f64.ln() / 0.1).count() as f64 / times.len() as f64;
+        assert!((before - 0.5).abs() < 0.03);
+
+        let none = DropoutConfig { hazards: vec![(0.0, 0.0)] };
+        assert_eq!(sample_dropout_time(&none, &mut rng), None);
+    }
+
+    #[test]
+    fn test_sample_times_in_order() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let sampling = SamplingConfig { window: 0.4 };
+        for _ in 0..100 {
+            let samples = sample_times(&sampling, &[4.0, 0.0, 1.0], &mut rng);
+            assert_eq!(samples.iter().map(|s| s.0).collect::<Vec<_>>(), [0.0, 1.0, 4.0]);
+            assert!(samples.windows(2).all(|w| w[0].1 < w[1].1));
+            assert!(samples.iter().all(|&(nominal, time)| time >= 0.0 && (time - nominal).abs() <= 0.4));
+        }
+
+        let trial = TrialConfig { sampling: Some(SamplingConfig { window: 0.5 }), ..TrialConfig::default() };
+        assert!(trial.validate(&[4.0, 0.0, 1.0]).is_err());
+        assert!(trial.validate(&[0.0, 2.0]).is_ok());
+    }
+
+    #[test]
+    fn test_trial_simulation_is_reproducible() {
+        let config = config_with(&[r#"{
+            "model": { "parameters": { "V": { "theta": 20.0, "omega": null } } },
+            "dosing": { "times": [0.0, 24.0, 48.0, 72.0] },
+            "simulation": { "time_points": [1.0, 12.0, 36.0, 60.0, 95.0], "lloq": 0.5 },
+            "trial": {
+                "dropout": { "hazards": [[0.0, 0.005]] },
+                "adherence": { "missed_probability": 0.2, "delay_probability": 0.5, "max_delay": 4.0 },
+                "sampling": { "window": 0.5 }
+            }
+        }"#]);
+        let results = Simulator::new(config.clone(), Some(8)).unwrap().simulate_population(50).unwrap();
+
+        let dropouts = results.iter().filter(|r| r.dropout_time.is_some()).count();
+        assert!(dropouts > 5 && dropouts < 45);
+        for result in &results {
+            let end = result.dropout_time.unwrap_or(f64::INFINITY);
+            assert!(result.observations.iter().all(|o| o.time <= end && (o.time - o.nominal_time).abs() <= 0.5));
+            assert!(result.doses.iter().all(|d| d.time <= end));
+            assert!(result.observations.iter().all(|o| o.blq == (o.concentration < 0.5)));
+        }
+        let doses: usize = results.iter().filter(|r| r.dropout_time.is_none()).map(|r| r.doses.len()).sum();
+        let completers = results.len() - dropouts;
+        assert!(doses < 4 * completers);
+        assert!(results.iter().flat_map(|r| &r.doses).any(|d| d.time % 24.0 != 0.0));
+
+        let again = Simulator::new(config, Some(8)).unwrap().simulate_population(50).unwrap();
+        for (a, b) in results.iter().zip(&again) {
+            assert_eq!(a.dropout_time, b.dropout_time);
+            let doses = |r: &PatientResult| r.doses.iter().map(|d| d.time).collect::<Vec<_>>();
+            assert_eq!(doses(a), doses(b));
+            let samples = |r: &PatientResult| r.observations.iter().map(|o| (o.time, o.concentration)).collect::<Vec<_>>();
+            assert_eq!(samples(a), samples(b));
+        }
+    }
+}
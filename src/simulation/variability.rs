@@ -1,5 +1,7 @@
 use rand_distr::{Normal, LogNormal, Distribution};
+use crate::config::BlqMethod;
 use crate::error::{PKError, PKResult};
+use super::Observation;
 
 /// NONMEM-style log-normal variability
 pub fn apply_log_normal_variability<R: rand::Rng>(
@@ -21,52 +23,91 @@ pub fn apply_log_normal_variability<R: rand::Rng>(
     Ok(log_normal.sample(rng))
 }
 
-/// NONMEM-style proportional error model
+/// NONMEM-style proportional error model. Returns the observation, which may
+/// be negative, and the EPS drawn. EPS is drawn whatever the prediction is;
+/// a prediction that is not positive counts as 0 and is observed as 0.
 pub fn apply_proportional_error<R: rand::Rng>(
     predicted: f64,
     proportional_sd: f64,
     rng: &mut R,
-) -> PKResult<f64> {
-    if predicted <= 0.0 {
-        return Ok(0.0);
-    }
-    
+) -> PKResult<(f64, Vec<f64>)> {
     let normal = Normal::new(0.0, proportional_sd)
         .map_err(|_| PKError::Random)?;
     let epsilon = normal.sample(rng);
     
     // Y = F * (1 + EPS(1))
-    let observed = predicted * (1.0 + epsilon);
-    Ok(observed.max(0.0))
+    let observed = predicted.max(0.0) * (1.0 + epsilon);
+    Ok((observed, vec![epsilon]))
 }
 
-/// Combined additive and proportional error model
+/// Additive error model. Returns the observation, which may be negative,
+/// and the EPS drawn. The error applies whatever the prediction is; a
+/// prediction that is not positive counts as 0.
+pub fn apply_additive_error<R: rand::Rng>(
+    predicted: f64,
+    additive_sd: f64,
+    rng: &mut R,
+) -> PKResult<(f64, Vec<f64>)> {
+    let normal = Normal::new(0.0, additive_sd)
+        .map_err(|_| PKError::Random)?;
+    let epsilon = normal.sample(rng);
+    
+    // Y = F + EPS(1)
+    Ok((predicted.max(0.0) + epsilon, vec![epsilon]))
+}
+
+/// Combined additive and proportional error model. Returns the observation,
+/// which may be negative, and the EPS drawn, proportional first as in the
+/// formula below. Both are drawn whatever the prediction is; a prediction
+/// that is not positive counts as 0, leaving only the additive error.
 pub fn apply_combined_error<R: rand::Rng>(
     predicted: f64,
     additive_sd: f64,
     proportional_sd: f64,
     rng: &mut R,
-) -> PKResult<f64> {
-    if predicted <= 0.0 {
-        return Ok(0.0);
-    }
-    
+) -> PKResult<(f64, Vec<f64>)> {
     let normal_add = Normal::new(0.0, additive_sd)
         .map_err(|_| PKError::Random)?;
     let normal_prop = Normal::new(0.0, proportional_sd)
         .map_err(|_| PKError::Random)?;
     
-    let eps1 = normal_add.sample(rng);
-    let eps2 = normal_prop.sample(rng);
+    let eps_add = normal_add.sample(rng);
+    let eps_prop = normal_prop.sample(rng);
     
     // Y = F * (1 + EPS(1)) + EPS(2)
-    let observed = predicted * (1.0 + eps2) + eps1;
-    Ok(observed.max(0.0))
+    let observed = predicted.max(0.0) * (1.0 + eps_prop) + eps_add;
+    Ok((observed, vec![eps_prop, eps_add]))
+}
+
+/// Apply the LLOQ to a subject's observations: flag those below it as BLQ
+/// and report them by `method`. Without an LLOQ every value is kept as
+/// simulated, negative ones included, as with [`BlqMethod::Keep`]; the
+/// configuration requires an LLOQ for [`BlqMethod::HalfLloq`] and
+/// [`BlqMethod::Drop`].
+pub fn censor_blq(observations: &mut Vec<Observation>, lloq: Option<f64>, method: BlqMethod) {
+    let Some(lloq) = lloq else {
+        for obs in observations.iter_mut() {
+            obs.blq = false;
+            obs.censored = false;
+        }
+        return;
+    };
+    for obs in observations.iter_mut() {
+        obs.blq = obs.concentration < lloq;
+        obs.censored = obs.blq && method == BlqMethod::Flag;
+        if obs.blq && method == BlqMethod::HalfLloq {
+            obs.concentration = lloq / 2.0;
+        }
+    }
+    if method == BlqMethod::Drop {
+        observations.retain(|obs| !obs.blq);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::testing::config_with;
     use rand::SeedableRng;
     use rand::rngs::StdRng;
     
@@ -87,8 +128,8 @@ mod tests {
         let predicted = 5.0;
         let prop_sd = 0.1;
         
-        let observed = apply_proportional_error(predicted, prop_sd, &mut rng).unwrap();
-        assert!(observed >= 0.0);
+        let (observed, eps) = apply_proportional_error(predicted, prop_sd, &mut rng).unwrap();
+        assert_eq!(observed, predicted * (1.0 + eps[0]));
     }
     
     #[test]
@@ -98,7 +139,59 @@ mod tests {
         let add_sd = 0.5;
         let prop_sd = 0.1;
         
-        let observed = apply_combined_error(predicted, add_sd, prop_sd, &mut rng).unwrap();
-        assert!(observed >= 0.0);
+        let (observed, eps) = apply_combined_error(predicted, add_sd, prop_sd, &mut rng).unwrap();
+        assert_eq!(observed, predicted * (1.0 + eps[0]) + eps[1]);
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn test_error_below_zero_prediction() {
+        let mut rng = StdRng::seed_from_u64(42);
+        
+        let (observed, eps) = apply_proportional_error(-1e-12, 0.1, &mut rng).unwrap();
+        assert!(eps[0] != 0.0);
+        assert_eq!(observed, 0.0);
+        
+        let (observed, eps) = apply_additive_error(0.0, 0.5, &mut rng).unwrap();
+        assert!(eps[0] != 0.0);
+        assert_eq!(observed, eps[0]);
+        
+        let (observed, eps) = apply_combined_error(-1e-12, 0.5, 0.1, &mut rng).unwrap();
+        assert!(eps.iter().all(|&e| e != 0.0));
+        assert_eq!(observed, eps[1]);
+    }
+    
+    #[test]
+    fn test_blq_methods() {
+        let observations: Vec<Observation> = [(1.0, 2.0), (2.0, 0.3), (3.0, -0.1)].iter()
+            .map(|&(time, concentration)| Observation {
+                time, nominal_time: time, concentration, predicted_concentration: 1.0, eps: Vec::new(),
+                blq: false, censored: false,
+            })
+            .collect();
+        let censored_at = |lloq, method| {
+            let mut observations = observations.clone();
+            censor_blq(&mut observations, lloq, method);
+            observations.iter().map(|o| (o.concentration, o.blq, o.censored)).collect::<Vec<_>>()
+        };
+        let censored = |method| censored_at(Some(0.5), method);
+        
+        assert_eq!(censored(BlqMethod::Flag), [(2.0, false, false), (0.3, true, true), (-0.1, true, true)]);
+        assert_eq!(censored(BlqMethod::HalfLloq), [(2.0, false, false), (0.25, true, false), (0.25, true, false)]);
+        assert_eq!(censored(BlqMethod::Drop), [(2.0, false, false)]);
+        assert_eq!(censored(BlqMethod::Keep), [(2.0, false, false), (0.3, true, false), (-0.1, true, false)]);
+        
+        // Without an LLOQ every value is kept, negative ones included
+        assert_eq!(censored_at(None, BlqMethod::Flag), [(2.0, false, false), (0.3, false, false), (-0.1, false, false)]);
+    }
+    
+    #[test]
+    fn test_half_lloq_and_drop_need_an_lloq() {
+        for method in ["half_lloq", "drop"] {
+            let patch = format!(r#"{{ "simulation": {{ "blq_method": "{}" }} }}"#, method);
+            assert!(config_with(&[&patch]).validate().is_err());
+            let limited = format!(r#"{{ "simulation": {{ "blq_method": "{}", "lloq": 0.1 }} }}"#, method);
+            config_with(&[&limited]).validate().unwrap();
+        }
+        config_with(&[r#"{ "simulation": { "blq_method": "flag" } }"#]).validate().unwrap();
+    }
+}
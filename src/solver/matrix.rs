@@ -0,0 +1,275 @@
+/// Small dense row-major matrix used by the compartmental solver.
+///
+/// PK systems have at most a handful of compartments, so a plain `Vec<f64>`
+/// with straightforward loops is faster than pulling in a linear algebra crate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Matrix {
+    rows: usize,
+    cols: usize,
+    data: Vec<f64>,
+}
+
+impl Matrix {
+    pub fn zeros(rows: usize, cols: usize) -> Self {
+        Self {
+            rows,
+            cols,
+            data: vec![0.0; rows * cols],
+        }
+    }
+
+    pub fn identity(n: usize) -> Self {
+        let mut m = Self::zeros(n, n);
+        for i in 0..n {
+            m.set(i, i, 1.0);
+        }
+        m
+    }
+
+    pub fn from_rows(rows: &[Vec<f64>]) -> Self {
+        let n_rows = rows.len();
+        let n_cols = rows.first().map(|r| r.len()).unwrap_or(0);
+        let mut m = Self::zeros(n_rows, n_cols);
+        for (i, row) in rows.iter().enumerate() {
+            for (j, &value) in row.iter().enumerate() {
+                m.set(i, j, value);
+            }
+        }
+        m
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn get(&self, i: usize, j: usize) -> f64 {
+        self.data[i * self.cols + j]
+    }
+
+    pub fn set(&mut self, i: usize, j: usize, value: f64) {
+        self.data[i * self.cols + j] = value;
+    }
+
+    pub fn add(&self, other: &Matrix) -> Matrix {
+        let data = self.data.iter().zip(&other.data).map(|(a, b)| a + b).collect();
+        Matrix { rows: self.rows, cols: self.cols, data }
+    }
+
+    pub fn sub(&self, other: &Matrix) -> Matrix {
+        let data = self.data.iter().zip(&other.data).map(|(a, b)| a - b).collect();
+        Matrix { rows: self.rows, cols: self.cols, data }
+    }
+
+    pub fn scale(&self, factor: f64) -> Matrix {
+        let data = self.data.iter().map(|v| v * factor).collect();
+        Matrix { rows: self.rows, cols: self.cols, data }
+    }
+
+    pub fn mul(&self, other: &Matrix) -> Matrix {
+        let mut out = Matrix::zeros(self.rows, other.cols);
+        for i in 0..self.rows {
+            for k in 0..self.cols {
+                let a = self.get(i, k);
+                if a == 0.0 {
+                    continue;
+                }
+                for j in 0..other.cols {
+                    out.data[i * other.cols + j] += a * other.get(k, j);
+                }
+            }
+        }
+        out
+    }
+
+    pub fn mul_vec(&self, v: &[f64]) -> Vec<f64> {
+        (0..self.rows)
+            .map(|i| (0..self.cols).map(|j| self.get(i, j) * v[j]).sum())
+            .collect()
+    }
+
+    /// Maximum absolute row sum (induced infinity norm).
+    pub fn norm_inf(&self) -> f64 {
+        (0..self.rows)
+            .map(|i| (0..self.cols).map(|j| self.get(i, j).abs()).sum::<f64>())
+            .fold(0.0, f64::max)
+    }
+
+    /// Solve `self * X = rhs` by LU decomposition with partial pivoting.
+    /// Returns `None` when the matrix is numerically singular.
+    pub fn solve(&self, rhs: &Matrix) -> Option<Matrix> {
+        let n = self.rows;
+        let mut a = self.clone();
+        let mut b = rhs.clone();
+
+        for col in 0..n {
+            let pivot = (col..n)
+                .max_by(|&x, &y| a.get(x, col).abs().total_cmp(&a.get(y, col).abs()))?;
+            if a.get(pivot, col).abs() < 1e-300 {
+                return None;
+            }
+            if pivot != col {
+                a.swap_rows(pivot, col);
+                b.swap_rows(pivot, col);
+            }
+
+            let diag = a.get(col, col);
+            for row in (col + 1)..n {
+                let factor = a.get(row, col) / diag;
+                if factor == 0.0 {
+                    continue;
+                }
+                for k in col..n {
+                    let value = a.get(row, k) - factor * a.get(col, k);
+                    a.set(row, k, value);
+                }
+                for k in 0..b.cols {
+                    let value = b.get(row, k) - factor * b.get(col, k);
+                    b.set(row, k, value);
+                }
+            }
+        }
+
+        let mut x = Matrix::zeros(n, b.cols);
+        for k in 0..b.cols {
+            for row in (0..n).rev() {
+                let mut sum = b.get(row, k);
+                for j in (row + 1)..n {
+                    sum -= a.get(row, j) * x.get(j, k);
+                }
+                x.set(row, k, sum / a.get(row, row));
+            }
+        }
+        Some(x)
+    }
+
+    /// Lower-triangular Cholesky factor `L` with `self = L Lᵀ`; `None` unless
+    /// the matrix is symmetric positive definite.
+    pub fn cholesky(&self) -> Option<Matrix> {
+        let n = self.rows;
+        let mut l = Matrix::zeros(n, n);
+        for i in 0..n {
+            for j in 0..=i {
+                let sum: f64 = (0..j).map(|k| l.get(i, k) * l.get(j, k)).sum();
+                if i == j {
+                    let d = self.get(i, i) - sum;
+                    if d <= 0.0 || !d.is_finite() {
+                        return None;
+                    }
+                    l.set(i, i, d.sqrt());
+                } else {
+                    l.set(i, j, (self.get(i, j) - sum) / l.get(j, j));
+                }
+            }
+        }
+        Some(l)
+    }
+
+    /// Log-determinant of a symmetric positive definite matrix.
+    pub fn log_det_spd(&self) -> Option<f64> {
+        let l = self.cholesky()?;
+        Some((0..self.rows).map(|i| 2.0 * l.get(i, i).ln()).sum())
+    }
+
+    pub fn inverse(&self) -> Option<Matrix> {
+        self.solve(&Matrix::identity(self.rows))
+    }
+
+    fn swap_rows(&mut self, r1: usize, r2: usize) {
+        for j in 0..self.cols {
+            self.data.swap(r1 * self.cols + j, r2 * self.cols + j);
+        }
+    }
+
+    /// Matrix exponential by scaling and squaring with a diagonal Padé(6)
+    /// approximant. Unlike eigen-decomposition formulas this has no
+    /// singularities when rate constants coincide.
+    pub fn expm(&self) -> Matrix {
+        const PADE_COEFFS: [f64; 7] = [
+            1.0,
+            0.5,
+            5.0 / 44.0,
+            1.0 / 66.0,
+            1.0 / 792.0,
+            1.0 / 15840.0,
+            1.0 / 665280.0,
+        ];
+
+        let n = self.rows;
+        let norm = self.norm_inf();
+        let squarings = if norm > 0.5 {
+            (norm / 0.5).log2().ceil() as i32
+        } else {
+            0
+        };
+        let a = self.scale(0.5f64.powi(squarings));
+
+        let mut numerator = Matrix::identity(n);
+        let mut denominator = Matrix::identity(n);
+        let mut power = Matrix::identity(n);
+        for (k, &c) in PADE_COEFFS.iter().enumerate().skip(1) {
+            power = power.mul(&a);
+            let term = power.scale(c);
+            numerator = numerator.add(&term);
+            if k % 2 == 0 {
+                denominator = denominator.add(&term);
+            } else {
+                denominator = denominator.sub(&term);
+            }
+        }
+
+        let mut result = denominator
+            .solve(&numerator)
+            .unwrap_or_else(|| Matrix::identity(n));
+        for _ in 0..squarings {
+            result = result.mul(&result);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_expm_diagonal() {
+        let m = Matrix::from_rows(&[vec![-0.5, 0.0], vec![0.0, -2.0]]);
+        let e = m.scale(3.0).expm();
+        assert_relative_eq!(e.get(0, 0), (-1.5f64).exp(), epsilon = 1e-12);
+        assert_relative_eq!(e.get(1, 1), (-6.0f64).exp(), epsilon = 1e-12);
+        assert_relative_eq!(e.get(0, 1), 0.0, epsilon = 1e-12);
+    }
+
+    #[test]
+    fn test_expm_defective_matrix() {
+        // Jordan block: exp([[-k, 0], [k, -k]] t) has a t*exp(-kt) term
+        let k = 0.7;
+        let t = 2.5;
+        let m = Matrix::from_rows(&[vec![-k, 0.0], vec![k, -k]]).scale(t);
+        let e = m.expm();
+        assert_relative_eq!(e.get(1, 0), k * t * (-k * t).exp(), epsilon = 1e-12);
+    }
+
+    #[test]
+    fn test_solve() {
+        let a = Matrix::from_rows(&[vec![2.0, 1.0], vec![1.0, 3.0]]);
+        let b = Matrix::from_rows(&[vec![3.0], vec![5.0]]);
+        let x = a.solve(&b).unwrap();
+        assert_relative_eq!(x.get(0, 0), 0.8, epsilon = 1e-12);
+        assert_relative_eq!(x.get(1, 0), 1.4, epsilon = 1e-12);
+    }
+
+    #[test]
+    fn test_cholesky() {
+        let a = Matrix::from_rows(&[vec![4.0, 2.0], vec![2.0, 3.0]]);
+        let l = a.cholesky().unwrap();
+        assert_relative_eq!(l.get(1, 0), 1.0, epsilon = 1e-12);
+        assert_relative_eq!(a.log_det_spd().unwrap(), 8.0f64.ln(), epsilon = 1e-12);
+        assert!(Matrix::from_rows(&[vec![1.0, 2.0], vec![2.0, 1.0]]).cholesky().is_none());
+    }
+}
@@ -0,0 +1,372 @@
+pub mod matrix;
+
+use crate::models::{DoseEvent, DoseRoute, ModelParameters, CENTRAL, DEPOT};
+use crate::error::{PKError, PKResult};
+use std::collections::HashMap;
+
+pub use matrix::Matrix;
+
+/// Individual parameter values that take effect from `time` onwards.
+#[derive(Debug, Clone)]
+pub struct ParameterChange {
+    pub time: f64,
+    pub parameters: HashMap<String, f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum EventKind {
+    // Ordering defines precedence for events sharing a time stamp
+    InfusionEnd,
+    ParameterChange,
+    Dose,
+    Observation,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Event {
+    time: f64,
+    kind: EventKind,
+    index: usize,
+}
+
+/// Event-ordered analytical solver for linear compartment models.
+///
+/// Compartment amounts are advanced from one event to the next with the exact
+/// transition `A(t + dt) = exp(K dt) A(t) + integral of the infusion input`, so
+/// each dose is handled once instead of being re-summed for every observation,
+/// and parameters may change between events.
+#[derive(Debug, Clone)]
+pub struct EventSolver {
+    params: ModelParameters,
+    propagators: HashMap<(u64, u64), Matrix>,
+}
+
+impl EventSolver {
+    pub fn new(params: ModelParameters) -> Self {
+        Self {
+            params,
+            propagators: HashMap::new(),
+        }
+    }
+
+    /// Concentrations in the central compartment at `times` (any order).
+    pub fn solve(&mut self, doses: &[DoseEvent], times: &[f64]) -> PKResult<Vec<f64>> {
+        self.solve_with_changes(doses, &[], times)
+    }
+
+    /// Same as [`EventSolver::solve`], applying parameter changes at their
+    /// event times. The solver's own parameters are left untouched.
+    pub fn solve_with_changes(
+        &mut self,
+        doses: &[DoseEvent],
+        changes: &[ParameterChange],
+        times: &[f64],
+    ) -> PKResult<Vec<f64>> {
+        let profile = self.solve_profile(doses, changes, times)?;
+        Ok(profile.iter().map(|point| point.concentration).collect())
+    }
+
+    /// Concentration, cumulative AUC and concentration slope at `times`.
+    /// The AUC is integrated exactly alongside the amounts, so it does not
+    /// depend on how densely `times` samples the profile.
+    pub fn solve_profile(
+        &mut self,
+        doses: &[DoseEvent],
+        changes: &[ParameterChange],
+        times: &[f64],
+    ) -> PKResult<Vec<ProfilePoint>> {
+        let mut profile = vec![ProfilePoint::default(); times.len()];
+        self.run(doses, changes, times, |index, segment| profile[index] = segment.point())?;
+        Ok(profile)
+    }
+
+    /// States just after every dose at each of `times`, from which
+    /// [`SegmentState::advance`] continues the solution up to the next event
+    /// without replaying the dose history.
+    pub fn segment_states(&mut self, doses: &[DoseEvent], times: &[f64]) -> PKResult<Vec<SegmentState>> {
+        let mut segments = vec![None; times.len()];
+        self.run(doses, &[], times, |index, state| segments[index] = Some(state.clone()))?;
+        Ok(segments.into_iter().flatten().collect())
+    }
+
+    /// Walk the events in time order and hand the state at each observation
+    /// to `observe` together with the observation's index in `times`.
+    fn run<F: FnMut(usize, &SegmentState)>(
+        &mut self,
+        doses: &[DoseEvent],
+        changes: &[ParameterChange],
+        times: &[f64],
+        mut observe: F,
+    ) -> PKResult<()> {
+        let mut events = Vec::with_capacity(doses.len() * 2 + changes.len() + times.len());
+        for (index, dose) in doses.iter().enumerate() {
+            events.push(Event { time: dose.time, kind: EventKind::Dose, index });
+            if dose.route == DoseRoute::IvInfusion {
+                let duration = infusion_duration(dose)?;
+                events.push(Event { time: dose.time + duration, kind: EventKind::InfusionEnd, index });
+            }
+        }
+        for (index, change) in changes.iter().enumerate() {
+            events.push(Event { time: change.time, kind: EventKind::ParameterChange, index });
+        }
+        for (index, &time) in times.iter().enumerate() {
+            events.push(Event { time, kind: EventKind::Observation, index });
+        }
+        events.sort_by(|a, b| a.time.total_cmp(&b.time).then(a.kind.cmp(&b.kind)));
+
+        let mut params = self.params.clone();
+        let mut active_infusions = 0usize;
+        let mut segment = SegmentState {
+            time: events.first().map(|e| e.time).unwrap_or(0.0).min(0.0),
+            rates: params.rate_matrix()?,
+            v1: params.v1,
+            // Compartment amounts followed by the cumulative AUC
+            state: vec![0.0; params.state_size() + 1],
+            infusion_rate: 0.0,
+        };
+
+        for event in events {
+            let dt = event.time - segment.time;
+            if dt > 0.0 && (segment.infusion_rate > 0.0 || segment.state.iter().any(|&a| a != 0.0)) {
+                segment.state = self.advance(&segment, dt);
+            }
+            segment.time = event.time;
+
+            match event.kind {
+                EventKind::Dose => {
+                    let dose = &doses[event.index];
+                    match dose.route {
+                        DoseRoute::Oral => {
+                            params.ka()?;
+                            segment.state[DEPOT] += dose.amount;
+                        },
+                        DoseRoute::IvBolus => segment.state[CENTRAL] += dose.amount,
+                        DoseRoute::IvInfusion => {
+                            segment.infusion_rate += dose.amount / infusion_duration(dose)?;
+                            active_infusions += 1;
+                        },
+                    }
+                },
+                EventKind::InfusionEnd => {
+                    let dose = &doses[event.index];
+                    active_infusions -= 1;
+                    segment.infusion_rate = if active_infusions == 0 {
+                        0.0
+                    } else {
+                        segment.infusion_rate - dose.amount / infusion_duration(dose)?
+                    };
+                },
+                EventKind::ParameterChange => {
+                    for (name, &value) in &changes[event.index].parameters {
+                        params.set_parameter(name, value)?;
+                    }
+                    segment.rates = params.rate_matrix()?;
+                    segment.v1 = params.v1;
+                    self.propagators.clear();
+                },
+                EventKind::Observation => observe(event.index, &segment),
+            }
+        }
+
+        if !changes.is_empty() {
+            self.propagators.clear();
+        }
+
+        Ok(())
+    }
+
+    fn advance(&mut self, segment: &SegmentState, dt: f64) -> Vec<f64> {
+        let propagator = self.propagators
+            .entry((dt.to_bits(), segment.infusion_rate.to_bits()))
+            .or_insert_with(|| transition_with_auc(&segment.rates, segment.v1, segment.infusion_rate, dt));
+        propagate(propagator, &segment.state)
+    }
+}
+
+/// Model state at one time between events: the amounts with the cumulative
+/// AUC, the infusion rate in effect and the rate matrix of the parameters.
+#[derive(Debug, Clone)]
+pub struct SegmentState {
+    pub time: f64,
+    rates: Matrix,
+    v1: f64,
+    state: Vec<f64>,
+    infusion_rate: f64,
+}
+
+impl SegmentState {
+    /// Solution at this state's time.
+    pub fn point(&self) -> ProfilePoint {
+        let n = self.rates.rows();
+        let inflow: f64 = (0..n).map(|j| self.rates.get(CENTRAL, j) * self.state[j]).sum::<f64>() + self.infusion_rate;
+        ProfilePoint {
+            concentration: (self.state[CENTRAL] / self.v1).max(0.0),
+            cumulative_auc: self.state[n],
+            slope: inflow / self.v1,
+        }
+    }
+
+    /// The state `dt` later, assuming no dose, infusion end or parameter
+    /// change falls within `dt`.
+    pub fn advance(&self, dt: f64) -> SegmentState {
+        self.steps(dt, 1).pop().expect("one step")
+    }
+
+    /// The states after each of `n` steps of `dt`, sharing one transition
+    /// matrix. No event may fall within the `n * dt` covered.
+    pub fn steps(&self, dt: f64, n: usize) -> Vec<SegmentState> {
+        let propagator = transition_with_auc(&self.rates, self.v1, self.infusion_rate, dt);
+        let mut state = self.state.clone();
+        (1..=n)
+            .map(|step| {
+                state = propagate(&propagator, &state);
+                SegmentState {
+                    time: self.time + dt * step as f64,
+                    rates: self.rates.clone(),
+                    v1: self.v1,
+                    state: state.clone(),
+                    infusion_rate: self.infusion_rate,
+                }
+            })
+            .collect()
+    }
+}
+
+fn propagate(propagator: &Matrix, state: &[f64]) -> Vec<f64> {
+    let mut augmented = state.to_vec();
+    augmented.push(1.0);
+    let mut next = propagator.mul_vec(&augmented);
+    next.pop();
+    next
+}
+
+/// Solution of the model at one time point.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ProfilePoint {
+    pub concentration: f64,
+    /// AUC from the start of the event history to this time
+    pub cumulative_auc: f64,
+    /// dC/dt just after any event at this time
+    pub slope: f64,
+}
+
+/// [`ModelParameters::transition_matrix`] for the rate matrix `k` extended
+/// with an AUC state, `dAUC/dt = A_central / V1`, placed before the constant
+/// input state.
+fn transition_with_auc(k: &Matrix, v1: f64, rate: f64, dt: f64) -> Matrix {
+    let n = k.rows();
+    let mut augmented = Matrix::zeros(n + 2, n + 2);
+    for i in 0..n {
+        for j in 0..n {
+            augmented.set(i, j, k.get(i, j));
+        }
+    }
+    augmented.set(n, CENTRAL, 1.0 / v1);
+    augmented.set(CENTRAL, n + 1, rate);
+    augmented.scale(dt).expm()
+}
+
+/// Duration of an infusion, 1 time unit if not given; it must be positive.
+pub(crate) fn infusion_duration(dose: &DoseEvent) -> PKResult<f64> {
+    let duration = dose.duration.unwrap_or(1.0);
+    if duration <= 0.0 {
+        return Err(PKError::InvalidDosing(
+            "Infusion duration must be positive".to_string()
+        ));
+    }
+    Ok(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::create_model;
+    use approx::assert_relative_eq;
+
+    fn params(values: &[(&str, f64)], compartments: u8) -> ModelParameters {
+        let mut model = create_model(compartments).unwrap();
+        let map: HashMap<String, f64> = values.iter()
+            .map(|(k, v)| (k.to_string(), *v))
+            .collect();
+        model.set_parameters(&map).unwrap();
+        model.model_parameters().clone()
+    }
+
+    fn dose(time: f64, amount: f64, route: DoseRoute, duration: Option<f64>) -> DoseEvent {
+        DoseEvent { time, amount, route, duration }
+    }
+
+    #[test]
+    fn test_matches_closed_form_multiple_dose() {
+        let one_cmt = vec![("CL", 2.0), ("V", 10.0), ("KA", 1.2)];
+        let two_cmt = vec![("CL", 2.0), ("V1", 10.0), ("Q2", 1.0), ("V2", 5.0), ("KA", 1.2)];
+        let three_cmt = vec![
+            ("CL", 2.0), ("V1", 10.0), ("Q2", 1.0), ("V2", 5.0), ("Q3", 0.5), ("V3", 3.0), ("KA", 1.2),
+        ];
+        let mut cases = Vec::new();
+        for route in [DoseRoute::Oral, DoseRoute::IvBolus, DoseRoute::IvInfusion] {
+            cases.push((1, &one_cmt, route.clone()));
+            cases.push((2, &two_cmt, route.clone()));
+            cases.push((3, &three_cmt, route));
+        }
+        let times: Vec<f64> = (0..=96).map(|t| t as f64 * 0.5).collect();
+
+        for (compartments, values, route) in cases {
+            let mut model = create_model(compartments).unwrap();
+            let map = values.iter().map(|(k, v)| (k.to_string(), *v)).collect();
+            model.set_parameters(&map).unwrap();
+            let doses: Vec<_> = [0.0, 12.0, 24.0, 36.0].iter()
+                .map(|&t| dose(t, 100.0, route.clone(), Some(2.0)))
+                .collect();
+
+            let mut solver = EventSolver::new(params(values, compartments));
+            let solved = solver.solve(&doses, &times).unwrap();
+
+            for (i, &t) in times.iter().enumerate() {
+                let history: Vec<_> = doses.iter().filter(|d| d.time <= t).cloned().collect();
+                let expected = model.calculate_concentration(t, &history).unwrap();
+                assert_relative_eq!(solved[i], expected, epsilon = 1e-8, max_relative = 1e-8);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parameter_change_at_event_boundary() {
+        let mut solver = EventSolver::new(params(&[("CL", 2.0), ("V", 10.0)], 1));
+        let doses = [dose(0.0, 100.0, DoseRoute::IvBolus, None)];
+        let changes = [ParameterChange {
+            time: 5.0,
+            parameters: HashMap::from([("CL".to_string(), 4.0)]),
+        }];
+
+        let conc = solver.solve_with_changes(&doses, &changes, &[5.0, 10.0]).unwrap();
+        let c5 = 10.0 * (-0.2f64 * 5.0).exp();
+        assert_relative_eq!(conc[0], c5, epsilon = 1e-10);
+        assert_relative_eq!(conc[1], c5 * (-0.4f64 * 5.0).exp(), epsilon = 1e-10);
+
+        // Solver parameters are restored after the run
+        let conc = solver.solve(&doses, &[10.0]).unwrap();
+        assert_relative_eq!(conc[0], 10.0 * (-0.2f64 * 10.0).exp(), epsilon = 1e-10);
+    }
+
+    #[test]
+    fn test_unsorted_observation_times() {
+        let mut solver = EventSolver::new(params(&[("CL", 2.0), ("V", 10.0)], 1));
+        let doses = [dose(0.0, 100.0, DoseRoute::IvBolus, None)];
+        let conc = solver.solve(&doses, &[4.0, 0.0, 2.0]).unwrap();
+        assert_relative_eq!(conc[1], 10.0, epsilon = 1e-10);
+        assert!(conc[0] < conc[2]);
+    }
+
+    #[test]
+    fn test_missing_structural_parameters_are_errors() {
+        let mut solver = EventSolver::new(params(&[("CL", 2.0), ("V", 10.0)], 1));
+        let oral = [dose(0.0, 100.0, DoseRoute::Oral, None)];
+        assert!(solver.solve(&oral, &[1.0]).is_err());
+
+        let mut peripheral = params(&[("CL", 2.0), ("V", 10.0)], 1);
+        peripheral.q2 = Some(1.0);
+        let bolus = [dose(0.0, 100.0, DoseRoute::IvBolus, None)];
+        assert!(EventSolver::new(peripheral).solve(&bolus, &[1.0]).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use crate::error::{PKError, PKResult};
+
+/// Exponents of the base dimensions of a unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Dimensions {
+    pub mass: i8,
+    pub substance: i8,
+    pub volume: i8,
+    pub time: i8,
+}
+
+impl Dimensions {
+    pub const MASS: Self = Self { mass: 1, substance: 0, volume: 0, time: 0 };
+    pub const SUBSTANCE: Self = Self { mass: 0, substance: 1, volume: 0, time: 0 };
+    pub const VOLUME: Self = Self { mass: 0, substance: 0, volume: 1, time: 0 };
+    pub const TIME: Self = Self { mass: 0, substance: 0, volume: 0, time: 1 };
+    pub const NONE: Self = Self { mass: 0, substance: 0, volume: 0, time: 0 };
+
+    fn combine(self, other: Self, sign: i8) -> Self {
+        Self {
+            mass: self.mass + sign * other.mass,
+            substance: self.substance + sign * other.substance,
+            volume: self.volume + sign * other.volume,
+            time: self.time + sign * other.time,
+        }
+    }
+
+    fn pow(self, exponent: i8) -> Self {
+        Self {
+            mass: self.mass * exponent,
+            substance: self.substance * exponent,
+            volume: self.volume * exponent,
+            time: self.time * exponent,
+        }
+    }
+}
+
+/// A parsed unit: its dimensions and its size relative to the base units
+/// g, mol, L and h.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unit {
+    pub factor: f64,
+    pub dimensions: Dimensions,
+}
+
+impl Unit {
+    /// Parse a unit expression such as `mg`, `ng/mL`, `mL/min`, `1/h`,
+    /// `h^-1` or `mg/L*h`. Factors combine left to right, so `mg/L*h` is
+    /// mg·h/L.
+    pub fn parse(text: &str) -> PKResult<Self> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Ok(Self { factor: 1.0, dimensions: Dimensions::NONE });
+        }
+
+        let mut unit = Self { factor: 1.0, dimensions: Dimensions::NONE };
+        let mut sign = 1i8;
+        let mut term = String::new();
+        for c in text.chars().chain(std::iter::once('\0')) {
+            match c {
+                '*' | '·' | '/' | '\0' => {
+                    let factor = parse_factor(term.trim(), text)?;
+                    unit = Self {
+                        factor: unit.factor * factor.factor.powi(sign as i32),
+                        dimensions: unit.dimensions.combine(factor.dimensions, sign),
+                    };
+                    sign = if c == '/' { -1 } else { 1 };
+                    term.clear();
+                },
+                _ => term.push(c),
+            }
+        }
+
+        Ok(unit)
+    }
+
+    /// Factor converting a value in `self` to `target`; the dimensions must
+    /// agree.
+    pub fn conversion_factor(&self, target: &Unit) -> PKResult<f64> {
+        if self.dimensions != target.dimensions {
+            return Err(PKError::Validation(format!(
+                "Incompatible units: {:?} cannot be converted to {:?}",
+                self.dimensions, target.dimensions
+            )));
+        }
+        Ok(self.factor / target.factor)
+    }
+}
+
+/// Factor converting a value from unit `from` to unit `to`.
+pub fn convert(from: &str, to: &str) -> PKResult<f64> {
+    Unit::parse(from)?.conversion_factor(&Unit::parse(to)?)
+        .map_err(|_| PKError::Validation(format!("Cannot convert {} to {}", from, to)))
+}
+
+/// Column or axis label with the unit appended, e.g. `CMAX (ng/mL)`.
+pub fn label(name: &str, unit: &str) -> String {
+    if unit.is_empty() {
+        name.to_string()
+    } else {
+        format!("{} ({})", name, unit)
+    }
+}
+
+fn parse_factor(term: &str, text: &str) -> PKResult<Unit> {
+    let invalid = || PKError::Validation(format!("Invalid unit '{}'", text));
+
+    let (symbol, exponent) = match term.split_once('^') {
+        Some((symbol, exponent)) => (symbol, exponent.trim().parse::<i8>().map_err(|_| invalid())?),
+        None => (term, 1),
+    };
+
+    let (factor, dimensions): (f64, Dimensions) = match symbol.trim() {
+        "1" => (1.0, Dimensions::NONE),
+        "kg" => (1e3, Dimensions::MASS),
+        "g" => (1.0, Dimensions::MASS),
+        "mg" => (1e-3, Dimensions::MASS),
+        "ug" | "µg" | "mcg" => (1e-6, Dimensions::MASS),
+        "ng" => (1e-9, Dimensions::MASS),
+        "pg" => (1e-12, Dimensions::MASS),
+        "mol" => (1.0, Dimensions::SUBSTANCE),
+        "mmol" => (1e-3, Dimensions::SUBSTANCE),
+        "umol" | "µmol" => (1e-6, Dimensions::SUBSTANCE),
+        "nmol" => (1e-9, Dimensions::SUBSTANCE),
+        "pmol" => (1e-12, Dimensions::SUBSTANCE),
+        "L" | "l" => (1.0, Dimensions::VOLUME),
+        "dL" | "dl" => (1e-1, Dimensions::VOLUME),
+        "mL" | "ml" => (1e-3, Dimensions::VOLUME),
+        "uL" | "µL" | "ul" => (1e-6, Dimensions::VOLUME),
+        "s" | "sec" => (1.0 / 3600.0, Dimensions::TIME),
+        "min" => (1.0 / 60.0, Dimensions::TIME),
+        "h" | "hr" => (1.0, Dimensions::TIME),
+        "d" | "day" => (24.0, Dimensions::TIME),
+        "wk" | "week" => (168.0, Dimensions::TIME),
+        _ => return Err(invalid()),
+    };
+
+    Ok(Unit {
+        factor: factor.powi(exponent as i32),
+        dimensions: dimensions.pow(exponent),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testing::config_with;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_conversions() {
+        assert_relative_eq!(convert("mg/L", "ng/mL").unwrap(), 1000.0, max_relative = 1e-12);
+        assert_relative_eq!(convert("mL/min", "L/h").unwrap(), 0.06, max_relative = 1e-12);
+        assert_relative_eq!(convert("1/min", "h^-1").unwrap(), 60.0, max_relative = 1e-12);
+        assert_relative_eq!(convert("mg/L*h", "ug*h/mL").unwrap(), 1.0, max_relative = 1e-12);
+    }
+
+    #[test]
+    fn test_dimension_mismatch() {
+        assert!(convert("L/h", "L").is_err());
+        assert!(convert("mg", "mmol").is_err());
+        assert!(Unit::parse("furlong").is_err());
+        assert_eq!(Unit::parse("mL/min").unwrap().dimensions, Dimensions {
+            volume: 1, time: -1, ..Dimensions::NONE
+        });
+    }
+
+    /// Deterministic CL and V observed at 0 and 2 h; tests set CL's value and unit.
+    const STUDY: &str = r#"{
+        "model": { "parameters": { "CL": { "omega": null }, "V": { "omega": null } } },
+        "simulation": { "time_points": [0.0, 2.0], "error_model": { "type": "additive", "sigma": 1e-12 } }
+    }"#;
+
+    #[test]
+    fn test_parameter_units_converted_to_model_units() {
+        let mut config = config_with(&[STUDY, r#"{ "model": { "parameters": {
+            "CL": { "theta": 50.0, "bounds": [10.0, 100.0], "unit": "mL/min" }
+        } } }"#]);
+        config.convert_parameter_units().unwrap();
+        let cl = &config.model.parameters["CL"];
+        assert_relative_eq!(cl.theta, 3.0, max_relative = 1e-12);
+        assert_relative_eq!(cl.bounds.unwrap().1, 6.0, max_relative = 1e-12);
+
+        let mut config = config_with(&[STUDY, r#"{ "model": { "parameters": { "CL": { "theta": 50.0, "unit": "mL" } } } }"#]);
+        assert!(config.convert_parameter_units().is_err());
+        assert!(config_with(&[STUDY, r#"{ "units": { "volume": "mg" } }"#]).validate().is_err());
+    }
+
+    #[test]
+    fn test_concentrations_in_output_unit() {
+        let config = config_with(&[STUDY, r#"{ "units": { "concentration": "ng/mL" } }"#]);
+        let mut simulator = crate::simulation::Simulator::new(config, Some(1)).unwrap();
+        let doses = [crate::models::DoseEvent {
+            time: 0.0, amount: 100.0, route: crate::models::DoseRoute::IvBolus, duration: None,
+        }];
+        let result = simulator.simulate_subject(1, None, &doses, &[0.0, 2.0]).unwrap();
+        // 100 mg in 10 L is 10 mg/L = 10000 ng/mL
+        assert_relative_eq!(result.observations[0].predicted_concentration, 10000.0, max_relative = 1e-9);
+    }
+}